@@ -0,0 +1,95 @@
+// Time from a raw evdev event entering the reader pipeline to the InputEvent
+// it produces leaving the channel, using the simulated source in place of a
+// device. Run with `cargo bench --bench input`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use evdev::{EventType, Key, RelativeAxisType};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::{broadcast, RwLock};
+
+use mirage_host::config::Config;
+use mirage_host::input::{InputControl, InputEvent, MouseButtons, MouseState, SimulatedSource};
+
+// Events per coalesced burst, about what a 1000 Hz mouse sends in the default window
+const BURST: usize = 4;
+
+struct Pipeline {
+    source: SimulatedSource,
+    events: broadcast::Receiver<InputEvent>,
+}
+
+/// A fresh reader on a 1920x1080 screen with the cursor in the middle, far
+/// from any edge
+fn pipeline(motion_coalesce_ms: u32) -> Pipeline {
+    let mut config = Config::default();
+    config.input.motion_coalesce_ms = motion_coalesce_ms;
+
+    let (event_tx, events) = broadcast::channel(1024);
+    let mouse_state = MouseState {
+        x: 960.0,
+        y: 540.0,
+        buttons: MouseButtons::default(),
+        screen_width: 1920,
+        screen_height: 1080,
+    };
+    let source = SimulatedSource::new(
+        event_tx,
+        Arc::new(RwLock::new(mouse_state)),
+        Arc::new(parking_lot::RwLock::new(config)),
+        InputControl::default(),
+    );
+    Pipeline { source, events }
+}
+
+fn motion(delta: i32) -> evdev::InputEvent {
+    evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, delta)
+}
+
+fn bench_input(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Failed to start the tokio runtime");
+    let mut group = c.benchmark_group("input");
+
+    group.bench_function("motion", |b| {
+        b.to_async(&runtime).iter_batched(
+            || pipeline(0),
+            |mut pipeline| async move {
+                pipeline.source.process(motion(8)).await;
+                black_box(pipeline.events.try_recv().expect("Motion was not forwarded"));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    // A window long enough that only the flush releases the burst
+    group.bench_function("motion_coalesced", |b| {
+        b.to_async(&runtime).iter_batched(
+            || pipeline(60_000),
+            |mut pipeline| async move {
+                for _ in 0..BURST {
+                    pipeline.source.process(motion(8)).await;
+                }
+                pipeline.source.flush_motion();
+                black_box(pipeline.events.try_recv().expect("Coalesced motion was not forwarded"));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("button", |b| {
+        b.to_async(&runtime).iter_batched(
+            || pipeline(0),
+            |mut pipeline| async move {
+                let press = evdev::InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 1);
+                pipeline.source.process(press).await;
+                black_box(pipeline.events.try_recv().expect("Button press was not forwarded"));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_input);
+criterion_main!(benches);
@@ -1,10 +1,12 @@
-use std::path::PathBuf;
-
 fn main() {
     let proto_file = "../common/proto/mirage.proto";
     
     println!("cargo:rerun-if-changed={}", proto_file);
 
+    // prost-build doesn't create the output directory itself
+    std::fs::create_dir_all("src/proto")
+        .unwrap_or_else(|e| panic!("Failed to create src/proto: {}", e));
+
     prost_build::Config::new()
         .out_dir("src/proto")
         .compile_protos(&[proto_file], &["../common/proto"])
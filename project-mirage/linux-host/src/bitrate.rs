@@ -0,0 +1,276 @@
+// Adapts the encoder bitrate to the link between `streaming.min_bitrate_mbps`
+// and `streaming.max_bitrate_mbps`. AIMD: creep up while the link keeps up,
+// back off hard on loss or a growing round trip. All streams of a session go
+// over the same link, so one controller per session sets the total and the
+// streams split it.
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::capture::Encoder;
+use crate::config::StreamingConfig;
+use crate::session::SessionManager;
+use crate::transport::PathStats;
+
+// How often the link is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Added to the target per sample while the link is healthy
+const INCREASE_KBPS: u32 = 250;
+
+// The target is multiplied by this on congestion
+const DECREASE_FACTOR: f64 = 0.7;
+
+// Share of packets lost in a sample that counts as congestion
+const LOSS_THRESHOLD: f64 = 0.02;
+
+// Round trip above the lowest one seen that counts as queueing
+const QUEUEING_DELAY: Duration = Duration::from_millis(40);
+
+pub struct BitrateController {
+    min_kbps: u32,
+    max_kbps: u32,
+    target_kbps: u32,
+    // Lowest round trip seen, taken as the link without queueing
+    base_rtt: Option<Duration>,
+    last: Option<PathStats>,
+}
+
+impl BitrateController {
+    /// Start at `bitrate_mbps`, kept within the configured bounds
+    pub fn new(streaming: &StreamingConfig) -> Self {
+        let min_kbps = streaming.min_bitrate_mbps.saturating_mul(1000);
+        let max_kbps = streaming.max_bitrate_mbps.saturating_mul(1000).max(min_kbps);
+        Self {
+            min_kbps,
+            max_kbps,
+            target_kbps: streaming.bitrate_mbps.saturating_mul(1000).clamp(min_kbps, max_kbps),
+            base_rtt: None,
+            last: None,
+        }
+    }
+
+    /// The bitrate to encode at, in kbit/s
+    pub fn target(&self) -> u32 {
+        self.target_kbps
+    }
+
+    /// Take one sample of the link, returning the new target if it changed
+    pub fn update(&mut self, stats: PathStats) -> Option<u32> {
+        let loss = match self.last.replace(stats) {
+            Some(last) => {
+                let sent = stats.sent_packets.saturating_sub(last.sent_packets);
+                let lost = stats.lost_packets.saturating_sub(last.lost_packets);
+                if sent == 0 { 0.0 } else { lost as f64 / sent as f64 }
+            }
+            None => 0.0,
+        };
+        let base_rtt = *self.base_rtt.get_or_insert(stats.rtt);
+        self.base_rtt = Some(base_rtt.min(stats.rtt));
+
+        let congested = loss > LOSS_THRESHOLD || stats.rtt > base_rtt + QUEUEING_DELAY;
+        let target = if congested {
+            ((self.target_kbps as f64 * DECREASE_FACTOR) as u32).max(self.min_kbps)
+        } else {
+            self.target_kbps.saturating_add(INCREASE_KBPS).min(self.max_kbps)
+        };
+        if target == self.target_kbps {
+            return None;
+        }
+
+        debug!(
+            "Bitrate {} -> {} kbit/s (rtt {:?}, base {:?}, {:.1}% loss)",
+            self.target_kbps, target, stats.rtt, base_rtt, loss * 100.0
+        );
+        self.target_kbps = target;
+        Some(target)
+    }
+}
+
+/// The encoders of one session's streams, which split its bitrate evenly.
+/// Cheap to clone; an encoder drops out once its stream lets go of it.
+#[derive(Clone, Default)]
+pub struct SharedBitrate(Arc<parking_lot::Mutex<Shares>>);
+
+#[derive(Default)]
+struct Shares {
+    // `None` until the controller has started
+    target_kbps: Option<u32>,
+    encoders: Vec<Weak<Encoder>>,
+}
+
+impl SharedBitrate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give `encoder` its share, taking it from the streams already running
+    pub fn add(&self, encoder: &Arc<Encoder>) {
+        let mut shares = self.0.lock();
+        shares.encoders.push(Arc::downgrade(encoder));
+        shares.apply();
+    }
+
+    fn set_target(&self, kbps: u32) {
+        let mut shares = self.0.lock();
+        shares.target_kbps = Some(kbps);
+        shares.apply();
+    }
+}
+
+impl Shares {
+    fn apply(&mut self) {
+        self.encoders.retain(|encoder| encoder.strong_count() > 0);
+        let Some(target) = self.target_kbps else {
+            return;
+        };
+        let share = split(target, self.encoders.len());
+        for encoder in self.encoders.iter().filter_map(Weak::upgrade) {
+            if encoder.bitrate() != share {
+                encoder.set_bitrate(share);
+            }
+        }
+    }
+}
+
+/// Each of `streams` streams' share of `target_kbps`
+fn split(target_kbps: u32, streams: usize) -> u32 {
+    target_kbps / streams.max(1) as u32
+}
+
+/// Keep the streams in `shared` matched to the link behind `session_id` until
+/// the session goes away
+pub async fn run(shared: SharedBitrate, session_manager: SessionManager, session_id: String) {
+    let Some(session) = session_manager.get_session(&session_id).await else {
+        return;
+    };
+    let streaming = session.streaming;
+    let mut controller = BitrateController::new(&streaming);
+    shared.set_target(controller.target());
+    info!(
+        "✓ Adapting bitrate for session {} between {} and {} Mbps",
+        session_id, streaming.min_bitrate_mbps, streaming.max_bitrate_mbps
+    );
+
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let stats = match session_manager.link_stats(&session_id).await {
+            Ok(Some(stats)) => stats,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("Stopped adapting bitrate for session {}: {:#}", session_id, e);
+                break;
+            }
+        };
+        if let Some(kbps) = controller.update(stats) {
+            shared.set_target(kbps);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const RTT: Duration = Duration::from_millis(10);
+
+    fn controller(min: u32, start: u32, max: u32) -> BitrateController {
+        let streaming = StreamingConfig {
+            min_bitrate_mbps: min,
+            bitrate_mbps: start,
+            max_bitrate_mbps: max,
+            ..Config::default().streaming
+        };
+        BitrateController::new(&streaming)
+    }
+
+    /// Feeds samples of 100 packets each
+    struct Link {
+        stats: PathStats,
+    }
+
+    impl Link {
+        fn new() -> Self {
+            Self { stats: PathStats { rtt: RTT, sent_packets: 0, lost_packets: 0 } }
+        }
+
+        fn sample(&mut self, rtt: Duration, lost: u64) -> PathStats {
+            self.stats.rtt = rtt;
+            self.stats.sent_packets += 100;
+            self.stats.lost_packets += lost;
+            self.stats
+        }
+    }
+
+    #[test]
+    fn healthy_link_creeps_up_to_the_maximum() {
+        let mut controller = controller(1, 1, 2);
+        let mut link = Link::new();
+        for step in 1..=4 {
+            assert_eq!(controller.update(link.sample(RTT, 0)), Some(1000 + step * INCREASE_KBPS));
+        }
+        assert_eq!(controller.target(), 2000);
+        assert_eq!(controller.update(link.sample(RTT, 0)), None);
+    }
+
+    #[test]
+    fn loss_backs_off() {
+        let mut controller = controller(1, 10, 20);
+        let mut link = Link::new();
+        controller.update(link.sample(RTT, 0));
+        assert_eq!(controller.target(), 10_250);
+
+        // Exactly at the threshold is still healthy
+        assert_eq!(controller.update(link.sample(RTT, 2)), Some(10_500));
+        assert_eq!(controller.update(link.sample(RTT, 3)), Some((10_500.0 * DECREASE_FACTOR) as u32));
+    }
+
+    #[test]
+    fn queueing_backs_off() {
+        let mut controller = controller(1, 10, 20);
+        let mut link = Link::new();
+        controller.update(link.sample(RTT, 0));
+        assert_eq!(controller.update(link.sample(RTT + QUEUEING_DELAY, 0)), Some(10_500));
+        assert_eq!(
+            controller.update(link.sample(RTT + QUEUEING_DELAY + Duration::from_millis(1), 0)),
+            Some((10_500.0 * DECREASE_FACTOR) as u32)
+        );
+
+        // A faster round trip becomes the new base
+        let target = controller.update(link.sample(Duration::from_millis(2), 0)).unwrap();
+        assert_eq!(
+            controller.update(link.sample(RTT + QUEUEING_DELAY, 0)),
+            Some((target as f64 * DECREASE_FACTOR) as u32)
+        );
+    }
+
+    #[test]
+    fn backoff_stops_at_the_minimum() {
+        let mut controller = controller(5, 6, 20);
+        let mut link = Link::new();
+        controller.update(link.sample(RTT, 0));
+        assert_eq!(controller.update(link.sample(RTT, 50)), Some(5000));
+        assert_eq!(controller.update(link.sample(RTT, 50)), None);
+        assert_eq!(controller.target(), 5000);
+    }
+
+    #[test]
+    fn starting_bitrate_is_kept_within_the_bounds() {
+        assert_eq!(controller(5, 50, 20).target(), 20_000);
+        assert_eq!(controller(5, 1, 20).target(), 5000);
+        assert_eq!(controller(5, 10, 20).target(), 10_000);
+        // A maximum below the minimum pins it to the minimum
+        assert_eq!(controller(5, 10, 2).target(), 5000);
+    }
+
+    #[test]
+    fn streams_split_the_session_bitrate() {
+        assert_eq!(split(9000, 1), 9000);
+        assert_eq!(split(9000, 3), 3000);
+        assert_eq!(split(9000, 0), 9000);
+    }
+}
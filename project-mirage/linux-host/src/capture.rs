@@ -1,13 +1,871 @@
-// Window capture and video encoding (Phase 0.2)
-// Will use PipeWire/DMA-buf for Wayland capture
-// and FFmpeg/GStreamer for hardware encoding
+// Screen capture and video encoding (Phase 0.2)
+// Frames come from XShm on X11 through GStreamer and are encoded with VA-API or
+// a software encoder. Wayland capture is refused until the ScreenCast portal is
+// supported.
 
-pub struct CaptureManager {
-    // TODO: Implement in Phase 0.2
+use anyhow::{anyhow, bail, Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tracing::{info, debug, warn};
+
+use crate::config::StreamingConfig;
+use crate::metrics::metrics;
+use crate::platform::{self, DisplayServer, Output};
+
+// Encoded packets waiting to be sent
+const PACKET_CHANNEL_CAPACITY: usize = 16;
+
+// Side of the squares frames are compared in, a multiple of the 16-pixel
+// macroblocks of both codecs
+const DAMAGE_TILE: u32 = 64;
+
+// A still screen is still encoded this often with damage tracking, so a new
+// viewer or a lost packet doesn't leave the peer on a broken picture
+const STILL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One captured frame, BGRx with tightly packed rows (4 bytes per pixel)
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// Presentation time relative to the start of capture
+    pub timestamp: Duration,
+    /// What changed since the previous frame, with `streaming.damage_tracking`.
+    /// `None` when that isn't known, so all of it counts as changed.
+    pub damage: Option<Vec<DamageRect>>,
+}
+
+/// A changed part of a frame, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Keeps a copy of the last frame to tell what the next one changed
+#[derive(Default)]
+struct DamageTracker {
+    previous: Vec<u8>,
+    size: (u32, u32),
+}
+
+impl DamageTracker {
+    /// What `frame` changed since the last one, or `None` for the first frame
+    /// and after the screen size changed
+    fn update(&mut self, frame: &RawFrame) -> Option<Vec<DamageRect>> {
+        let comparable = self.size == (frame.width, frame.height) && self.previous.len() == frame.data.len();
+        let damage = comparable.then(|| damaged_tiles(&self.previous, &frame.data, frame.width, frame.height));
+        self.previous.clear();
+        self.previous.extend_from_slice(&frame.data);
+        self.size = (frame.width, frame.height);
+        damage
+    }
+}
+
+/// The tiles of `current` that differ from `previous`, both packed BGRx
+/// frames of `width`x`height`. Damaged tiles next to each other in a row are
+/// merged into one rectangle.
+fn damaged_tiles(previous: &[u8], current: &[u8], width: u32, height: u32) -> Vec<DamageRect> {
+    let stride = width as usize * 4;
+    let mut damage: Vec<DamageRect> = Vec::new();
+    for y in (0..height).step_by(DAMAGE_TILE as usize) {
+        let tile_height = DAMAGE_TILE.min(height - y);
+        for x in (0..width).step_by(DAMAGE_TILE as usize) {
+            let tile_width = DAMAGE_TILE.min(width - x);
+            let changed = (y..y + tile_height).any(|row| {
+                let start = row as usize * stride + x as usize * 4;
+                let end = start + tile_width as usize * 4;
+                previous[start..end] != current[start..end]
+            });
+            if !changed {
+                continue;
+            }
+            match damage.last_mut() {
+                Some(last) if last.y == y && last.x + last.width == x => last.width += tile_width,
+                _ => damage.push(DamageRect { x, y, width: tile_width, height: tile_height }),
+            }
+        }
+    }
+    damage
+}
+
+/// Share of a `width`x`height` frame that `damage` covers, 0.0 to 1.0
+fn damaged_share(damage: &[DamageRect], width: u32, height: u32) -> f64 {
+    let area: u64 = damage.iter().map(|rect| rect.width as u64 * rect.height as u64).sum();
+    area as f64 / (width as u64 * height as u64).max(1) as f64
+}
+
+// Wayland would add a PipeWire node from the ScreenCast portal here
+#[derive(Debug, Clone, Copy)]
+enum CaptureBackend {
+    /// XShm via ximagesrc
+    X11,
+}
+
+/// Frames between the capturer and the encoder. Holds at most `capacity`;
+/// the newest frame always gets in, pushing out the oldest, so an encoder
+/// that stalls resumes on a current frame instead of a backlog.
+struct FrameQueue {
+    frames: parking_lot::Mutex<VecDeque<RawFrame>>,
+    capacity: usize,
+    ready: Notify,
+    /// Set when either end goes away
+    closed: AtomicBool,
+}
+
+impl FrameQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frames: parking_lot::Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            ready: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queue `frame`, dropping the oldest one if full. Returns false once the
+    /// receiver is gone.
+    fn push(&self, frame: RawFrame) -> bool {
+        if self.closed.load(Ordering::Acquire) {
+            return false;
+        }
+        let mut frames = self.frames.lock();
+        if frames.len() >= self.capacity {
+            frames.pop_front();
+            metrics().frame_dropped();
+        }
+        frames.push_back(frame);
+        drop(frames);
+        self.ready.notify_one();
+        true
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.ready.notify_one();
+    }
+}
+
+/// Receiving end of a `ScreenCapturer`
+pub struct FrameReceiver {
+    queue: Arc<FrameQueue>,
+}
+
+impl FrameReceiver {
+    /// The oldest frame still queued, waiting for one if there is none.
+    /// `None` once the capturer is dropped and its frames are taken.
+    pub async fn recv(&mut self) -> Option<RawFrame> {
+        loop {
+            if let Some(frame) = self.queue.frames.lock().pop_front() {
+                return Some(frame);
+            }
+            if self.queue.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.queue.ready.notified().await;
+        }
+    }
 }
 
-impl CaptureManager {
-    pub fn new() -> Self {
-        Self {}
+impl Drop for FrameReceiver {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// Captures one output, or the whole desktop without one, at up to
+/// `streaming.max_fps`
+pub struct ScreenCapturer {
+    streaming: StreamingConfig,
+    output: Option<Output>,
+    queue: Arc<FrameQueue>,
+    pipeline: Option<gst::Pipeline>,
+}
+
+impl ScreenCapturer {
+    pub fn new(streaming: StreamingConfig, output: Option<Output>) -> Result<(Self, FrameReceiver)> {
+        gst::init().context("Failed to initialize GStreamer")?;
+        let queue = Arc::new(FrameQueue::new(streaming.max_queued_frames));
+
+        Ok((
+            Self {
+                streaming,
+                output,
+                queue: queue.clone(),
+                pipeline: None,
+            },
+            FrameReceiver { queue },
+        ))
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        if self.pipeline.is_some() {
+            return Ok(());
+        }
+
+        let backend = detect_backend(self.output.as_ref())?;
+        let source = capture_source(backend, self.output.as_ref());
+
+        let fps = self.streaming.max_fps.max(1);
+        let description = format!(
+            "{} ! videoconvert ! videorate drop-only=true ! \
+             video/x-raw,format=BGRx,framerate={}/1 ! \
+             appsink name=sink max-buffers=2 drop=true sync=false",
+            source, fps
+        );
+        debug!("Capture pipeline: {}", description);
+
+        let pipeline = gst::parse_launch(&description)
+            .context("Failed to build capture pipeline")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow!("Capture pipeline is not a gst::Pipeline"))?;
+
+        let sink = pipeline
+            .by_name("sink")
+            .and_then(|element| element.downcast::<gst_app::AppSink>().ok())
+            .ok_or_else(|| anyhow!("Capture pipeline has no appsink"))?;
+
+        let queue = self.queue.clone();
+        let mut damage_tracker = self.streaming.damage_tracking.then(DamageTracker::default);
+        sink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let mut frame = to_raw_frame(&sample).ok_or(gst::FlowError::Error)?;
+                    metrics().frame_captured();
+                    if let Some(ref mut tracker) = damage_tracker {
+                        frame.damage = tracker.update(&frame);
+                        let share = frame.damage
+                            .as_deref()
+                            .map_or(1.0, |damage| damaged_share(damage, frame.width, frame.height));
+                        metrics().frame_damaged(share);
+                    }
+
+                    // A slow consumer loses its oldest frames instead of adding latency
+                    if queue.push(frame) {
+                        Ok(gst::FlowSuccess::Ok)
+                    } else {
+                        Err(gst::FlowError::Eos)
+                    }
+                })
+                .build(),
+        );
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to start screen capture")?;
+
+        match self.output {
+            Some(ref output) => info!("✓ Capturing {} ({:?}, {} fps)", output.name, backend, fps),
+            None => info!("✓ Screen capture started ({:?}, {} fps)", backend, fps),
+        }
+        self.pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline
+                .set_state(gst::State::Null)
+                .context("Failed to stop screen capture")?;
+            info!("Screen capture stopped");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScreenCapturer {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            warn!("{}", e);
+        }
+        self.queue.close();
+    }
+}
+
+/// One encoded access unit in Annex B byte-stream format
+#[derive(Debug, Clone)]
+pub struct EncodedPacket {
+    pub data: Vec<u8>,
+    pub is_keyframe: bool,
+    pub pts: Duration,
+}
+
+/// Encodes `RawFrame`s with `streaming.codec` at `streaming.bitrate_mbps`, on
+/// the GPU through VA-API when `streaming.hardware_encode` is set and available
+pub struct Encoder {
+    pipeline: gst::Pipeline,
+    appsrc: gst_app::AppSrc,
+    appsink: gst_app::AppSink,
+    width: u32,
+    height: u32,
+    backend: EncoderBackend,
+    encoder: gst::Element,
+    bitrate_property: BitrateProperty,
+    bitrate_kbps: AtomicU32,
+    // For skipping frames that changed nothing: when one was last encoded, and
+    // whether a keyframe was asked for that needs a frame to happen on
+    last_encoded: parking_lot::Mutex<Option<Instant>>,
+    refresh_pending: AtomicBool,
+}
+
+/// Where encoding happens, as picked by `Encoder::new`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderBackend {
+    VaApi,
+    Software,
+}
+
+impl EncoderBackend {
+    /// Name used in metrics labels
+    pub fn label(self) -> &'static str {
+        match self {
+            EncoderBackend::VaApi => "vaapi",
+            EncoderBackend::Software => "software",
+        }
+    }
+}
+
+impl std::fmt::Display for EncoderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EncoderBackend::VaApi => "VA-API",
+            EncoderBackend::Software => "Software",
+        })
+    }
+}
+
+impl Encoder {
+    pub fn new(
+        streaming: &StreamingConfig,
+        width: u32,
+        height: u32,
+    ) -> Result<(Self, mpsc::Receiver<EncodedPacket>)> {
+        gst::init().context("Failed to initialize GStreamer")?;
+
+        let codec = match streaming.codec.to_ascii_lowercase().as_str() {
+            "h264" => VideoCodec::H264,
+            "h265" | "hevc" => VideoCodec::H265,
+            other => bail!("Unsupported codec {}", other),
+        };
+        let candidates = encoder_candidates(codec, streaming.hardware_encode);
+        if candidates.is_empty() {
+            bail!("No {} encoder available; install gst-plugins-ugly or gst-plugins-bad", codec.name());
+        }
+
+        // An encoder element can exist but fail once started, e.g. VA-API
+        // without a usable GPU, so each one is tried until a pipeline runs
+        let (packet_tx, packet_rx) = mpsc::channel(PACKET_CHANNEL_CAPACITY);
+        let fps = streaming.max_fps.max(1);
+        let kbps = streaming.bitrate_mbps.saturating_mul(1000);
+        let mut last_error = None;
+        for candidate in candidates {
+            let started = start_pipeline(codec, &candidate.description(kbps), width, height, fps, packet_tx.clone());
+            let (pipeline, appsrc, appsink, encoder) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    warn!("⚠ {} encoder {} failed to start: {:#}", candidate.backend, candidate.element, e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            info!("✓ {} encoder ready ({}x{}, {} Mbps, {} via {})",
+                codec.name(), width, height, streaming.bitrate_mbps, candidate.backend, candidate.element);
+            if streaming.hardware_encode && candidate.backend == EncoderBackend::Software {
+                warn!("⚠ Encoding in software although streaming.hardware_encode is set; expect high CPU use");
+            }
+            metrics().set_encoder_backend(candidate.backend.label());
+            metrics().set_target_bitrate(kbps);
+
+            return Ok((
+                Self {
+                    pipeline,
+                    appsrc,
+                    appsink,
+                    width,
+                    height,
+                    backend: candidate.backend,
+                    encoder,
+                    bitrate_property: candidate.bitrate,
+                    bitrate_kbps: AtomicU32::new(kbps),
+                    last_encoded: parking_lot::Mutex::new(None),
+                    refresh_pending: AtomicBool::new(false),
+                },
+                packet_rx,
+            ));
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| anyhow!("No encoder could be started"))
+            .context(format!("No {} encoder could be started", codec.name())))
+    }
+
+    /// Whether frames are encoded on the GPU or the CPU
+    pub fn backend(&self) -> EncoderBackend {
+        self.backend
+    }
+
+    /// The bitrate the encoder is currently aiming for, in kbit/s
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate_kbps.load(Ordering::Relaxed)
+    }
+
+    /// Change the target bitrate while the stream is running
+    pub fn set_bitrate(&self, kbps: u32) {
+        let property = self.bitrate_property;
+        self.encoder.set_property_from_str(property.name, &property.value(kbps).to_string());
+        self.bitrate_kbps.store(kbps, Ordering::Relaxed);
+        metrics().set_target_bitrate(kbps);
+        debug!("Encoder bitrate set to {} kbit/s", kbps);
+    }
+
+    /// Encode `frame`, unless damage tracking found nothing changed in it and
+    /// the peer's picture is recent enough
+    pub fn encode(&self, frame: RawFrame) -> Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            bail!("Frame is {}x{} but the encoder was set up for {}x{}",
+                frame.width, frame.height, self.width, self.height);
+        }
+
+        let unchanged = frame.damage.as_ref().is_some_and(|damage| damage.is_empty());
+        if unchanged && !self.refresh_pending.load(Ordering::Relaxed) {
+            let recent = self.last_encoded.lock().is_some_and(|at| at.elapsed() < STILL_REFRESH_INTERVAL);
+            if recent {
+                metrics().frame_unchanged();
+                return Ok(());
+            }
+        }
+
+        let pts = gst::ClockTime::from_nseconds(frame.timestamp.as_nanos() as u64);
+        let mut buffer = gst::Buffer::from_slice(frame.data);
+        if let Some(buffer) = buffer.get_mut() {
+            buffer.set_pts(pts);
+        }
+
+        self.appsrc
+            .push_buffer(buffer)
+            .map_err(|e| anyhow!("Encoder rejected frame: {:?}", e))?;
+        *self.last_encoded.lock() = Some(Instant::now());
+        self.refresh_pending.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Make the next packet a keyframe, e.g. so a new viewer can start decoding
+    pub fn force_keyframe(&self) {
+        // The keyframe needs a frame to happen on, even one that changed nothing
+        self.refresh_pending.store(true, Ordering::Relaxed);
+        let event = gst_video::UpstreamForceKeyUnitEvent::builder()
+            .all_headers(true)
+            .build();
+        if !self.appsink.send_event(event) {
+            warn!("Encoder ignored keyframe request");
+        }
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        let _ = self.appsrc.end_of_stream();
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    fn name(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H.264",
+            VideoCodec::H265 => "H.265",
+        }
+    }
+
+    fn parser(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264parse",
+            VideoCodec::H265 => "h265parse",
+        }
+    }
+
+    fn caps(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "video/x-h264",
+            VideoCodec::H265 => "video/x-h265",
+        }
+    }
+}
+
+/// An installed encoder element and how to configure it
+struct EncoderCandidate {
+    element: &'static str,
+    options: &'static str,
+    bitrate: BitrateProperty,
+    backend: EncoderBackend,
+}
+
+impl EncoderCandidate {
+    fn description(&self, kbps: u32) -> String {
+        format!("{} name=encoder {}={} {}",
+            self.element, self.bitrate.name, self.bitrate.value(kbps), self.options)
+    }
+}
+
+/// The property an encoder takes its bitrate in, which is kbit/s for most
+/// but bit/s for openh264enc
+#[derive(Debug, Clone, Copy)]
+struct BitrateProperty {
+    name: &'static str,
+    bits_per_unit: u32,
+}
+
+impl BitrateProperty {
+    const KBPS: Self = Self { name: "bitrate", bits_per_unit: 1000 };
+
+    fn value(self, kbps: u32) -> u32 {
+        kbps.saturating_mul(1000 / self.bits_per_unit)
+    }
+}
+
+/// Installed encoder elements in the order to try them: VA-API first when
+/// asked for, then software
+fn encoder_candidates(codec: VideoCodec, hardware: bool) -> Vec<EncoderCandidate> {
+    let mut candidates = Vec::new();
+
+    if hardware {
+        // The newer `va` plugin first, then the older gstreamer-vaapi one
+        let elements = match codec {
+            VideoCodec::H264 => ["vah264enc", "vaapih264enc"],
+            VideoCodec::H265 => ["vah265enc", "vaapih265enc"],
+        };
+        for element in elements {
+            candidates.push(EncoderCandidate {
+                element,
+                options: "rate-control=cbr",
+                bitrate: BitrateProperty::KBPS,
+                backend: EncoderBackend::VaApi,
+            });
+        }
+    }
+
+    let software = match codec {
+        VideoCodec::H264 => [
+            ("x264enc", "tune=zerolatency speed-preset=ultrafast", BitrateProperty::KBPS),
+            ("openh264enc", "complexity=low", BitrateProperty { name: "bitrate", bits_per_unit: 1 }),
+        ],
+        VideoCodec::H265 => [
+            ("x265enc", "tune=zerolatency speed-preset=ultrafast", BitrateProperty::KBPS),
+            ("svthevcenc", "", BitrateProperty { name: "target-bitrate", bits_per_unit: 1000 }),
+        ],
+    };
+    for (element, options, bitrate) in software {
+        candidates.push(EncoderCandidate { element, options, bitrate, backend: EncoderBackend::Software });
+    }
+
+    candidates.retain(|candidate| gst::ElementFactory::find(candidate.element).is_some());
+    if hardware && !candidates.iter().any(|candidate| candidate.backend == EncoderBackend::VaApi) {
+        warn!("⚠ No VA-API {} encoder available, falling back to software", codec.name());
+    }
+    candidates
+}
+
+/// Build and start an encoder pipeline around `encoder`, sending its output
+/// to `packet_tx`
+fn start_pipeline(
+    codec: VideoCodec,
+    encoder: &str,
+    width: u32,
+    height: u32,
+    fps: u32,
+    packet_tx: mpsc::Sender<EncodedPacket>,
+) -> Result<(gst::Pipeline, gst_app::AppSrc, gst_app::AppSink, gst::Element)> {
+    let description = format!(
+        "appsrc name=src is-live=true format=time \
+         caps=\"video/x-raw,format=BGRx,width={width},height={height},framerate={fps}/1\" ! \
+         videoconvert ! {encoder} ! {parser} ! \
+         {caps},stream-format=byte-stream,alignment=au ! \
+         appsink name=sink sync=false",
+        parser = codec.parser(),
+        caps = codec.caps(),
+    );
+    debug!("Encoder pipeline: {}", description);
+
+    let pipeline = gst::parse_launch(&description)
+        .context("Failed to build encoder pipeline")?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Encoder pipeline is not a gst::Pipeline"))?;
+
+    let appsrc = pipeline
+        .by_name("src")
+        .and_then(|element| element.downcast::<gst_app::AppSrc>().ok())
+        .ok_or_else(|| anyhow!("Encoder pipeline has no appsrc"))?;
+    let appsink = pipeline
+        .by_name("sink")
+        .and_then(|element| element.downcast::<gst_app::AppSink>().ok())
+        .ok_or_else(|| anyhow!("Encoder pipeline has no appsink"))?;
+    let encoder = pipeline
+        .by_name("encoder")
+        .ok_or_else(|| anyhow!("Encoder pipeline has no encoder"))?;
+
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let packet = to_encoded_packet(&sample).ok_or(gst::FlowError::Error)?;
+                metrics().packet_encoded(packet.data.len());
+
+                match packet_tx.try_send(packet) {
+                    Ok(()) => Ok(gst::FlowSuccess::Ok),
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!("Encoded packet dropped, consumer is falling behind");
+                        Ok(gst::FlowSuccess::Ok)
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => Err(gst::FlowError::Eos),
+                }
+            })
+            .build(),
+    );
+
+    if let Err(e) = pipeline.set_state(gst::State::Playing) {
+        let _ = pipeline.set_state(gst::State::Null);
+        return Err(anyhow::Error::new(e).context("Failed to start encoder"));
+    }
+    Ok((pipeline, appsrc, appsink, encoder))
+}
+
+fn to_encoded_packet(sample: &gst::Sample) -> Option<EncodedPacket> {
+    let buffer = sample.buffer()?;
+    let map = buffer.map_readable().ok()?;
+
+    Some(EncodedPacket {
+        data: map.as_slice().to_vec(),
+        is_keyframe: !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+        pts: buffer.pts().map(|pts| Duration::from_nanos(pts.nseconds())).unwrap_or_default(),
+    })
+}
+
+fn to_raw_frame(sample: &gst::Sample) -> Option<RawFrame> {
+    let buffer = sample.buffer()?;
+    let info = gst_video::VideoInfo::from_caps(sample.caps()?).ok()?;
+    let map = buffer.map_readable().ok()?;
+
+    let width = info.width();
+    let height = info.height();
+    let stride = info.stride()[0] as usize;
+    let row_bytes = width as usize * 4;
+
+    // Drop any row padding so consumers can assume packed rows
+    let data = if stride == row_bytes {
+        map.as_slice().to_vec()
+    } else {
+        map.as_slice()
+            .chunks(stride)
+            .take(height as usize)
+            .flat_map(|row| &row[..row_bytes])
+            .copied()
+            .collect()
+    };
+
+    Some(RawFrame {
+        width,
+        height,
+        data,
+        timestamp: buffer.pts().map(|pts| Duration::from_nanos(pts.nseconds())).unwrap_or_default(),
+        damage: None,
+    })
+}
+
+fn detect_backend(output: Option<&Output>) -> Result<CaptureBackend> {
+    match platform::display_server() {
+        // Compositors only hand out screen content through the ScreenCast
+        // portal, after asking the user; XWayland would capture a black screen
+        Some(DisplayServer::Wayland) => bail!(
+            "Can't stream {}: screen capture on Wayland isn't supported yet, log in to an X11 session to stream",
+            output.map_or("the desktop", |output| output.name.as_str())
+        ),
+        Some(DisplayServer::X11) => Ok(CaptureBackend::X11),
+        None => bail!("No display found to capture (set host.display_server if detection fails)"),
+    }
+}
+
+/// The GStreamer source for `output`. X11 crops the root window to it; a
+/// PipeWire node already is one output.
+fn capture_source(backend: CaptureBackend, output: Option<&Output>) -> String {
+    match backend {
+        CaptureBackend::X11 => {
+            let mut source = "ximagesrc use-damage=false show-pointer=true".to_string();
+            if let Some(Output { position: Some((x, y)), width, height, .. }) = output {
+                // The end coordinates are inclusive
+                source.push_str(&format!(
+                    " startx={} starty={} endx={} endy={}",
+                    x, y, x + *width as i32 - 1, y + *height as i32 - 1
+                ));
+            }
+            source
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x11_capture_is_cropped_to_the_output() {
+        let output = Output {
+            name: "HDMI-1".to_string(),
+            position: Some((2560, 0)),
+            width: 1920,
+            height: 1080,
+            primary: false,
+        };
+        assert_eq!(
+            capture_source(CaptureBackend::X11, Some(&output)),
+            "ximagesrc use-damage=false show-pointer=true startx=2560 starty=0 endx=4479 endy=1079"
+        );
+        // Without one the whole desktop is captured
+        assert_eq!(capture_source(CaptureBackend::X11, None), "ximagesrc use-damage=false show-pointer=true");
+    }
+
+    fn frame(width: u32, height: u32) -> RawFrame {
+        RawFrame {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+            timestamp: Duration::ZERO,
+            damage: None,
+        }
+    }
+
+    fn paint(frame: &mut RawFrame, x: u32, y: u32) {
+        let offset = ((y * frame.width + x) * 4) as usize;
+        frame.data[offset] ^= 0xff;
+    }
+
+    #[test]
+    fn unchanged_frame_has_no_damage() {
+        let frame = frame(256, 128);
+        assert!(damaged_tiles(&frame.data, &frame.data, 256, 128).is_empty());
+    }
+
+    #[test]
+    fn one_pixel_damages_its_tile() {
+        let previous = frame(256, 128);
+        let mut current = previous.clone();
+        paint(&mut current, 130, 70);
+        assert_eq!(
+            damaged_tiles(&previous.data, &current.data, 256, 128),
+            vec![DamageRect { x: 128, y: 64, width: 64, height: 64 }]
+        );
+    }
+
+    #[test]
+    fn neighbouring_tiles_in_a_row_merge() {
+        let previous = frame(320, 128);
+        let mut current = previous.clone();
+        paint(&mut current, 10, 10);
+        paint(&mut current, 70, 10);
+        paint(&mut current, 140, 10);
+        // A gap keeps rectangles apart, and rows never merge
+        paint(&mut current, 300, 10);
+        paint(&mut current, 10, 100);
+        assert_eq!(
+            damaged_tiles(&previous.data, &current.data, 320, 128),
+            vec![
+                DamageRect { x: 0, y: 0, width: 192, height: 64 },
+                DamageRect { x: 256, y: 0, width: 64, height: 64 },
+                DamageRect { x: 0, y: 64, width: 64, height: 64 },
+            ]
+        );
+    }
+
+    #[test]
+    fn edge_tiles_are_cut_to_the_frame() {
+        let previous = frame(100, 70);
+        let mut current = previous.clone();
+        paint(&mut current, 99, 69);
+        paint(&mut current, 0, 69);
+        assert_eq!(
+            damaged_tiles(&previous.data, &current.data, 100, 70),
+            vec![DamageRect { x: 0, y: 64, width: 100, height: 6 }]
+        );
+    }
+
+    #[test]
+    fn tracker_compares_only_frames_of_the_same_size() {
+        let mut tracker = DamageTracker::default();
+        let mut current = frame(128, 64);
+        assert_eq!(tracker.update(&current), None);
+        assert_eq!(tracker.update(&current), Some(vec![]));
+
+        paint(&mut current, 100, 0);
+        assert_eq!(tracker.update(&current), Some(vec![DamageRect { x: 64, y: 0, width: 64, height: 64 }]));
+
+        // A new resolution starts over, then compares against itself
+        let resized = frame(64, 64);
+        assert_eq!(tracker.update(&resized), None);
+        assert_eq!(tracker.update(&resized), Some(vec![]));
+    }
+
+    fn stamped(millis: u64) -> RawFrame {
+        RawFrame { timestamp: Duration::from_millis(millis), ..frame(1, 1) }
+    }
+
+    #[tokio::test]
+    async fn full_queue_keeps_the_newest_frames() {
+        let queue = Arc::new(FrameQueue::new(2));
+        let mut receiver = FrameReceiver { queue: queue.clone() };
+        for millis in 1..=5 {
+            assert!(queue.push(stamped(millis)));
+        }
+
+        assert_eq!(receiver.recv().await.unwrap().timestamp, Duration::from_millis(4));
+        assert_eq!(receiver.recv().await.unwrap().timestamp, Duration::from_millis(5));
+        assert!(queue.frames.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn closed_queue_hands_out_what_is_left_then_ends() {
+        let queue = Arc::new(FrameQueue::new(2));
+        let mut receiver = FrameReceiver { queue: queue.clone() };
+        queue.push(stamped(1));
+        queue.close();
+
+        assert!(!queue.push(stamped(2)));
+        assert_eq!(receiver.recv().await.unwrap().timestamp, Duration::from_millis(1));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closing_wakes_a_waiting_receiver() {
+        let queue = Arc::new(FrameQueue::new(2));
+        let mut receiver = FrameReceiver { queue: queue.clone() };
+        let waiting = tokio::spawn(async move { receiver.recv().await });
+        tokio::task::yield_now().await;
+
+        queue.close();
+        let received = tokio::time::timeout(Duration::from_secs(1), waiting).await.expect("recv didn't wake up");
+        assert!(received.unwrap().is_none());
+    }
+
+    #[test]
+    fn dropping_the_receiver_stops_the_capturer() {
+        let queue = Arc::new(FrameQueue::new(2));
+        drop(FrameReceiver { queue: queue.clone() });
+        assert!(!queue.push(stamped(1)));
     }
 }
@@ -0,0 +1,298 @@
+// Clipboard sharing
+// Polls the local clipboard (Wayland data-control or X11 selections) and
+// applies text copied on peers
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, debug};
+use wl_clipboard_rs::{copy, paste};
+
+use crate::platform::{self, DisplayServer};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// X11 selection owners that don't answer within this are treated as empty
+const X11_READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// The regular Ctrl+C / Ctrl+V clipboard
+    Clipboard,
+    /// The X11 middle-click selection
+    Primary,
+}
+
+/// Clipboard text and the node it was copied on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipboardContents {
+    pub selection: Selection,
+    pub origin_node_id: String,
+    pub text: String,
+}
+
+enum Backend {
+    Wayland,
+    X11(x11_clipboard::Clipboard),
+}
+
+impl Backend {
+    fn detect() -> Result<Self> {
+        if platform::display_server() == Some(DisplayServer::Wayland) {
+            return Ok(Backend::Wayland);
+        }
+        let clipboard = x11_clipboard::Clipboard::new()
+            .map_err(|e| anyhow!("{}", e))
+            .context("No Wayland session and the X11 clipboard is unavailable")?;
+        Ok(Backend::X11(clipboard))
+    }
+
+    /// Current text in `selection`; `None` if it's empty or not text
+    fn read(&self, selection: Selection) -> Result<Option<String>> {
+        match self {
+            Backend::Wayland => {
+                let clipboard = match selection {
+                    Selection::Clipboard => paste::ClipboardType::Regular,
+                    Selection::Primary => paste::ClipboardType::Primary,
+                };
+                match paste::get_contents(clipboard, paste::Seat::Unspecified, paste::MimeType::Text) {
+                    Ok((mut pipe, _)) => {
+                        let mut bytes = Vec::new();
+                        pipe.read_to_end(&mut bytes)?;
+                        Ok(String::from_utf8(bytes).ok())
+                    }
+                    Err(paste::Error::ClipboardEmpty | paste::Error::NoMimeType) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Backend::X11(clipboard) => {
+                let atoms = &clipboard.getter.atoms;
+                let selection = match selection {
+                    Selection::Clipboard => atoms.clipboard,
+                    Selection::Primary => atoms.primary,
+                };
+                match clipboard.load(selection, atoms.utf8_string, atoms.property, X11_READ_TIMEOUT) {
+                    Ok(bytes) => Ok(String::from_utf8(bytes).ok()),
+                    Err(x11_clipboard::error::Error::Timeout) => Ok(None),
+                    Err(e) => Err(anyhow!("{}", e)),
+                }
+            }
+        }
+    }
+
+    fn write(&self, selection: Selection, text: &str) -> Result<()> {
+        match self {
+            Backend::Wayland => {
+                let clipboard = match selection {
+                    Selection::Clipboard => copy::ClipboardType::Regular,
+                    Selection::Primary => copy::ClipboardType::Primary,
+                };
+                // Serves the text from a background thread until something else is copied
+                let mut options = copy::Options::new();
+                options.clipboard(clipboard);
+                options.copy(
+                    copy::Source::Bytes(text.as_bytes().to_vec().into_boxed_slice()),
+                    copy::MimeType::Text,
+                )?;
+                Ok(())
+            }
+            Backend::X11(clipboard) => {
+                let atoms = &clipboard.setter.atoms;
+                let selection = match selection {
+                    Selection::Clipboard => atoms.clipboard,
+                    Selection::Primary => atoms.primary,
+                };
+                clipboard
+                    .store(selection, atoms.utf8_string, text)
+                    .map_err(|e| anyhow!("{}", e))
+            }
+        }
+    }
+}
+
+struct State {
+    backend: Backend,
+    last: LastSeen,
+}
+
+/// Last text seen or written per selection, so neither a local copy nor a
+/// remote update is reported twice. Each selection is tracked on its own, so a
+/// change to one never hides or repeats the other.
+#[derive(Debug, Default)]
+struct LastSeen {
+    clipboard: Option<String>,
+    primary: Option<String>,
+}
+
+impl LastSeen {
+    fn get_mut(&mut self, selection: Selection) -> &mut Option<String> {
+        match selection {
+            Selection::Clipboard => &mut self.clipboard,
+            Selection::Primary => &mut self.primary,
+        }
+    }
+
+    /// Record `text` as what `selection` holds, returning whether that's a change
+    fn update(&mut self, selection: Selection, text: &str) -> bool {
+        let last = self.get_mut(selection);
+        if last.as_deref() == Some(text) {
+            return false;
+        }
+        *last = Some(text.to_string());
+        true
+    }
+}
+
+/// Watches the local clipboard and applies peers' updates. Cheap to clone.
+#[derive(Clone)]
+pub struct ClipboardSync {
+    node_id: String,
+    selections: Vec<Selection>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ClipboardSync {
+    /// Tag local copies with `node_id`. PRIMARY is only synced on X11, and only
+    /// when `sync_primary` is set.
+    pub fn new(node_id: String, sync_primary: bool) -> Result<Self> {
+        let backend = Backend::detect()?;
+        let mut selections = vec![Selection::Clipboard];
+        if sync_primary && matches!(backend, Backend::X11(_)) {
+            selections.push(Selection::Primary);
+        }
+
+        info!(
+            "✓ Clipboard sync available ({})",
+            match backend {
+                Backend::Wayland => "Wayland",
+                Backend::X11(_) => "X11",
+            }
+        );
+
+        Ok(Self {
+            node_id,
+            selections,
+            state: Arc::new(Mutex::new(State {
+                backend,
+                last: LastSeen::default(),
+            })),
+        })
+    }
+
+    /// Start polling the local clipboard. Changes arrive on the returned
+    /// channel; polling stops once the receiver is dropped.
+    pub fn watch(&self) -> mpsc::Receiver<ClipboardContents> {
+        let (tx, rx) = mpsc::channel(8);
+        let sync = self.clone();
+
+        std::thread::spawn(move || {
+            // Whatever was copied before the session started stays local
+            for &selection in &sync.selections {
+                let current = sync.read(selection);
+                *sync.state.lock().last.get_mut(selection) = current;
+            }
+
+            while !tx.is_closed() {
+                std::thread::sleep(POLL_INTERVAL);
+
+                for &selection in &sync.selections {
+                    let Some(text) = sync.read(selection) else {
+                        continue;
+                    };
+
+                    if !sync.state.lock().last.update(selection, &text) {
+                        continue;
+                    }
+
+                    debug!("Local {:?} selection changed ({} bytes)", selection, text.len());
+                    let contents = ClipboardContents {
+                        selection,
+                        origin_node_id: sync.node_id.clone(),
+                        text,
+                    };
+                    if tx.blocking_send(contents).is_err() {
+                        return;
+                    }
+                }
+            }
+            debug!("Clipboard polling stopped");
+        });
+
+        rx
+    }
+
+    /// Put a peer's clipboard text into the local selection. Updates that
+    /// started here are dropped so copies don't bounce between nodes.
+    pub fn apply(&self, contents: &ClipboardContents) -> Result<()> {
+        if contents.origin_node_id == self.node_id {
+            return Ok(());
+        }
+        if !self.selections.contains(&contents.selection) {
+            debug!("Ignoring {:?} selection update from {}", contents.selection, contents.origin_node_id);
+            return Ok(());
+        }
+
+        let mut state = self.state.lock();
+        if state.last.get_mut(contents.selection).as_deref() == Some(contents.text.as_str()) {
+            return Ok(());
+        }
+        state.backend
+            .write(contents.selection, &contents.text)
+            .context("Failed to set the local clipboard")?;
+        // Recorded as seen so the next poll doesn't send it straight back
+        state.last.update(contents.selection, &contents.text);
+
+        debug!(
+            "Applied {:?} selection from {} ({} bytes)",
+            contents.selection, contents.origin_node_id, contents.text.len()
+        );
+        Ok(())
+    }
+
+    fn read(&self, selection: Selection) -> Option<String> {
+        match self.state.lock().backend.read(selection) {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("Failed to read the {:?} selection: {:#}", selection, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::ClipboardUpdate;
+
+    #[test]
+    fn selections_change_independently() {
+        let mut last = LastSeen::default();
+        assert!(last.update(Selection::Clipboard, "copied"));
+        // The same text in PRIMARY is still news for PRIMARY
+        assert!(last.update(Selection::Primary, "copied"));
+        assert!(!last.update(Selection::Clipboard, "copied"));
+
+        // Selecting text changes PRIMARY alone
+        assert!(last.update(Selection::Primary, "selected"));
+        assert!(!last.update(Selection::Clipboard, "copied"));
+        assert_eq!(last.clipboard.as_deref(), Some("copied"));
+        assert_eq!(last.primary.as_deref(), Some("selected"));
+    }
+
+    #[test]
+    fn selection_survives_the_wire() {
+        for selection in [Selection::Clipboard, Selection::Primary] {
+            let contents = ClipboardContents {
+                selection,
+                origin_node_id: "node".to_string(),
+                text: "text".to_string(),
+            };
+            let update = ClipboardUpdate::from(contents.clone());
+            assert_eq!(ClipboardContents::try_from(update).unwrap(), contents);
+        }
+    }
+}
@@ -1,10 +1,57 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use ipnet::IpNet;
+use notify::{EventKind, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn, error};
+
+use crate::discovery::RESERVED_PROPERTIES;
+use crate::platform::DisplayServer;
+use crate::session::ScreenLayout;
+
+// Editors often save in several steps; wait for them to settle before reloading
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// Codec names the encoder understands
+const KNOWN_CODECS: &[&str] = &["h264", "h265", "hevc"];
+
+// Smaller frame limits would refuse ordinary handshakes
+const MIN_FRAME_SIZE: usize = 4096;
+
+// Smaller input buffers overflow on a single fast flick of the mouse
+const MIN_EVENT_CHANNEL_CAPACITY: usize = 64;
+
+// Each queued frame is a whole raw screen; more than a few only adds latency
+const MAX_QUEUED_FRAMES: usize = 8;
+
+/// The config layout this build reads and writes. Files without a `version`
+/// predate it and are version 1.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// What loading the config may do to the file system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAccess {
+    /// The file must exist; it's only written to upgrade it or save changes
+    Existing,
+    /// Like `Existing`, but a missing file is created with the defaults
+    CreateMissing,
+    /// Never write anything: a missing file means the defaults, kept in memory,
+    /// and older files are upgraded in memory only
+    ReadOnly,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// Layout of the file; older files are upgraded by `migrate` on load
+    #[serde(default = "default_config_version")]
+    pub version: u32,
+
     #[serde(default)]
     pub host: HostConfig,
     
@@ -19,13 +66,36 @@ pub struct Config {
     
     #[serde(default)]
     pub input: InputConfig,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    /// Which peer sits past each screen edge
+    #[serde(default)]
+    pub layout: ScreenLayout,
+
+    /// Per-peer overrides, written as `[[peer]]` tables
+    #[serde(default, rename = "peer", skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerOverride>,
+
+    /// Named overrides of the settings above, written as `[profiles.<name>]`
+    /// tables and picked with `--profile`. Kept as written so saving the file
+    /// doesn't lose them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, toml::Table>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostConfig {
     pub name: Option<String>,
-    #[serde(default = "default_edge_threshold")]
-    pub display_edge_threshold: u32,
+
+    /// Force the Wayland or X11 backends; detected from the environment when unset
+    #[serde(default)]
+    pub display_server: Option<DisplayServer>,
+
+    /// Extra mDNS TXT properties for peer pickers, e.g. a room or icon
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub advertise: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,8 +106,100 @@ pub struct NetworkConfig {
     #[serde(default = "default_control_port")]
     pub control_port: u16,
     
+    /// CIDR ranges peers must be in; empty allows any address
     #[serde(default)]
     pub allowed_subnets: Vec<String>,
+
+    /// Drop peers that haven't been seen on mDNS for this long
+    #[serde(default = "default_peer_ttl")]
+    pub peer_ttl_secs: u64,
+
+    /// Localhost port serving Prometheus metrics at /metrics (0 = off)
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
+
+    /// Localhost port for the status dashboard (0 = off). Needs a build with
+    /// the `web-ui` feature.
+    #[serde(default)]
+    pub web_ui_port: u16,
+
+    /// What control connections run over; both ends must agree
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// Send mouse motion as UDP datagrams instead of on the control connection;
+    /// the peer must enable it too. Datagrams are signed with a per-session key
+    /// but not encrypted, so anyone on the path can see the pointer move.
+    #[serde(default)]
+    pub motion_over_udp: bool,
+
+    /// Interfaces to advertise and browse on, e.g. `["wlan0"]`; empty uses all,
+    /// which can include Docker bridges and VPNs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bind_interfaces: Vec<String>,
+
+    /// Largest control message accepted from a peer, in bytes
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// TLS over TCP
+    #[default]
+    Tcp,
+    /// QUIC, with input on its own stream so it isn't held up behind other traffic
+    Quic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// Also log to this file; relative paths are next to the config file
+    #[serde(default)]
+    pub file: Option<String>,
+
+    #[serde(default)]
+    pub rotation: LogRotation,
+
+    /// Size a log file may grow to before `rotation = "size"` starts a new one
+    #[serde(default = "default_log_max_size")]
+    pub max_size_mb: u64,
+
+    /// Log files kept, counting the current one
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+
+    /// Level or `EnvFilter` directives for the file, independent of the console
+    #[serde(default = "default_log_level")]
+    pub level: String,
+
+    /// How lines are written, on the console and in the file
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Plain,
+    /// One JSON object per event, with the fields of the spans it happened in
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// A new file every day
+    #[default]
+    Daily,
+    Hourly,
+    /// A new file once the current one reaches `max_size_mb`
+    Size,
+    /// One ever-growing file
+    Never,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +215,47 @@ pub struct StreamingConfig {
     
     #[serde(default = "default_true")]
     pub hardware_encode: bool,
+
+    /// Bounds for adapting the bitrate to the link; `bitrate_mbps` is where
+    /// it starts. Set both to `bitrate_mbps` to keep it fixed.
+    #[serde(default = "default_min_bitrate")]
+    pub min_bitrate_mbps: u32,
+
+    #[serde(default = "default_max_bitrate")]
+    pub max_bitrate_mbps: u32,
+
+    /// Captured frames held for an encoder that is still busy. When another
+    /// arrives the oldest is dropped, so a slow encoder skips ahead instead of
+    /// falling further behind.
+    #[serde(default = "default_max_queued_frames")]
+    pub max_queued_frames: usize,
+
+    /// Compare each captured frame with the one before and don't encode
+    /// frames where nothing changed; costs a frame copy and compare on the CPU
+    #[serde(default)]
+    pub damage_tracking: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerOverride {
+    /// Match the peer by node_id (takes precedence over `name`)
+    #[serde(default)]
+    pub node_id: Option<String>,
+
+    /// Match the peer by its advertised node name
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub codec: Option<String>,
+
+    #[serde(default)]
+    pub bitrate_mbps: Option<u32>,
+
+    /// The peer's screen as "WIDTHxHEIGHT", for peers that don't report it
+    /// themselves; edge positions and absolute coordinates are scaled to it
+    #[serde(default)]
+    pub screen: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,31 +265,181 @@ pub struct SecurityConfig {
     
     #[serde(default = "default_session_timeout")]
     pub session_timeout_minutes: u64,
+
+    /// Seconds between heartbeat pings; three missed pongs close the session
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval_secs: u64,
+
+    /// How long a peer can bring back a session that closed after its
+    /// connection dropped, using the resumption token it was given
+    #[serde(default = "default_resume_grace")]
+    pub resume_grace_secs: u64,
     
     #[serde(default)]
     pub cert_path: Option<String>,
     
     #[serde(default)]
     pub key_path: Option<String>,
+
+    /// PEM bundle of CA or peer certificates trusted when connecting to peers
+    #[serde(default)]
+    pub ca_path: Option<String>,
+
+    /// Windows during which new sessions are accepted; empty means always
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_hours: Vec<AllowedHours>,
+
+    /// Close active sessions when the allowed window ends
+    #[serde(default)]
+    pub close_sessions_outside_hours: bool,
+
+    /// Node IDs of the only peers that may connect; empty allows any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_nodes: Vec<String>,
+
+    /// Node IDs of peers that may never connect, and are left out of discovery
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_nodes: Vec<String>,
+
+    /// Record session lifecycle events as JSON lines
+    #[serde(default)]
+    pub audit_log: bool,
+
+    /// Where the audit log goes; defaults to `sessions.jsonl` next to the config file
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+
+    /// Control connections allowed at once from a single address
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: u32,
+
+    /// Messages per second a peer may send on average, and how many it may
+    /// send in a burst; connections going over are dropped
+    #[serde(default = "default_max_message_rate")]
+    pub max_message_rate: u32,
+
+    #[serde(default = "default_message_burst")]
+    pub message_burst: u32,
+
+    /// Take the mouse back and stop forwarding input while the local screen
+    /// is locked
+    #[serde(default = "default_true")]
+    pub pause_on_lock: bool,
+}
+
+/// A daily time range, e.g. `{ days = ["mon", "fri"], start = "09:00", end = "18:00" }`.
+/// Ranges where `end` is before `start` run past midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllowedHours {
+    /// Weekdays the range starts on ("mon", "tuesday", ...); empty means every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub start: String,
+    pub end: String,
+}
+
+/// How forwarded keys are identified to the peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyForwarding {
+    /// The Linux keycode and its HID usage
+    #[default]
+    Keycode,
+    /// The keyboard's scancode (evdev MSC_SCAN) as well, so the peer can go by
+    /// the physical key and apply its own layout
+    Scancode,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputConfig {
     #[serde(default = "default_mouse_acceleration")]
     pub mouse_acceleration: f32,
+
+    /// Enables a speed-dependent acceleration curve when `mouse_acceleration` > 1.0.
+    /// Deltas of this size get double the linear gain.
+    #[serde(default)]
+    pub acceleration_curve_threshold: Option<f32>,
     
+    /// Per-axis motion multipliers, applied before acceleration, e.g. to match
+    /// displays with different pixel densities
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity_x: f32,
+
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity_y: f32,
+
+    /// Per-axis motion smaller than this (in device units) is dropped as jitter (0 = off)
+    #[serde(default)]
+    pub dead_zone_x: f32,
+
+    #[serde(default)]
+    pub dead_zone_y: f32,
+
     #[serde(default = "default_true")]
     pub enable_smooth_scroll: bool,
     
+    /// Pixels from a screen edge where the cursor counts as crossing it
+    #[serde(default = "default_edge_threshold")]
+    pub edge_threshold: u32,
+
     #[serde(default = "default_edge_activation_delay")]
     pub edge_activation_delay_ms: u32,
+
+    /// Pixels beyond the edge zone the local cursor is moved to when the mouse
+    /// crosses to a peer or comes back, so it can't cross again at once
+    #[serde(default = "default_edge_release_margin")]
+    pub edge_release_margin: u32,
+
+    /// Also sync the X11 PRIMARY selection (middle-click paste) as its own
+    /// channel, independent of CLIPBOARD. Ignored on Wayland.
+    #[serde(default)]
+    pub sync_primary_selection: bool,
+
+    /// After a button press, hold back motion for this long unless it exceeds
+    /// `click_motion_threshold`, so touchpad taps don't become micro-drags (0 = off)
+    #[serde(default)]
+    pub click_motion_window_ms: u32,
+
+    /// Accumulated motion (in pixels) that turns a held button into a drag
+    #[serde(default = "default_click_motion_threshold")]
+    pub click_motion_threshold: f32,
+
+    /// Window over which motion deltas are summed into one MouseMove (0 = off)
+    #[serde(default = "default_motion_coalesce")]
+    pub motion_coalesce_ms: u32,
+
+    /// Key combination that takes the mouse back from any peer, e.g.
+    /// "ctrl+alt+scrolllock" (empty = off)
+    #[serde(default = "default_force_local_hotkey")]
+    pub force_local_hotkey: String,
+
+    /// Key combinations never forwarded to a peer, written like
+    /// `force_local_hotkey`, e.g. "super+l" to keep the screen-lock shortcut
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_keys: Vec<String>,
+
+    /// "scancode" also sends the physical scancode of each key, for peers
+    /// whose keyboard layout differs from ours
+    #[serde(default)]
+    pub key_forwarding: KeyForwarding,
+
+    /// Only capture mice whose name contains this (case-insensitive); all
+    /// mice are used if none match. See `mirage-host devices`.
+    #[serde(default)]
+    pub preferred_device: Option<String>,
+
+    /// Input events buffered for slow consumers before the oldest are dropped
+    /// (see `mirage_input_events_dropped_total`). Read at startup.
+    #[serde(default = "default_event_channel_capacity")]
+    pub event_channel_capacity: usize,
 }
 
 impl Default for HostConfig {
     fn default() -> Self {
         Self {
             name: None,
-            display_edge_threshold: default_edge_threshold(),
+            display_server: None,
+            advertise: HashMap::new(),
         }
     }
 }
@@ -96,7 +449,33 @@ impl Default for NetworkConfig {
         Self {
             discovery_port: default_discovery_port(),
             control_port: default_control_port(),
-            allowed_subnets: vec!["192.168.0.0/16".to_string(), "10.0.0.0/8".to_string()],
+            allowed_subnets: vec![
+                "192.168.0.0/16".to_string(),
+                "10.0.0.0/8".to_string(),
+                "172.16.0.0/12".to_string(),
+                "fe80::/10".to_string(),
+                "fc00::/7".to_string(),
+            ],
+            peer_ttl_secs: default_peer_ttl(),
+            metrics_port: default_metrics_port(),
+            web_ui_port: 0,
+            transport: Transport::default(),
+            motion_over_udp: false,
+            max_frame_size: default_max_frame_size(),
+            bind_interfaces: Vec::new(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            rotation: LogRotation::default(),
+            max_size_mb: default_log_max_size(),
+            max_files: default_log_max_files(),
+            level: default_log_level(),
+            format: LogFormat::default(),
         }
     }
 }
@@ -108,6 +487,10 @@ impl Default for StreamingConfig {
             codec: default_codec(),
             bitrate_mbps: default_bitrate(),
             hardware_encode: true,
+            min_bitrate_mbps: default_min_bitrate(),
+            max_bitrate_mbps: default_max_bitrate(),
+            max_queued_frames: default_max_queued_frames(),
+            damage_tracking: false,
         }
     }
 }
@@ -117,8 +500,21 @@ impl Default for SecurityConfig {
         Self {
             require_pairing: true,
             session_timeout_minutes: default_session_timeout(),
+            heartbeat_interval_secs: default_heartbeat_interval(),
+            resume_grace_secs: default_resume_grace(),
             cert_path: None,
             key_path: None,
+            ca_path: None,
+            allowed_hours: Vec::new(),
+            close_sessions_outside_hours: false,
+            allowed_nodes: Vec::new(),
+            blocked_nodes: Vec::new(),
+            audit_log: false,
+            audit_log_path: None,
+            max_connections_per_ip: default_max_connections_per_ip(),
+            max_message_rate: default_max_message_rate(),
+            message_burst: default_message_burst(),
+            pause_on_lock: true,
         }
     }
 }
@@ -127,8 +523,24 @@ impl Default for InputConfig {
     fn default() -> Self {
         Self {
             mouse_acceleration: default_mouse_acceleration(),
+            acceleration_curve_threshold: None,
+            sensitivity_x: default_sensitivity(),
+            sensitivity_y: default_sensitivity(),
+            dead_zone_x: 0.0,
+            dead_zone_y: 0.0,
             enable_smooth_scroll: true,
+            edge_threshold: default_edge_threshold(),
             edge_activation_delay_ms: default_edge_activation_delay(),
+            edge_release_margin: default_edge_release_margin(),
+            sync_primary_selection: false,
+            click_motion_window_ms: 0,
+            click_motion_threshold: default_click_motion_threshold(),
+            motion_coalesce_ms: default_motion_coalesce(),
+            force_local_hotkey: default_force_local_hotkey(),
+            blocked_keys: Vec::new(),
+            key_forwarding: KeyForwarding::default(),
+            preferred_device: None,
+            event_channel_capacity: default_event_channel_capacity(),
         }
     }
 }
@@ -136,52 +548,655 @@ impl Default for InputConfig {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             host: HostConfig::default(),
             network: NetworkConfig::default(),
             streaming: StreamingConfig::default(),
             security: SecurityConfig::default(),
             input: InputConfig::default(),
+            logging: LoggingConfig::default(),
+            layout: ScreenLayout::default(),
+            peers: Vec::new(),
+            profiles: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
-    pub async fn load(path: &str) -> Result<Self> {
+    /// Load the config from `path` with `profile` laid over it, if given.
+    /// `access` decides what happens when the file is missing.
+    pub async fn load(path: &str, access: FileAccess, profile: Option<&str>) -> Result<Self> {
         let expanded_path = shellexpand::tilde(path);
         let path = Path::new(expanded_path.as_ref());
 
         if path.exists() {
-            let contents = fs::read_to_string(path)
-                .await
-                .context("Failed to read config file")?;
-            
-            toml::from_str(&contents).context("Failed to parse config file")
+            Self::read(path, profile, access).await
+        } else if let Some(name) = profile {
+            bail!("Config file {} does not exist, so it has no profile {:?}", path.display(), name)
+        } else if access == FileAccess::Existing {
+            bail!("Config file {} does not exist", path.display())
+        } else if access == FileAccess::ReadOnly {
+            info!("Config file {} does not exist, using the defaults", path.display());
+            Ok(Config::default())
         } else {
-            // Create default config
             let config = Config::default();
-            
-            // Try to create parent directory
-            if let Some(parent) = path.parent() {
-                let _ = fs::create_dir_all(parent).await;
-            }
-            
-            // Try to write default config
-            let toml_string = toml::to_string_pretty(&config)?;
-            let _ = fs::write(path, toml_string).await;
-            
+
+            if let Err(e) = Self::write(path, &config).await {
+                warn!("⚠ Could not write default config to {}: {:#}", path.display(), e);
+            } else {
+                info!("✓ Wrote default configuration to {}", path.display());
+            }
+
             Ok(config)
         }
     }
+
+    /// Write via a temp file and rename so a failed write never leaves a partial config
+    async fn write(path: &Path, config: &Config) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let toml_string = toml::to_string_pretty(config)?;
+        Self::replace_file(path, toml_string).await
+    }
+
+    /// Write `contents` next to `path` and rename it into place, so a crash
+    /// midway leaves the old file rather than half of the new one
+    async fn replace_file(path: &Path, contents: String) -> Result<()> {
+        let tmp_path = path.with_extension("toml.tmp");
+        if let Err(e) = fs::write(&tmp_path, contents).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e).context("Failed to write config file");
+        }
+        if let Err(e) = fs::rename(&tmp_path, path).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e).context("Failed to move config file into place");
+        }
+
+        Ok(())
+    }
+
+    /// Load the config and keep the returned receiver up to date as the file changes.
+    /// Edits that fail to load are logged and the last good config is kept. The
+    /// handle changes the config on request instead.
+    pub async fn watch(
+        path: &str,
+        access: FileAccess,
+        profile: Option<&str>,
+    ) -> Result<(watch::Receiver<Config>, ConfigHandle)> {
+        let config = Self::load(path, access, profile).await?;
+        let path = PathBuf::from(shellexpand::tilde(path).as_ref());
+        let profile = profile.map(str::to_string);
+        let (config_tx, config_rx) = watch::channel(config);
+        let config_tx = Arc::new(config_tx);
+        let handle = ConfigHandle {
+            path: path.clone(),
+            access,
+            profile: profile.clone(),
+            updates: config_tx.clone(),
+        };
+
+        // Watch the directory rather than the file so editors that save by
+        // replacing the file don't end the watch
+        let (change_tx, mut change_rx) = mpsc::unbounded_channel();
+        let file_name = path.file_name().map(|name| name.to_os_string());
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            let Ok(event) = result else { return };
+            let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event.paths.iter().any(|p| p.file_name() == file_name.as_deref());
+            if relevant {
+                let _ = change_tx.send(());
+            }
+        })
+        .context("Failed to create config file watcher")?;
+
+        let dir = path.parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            // A read-only setup may well run without the directory at all
+            if access == FileAccess::ReadOnly {
+                warn!("⚠ Not watching {} for changes: {}", dir.display(), e);
+                return Ok((config_rx, handle));
+            }
+            return Err(e).with_context(|| format!("Failed to watch {}", dir.display()));
+        }
+
+        tokio::spawn(async move {
+            // The watcher stops when dropped, so it lives as long as this task
+            let _watcher = watcher;
+
+            while change_rx.recv().await.is_some() {
+                tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                while change_rx.try_recv().is_ok() {}
+
+                match Self::read(&path, profile.as_deref(), access).await {
+                    Ok(config) => {
+                        info!("✓ Configuration reloaded from {}", path.display());
+                        if config_tx.send(config).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("Keeping previous configuration: {:#}", e),
+                }
+            }
+        });
+
+        Ok((config_rx, handle))
+    }
+
+    async fn read(path: &Path, profile: Option<&str>, access: FileAccess) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .await
+            .context("Failed to read config file")?;
+
+        let mut table: toml::Table = toml::from_str(&contents).context("Failed to parse config file")?;
+        let version = match table.get("version") {
+            None => 1,
+            Some(value) => value.as_integer()
+                .and_then(|version| u32::try_from(version).ok())
+                .with_context(|| format!("Config file {} has an invalid version {}", path.display(), value))?,
+        };
+        if version != CONFIG_VERSION {
+            table = migrate(version, table)
+                .with_context(|| format!("Failed to upgrade config file {}", path.display()))?;
+            if access != FileAccess::ReadOnly {
+                Self::upgrade_file(path, version, &table).await;
+            }
+        }
+
+        if let Some(name) = profile {
+            let overrides = table.get("profiles")
+                .and_then(|profiles| profiles.get(name))
+                .and_then(toml::Value::as_table)
+                .cloned()
+                .with_context(|| format!("Config file {} has no [profiles.{}] table", path.display(), name))?;
+            merge_tables(&mut table, overrides);
+        }
+        let config: Self = toml::Value::Table(table).try_into().context("Failed to parse config file")?;
+        config.validate()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    /// Save a config `migrate`d from `version` over the old file, keeping a copy
+    /// of that. Failing only costs migrating again next time, so it's logged.
+    async fn upgrade_file(path: &Path, version: u32, table: &toml::Table) {
+        let backup = path.with_extension(format!("toml.v{}", version));
+        let result = async {
+            fs::copy(path, &backup).await
+                .with_context(|| format!("Failed to back up the old config to {}", backup.display()))?;
+            let contents = toml::to_string_pretty(table)?;
+            Self::replace_file(path, contents).await
+        };
+        match result.await {
+            Ok(()) => info!(
+                "✓ Upgraded {} from config version {} to {}, the old file is at {}",
+                path.display(), version, CONFIG_VERSION, backup.display()
+            ),
+            Err(e) => warn!("⚠ Upgraded config version {} to {} in memory only: {:#}", version, CONFIG_VERSION, e),
+        }
+    }
+
+    /// Reject values that deserialize fine but can't work at runtime
+    pub fn validate(&self) -> Result<()> {
+        let fps = self.streaming.max_fps;
+        if !(1..=240).contains(&fps) {
+            bail!("streaming.max_fps must be between 1 and 240 (got {})", fps);
+        }
+        if self.streaming.bitrate_mbps == 0 {
+            bail!("streaming.bitrate_mbps must be greater than 0");
+        }
+        let (min, max) = (self.streaming.min_bitrate_mbps, self.streaming.max_bitrate_mbps);
+        if min == 0 || min > max {
+            bail!("streaming.min_bitrate_mbps must be between 1 and max_bitrate_mbps (got {} and {})", min, max);
+        }
+        validate_codec("streaming.codec", &self.streaming.codec)?;
+        if !(1..=MAX_QUEUED_FRAMES).contains(&self.streaming.max_queued_frames) {
+            bail!("streaming.max_queued_frames must be between 1 and {} (got {})",
+                MAX_QUEUED_FRAMES, self.streaming.max_queued_frames);
+        }
+        for (key, value) in &self.host.advertise {
+            if RESERVED_PROPERTIES.contains(&key.as_str()) {
+                bail!("host.advertise can't set {:?}, it is advertised by the host itself", key);
+            }
+            // Each TXT entry is a length byte followed by key=value
+            if key.is_empty() || key.contains('=') || key.len() + value.len() + 1 > 255 {
+                bail!("host.advertise entry {:?} must have a key without '=' and fit in 255 bytes", key);
+            }
+        }
+        if tracing_subscriber::EnvFilter::try_new(&self.logging.level).is_err() {
+            bail!("logging.level is not a valid level or filter (got {:?})", self.logging.level);
+        }
+        if self.logging.max_size_mb == 0 || self.logging.max_files == 0 {
+            bail!("logging.max_size_mb and logging.max_files must be greater than 0");
+        }
+        self.layout.validate()?;
+
+        let input = &self.input;
+        if input.sensitivity_x <= 0.0 || input.sensitivity_y <= 0.0 {
+            bail!("input.sensitivity_x and input.sensitivity_y must be greater than 0");
+        }
+        if input.dead_zone_x < 0.0 || input.dead_zone_y < 0.0 {
+            bail!("input.dead_zone_x and input.dead_zone_y must not be negative");
+        }
+
+        let threshold = input.edge_threshold;
+        if let Some((width, height)) = crate::input::detect_screen_size() {
+            if threshold >= width.min(height) {
+                bail!(
+                    "input.edge_threshold ({}) must be smaller than the screen ({}x{})",
+                    threshold, width, height
+                );
+            }
+            if threshold.saturating_add(input.edge_release_margin) >= width.min(height) / 2 {
+                bail!(
+                    "input.edge_threshold plus input.edge_release_margin ({}) must be less than half the screen ({}x{})",
+                    threshold.saturating_add(input.edge_release_margin), width, height
+                );
+            }
+        }
+
+        if self.input.event_channel_capacity < MIN_EVENT_CHANNEL_CAPACITY {
+            bail!("input.event_channel_capacity must be at least {}", MIN_EVENT_CHANNEL_CAPACITY);
+        }
+
+        if !self.input.force_local_hotkey.is_empty() {
+            crate::input::Hotkey::parse(&self.input.force_local_hotkey)
+                .context("input.force_local_hotkey is not a valid key combination")?;
+        }
+        for keys in &self.input.blocked_keys {
+            crate::input::Hotkey::parse(keys)
+                .with_context(|| format!("input.blocked_keys: {:?} is not a valid key combination", keys))?;
+        }
+
+        let security = &self.security;
+        if security.max_connections_per_ip == 0 {
+            bail!("security.max_connections_per_ip must be greater than 0");
+        }
+        if security.max_message_rate == 0 || security.message_burst == 0 {
+            bail!("security.max_message_rate and security.message_burst must be greater than 0");
+        }
+        if let Some(node_id) = security.allowed_nodes.iter().find(|node_id| security.blocks_node(node_id)) {
+            bail!("security.allowed_nodes and security.blocked_nodes both list {}", node_id);
+        }
+
+        if self.network.max_frame_size < MIN_FRAME_SIZE {
+            bail!("network.max_frame_size must be at least {} bytes", MIN_FRAME_SIZE);
+        }
+
+        for subnet in &self.network.allowed_subnets {
+            subnet.parse::<IpNet>()
+                .with_context(|| format!("network.allowed_subnets: {:?} is not a valid CIDR", subnet))?;
+        }
+
+        for peer in &self.peers {
+            if let Some(ref codec) = peer.codec {
+                validate_codec("peer.codec", codec)?;
+            }
+            if peer.bitrate_mbps == Some(0) {
+                bail!("peer.bitrate_mbps must be greater than 0");
+            }
+            if let Some(ref screen) = peer.screen {
+                if crate::input::parse_mode(screen).is_none() {
+                    bail!("peer.screen must look like 1920x1080 (got {:?})", screen);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the `[[peer]]` override matching a peer, if any
+    pub fn peer_override(&self, node_id: &str, node_name: &str) -> Option<&PeerOverride> {
+        self.peers.iter().find(|o| o.matches(node_id, node_name))
+    }
+
+    /// Configured screen size of a peer, if its `[[peer]]` entry sets one
+    pub fn peer_screen(&self, node_id: &str, node_name: &str) -> Option<(u32, u32)> {
+        self.peer_override(node_id, node_name)?
+            .screen
+            .as_deref()
+            .and_then(crate::input::parse_mode)
+    }
+
+    /// Streaming settings for a specific peer, layered over the global `[streaming]` section
+    pub fn streaming_for_peer(&self, node_id: &str, node_name: &str) -> StreamingConfig {
+        let mut streaming = self.streaming.clone();
+
+        if let Some(peer) = self.peer_override(node_id, node_name) {
+            if let Some(ref codec) = peer.codec {
+                streaming.codec = codec.clone();
+            }
+            if let Some(bitrate) = peer.bitrate_mbps {
+                streaming.bitrate_mbps = bitrate;
+            }
+        }
+
+        streaming
+    }
+}
+
+/// Changes the live config without waiting for the file to change, e.g. from
+/// the control socket. Cheap to clone.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    path: PathBuf,
+    access: FileAccess,
+    profile: Option<String>,
+    updates: Arc<watch::Sender<Config>>,
+}
+
+impl ConfigHandle {
+    pub fn current(&self) -> Config {
+        self.updates.borrow().clone()
+    }
+
+    /// Read the config file again
+    pub async fn reload(&self) -> Result<()> {
+        let config = Config::read(&self.path, self.profile.as_deref(), self.access).await?;
+        self.updates.send_replace(config);
+        info!("✓ Configuration reloaded from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Apply `change` to the live config, and write the result back to the
+    /// file if `persist` is set. Nothing changes if the result is invalid.
+    /// Changes can't be saved while a profile is active, since they would
+    /// land in the base settings along with the rest of the profile.
+    pub async fn update(&self, persist: bool, change: impl FnOnce(&mut Config)) -> Result<()> {
+        if let (true, Some(profile)) = (persist, &self.profile) {
+            bail!("Can't save changes while profile {:?} is active; edit [profiles.{}] in {}",
+                profile, profile, self.path.display());
+        }
+        if persist && self.access == FileAccess::ReadOnly {
+            bail!("Can't save changes, the daemon was started with --no-write-config");
+        }
+        let mut config = self.current();
+        change(&mut config);
+        config.validate()?;
+        if persist {
+            Config::write(&self.path, &config).await?;
+        }
+        self.updates.send_replace(config);
+        Ok(())
+    }
+}
+
+impl NetworkConfig {
+    /// Parsed `allowed_subnets`; invalid entries are skipped with a warning
+    pub fn allowed_networks(&self) -> Vec<IpNet> {
+        self.allowed_subnets.iter()
+            .filter_map(|subnet| match subnet.parse::<IpNet>() {
+                Ok(net) => Some(net),
+                Err(_) => {
+                    warn!("Ignoring invalid allowed_subnets entry {}", subnet);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl SecurityConfig {
+    /// Whether new sessions may be accepted at the given local time
+    pub fn accepts_sessions_at(&self, at: NaiveDateTime) -> bool {
+        self.allowed_hours.is_empty() || self.allowed_hours.iter().any(|range| range.contains(at))
+    }
+
+    /// Whether `node_id` is in `blocked_nodes`
+    pub fn blocks_node(&self, node_id: &str) -> bool {
+        self.blocked_nodes.iter().any(|blocked| blocked.eq_ignore_ascii_case(node_id))
+    }
+
+    /// Why the peer `node_id` may not connect, if it may not
+    pub fn refuses_node(&self, node_id: &str) -> Option<&'static str> {
+        if self.blocks_node(node_id) {
+            Some("it is in security.blocked_nodes")
+        } else if !self.allowed_nodes.is_empty()
+            && !self.allowed_nodes.iter().any(|allowed| allowed.eq_ignore_ascii_case(node_id))
+        {
+            Some("it is not in security.allowed_nodes")
+        } else {
+            None
+        }
+    }
+}
+
+impl AllowedHours {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let (Some(start), Some(end)) = (parse_time(&self.start), parse_time(&self.end)) else {
+            warn!("Ignoring allowed_hours range with invalid time {}-{}", self.start, self.end);
+            return false;
+        };
+
+        let time = at.time();
+        let today = at.weekday();
+
+        if start <= end {
+            self.applies_on(today) && time >= start && time < end
+        } else {
+            // Overnight: the part after midnight belongs to the previous day's range
+            (self.applies_on(today) && time >= start) || (self.applies_on(today.pred()) && time < end)
+        }
+    }
+
+    fn applies_on(&self, day: Weekday) -> bool {
+        self.days.is_empty()
+            || self.days.iter().any(|d| d.parse::<Weekday>().map(|w| w == day).unwrap_or(false))
+    }
+}
+
+fn validate_codec(field: &str, codec: &str) -> Result<()> {
+    if !KNOWN_CODECS.contains(&codec.to_ascii_lowercase().as_str()) {
+        bail!("{} must be one of {} (got {:?})", field, KNOWN_CODECS.join(", "), codec);
+    }
+    Ok(())
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M").ok()
+}
+
+impl PeerOverride {
+    fn matches(&self, node_id: &str, node_name: &str) -> bool {
+        match (&self.node_id, &self.name) {
+            (Some(id), _) => id == node_id,
+            (None, Some(name)) => name == node_name,
+            (None, None) => false,
+        }
+    }
+}
+
+/// Upgrade a config table written at `from_version` to `CONFIG_VERSION`, one
+/// version at a time, moving renamed settings to where they live now. Settings
+/// added since need no help: their defaults fill them in. Profiles are
+/// upgraded along with the rest.
+pub fn migrate(from_version: u32, mut table: toml::Table) -> Result<toml::Table> {
+    if from_version == 0 || from_version > CONFIG_VERSION {
+        bail!(
+            "Config version {} is not one this build knows (1 to {}); was it written by a newer mirage-host?",
+            from_version, CONFIG_VERSION
+        );
+    }
+
+    for version in from_version..CONFIG_VERSION {
+        let step: fn(&mut toml::Table) = match version {
+            // 1 → 2: the edge threshold joined the other edge settings
+            1 => |table| move_key(table, ("host", "display_edge_threshold"), ("input", "edge_threshold")),
+            _ => unreachable!("no migration from config version {}", version),
+        };
+        step(&mut table);
+        if let Some(profiles) = table.get_mut("profiles").and_then(toml::Value::as_table_mut) {
+            for (_, profile) in profiles.iter_mut() {
+                if let Some(profile) = profile.as_table_mut() {
+                    step(profile);
+                }
+            }
+        }
+    }
+
+    table.insert("version".to_string(), toml::Value::Integer(CONFIG_VERSION.into()));
+    Ok(table)
+}
+
+/// Move `[from.0] from.1` to `[to.0] to.1`, unless the new key is already set
+fn move_key(table: &mut toml::Table, from: (&str, &str), to: (&str, &str)) {
+    let Some(value) = table.get_mut(from.0)
+        .and_then(toml::Value::as_table_mut)
+        .and_then(|section| section.remove(from.1))
+    else {
+        return;
+    };
+    let section = table.entry(to.0).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let Some(section) = section.as_table_mut() {
+        section.entry(to.1).or_insert(value);
+    }
+}
+
+/// Lay `overrides` over `base`: tables merge key by key, anything else,
+/// `[[peer]]` lists included, replaces what was there
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge_tables(base, value),
+            (Some(existing), value) => *existing = value,
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
 }
 
 // Default value functions
+fn default_config_version() -> u32 { CONFIG_VERSION }
 fn default_edge_threshold() -> u32 { 10 }
 fn default_discovery_port() -> u16 { 5353 }
 fn default_control_port() -> u16 { 8443 }
+fn default_peer_ttl() -> u64 { 30 }
+fn default_metrics_port() -> u16 { 9464 }
+fn default_max_frame_size() -> usize { 1024 * 1024 }
 fn default_max_fps() -> u32 { 60 }
 fn default_codec() -> String { "h264".to_string() }
 fn default_bitrate() -> u32 { 10 }
+fn default_min_bitrate() -> u32 { 2 }
+fn default_max_bitrate() -> u32 { 20 }
+fn default_max_queued_frames() -> usize { 2 }
 fn default_session_timeout() -> u64 { 60 }
+fn default_heartbeat_interval() -> u64 { 5 }
+fn default_resume_grace() -> u64 { 120 }
+fn default_max_connections_per_ip() -> u32 { 4 }
+fn default_max_message_rate() -> u32 { 1000 }
+fn default_message_burst() -> u32 { 2000 }
 fn default_mouse_acceleration() -> f32 { 1.0 }
+fn default_sensitivity() -> f32 { 1.0 }
 fn default_edge_activation_delay() -> u32 { 100 }
+fn default_click_motion_threshold() -> f32 { 4.0 }
+fn default_motion_coalesce() -> u32 { 4 }
+fn default_force_local_hotkey() -> String { "ctrl+alt+scrolllock".to_string() }
+fn default_event_channel_capacity() -> usize { 1000 }
+fn default_edge_release_margin() -> u32 { 5 }
+fn default_log_max_size() -> u64 { 10 }
+fn default_log_max_files() -> usize { 7 }
+fn default_log_level() -> String { "info".to_string() }
 fn default_true() -> bool { true }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1_CONFIG: &str = r#"
+        [host]
+        name = "desk"
+        display_edge_threshold = 25
+
+        [input]
+        mouse_acceleration = 1.5
+
+        [profiles.couch.host]
+        display_edge_threshold = 40
+    "#;
+
+    #[test]
+    fn v1_config_migrates_to_current() {
+        let table = migrate(1, toml::from_str(V1_CONFIG).unwrap()).unwrap();
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.host.name.as_deref(), Some("desk"));
+        assert_eq!(config.input.edge_threshold, 25);
+        assert_eq!(config.input.mouse_acceleration, 1.5);
+        // Settings added since version 1 take their defaults
+        assert_eq!(config.input.edge_release_margin, default_edge_release_margin());
+
+        let couch = &config.profiles["couch"];
+        assert_eq!(couch["input"]["edge_threshold"].as_integer(), Some(40));
+        assert!(couch["host"].as_table().unwrap().is_empty());
+    }
+
+    #[test]
+    fn node_lists_decide_who_may_connect() {
+        let mut security = SecurityConfig::default();
+        assert_eq!(security.refuses_node("a"), None);
+
+        security.blocked_nodes = vec!["B".to_string()];
+        assert!(security.refuses_node("b").is_some());
+        assert_eq!(security.refuses_node("a"), None);
+
+        security.allowed_nodes = vec!["a".to_string()];
+        assert_eq!(security.refuses_node("a"), None);
+        assert!(security.refuses_node("c").is_some());
+
+        let mut config = Config::default();
+        config.security.allowed_nodes = vec!["a".to_string()];
+        config.security.blocked_nodes = vec!["a".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn newer_config_versions_are_refused() {
+        assert!(migrate(CONFIG_VERSION + 1, toml::Table::new()).is_err());
+        assert!(migrate(0, toml::Table::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn loading_a_v1_file_rewrites_it() {
+        let dir = std::env::temp_dir().join(format!("mirage-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, V1_CONFIG).unwrap();
+
+        let config = Config::load(path.to_str().unwrap(), FileAccess::Existing, Some("couch")).await.unwrap();
+        assert_eq!(config.input.edge_threshold, 40);
+
+        let rewritten: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(rewritten["version"].as_integer(), Some(CONFIG_VERSION.into()));
+        assert_eq!(std::fs::read_to_string(path.with_extension("toml.v1")).unwrap(), V1_CONFIG);
+        assert!(!path.with_extension("toml.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_loading_writes_nothing() {
+        let dir = std::env::temp_dir().join(format!("mirage-config-test-ro-{}", std::process::id()));
+        let missing = dir.join("config.toml");
+        let config = Config::load(missing.to_str().unwrap(), FileAccess::ReadOnly, None).await.unwrap();
+        assert_eq!(config.input.edge_threshold, default_edge_threshold());
+        assert!(!dir.exists());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old.toml");
+        std::fs::write(&old, V1_CONFIG).unwrap();
+        let config = Config::load(old.to_str().unwrap(), FileAccess::ReadOnly, None).await.unwrap();
+        assert_eq!(config.input.edge_threshold, 25);
+        assert_eq!(std::fs::read_to_string(&old).unwrap(), V1_CONFIG);
+        assert!(!old.with_extension("toml.v1").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+// Local control socket for `mirage-host ctl` and other tools on this machine.
+// Each line sent is one JSON request, answered with one JSON line:
+// `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::RwLock;
+use tracing::{info, debug};
+
+use crate::config::ConfigHandle;
+use crate::discovery::PeerDevice;
+use crate::input::ScreenEdge;
+use crate::session::{CloseReason, MouseOwner, SessionManager, SessionState};
+
+/// What a client can ask the daemon
+#[derive(Debug, Clone, Serialize, Deserialize, Subcommand)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Request {
+    /// List open sessions
+    Sessions,
+    /// List discovered peers
+    Peers,
+    /// Give the mouse to a session's peer
+    Transfer {
+        session_id: String,
+    },
+    /// Take the mouse back from whichever peer has it
+    Reclaim,
+    /// Close a session
+    Close {
+        session_id: String,
+    },
+    /// Re-read the config file
+    Reload,
+    /// Show the screen layout
+    Layout,
+    /// Put a discovered peer past an edge of this screen, or clear the edge
+    SetLayout {
+        #[arg(value_enum)]
+        edge: ScreenEdge,
+        /// Node ID of the peer; leave out to clear the edge
+        peer: Option<String>,
+        /// Write the change to the config file as well
+        #[arg(long)]
+        #[serde(default)]
+        persist: bool,
+    },
+}
+
+/// A session as `ctl sessions` and the dashboard show it
+#[derive(Serialize)]
+pub struct SessionSummary {
+    session_id: String,
+    peer_node_id: String,
+    peer_name: String,
+    state: &'static str,
+    owns_mouse: bool,
+    created_at: String,
+    last_activity: String,
+}
+
+/// Every open session, marking the one with the mouse
+pub async fn session_summaries(sessions: &SessionManager) -> Vec<SessionSummary> {
+    let owner = sessions.current_mouse_owner().await;
+    sessions.list_sessions().await
+        .into_iter()
+        .map(|session| SessionSummary {
+            owns_mouse: owner.as_deref() == Some(session.session_id.as_str()),
+            state: match session.state {
+                SessionState::Connected => "connected",
+                SessionState::Reconnecting { .. } => "reconnecting",
+                SessionState::Disconnected => "disconnected",
+            },
+            session_id: session.session_id,
+            peer_node_id: session.peer_node_id,
+            peer_name: session.peer_name,
+            created_at: session.created_at.to_rfc3339(),
+            last_activity: session.last_activity.to_rfc3339(),
+        })
+        .collect()
+}
+
+/// `$XDG_RUNTIME_DIR/mirage/control.sock`, or a per-user directory under the
+/// temp dir where there is no runtime dir. A named `instance` gets
+/// `control-<instance>.sock` instead.
+pub fn socket_path(instance: Option<&str>) -> PathBuf {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("mirage"),
+        None => std::env::temp_dir().join(format!("mirage-{}", unsafe { libc::getuid() })),
+    };
+    match instance {
+        Some(instance) => dir.join(format!("control-{}.sock", instance)),
+        None => dir.join("control.sock"),
+    }
+}
+
+pub struct ControlSocket {
+    path: PathBuf,
+    sessions: SessionManager,
+    peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+    config: ConfigHandle,
+}
+
+impl ControlSocket {
+    pub fn new(
+        path: PathBuf,
+        sessions: SessionManager,
+        peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+        config: ConfigHandle,
+    ) -> Self {
+        Self {
+            path,
+            sessions,
+            peers,
+            config,
+        }
+    }
+
+    /// Answer requests until the listener fails
+    pub async fn run(self) -> Result<()> {
+        let listener = bind(&self.path).await?;
+        info!("✓ Control socket at {}", self.path.display());
+
+        let this = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await.context("Failed to accept on the control socket")?;
+            let this = this.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.serve(stream).await {
+                    debug!("Control socket client failed: {:#}", e);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => match self.handle(request).await {
+                    Ok(result) => json!({ "ok": true, "result": result }),
+                    Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+                },
+                Err(e) => json!({ "ok": false, "error": format!("Bad request: {}", e) }),
+            };
+            let mut line = serde_json::to_string(&response)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&self, request: Request) -> Result<Value> {
+        debug!("Control socket request: {:?}", request);
+        match request {
+            Request::Sessions => Ok(serde_json::to_value(session_summaries(&self.sessions).await)?),
+            Request::Peers => {
+                let peers: Vec<PeerDevice> = self.peers.read().await.values().cloned().collect();
+                Ok(serde_json::to_value(peers)?)
+            }
+            Request::Transfer { session_id } => {
+                if let Some(owner) = self.sessions.current_mouse_owner().await {
+                    if owner != session_id {
+                        self.sessions.transfer_mouse(&owner, MouseOwner::Local).await?;
+                    }
+                }
+                self.sessions.transfer_mouse(&session_id, MouseOwner::Remote).await?;
+                Ok(Value::Null)
+            }
+            Request::Reclaim => {
+                self.sessions.force_local().await;
+                Ok(Value::Null)
+            }
+            Request::Close { session_id } => {
+                if !self.sessions.list_sessions().await.iter().any(|s| s.session_id == session_id) {
+                    bail!("Unknown session {}", session_id);
+                }
+                self.sessions.close_session(&session_id, CloseReason::Requested).await;
+                Ok(Value::Null)
+            }
+            Request::Reload => {
+                self.config.reload().await?;
+                Ok(Value::Null)
+            }
+            Request::Layout => Ok(self.layout()),
+            Request::SetLayout { edge, peer, persist } => {
+                if let Some(ref node_id) = peer {
+                    if !self.peers.read().await.contains_key(node_id) {
+                        bail!("No discovered peer with node ID {}", node_id);
+                    }
+                }
+                self.config.update(persist, |config| config.layout.set_neighbor(edge, peer)).await?;
+                info!("✓ Layout changed over the control socket{}", if persist { " and saved" } else { "" });
+                Ok(self.layout())
+            }
+        }
+    }
+
+    fn layout(&self) -> Value {
+        serde_json::to_value(self.config.current().layout).unwrap_or_default()
+    }
+}
+
+/// Listen at `path`, replacing a socket left behind by a daemon that didn't
+/// shut down cleanly, and only let this user connect
+async fn bind(path: &Path) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        tokio::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700)).await?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("Another daemon is already listening on {}", path.display());
+        }
+        tokio::fs::remove_file(path).await
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+/// Send one request to the daemon listening at `path` and print what it answers
+pub async fn run_client(path: &Path, request: Request) -> Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Is mirage-host running? No control socket at {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let Some(reply) = BufReader::new(reader).lines().next_line().await? else {
+        bail!("The daemon closed the control socket without answering");
+    };
+    let reply: Value = serde_json::from_str(&reply).context("Malformed reply from the daemon")?;
+    if reply["ok"] != Value::Bool(true) {
+        bail!("{}", reply["error"].as_str().unwrap_or("Request failed"));
+    }
+
+    match reply["result"] {
+        Value::Null => println!("ok"),
+        ref result => println!("{}", serde_json::to_string_pretty(result)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, FileAccess};
+    use crate::discovery::PeerCapabilities;
+    use crate::proto::PROTOCOL_VERSION;
+
+    fn peer(node_id: &str) -> PeerDevice {
+        PeerDevice {
+            node_id: node_id.to_string(),
+            node_name: node_id.to_string(),
+            os_type: "linux".to_string(),
+            addresses: Vec::new(),
+            control_port: 0,
+            capabilities: PeerCapabilities::local(),
+            extra: HashMap::new(),
+            last_seen: std::time::Instant::now(),
+            online: true,
+            manual: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn set_layout_reroutes_the_next_edge_crossing() {
+        let dir = std::env::temp_dir().join(format!("mirage-ctl-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.toml");
+        let (updates, config) = Config::watch(config_file.to_str().unwrap(), FileAccess::ReadOnly, None)
+            .await
+            .unwrap();
+        let sessions = SessionManager::new(config.current(), "host".to_string())
+            .await
+            .unwrap()
+            .with_config_updates(updates);
+
+        // Both peers are connected already, so a crossing only has to pick one
+        let mut session_ids = HashMap::new();
+        for node_id in ["a", "b"] {
+            let session = sessions
+                .create_session(node_id.to_string(), node_id.to_string(), PROTOCOL_VERSION, None)
+                .await
+                .unwrap();
+            session_ids.insert(node_id, session.session_id);
+        }
+        let peers = HashMap::from([("a".to_string(), peer("a")), ("b".to_string(), peer("b"))]);
+        let control = ControlSocket::new(dir.join("control.sock"), sessions.clone(), Arc::new(RwLock::new(peers)), config);
+
+        let set_right = |peer: &str| Request::SetLayout { edge: ScreenEdge::Right, peer: Some(peer.to_string()), persist: false };
+        control.handle(set_right("a")).await.unwrap();
+        sessions.handle_edge_crossed(ScreenEdge::Right, (1920.0, 500.0)).await.unwrap();
+        assert_eq!(sessions.current_mouse_owner().await.as_ref(), Some(&session_ids["a"]));
+
+        sessions.force_local().await;
+        control.handle(set_right("b")).await.unwrap();
+        sessions.handle_edge_crossed(ScreenEdge::Right, (1920.0, 500.0)).await.unwrap();
+        assert_eq!(sessions.current_mouse_owner().await.as_ref(), Some(&session_ids["b"]));
+        assert_eq!(control.layout()["right"], "b");
+
+        // Only discovered peers can be put in the layout
+        assert!(control.handle(set_right("c")).await.is_err());
+        assert_eq!(control.layout()["right"], "b");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
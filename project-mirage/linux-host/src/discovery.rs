@@ -1,28 +1,77 @@
-use anyhow::{Context, Result};
-use mdns_sd::{ServiceDaemon, ServiceInfo, ServiceEvent};
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use mdns_sd::{IfKind, Receiver, ServiceDaemon, ServiceInfo, ServiceEvent};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, RwLock};
 use tracing::{info, warn, debug, error};
 use uuid::Uuid;
 
 use crate::config::Config;
+use crate::network::ControlClient;
+use crate::security::Identity;
+use crate::session::reconnect_delay;
+use crate::transport;
 
 const SERVICE_TYPE: &str = "_mirage._tcp.local.";
 
-#[derive(Debug, Clone)]
+// TXT properties the host sets itself; `host.advertise` can't override them
+pub const RESERVED_PROPERTIES: &[&str] = &[
+    "node_id", "node_name", "os_type", "can_host_mouse", "can_capture_windows", "can_render_streams", "video_codecs",
+];
+
+// How long to wait for the mDNS goodbye to go out on shutdown
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Tries at bringing back a daemon that stopped, backing off between them
+const RECOVERY_ATTEMPTS: u32 = 8;
+
+// How often local addresses are checked; the advertisement goes stale when
+// they change, e.g. on a VPN connect or Wi-Fi switch
+const ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
 pub struct PeerDevice {
     pub node_id: String,
     pub node_name: String,
     pub os_type: String,
-    pub ip_address: IpAddr,
+    /// Every resolved address, most preferred first
+    pub addresses: Vec<IpAddr>,
     pub control_port: u16,
     pub capabilities: PeerCapabilities,
+    /// TXT properties beyond our own, from the peer's `host.advertise`
+    pub extra: HashMap<String, String>,
+    #[serde(skip)]
     pub last_seen: std::time::Instant,
+    /// False for peers loaded from the cache that mDNS hasn't resolved again yet
+    pub online: bool,
+    /// Added by address with `--connect`. mDNS never refreshes these, so they
+    /// don't expire.
+    pub manual: bool,
 }
 
-#[derive(Debug, Clone)]
+/// Peers are equal when everything but `last_seen` matches, so a re-resolution
+/// that only proves the peer is still there doesn't count as a change
+impl PartialEq for PeerDevice {
+    fn eq(&self, other: &Self) -> bool {
+        self.node_id == other.node_id
+            && self.node_name == other.node_name
+            && self.os_type == other.os_type
+            && self.addresses == other.addresses
+            && self.control_port == other.control_port
+            && self.capabilities == other.capabilities
+            && self.extra == other.extra
+            && self.online == other.online
+            && self.manual == other.manual
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PeerCapabilities {
     pub can_host_mouse: bool,
     pub can_capture_windows: bool,
@@ -30,14 +79,133 @@ pub struct PeerCapabilities {
     pub video_codecs: Vec<String>,
 }
 
+impl PeerCapabilities {
+    /// What this host offers
+    pub fn local() -> Self {
+        Self {
+            can_host_mouse: true,
+            can_capture_windows: cfg!(feature = "streaming"),
+            can_render_streams: cfg!(feature = "streaming"),
+            video_codecs: vec!["h264".to_string(), "h265".to_string()],
+        }
+    }
+
+    /// Peers that don't advertise codecs are assumed to accept anything
+    pub fn supports_codec(&self, codec: &str) -> bool {
+        self.video_codecs.is_empty()
+            || self.video_codecs.iter().any(|c| c.eq_ignore_ascii_case(codec))
+    }
+}
+
+/// On-disk form of a peer; `last_seen` is wall-clock seconds since the epoch
+#[derive(Serialize, Deserialize)]
+struct CachedPeer {
+    node_id: String,
+    node_name: String,
+    os_type: String,
+    addresses: Vec<IpAddr>,
+    control_port: u16,
+    capabilities: PeerCapabilities,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    extra: HashMap<String, String>,
+    last_seen: u64,
+}
+
+impl From<&PeerDevice> for CachedPeer {
+    fn from(peer: &PeerDevice) -> Self {
+        let age = peer.last_seen.elapsed();
+        let last_seen = SystemTime::now()
+            .checked_sub(age)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            node_id: peer.node_id.clone(),
+            node_name: peer.node_name.clone(),
+            os_type: peer.os_type.clone(),
+            addresses: peer.addresses.clone(),
+            control_port: peer.control_port,
+            capabilities: peer.capabilities.clone(),
+            extra: peer.extra.clone(),
+            last_seen,
+        }
+    }
+}
+
+impl From<CachedPeer> for PeerDevice {
+    fn from(cached: CachedPeer) -> Self {
+        let age = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(cached.last_seen))
+            .unwrap_or_default();
+
+        Self {
+            node_id: cached.node_id,
+            node_name: cached.node_name,
+            os_type: cached.os_type,
+            addresses: cached.addresses,
+            control_port: cached.control_port,
+            capabilities: cached.capabilities,
+            extra: cached.extra,
+            last_seen: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            online: false,
+            manual: false,
+        }
+    }
+}
+
 pub struct DiscoveryService {
     config: Config,
+    identity: Identity,
+    node_id: String,
+    node_name: String,
+    /// Set for a named daemon instance, to tell it apart from others on this machine
+    instance: Option<String>,
+    /// Replaced when the daemon has to be restarted
+    daemon: Arc<parking_lot::Mutex<ServiceDaemon>>,
+    peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+    peer_cache: Option<PathBuf>,
+    event_tx: broadcast::Sender<DiscoveryEvent>,
+    /// Set while our service is registered
+    service_fullname: Option<String>,
+    /// Set by `stop` so the daemon shutting down isn't taken for a failure
+    stopped: Arc<AtomicBool>,
+}
+
+/// What we advertise, kept to register it again on a restarted daemon
+#[derive(Clone)]
+struct Advertisement {
     node_id: String,
     node_name: String,
-    daemon: ServiceDaemon,
+    instance: Option<String>,
+    port: u16,
+    extra: HashMap<String, String>,
+    allowed_networks: Vec<IpNet>,
+    interfaces: Vec<String>,
+}
+
+/// Applies browse events to the peer table
+struct Browser {
     peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
-    event_tx: mpsc::Sender<DiscoveryEvent>,
-    event_rx: mpsc::Receiver<DiscoveryEvent>,
+    /// Node ID by the full service name each peer resolved under, to match
+    /// later events for it
+    fullnames: parking_lot::Mutex<HashMap<String, String>>,
+    event_tx: broadcast::Sender<DiscoveryEvent>,
+    node_id: String,
+    peer_cache: Option<PathBuf>,
+    allowed_networks: Vec<IpNet>,
+    interfaces: Vec<String>,
+}
+
+/// What became of a resolved mDNS record
+enum ParsedService {
+    Peer(PeerDevice),
+    /// Our own advertisement
+    Own,
+    /// A required property (or any address) is missing
+    Missing(&'static str),
+    /// Every address is outside `network.allowed_subnets`
+    NotAllowed(Vec<IpAddr>),
 }
 
 #[derive(Debug, Clone)]
@@ -48,126 +216,182 @@ pub enum DiscoveryEvent {
 }
 
 impl DiscoveryService {
-    pub async fn new(config: Config, node_name: String) -> Result<Self> {
-        let node_id = Uuid::new_v4().to_string();
-        let daemon = ServiceDaemon::new().context("Failed to create mDNS daemon")?;
-        let (event_tx, event_rx) = mpsc::channel(100);
+    pub async fn new(config: Config, identity: Identity, node_name: String) -> Result<Self> {
+        let node_id = identity.node_id().to_string();
+        let daemon = new_daemon(&config.network.bind_interfaces)?;
+        let (event_tx, _) = broadcast::channel(100);
 
         Ok(Self {
             config,
+            identity,
             node_id,
             node_name,
-            daemon,
+            instance: None,
+            daemon: Arc::new(parking_lot::Mutex::new(daemon)),
             peers: Arc::new(RwLock::new(HashMap::new())),
+            peer_cache: None,
             event_tx,
-            event_rx,
+            service_fullname: None,
+            stopped: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Remember discovered peers in `path` (JSON) across restarts
+    pub fn with_peer_cache(mut self, path: PathBuf) -> Self {
+        self.peer_cache = Some(path);
+        self
+    }
+
+    /// Advertise as daemon instance `instance`, next to others on this machine
+    pub fn with_instance(mut self, instance: String) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    /// Load previously discovered peers as offline until mDNS resolves them again
+    pub async fn load_cached_peers(&self) -> Result<usize> {
+        let Some(ref path) = self.peer_cache else {
+            return Ok(0);
+        };
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .context("Failed to read peer cache")?;
+        let cached: Vec<CachedPeer> = serde_json::from_str(&contents)
+            .context("Failed to parse peer cache")?;
+
+        let mut peers = self.peers.write().await;
+        let mut loaded = 0;
+        for peer in cached {
+            if peer.node_id != self.node_id && !peers.contains_key(&peer.node_id) {
+                peers.insert(peer.node_id.clone(), peer.into());
+                loaded += 1;
+            }
+        }
+
+        Ok(loaded)
+    }
+
     pub async fn start(&mut self) -> Result<()> {
+        match self.load_cached_peers().await {
+            Ok(0) => {}
+            Ok(count) => info!("✓ Loaded {} cached peer(s)", count),
+            Err(e) => warn!("⚠ Ignoring peer cache: {}", e),
+        }
+
         // Register our service
         self.register_service().await?;
         
         // Start browsing for peers
         self.browse_services().await?;
+
+        self.spawn_expiry();
         
         Ok(())
     }
 
-    pub async fn stop(&mut self) -> Result<()> {
-        // Unregister service
-        self.daemon.shutdown().context("Failed to shutdown mDNS daemon")?;
-        Ok(())
-    }
+    /// Drop online peers that mDNS hasn't seen within `network.peer_ttl_secs`.
+    /// ServiceRemoved is easily missed on flaky networks.
+    fn spawn_expiry(&self) {
+        let ttl = Duration::from_secs(self.config.network.peer_ttl_secs);
+        let peers = Arc::clone(&self.peers);
+        let event_tx = self.event_tx.clone();
 
-    async fn register_service(&self) -> Result<()> {
-        let hostname = hostname::get()
-            .ok()
-            .and_then(|h| h.into_string().ok())
-            .unwrap_or_else(|| "linux-host".to_string());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((ttl / 3).max(Duration::from_secs(1)));
 
-        let service_name = format!("{}._mirage", self.node_name);
-        let port = self.config.network.control_port;
+            loop {
+                interval.tick().await;
 
-        // Get local IP address
-        let local_ip = get_local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+                let mut peers_lock = peers.write().await;
+                let expired: Vec<PeerDevice> = peers_lock.values()
+                    .filter(|p| p.online && !p.manual && p.last_seen.elapsed() > ttl)
+                    .cloned()
+                    .collect();
 
-        let mut properties = HashMap::new();
-        properties.insert("node_id".to_string(), self.node_id.clone());
-        properties.insert("os_type".to_string(), "linux".to_string());
-        properties.insert("can_host_mouse".to_string(), "true".to_string());
-        properties.insert("can_capture_windows".to_string(), "true".to_string());
-        properties.insert("can_render_streams".to_string(), "true".to_string());
-        properties.insert("video_codecs".to_string(), "h264,h265".to_string());
+                for peer in expired {
+                    info!("👋 Peer lost: {} ({}), not seen for {:?}",
+                        peer.node_name, peer.os_type, peer.last_seen.elapsed());
+                    peers_lock.remove(&peer.node_id);
+                    let _ = event_tx.send(DiscoveryEvent::PeerLost(peer.node_id));
+                }
+            }
+        });
+    }
 
-        let service_info = ServiceInfo::new(
-            SERVICE_TYPE,
-            &service_name,
-            &hostname,
-            local_ip,
-            port,
-            Some(properties),
-        )?;
+    pub async fn stop(&mut self) -> Result<()> {
+        self.stopped.store(true, Ordering::Relaxed);
+        let daemon = self.daemon.lock().clone();
 
-        self.daemon.register(service_info)
-            .context("Failed to register mDNS service")?;
+        // The goodbye packet lets peers drop us now instead of when the record expires
+        if let Some(fullname) = self.service_fullname.take() {
+            match daemon.unregister(&fullname) {
+                Ok(status) => {
+                    if tokio::time::timeout(UNREGISTER_TIMEOUT, status.recv_async()).await.is_err() {
+                        warn!("⚠ Timed out unregistering {}", fullname);
+                    } else {
+                        info!("✓ Unregistered service {}", fullname);
+                    }
+                }
+                Err(e) => warn!("⚠ Failed to unregister {}: {}", fullname, e),
+            }
+        }
 
-        info!("✓ Registered service: {} at {}:{}", service_name, local_ip, port);
+        daemon.shutdown().context("Failed to shutdown mDNS daemon")?;
         Ok(())
     }
 
+    fn advertisement(&self) -> Advertisement {
+        Advertisement {
+            node_id: self.node_id.clone(),
+            node_name: self.node_name.clone(),
+            instance: self.instance.clone(),
+            port: self.config.network.control_port,
+            extra: self.config.host.advertise.clone(),
+            allowed_networks: self.config.network.allowed_networks(),
+            interfaces: self.config.network.bind_interfaces.clone(),
+        }
+    }
+
+    async fn register_service(&mut self) -> Result<()> {
+        let fullname = self.advertisement().register(&self.daemon.lock())?;
+        self.service_fullname = Some(fullname);
+        Ok(())
+    }
+
+    /// Browse for peers on a task of its own, which restarts the daemon if
+    /// browsing stops
     async fn browse_services(&mut self) -> Result<()> {
-        let receiver = self.daemon.browse(SERVICE_TYPE)
+        let receiver = self.daemon.lock().browse(SERVICE_TYPE)
             .context("Failed to browse for mDNS services")?;
 
-        let peers = Arc::clone(&self.peers);
-        let event_tx = self.event_tx.clone();
-        let node_id = self.node_id.clone();
+        let browser = Browser {
+            peers: Arc::clone(&self.peers),
+            fullnames: parking_lot::Mutex::new(HashMap::new()),
+            event_tx: self.event_tx.clone(),
+            node_id: self.node_id.clone(),
+            peer_cache: self.peer_cache.clone(),
+            allowed_networks: self.config.network.allowed_networks(),
+            interfaces: self.config.network.bind_interfaces.clone(),
+        };
+        let daemon = Arc::clone(&self.daemon);
+        let advertisement = self.advertisement();
+        let stopped = Arc::clone(&self.stopped);
 
         tokio::spawn(async move {
-            while let Ok(event) = receiver.recv_async().await {
-                match event {
-                    ServiceEvent::ServiceResolved(info) => {
-                        debug!("Service resolved: {:?}", info);
-                        
-                        if let Some(peer) = Self::parse_service_info(&info, &node_id) {
-                            info!("🔍 Discovered peer: {} ({}) at {}:{}", 
-                                peer.node_name, peer.os_type, peer.ip_address, peer.control_port);
-                            
-                            let mut peers_lock = peers.write().await;
-                            let is_new = !peers_lock.contains_key(&peer.node_id);
-                            peers_lock.insert(peer.node_id.clone(), peer.clone());
-                            drop(peers_lock);
-
-                            let event = if is_new {
-                                DiscoveryEvent::PeerDiscovered(peer)
-                            } else {
-                                DiscoveryEvent::PeerUpdated(peer)
-                            };
-                            
-                            let _ = event_tx.send(event).await;
-                        }
-                    }
-                    ServiceEvent::ServiceRemoved(_, fullname) => {
-                        debug!("Service removed: {}", fullname);
-                        
-                        let mut peers_lock = peers.write().await;
-                        if let Some((node_id, peer)) = peers_lock.iter()
-                            .find(|(_, p)| fullname.contains(&p.node_name))
-                            .map(|(k, v)| (k.clone(), v.clone()))
-                        {
-                            info!("👋 Peer lost: {} ({})", peer.node_name, peer.os_type);
-                            peers_lock.remove(&node_id);
-                            let _ = event_tx.send(DiscoveryEvent::PeerLost(node_id)).await;
-                        }
-                    }
-                    ServiceEvent::SearchStarted(_) => {
-                        debug!("Search started");
-                    }
-                    ServiceEvent::SearchStopped(_) => {
-                        debug!("Search stopped");
-                    }
-                    _ => {}
+            let mut receiver = receiver;
+            loop {
+                let reason = browser.run(&receiver).await;
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                warn!("⚠ mDNS discovery interrupted: {}", reason);
+                match Self::recover(&daemon, &advertisement, &stopped).await {
+                    Some(restarted) => receiver = restarted,
+                    None => break,
                 }
             }
         });
@@ -175,47 +399,122 @@ impl DiscoveryService {
         Ok(())
     }
 
-    fn parse_service_info(info: &ServiceInfo, our_node_id: &str) -> Option<PeerDevice> {
-        let properties = info.get_properties();
-        
-        let node_id = properties.get("node_id")?.to_string();
+    /// Bring discovery back on a new daemon with our service registered again,
+    /// backing off between attempts. None once it gives up or we're stopping.
+    async fn recover(
+        daemon: &parking_lot::Mutex<ServiceDaemon>,
+        advertisement: &Advertisement,
+        stopped: &AtomicBool,
+    ) -> Option<Receiver<ServiceEvent>> {
+        for attempt in 0..RECOVERY_ATTEMPTS {
+            tokio::time::sleep(reconnect_delay(attempt)).await;
+            if stopped.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            info!("🔍 Restarting mDNS discovery (attempt {}/{})", attempt + 1, RECOVERY_ATTEMPTS);
+            match Self::restart(advertisement) {
+                Ok((restarted, receiver)) => {
+                    let previous = std::mem::replace(&mut *daemon.lock(), restarted);
+                    let _ = previous.shutdown();
+                    info!("✓ mDNS discovery restarted");
+                    return Some(receiver);
+                }
+                Err(e) => warn!("⚠ Restarting mDNS discovery failed: {:#}", e),
+            }
+        }
+
+        error!(
+            "mDNS discovery stopped after {} attempts to restart it; new peers won't be found",
+            RECOVERY_ATTEMPTS
+        );
+        None
+    }
+
+    fn restart(advertisement: &Advertisement) -> Result<(ServiceDaemon, Receiver<ServiceEvent>)> {
+        let daemon = new_daemon(&advertisement.interfaces)?;
+        let browsing = advertisement.register(&daemon)
+            .and_then(|_| daemon.browse(SERVICE_TYPE).context("Failed to browse for mDNS services"));
+        match browsing {
+            Ok(receiver) => Ok((daemon, receiver)),
+            Err(e) => {
+                let _ = daemon.shutdown();
+                Err(e)
+            }
+        }
+    }
+
+    fn parse_service_info(
+        info: &ServiceInfo,
+        our_node_id: &str,
+        allowed_networks: &[IpNet],
+    ) -> ParsedService {
+        let Some(node_id) = info.get_property_val_str("node_id") else {
+            return ParsedService::Missing("node_id");
+        };
         
         // Don't discover ourselves
         if node_id == our_node_id {
-            return None;
+            return ParsedService::Own;
         }
+        let node_id = node_id.to_string();
 
-        let node_name = info.get_fullname()
-            .split('.')
-            .next()?
-            .trim_start_matches('_')
-            .to_string();
+        // Older hosts only put the name in the instance label
+        let node_name = match info.get_property_val_str("node_name") {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => info.get_fullname()
+                .strip_suffix(SERVICE_TYPE)
+                .unwrap_or(info.get_fullname())
+                .trim_end_matches('.')
+                .trim_end_matches("._mirage")
+                .to_string(),
+        };
 
-        let os_type = properties.get("os_type")?.to_string();
-        let ip_address = *info.get_addresses().iter().next()?;
+        let Some(os_type) = info.get_property_val_str("os_type") else {
+            return ParsedService::Missing("os_type");
+        };
+        let os_type = os_type.to_string();
         let control_port = info.get_port();
 
-        let can_host_mouse = properties.get("can_host_mouse")
+        let resolved: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        if resolved.is_empty() {
+            return ParsedService::Missing("addresses");
+        }
+        let mut addresses: Vec<IpAddr> = resolved.iter()
+            .filter(|ip| allowed_networks.is_empty() || allowed_networks.iter().any(|net| net.contains(*ip)))
+            .copied()
+            .collect();
+        if addresses.is_empty() {
+            return ParsedService::NotAllowed(resolved);
+        }
+        addresses.sort_by_key(address_preference);
+
+        let can_host_mouse = info.get_property_val_str("can_host_mouse")
             .map(|v| v == "true")
             .unwrap_or(false);
         
-        let can_capture_windows = properties.get("can_capture_windows")
+        let can_capture_windows = info.get_property_val_str("can_capture_windows")
             .map(|v| v == "true")
             .unwrap_or(false);
         
-        let can_render_streams = properties.get("can_render_streams")
+        let can_render_streams = info.get_property_val_str("can_render_streams")
             .map(|v| v == "true")
             .unwrap_or(false);
 
-        let video_codecs = properties.get("video_codecs")
+        let video_codecs = info.get_property_val_str("video_codecs")
             .map(|v| v.split(',').map(String::from).collect())
             .unwrap_or_default();
 
-        Some(PeerDevice {
+        let extra = info.get_properties().iter()
+            .filter(|property| !RESERVED_PROPERTIES.contains(&property.key()))
+            .map(|property| (property.key().to_string(), property.val_str().to_string()))
+            .collect();
+
+        ParsedService::Peer(PeerDevice {
             node_id,
             node_name,
             os_type,
-            ip_address,
+            addresses,
             control_port,
             capabilities: PeerCapabilities {
                 can_host_mouse,
@@ -223,20 +522,465 @@ impl DiscoveryService {
                 can_render_streams,
                 video_codecs,
             },
+            extra,
             last_seen: std::time::Instant::now(),
+            online: true,
+            manual: false,
         })
     }
 
+    /// Add a peer mDNS can't see (e.g. multicast is blocked) by asking it for its
+    /// details over the control channel
+    pub async fn add_manual_peer(&self, ip: IpAddr, port: u16) -> Result<PeerDevice> {
+        let addr = SocketAddr::new(ip, port);
+        let probe = PeerDevice {
+            node_id: String::new(),
+            node_name: addr.to_string(),
+            os_type: String::new(),
+            addresses: vec![ip],
+            control_port: port,
+            capabilities: PeerCapabilities::local(),
+            extra: HashMap::new(),
+            last_seen: Instant::now(),
+            online: false,
+            manual: true,
+        };
+
+        let connector = transport::connector(&self.config)?;
+        let mut client = ControlClient::connect(&probe, &self.identity, &self.node_name, &connector).await?;
+        let info = client.peer_info().clone();
+        client.close().await?;
+
+        // Peers that don't report an ID are keyed by address so re-adding is idempotent
+        let node_id = if info.node_id.is_empty() {
+            format!("manual-{}", addr)
+        } else {
+            info.node_id
+        };
+
+        let peer = PeerDevice {
+            node_id,
+            node_name: if info.node_name.is_empty() { addr.to_string() } else { info.node_name },
+            os_type: info.os_type,
+            capabilities: info.capabilities.map(PeerCapabilities::from).unwrap_or(probe.capabilities),
+            online: true,
+            ..probe
+        };
+
+        info!("🔍 Added manual peer: {} ({}) at {}", peer.node_name, peer.os_type, addr);
+
+        let mut peers = self.peers.write().await;
+        let previous = peers.insert(peer.node_id.clone(), peer.clone());
+        if let Some(ref path) = self.peer_cache {
+            if let Err(e) = save_peer_cache(path, &peers).await {
+                warn!("Failed to save peer cache: {}", e);
+            }
+        }
+        drop(peers);
+
+        let event = match previous {
+            None => Some(DiscoveryEvent::PeerDiscovered(peer.clone())),
+            Some(previous) if previous != peer => Some(DiscoveryEvent::PeerUpdated(peer.clone())),
+            Some(_) => None,
+        };
+        if let Some(event) = event {
+            let _ = self.event_tx.send(event);
+        }
+
+        Ok(peer)
+    }
+
+    /// Receive peer discovered/updated/lost events. Each subscriber sees every
+    /// event sent after it subscribed; slow ones get `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// The ID we advertise and introduce ourselves with
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Who we are to peers, with the key we prove it with
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Shared handle to the live peer table
+    pub fn peer_table(&self) -> Arc<RwLock<HashMap<String, PeerDevice>>> {
+        Arc::clone(&self.peers)
+    }
+
+    /// Discovered peers, leaving out those in `security.blocked_nodes`
     pub async fn get_peers(&self) -> Vec<PeerDevice> {
-        self.peers.read().await.values().cloned().collect()
+        self.peers.read().await
+            .values()
+            .filter(|peer| !self.config.security.blocks_node(&peer.node_id))
+            .cloned()
+            .collect()
     }
 
     pub async fn get_peer(&self, node_id: &str) -> Option<PeerDevice> {
+        if self.config.security.blocks_node(node_id) {
+            return None;
+        }
         self.peers.read().await.get(node_id).cloned()
     }
 }
 
-fn get_local_ip() -> Option<IpAddr> {
-    // Try to get a non-loopback IP address
-    local_ip_address::local_ip().ok()
+impl Advertisement {
+    /// Register on `daemon` with the addresses we have now, returning the
+    /// service's full name
+    fn register(&self, daemon: &ServiceDaemon) -> Result<String> {
+        let hostname = hostname::get()
+            .ok()
+            .and_then(|h| h.into_string().ok())
+            .unwrap_or_else(|| "linux-host".to_string());
+
+        let service_name = instance_name(&self.node_name, self.instance.as_deref(), &self.node_id);
+        let port = self.port;
+
+        let mut local_ips = get_local_ips(&self.allowed_networks, &self.interfaces);
+        if local_ips.is_empty() {
+            warn!("⚠ No local address in network.allowed_subnets to advertise, peers won't reach us");
+            local_ips.push(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        }
+
+        let capabilities = PeerCapabilities::local();
+        let mut properties = self.extra.clone();
+        properties.insert("node_id".to_string(), self.node_id.clone());
+        properties.insert("node_name".to_string(), self.node_name.clone());
+        properties.insert("os_type".to_string(), "linux".to_string());
+        properties.insert("can_host_mouse".to_string(), capabilities.can_host_mouse.to_string());
+        properties.insert("can_capture_windows".to_string(), capabilities.can_capture_windows.to_string());
+        properties.insert("can_render_streams".to_string(), capabilities.can_render_streams.to_string());
+        properties.insert("video_codecs".to_string(), capabilities.video_codecs.join(","));
+
+        let service_info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &service_name,
+            &hostname,
+            local_ips.as_slice(),
+            port,
+            Some(properties),
+        )?;
+
+        let fullname = service_info.get_fullname().to_string();
+        daemon.register(service_info)
+            .context("Failed to register mDNS service")?;
+
+        info!("✓ Registered service: {} at {:?} port {}", service_name, local_ips, port);
+        Ok(fullname)
+    }
+}
+
+impl Browser {
+    /// Apply events until the daemon stops sending them or our addresses
+    /// change, returning why it stopped
+    async fn run(&self, receiver: &Receiver<ServiceEvent>) -> &'static str {
+        let addresses = get_local_ips(&self.allowed_networks, &self.interfaces);
+        let mut address_check = tokio::time::interval(ADDRESS_CHECK_INTERVAL);
+        address_check.tick().await;
+
+        loop {
+            tokio::select! {
+                event = receiver.recv_async() => match event {
+                    Ok(event) => self.handle_event(event).await,
+                    Err(_) => return "the mDNS daemon stopped",
+                },
+                _ = address_check.tick() => {
+                    if get_local_ips(&self.allowed_networks, &self.interfaces) != addresses {
+                        return "local addresses changed";
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_event(&self, event: ServiceEvent) {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                debug!("Service resolved: {:?}", info);
+                
+                let peer = match DiscoveryService::parse_service_info(&info, &self.node_id, &self.allowed_networks) {
+                    ParsedService::Peer(peer) => Some(peer),
+                    ParsedService::Own => {
+                        debug!("Ignoring own advertisement {}", info.get_fullname());
+                        None
+                    }
+                    ParsedService::Missing(what) => {
+                        warn!("⚠ Ignoring mDNS record {}: no {}", info.get_fullname(), what);
+                        None
+                    }
+                    ParsedService::NotAllowed(addresses) => {
+                        warn!("⚠ Ignoring peer {} at {:?}: not in network.allowed_subnets",
+                            info.get_fullname(), addresses);
+                        None
+                    }
+                };
+
+                if let Some(peer) = peer {
+                    self.fullnames.lock().insert(info.get_fullname().to_string(), peer.node_id.clone());
+                    let mut peers_lock = self.peers.write().await;
+                    let previous = peers_lock.insert(peer.node_id.clone(), peer.clone());
+
+                    if let Some(ref path) = self.peer_cache {
+                        if let Err(e) = save_peer_cache(path, &peers_lock).await {
+                            warn!("Failed to save peer cache: {}", e);
+                        }
+                    }
+                    drop(peers_lock);
+
+                    // Re-resolving an unchanged peer only refreshes last_seen
+                    let event = match previous {
+                        None => DiscoveryEvent::PeerDiscovered(peer.clone()),
+                        Some(previous) if previous != peer => DiscoveryEvent::PeerUpdated(peer.clone()),
+                        Some(_) => {
+                            debug!("Peer {} re-resolved unchanged", peer.node_name);
+                            return;
+                        }
+                    };
+                    info!("🔍 Discovered peer: {} ({}) at {:?} port {}",
+                        peer.node_name, peer.os_type, peer.addresses, peer.control_port);
+
+                    let _ = self.event_tx.send(event);
+                }
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                debug!("Service removed: {}", fullname);
+                
+                let Some(node_id) = self.fullnames.lock().remove(&fullname) else {
+                    return;
+                };
+                if let Some(peer) = self.peers.write().await.remove(&node_id) {
+                    info!("👋 Peer lost: {} ({})", peer.node_name, peer.os_type);
+                    let _ = self.event_tx.send(DiscoveryEvent::PeerLost(node_id));
+                }
+            }
+            ServiceEvent::ServiceFound(_, fullname) => {
+                // Repeated answers to our queries show the peer is still around
+                let Some(node_id) = self.fullnames.lock().get(&fullname).cloned() else {
+                    return;
+                };
+                if let Some(peer) = self.peers.write().await.get_mut(&node_id).filter(|p| p.online) {
+                    peer.last_seen = Instant::now();
+                }
+            }
+            ServiceEvent::SearchStarted(_) => {
+                debug!("Search started");
+            }
+            ServiceEvent::SearchStopped(_) => {
+                debug!("Search stopped");
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn save_peer_cache(path: &Path, peers: &HashMap<String, PeerDevice>) -> Result<()> {
+    let cached: Vec<CachedPeer> = peers.values().map(CachedPeer::from).collect();
+    let json = serde_json::to_string_pretty(&cached)?;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, json).await?;
+    Ok(())
+}
+
+/// Register a throwaway service and wait to see it answered, which only
+/// happens if multicast gets out and back in on this machine. For
+/// `mirage-host doctor`.
+pub async fn self_test(config: &Config, timeout: Duration) -> Result<()> {
+    let interfaces = &config.network.bind_interfaces;
+    let addresses = get_local_ips(&config.network.allowed_networks(), interfaces);
+    if addresses.is_empty() {
+        bail!("No local address in network.allowed_subnets to advertise");
+    }
+
+    let daemon = new_daemon(interfaces)?;
+    let receiver = daemon.browse(SERVICE_TYPE).context("Failed to browse for mDNS services")?;
+    let instance = format!("mirage-doctor-{}", &Uuid::new_v4().simple().to_string()[..8]);
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        "mirage-doctor.local.",
+        addresses.as_slice(),
+        config.network.control_port,
+        None::<HashMap<String, String>>,
+    )?;
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service).context("Failed to register an mDNS service")?;
+
+    let answered = tokio::time::timeout(timeout, async {
+        while let Ok(event) = receiver.recv_async().await {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if info.get_fullname() == fullname {
+                    return true;
+                }
+            }
+        }
+        false
+    }).await;
+    let _ = daemon.shutdown();
+
+    match answered {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("The mDNS daemon stopped"),
+        Err(_) => bail!("A test service was registered but never answered within {:?}", timeout),
+    }
+}
+
+/// An mDNS daemon that only uses `interfaces`, or every interface when empty
+fn new_daemon(interfaces: &[String]) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().context("Failed to create mDNS daemon")?;
+    if !interfaces.is_empty() {
+        daemon.disable_interface(IfKind::All)
+            .and_then(|_| daemon.enable_interface(interfaces.iter().cloned().map(IfKind::Name).collect::<Vec<_>>()))
+            .context("Failed to limit mDNS to network.bind_interfaces")?;
+    }
+    Ok(daemon)
+}
+
+/// Every non-loopback address in `allowed_networks` (any, when empty) on
+/// `interfaces` (all, when empty), most preferred first
+fn get_local_ips(allowed_networks: &[IpNet], interfaces: &[String]) -> Vec<IpAddr> {
+    let interface_addresses = match local_ip_address::list_afinet_netifas() {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            // Fall back to the address of the default route, one per family
+            debug!("Failed to list network interfaces: {}", e);
+            return [local_ip_address::local_ip(), local_ip_address::local_ipv6()]
+                .into_iter()
+                .filter_map(|ip| ip.ok())
+                .collect();
+        }
+    };
+
+    let mut addresses: Vec<IpAddr> = interface_addresses.into_iter()
+        .filter(|(name, _)| interfaces.is_empty() || interfaces.contains(name))
+        .map(|(_, ip)| ip)
+        .filter(|ip| !ip.is_loopback())
+        .filter(|ip| allowed_networks.is_empty() || allowed_networks.iter().any(|net| net.contains(ip)))
+        .collect();
+    addresses.sort_by_key(|ip| (address_preference(ip), *ip));
+    addresses.dedup();
+    addresses
+}
+
+/// IPv6 link-local first (no routing involved), then IPv4, then other IPv6
+fn address_preference(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V6(_) if is_ipv6_link_local(ip) => 0,
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(_) => 2,
+    }
+}
+
+/// mDNS instance label: the node name plus a short node ID suffix, so hosts
+/// sharing a hostname don't collide, and the daemon instance if it has a
+/// name. Labels are capped at 63 bytes.
+fn instance_name(node_name: &str, instance: Option<&str>, node_id: &str) -> String {
+    let id: String = node_id.chars().filter(|c| *c != '-').take(6).collect();
+    let suffix = match instance {
+        Some(instance) => format!("{}-{}", instance, id),
+        None => id,
+    };
+    let mut name = node_name.to_string();
+    while name.len() > 63 - suffix.len() - 1 {
+        name.pop();
+    }
+    format!("{}-{}", name, suffix)
+}
+
+pub fn is_ipv6_link_local(ip: &IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_ID: &str = "ab12cd34-5678-90ef-1234-567890abcdef";
+
+    fn browser() -> Browser {
+        let (event_tx, _) = broadcast::channel(16);
+        Browser {
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            fullnames: parking_lot::Mutex::new(HashMap::new()),
+            event_tx,
+            node_id: "host".to_string(),
+            peer_cache: None,
+            allowed_networks: Vec::new(),
+            interfaces: Vec::new(),
+        }
+    }
+
+    fn service(node_name: &str, node_id: &str, last_octet: u8) -> ServiceInfo {
+        let properties = HashMap::from([
+            ("node_id".to_string(), node_id.to_string()),
+            ("node_name".to_string(), node_name.to_string()),
+            ("os_type".to_string(), "linux".to_string()),
+        ]);
+        let address = IpAddr::from([192, 168, 1, last_octet]);
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance_name(node_name, None, node_id),
+            &format!("{}.local.", node_name),
+            [address].as_slice(),
+            8443,
+            Some(properties),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn instance_names_are_capped_and_tell_instances_apart() {
+        assert_eq!(instance_name("desk", None, NODE_ID), "desk-ab12cd");
+        assert_eq!(instance_name("desk", Some("work"), NODE_ID), "desk-work-ab12cd");
+
+        let long = "x".repeat(80);
+        let name = instance_name(&long, Some("work"), NODE_ID);
+        assert_eq!(name.len(), 63);
+        assert!(name.ends_with("x-work-ab12cd"), "{}", name);
+    }
+
+    #[test]
+    fn re_resolving_only_refreshes_last_seen() {
+        let peer = match DiscoveryService::parse_service_info(&service("desk", NODE_ID, 10), "host", &[]) {
+            ParsedService::Peer(peer) => peer,
+            _ => panic!("the service didn't parse"),
+        };
+        let later = PeerDevice { last_seen: peer.last_seen + Duration::from_secs(30), ..peer.clone() };
+        assert_eq!(later, peer);
+
+        let moved = PeerDevice { addresses: vec![IpAddr::from([192, 168, 1, 11])], ..peer.clone() };
+        assert_ne!(moved, peer);
+    }
+
+    #[tokio::test]
+    async fn removal_matches_the_full_service_name() {
+        let browser = browser();
+        let mut events = browser.event_tx.subscribe();
+        let desk = service("desk", "desk-id", 10);
+        let desktop = service("desktop", "desktop-id", 11);
+        browser.handle_event(ServiceEvent::ServiceResolved(desk.clone())).await;
+        browser.handle_event(ServiceEvent::ServiceResolved(desktop)).await;
+
+        // Unknown names touch nothing
+        browser.handle_event(ServiceEvent::ServiceRemoved(SERVICE_TYPE.to_string(), format!("desk.{}", SERVICE_TYPE))).await;
+        assert_eq!(browser.peers.read().await.len(), 2);
+
+        browser.handle_event(ServiceEvent::ServiceRemoved(SERVICE_TYPE.to_string(), desk.get_fullname().to_string())).await;
+        let remaining: Vec<String> = browser.peers.read().await.keys().cloned().collect();
+        assert_eq!(remaining, vec!["desktop-id".to_string()]);
+
+        let mut lost = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DiscoveryEvent::PeerLost(node_id) = event {
+                lost.push(node_id);
+            }
+        }
+        assert_eq!(lost, vec!["desk-id".to_string()]);
+    }
 }
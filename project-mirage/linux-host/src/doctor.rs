@@ -0,0 +1,194 @@
+// `mirage-host doctor`: checks what a new setup most often gets wrong and says
+// how to fix each problem. Critical failures mean the daemon can't work and
+// make the command exit nonzero; warnings only limit what it can do.
+
+use anyhow::{bail, Result};
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{Config, FileAccess, Transport};
+use crate::discovery;
+use crate::input::InputManager;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+// Long enough for a multicast round trip on a busy network
+const MDNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+enum Outcome {
+    Pass(String),
+    /// Something works less well than it could
+    Warn { problem: String, hint: String },
+    /// The daemon won't work until this is fixed
+    Fail { problem: String, hint: String },
+}
+
+impl Outcome {
+    fn warn(problem: impl Into<String>, hint: impl Into<String>) -> Self {
+        Outcome::Warn { problem: problem.into(), hint: hint.into() }
+    }
+
+    fn fail(problem: impl Into<String>, hint: impl Into<String>) -> Self {
+        Outcome::Fail { problem: problem.into(), hint: hint.into() }
+    }
+
+    /// Print the outcome of check `name`, returning whether it failed
+    fn report(self, name: &str) -> bool {
+        match self {
+            Outcome::Pass(detail) => {
+                println!("✓ {:<14} {}", name, detail);
+                false
+            }
+            Outcome::Warn { problem, hint } => {
+                println!("⚠ {:<14} {}\n  {:<14} → {}", name, problem, "", hint);
+                false
+            }
+            Outcome::Fail { problem, hint } => {
+                println!("✗ {:<14} {}\n  {:<14} → {}", name, problem, "", hint);
+                true
+            }
+        }
+    }
+}
+
+/// Run every check against `config_file` with `profile` applied. A missing
+/// file is judged by the `access` the daemon would start with; nothing is
+/// written either way.
+pub async fn run(config_file: &str, access: FileAccess, profile: Option<&str>) -> Result<()> {
+    let (outcome, config) = check_config(config_file, access, profile).await;
+    let mut failed = outcome.report("config") as u32;
+
+    // The remaining checks go by the defaults when the config is unusable
+    let config = config.unwrap_or_default();
+    for (name, outcome) in [
+        ("input device", check_input_devices(&config)),
+        ("uinput", check_uinput()),
+        ("control port", check_control_port(&config)),
+        ("mdns", check_mdns(&config).await),
+    ] {
+        failed += outcome.report(name) as u32;
+    }
+
+    if failed > 0 {
+        bail!("{} critical check{} failed", failed, if failed == 1 { "" } else { "s" });
+    }
+    println!("\nAll critical checks passed");
+    Ok(())
+}
+
+async fn check_config(config_file: &str, access: FileAccess, profile: Option<&str>) -> (Outcome, Option<Config>) {
+    let path = shellexpand::tilde(config_file).into_owned();
+    if !Path::new(&path).exists() {
+        let outcome = match access {
+            _ if profile.is_some() => Outcome::fail(
+                format!("{} doesn't exist, so it has no profile", path),
+                "create it with a [profiles.<name>] table, or leave out --profile",
+            ),
+            FileAccess::CreateMissing => Outcome::warn(
+                format!("{} doesn't exist yet", path),
+                "that's fine; the daemon writes the defaults there when it first starts",
+            ),
+            FileAccess::ReadOnly => Outcome::warn(
+                format!("{} doesn't exist", path),
+                "the daemon will run on the defaults; create the file to change them",
+            ),
+            FileAccess::Existing => Outcome::fail(
+                format!("{} doesn't exist", path),
+                "create it, or leave out --config to use the default",
+            ),
+        };
+        return (outcome, None);
+    }
+
+    // Read-only, so checking never upgrades an older file in place
+    match Config::load(config_file, FileAccess::ReadOnly, profile).await {
+        Ok(config) => (Outcome::Pass(format!("{} parses and is valid", path)), Some(config)),
+        Err(e) => (Outcome::fail(format!("{:#}", e), format!("fix the setting named above in {}", path)), None),
+    }
+}
+
+fn check_input_devices(config: &Config) -> Outcome {
+    let devices = InputManager::list_devices();
+    if devices.is_empty() {
+        return Outcome::fail(
+            "no input devices can be read",
+            "add yourself to the input group (`sudo usermod -aG input $USER`) and log in again",
+        );
+    }
+
+    let mice: Vec<&str> = devices.iter().filter(|d| d.is_mouse).map(|d| d.name.as_str()).collect();
+    if mice.is_empty() {
+        return Outcome::fail(
+            format!("none of the {} input devices is a mouse", devices.len()),
+            "plug in a mouse; `mirage-host devices` lists what was found",
+        );
+    }
+
+    if let Some(preferred) = config.input.preferred_device.as_deref().filter(|name| !name.is_empty()) {
+        let preferred_lower = preferred.to_lowercase();
+        if !mice.iter().any(|name| name.to_lowercase().contains(&preferred_lower)) {
+            return Outcome::warn(
+                format!("no mouse matches input.preferred_device {:?}, so all {} will be used", preferred, mice.len()),
+                "check the name against `mirage-host devices`",
+            );
+        }
+    }
+    Outcome::Pass(format!("{} mouse device(s): {}", mice.len(), mice.join(", ")))
+}
+
+fn check_uinput() -> Outcome {
+    match OpenOptions::new().write(true).open(UINPUT_PATH) {
+        Ok(_) => Outcome::Pass(format!("{} is writable", UINPUT_PATH)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Outcome::fail(
+            format!("{} doesn't exist", UINPUT_PATH),
+            "load the module with `sudo modprobe uinput`",
+        ),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Outcome::fail(
+            format!("{} isn't writable by this user", UINPUT_PATH),
+            "add a udev rule such as KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\" and join the input group",
+        ),
+        Err(e) => Outcome::fail(format!("can't open {}: {}", UINPUT_PATH, e), "check the device node's permissions"),
+    }
+}
+
+fn check_control_port(config: &Config) -> Outcome {
+    let port = config.network.control_port;
+    let transport = config.network.transport;
+    let bind = |addr: SocketAddr| match transport {
+        Transport::Tcp => TcpListener::bind(addr).map(drop),
+        Transport::Quic => UdpSocket::bind(addr).map(drop),
+    };
+    // Same fallback as the daemon: [::] where there's IPv6, 0.0.0.0 otherwise
+    let result = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+        .or_else(|_| bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))));
+    let protocol = match transport {
+        Transport::Tcp => "TCP",
+        Transport::Quic => "UDP",
+    };
+
+    match result {
+        Ok(()) => Outcome::Pass(format!("{} port {} is free", protocol, port)),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => Outcome::fail(
+            format!("{} port {} is already in use", protocol, port),
+            "stop the mirage-host that's running, or pick another network.control_port",
+        ),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Outcome::fail(
+            format!("not allowed to bind {} port {}", protocol, port),
+            "use a network.control_port above 1023",
+        ),
+        Err(e) => Outcome::fail(format!("can't bind {} port {}: {}", protocol, port, e), "check network.control_port"),
+    }
+}
+
+async fn check_mdns(config: &Config) -> Outcome {
+    match discovery::self_test(config, MDNS_TIMEOUT).await {
+        Ok(()) => Outcome::Pass("a test service was registered and answered".to_string()),
+        Err(e) => Outcome::warn(
+            format!("{:#}; peers won't find each other automatically", e),
+            "allow UDP 5353 through the firewall, or add peers with --connect IP:PORT",
+        ),
+    }
+}
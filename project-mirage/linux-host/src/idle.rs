@@ -0,0 +1,107 @@
+// Notices when the local session locks, for `security.pause_on_lock`. logind
+// tracks it as the LockedHint property of the session, read through loginctl,
+// which saves pulling in a D-Bus client. IdleHint is left alone: the local
+// session looks idle exactly while its mouse and keyboard drive a peer.
+
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::session::SessionManager;
+
+// How often logind is asked; locking doesn't need to be noticed instantly,
+// but the peer shouldn't get input for long after it
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct LockWatcher {
+    session: String,
+}
+
+impl LockWatcher {
+    /// Watch the logind session we were started in
+    pub fn new() -> Self {
+        // "auto" is the caller's session, for daemons started without XDG_SESSION_ID
+        let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_string());
+        Self { session }
+    }
+
+    /// Pause input in `session_manager` while the session is locked.
+    /// Returns if logind can't be asked, since nothing would ever resume then.
+    pub async fn run(self, session_manager: SessionManager) -> Result<()> {
+        if let Err(e) = self.locked().await {
+            warn!("⚠ Can't tell when the screen locks, input keeps flowing while it is: {:#}", e);
+            return Ok(());
+        }
+        info!("✓ Watching logind session {} for screen locks", self.session);
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if !session_manager.pauses_on_lock() {
+                session_manager.set_input_paused(false).await;
+                continue;
+            }
+            match self.locked().await {
+                Ok(locked) => session_manager.set_input_paused(locked).await,
+                Err(e) => debug!("Checking for a screen lock failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Whether logind considers the session locked
+    async fn locked(&self) -> Result<bool> {
+        let output = Command::new("loginctl")
+            .args(["show-session", &self.session, "-p", "LockedHint"])
+            .output()
+            .await
+            .context("Failed to run loginctl")?;
+        if !output.status.success() {
+            bail!("loginctl failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        parse_locked_hint(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Read LockedHint out of `loginctl show-session` output
+fn parse_locked_hint(output: &str) -> Result<bool> {
+    for line in output.lines() {
+        if let Some(value) = line.trim().strip_prefix("LockedHint=") {
+            return match value {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                _ => bail!("Unexpected LockedHint {:?}", value),
+            };
+        }
+    }
+    bail!("loginctl didn't report LockedHint")
+}
+
+impl Default for LockWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_lock_pauses_input() {
+        assert!(parse_locked_hint("LockedHint=yes\n").unwrap());
+        assert!(!parse_locked_hint("LockedHint=no\n").unwrap());
+        // Not touching the local mouse while it drives a peer makes the session idle
+        assert!(!parse_locked_hint("IdleHint=yes\nLockedHint=no\n").unwrap());
+        assert!(parse_locked_hint("IdleHint=no\nLockedHint=yes\n").unwrap());
+    }
+
+    #[test]
+    fn output_without_the_hint_is_an_error() {
+        assert!(parse_locked_hint("").is_err());
+        assert!(parse_locked_hint("IdleHint=yes\n").is_err());
+        assert!(parse_locked_hint("LockedHint=\n").is_err());
+    }
+}
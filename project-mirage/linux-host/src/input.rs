@@ -1,10 +1,21 @@
-use anyhow::{Context, Result};
-use evdev::{Device, EventType, InputEventKind, Key};
+use anyhow::{anyhow, bail, Context, Result};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AbsoluteAxisType, AttributeSet, Device, EventType, InputEventKind, Key, PropType, RelativeAxisType};
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
+use std::os::fd::{AsRawFd, RawFd};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
+use tokio::task::JoinSet;
 use tracing::{info, debug, warn, error};
 
-use crate::config::Config;
+use crate::config::{Config, InputConfig, KeyForwarding};
+use crate::metrics::metrics;
+use crate::platform;
 
 #[derive(Debug, Clone)]
 pub struct MouseState {
@@ -20,18 +31,41 @@ pub struct MouseButtons {
     pub left: bool,
     pub right: bool,
     pub middle: bool,
+    pub back: bool,
+    pub forward: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputEvent {
     MouseMove { delta_x: f32, delta_y: f32 },
     MouseButton { button: MouseButton, pressed: bool },
     MouseWheel { delta: f32, horizontal: bool },
-    KeyPress { key_code: u32, pressed: bool },
+    /// `scan_code` is the keyboard's own code for the key, sent along with
+    /// `input.key_forwarding = "scancode"`
+    KeyPress { key_code: u32, pressed: bool, scan_code: Option<u32> },
     EdgeCrossed { edge: ScreenEdge, position: (f32, f32) },
+    /// Cursor position from a touchscreen or tablet, in local screen pixels
+    MouseAbsolute { x: f32, y: f32 },
+    /// The force-local hotkey was pressed: take the mouse back from any peer
+    ForceLocal,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceRole {
+    Mouse,
+    Keyboard,
+}
+
+/// An input device as listed by `InputManager::list_devices`
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub is_mouse: bool,
+    pub is_keyboard: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MouseButton {
     Left,
     Right,
@@ -40,7 +74,8 @@ pub enum MouseButton {
     Forward,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum ScreenEdge {
     Left,
     Right,
@@ -48,231 +83,1157 @@ pub enum ScreenEdge {
     Bottom,
 }
 
+impl ScreenEdge {
+    /// The edge facing this one across a border between two screens
+    pub fn opposite(self) -> Self {
+        match self {
+            ScreenEdge::Left => ScreenEdge::Right,
+            ScreenEdge::Right => ScreenEdge::Left,
+            ScreenEdge::Top => ScreenEdge::Bottom,
+            ScreenEdge::Bottom => ScreenEdge::Top,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+}
+
+/// Holds back the small motion touchpads generate while tapping, so a tap reaches
+/// the peer as a clean click. Once the motion since a press exceeds the threshold
+/// it is a drag, and the held-back motion is released so the drag is preserved.
+struct ClickFilter {
+    window: Duration,
+    threshold: f32,
+    pending: Vec<(MouseButton, Instant)>,
+    suppressed: (f32, f32),
+}
+
+impl ClickFilter {
+    fn new(config: &InputConfig) -> Self {
+        Self {
+            window: Duration::from_millis(config.click_motion_window_ms as u64),
+            threshold: config.click_motion_threshold,
+            pending: Vec::new(),
+            suppressed: (0.0, 0.0),
+        }
+    }
+
+    fn button(&mut self, button: MouseButton, pressed: bool) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        if pressed {
+            self.pending.push((button, Instant::now()));
+        } else if let Some(idx) = self.pending.iter().position(|(b, _)| *b == button) {
+            self.pending.remove(idx);
+            if self.pending.is_empty() {
+                // A click: the held-back jitter is dropped for good
+                self.suppressed = (0.0, 0.0);
+            }
+        }
+    }
+
+    /// Returns the delta to forward for this axis, or `None` while it is held back
+    fn motion(&mut self, axis: Axis, delta: f32) -> Option<f32> {
+        let window_open = self.pending.iter()
+            .any(|(_, pressed_at)| pressed_at.elapsed() < self.window);
+
+        if window_open {
+            match axis {
+                Axis::X => self.suppressed.0 += delta,
+                Axis::Y => self.suppressed.1 += delta,
+            }
+
+            if self.suppressed.0.hypot(self.suppressed.1) < self.threshold {
+                return None;
+            }
+
+            // It's a drag, stop filtering until the next press
+            self.pending.clear();
+            return Some(self.take_suppressed(axis));
+        }
+
+        self.pending.clear();
+        Some(delta + self.take_suppressed(axis))
+    }
+
+    fn take_suppressed(&mut self, axis: Axis) -> f32 {
+        match axis {
+            Axis::X => std::mem::take(&mut self.suppressed.0),
+            Axis::Y => std::mem::take(&mut self.suppressed.1),
+        }
+    }
+}
+
+// Name of our uinput device, never captured so injected input can't loop back
+const VIRTUAL_DEVICE_NAME: &str = "Mirage Virtual Input";
+
+pub const FALLBACK_SCREEN_SIZE: (u32, u32) = (1920, 1080);
+
+const INPUT_DEVICE_DIR: &str = "/dev/input";
+
+// How often to look for devices while a mouse or keyboard is missing, or all
+// the time if /dev/input can't be watched
+const DEVICE_RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+// Time for udev to set up a new device node before we open it
+const HOTPLUG_SETTLE: Duration = Duration::from_millis(500);
+
+// How often an idle reader wakes up to apply grab requests
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+// Raw evdev events buffered between a device's reader thread and its processor
+const RAW_EVENT_CAPACITY: usize = 256;
+
+// Minimum time between warnings about dropped events
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+// Hi-res wheel axes report 120 units per physical notch
+const HI_RES_SCROLL_UNITS: f32 = 120.0;
+
+/// Sums motion over a short window so fast movement produces one `MouseMove`
+/// per window instead of one per evdev event
+struct MotionCoalescer {
+    window: Duration,
+    pending: (f32, f32),
+    started: Option<Instant>,
+}
+
+impl MotionCoalescer {
+    fn new(config: &InputConfig) -> Self {
+        Self {
+            window: Duration::from_millis(config.motion_coalesce_ms as u64),
+            pending: (0.0, 0.0),
+            started: None,
+        }
+    }
+
+    /// Add motion; returns the combined move once the window has elapsed
+    fn add(&mut self, delta_x: f32, delta_y: f32) -> Option<InputEvent> {
+        self.pending.0 += delta_x;
+        self.pending.1 += delta_y;
+
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if started.elapsed() >= self.window {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Flush whatever motion is pending, regardless of the window
+    fn take(&mut self) -> Option<InputEvent> {
+        self.started.take()?;
+        let (delta_x, delta_y) = std::mem::take(&mut self.pending);
+        Some(InputEvent::MouseMove { delta_x, delta_y })
+    }
+
+    fn time_until_due(&self) -> Option<Duration> {
+        self.started.map(|started| self.window.saturating_sub(started.elapsed()))
+    }
+}
+
+// Smooth scrolling spreads each wheel delta over this many steps, this far apart
+const SMOOTH_SCROLL_STEPS: f32 = 6.0;
+const SMOOTH_SCROLL_STEP: Duration = Duration::from_millis(16);
+
+/// With `input.enable_smooth_scroll`, turns wheel notches into a short run of
+/// fractional deltas so scrolling on the peer glides like a touchpad. Otherwise
+/// wheel events pass straight through.
+struct ScrollSmoother {
+    enabled: bool,
+    // (vertical, horizontal) still to be emitted, and the size of each step
+    remaining: (f32, f32),
+    per_step: (f32, f32),
+    next_step: Option<Instant>,
+}
+
+impl ScrollSmoother {
+    fn new(config: &InputConfig) -> Self {
+        Self {
+            enabled: config.enable_smooth_scroll,
+            remaining: (0.0, 0.0),
+            per_step: (0.0, 0.0),
+            next_step: None,
+        }
+    }
+
+    /// Add a wheel delta; returns it unchanged when smoothing is off
+    fn add(&mut self, delta: f32, horizontal: bool) -> Option<InputEvent> {
+        if !self.enabled {
+            return Some(InputEvent::MouseWheel { delta, horizontal });
+        }
+
+        // New notches speed up a scroll that's still running
+        let (remaining, per_step) = if horizontal {
+            (&mut self.remaining.1, &mut self.per_step.1)
+        } else {
+            (&mut self.remaining.0, &mut self.per_step.0)
+        };
+        *remaining += delta;
+        *per_step = *remaining / SMOOTH_SCROLL_STEPS;
+
+        self.next_step.get_or_insert_with(Instant::now);
+        None
+    }
+
+    /// Emit the next slice of each running scroll
+    fn step(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for (horizontal, remaining, per_step) in [
+            (false, &mut self.remaining.0, self.per_step.0),
+            (true, &mut self.remaining.1, self.per_step.1),
+        ] {
+            if *remaining == 0.0 {
+                continue;
+            }
+            // The last step takes whatever rounding left over
+            let delta = if remaining.abs() <= per_step.abs() * 1.001 { *remaining } else { per_step };
+            *remaining -= delta;
+            if delta != 0.0 {
+                events.push(InputEvent::MouseWheel { delta, horizontal });
+            }
+        }
+
+        self.next_step = (self.remaining != (0.0, 0.0)).then(|| Instant::now() + SMOOTH_SCROLL_STEP);
+        events
+    }
+
+    fn time_until_due(&self) -> Option<Duration> {
+        self.next_step.map(|next| next.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Holds an edge crossing back until the cursor has stayed at that edge for
+/// `input.edge_activation_delay_ms`, so grazing an edge doesn't hand the mouse over
+struct EdgeDwell {
+    delay: Duration,
+    pending: Option<(ScreenEdge, (f32, f32), Instant)>,
+}
+
+impl EdgeDwell {
+    fn new(config: &InputConfig) -> Self {
+        Self {
+            delay: Duration::from_millis(config.edge_activation_delay_ms as u64),
+            pending: None,
+        }
+    }
+
+    /// The cursor entered an edge zone. Without a delay the crossing fires right away.
+    fn enter(&mut self, edge: ScreenEdge, position: (f32, f32), now: Instant) -> Option<InputEvent> {
+        self.pending = Some((edge, position, now + self.delay));
+        self.take_due(now)
+    }
+
+    /// The cursor moved without crossing into a new zone; `at_edge` tells
+    /// whether it is still inside the zone of the given edge
+    fn moved(&mut self, now: Instant, at_edge: impl Fn(ScreenEdge) -> bool) -> Option<InputEvent> {
+        let (edge, _, _) = self.pending?;
+        if !at_edge(edge) {
+            self.pending = None;
+            return None;
+        }
+        self.take_due(now)
+    }
+
+    fn take_due(&mut self, now: Instant) -> Option<InputEvent> {
+        let (edge, position, due) = self.pending?;
+        if now < due {
+            return None;
+        }
+        self.pending = None;
+        Some(InputEvent::EdgeCrossed { edge, position })
+    }
+
+    fn time_until_due(&self) -> Option<Duration> {
+        self.pending.map(|(_, _, due)| due.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Whether `position` lies within `threshold` pixels of `edge`
+fn in_edge_zone(edge: ScreenEdge, position: (f32, f32), screen: (u32, u32), threshold: f32) -> bool {
+    match edge {
+        ScreenEdge::Left => position.0 < threshold,
+        ScreenEdge::Right => position.0 > screen.0 as f32 - threshold,
+        ScreenEdge::Top => position.1 < threshold,
+        ScreenEdge::Bottom => position.1 > screen.1 as f32 - threshold,
+    }
+}
+
+/// The edge whose zone a move from `old` to `new` entered, and the point on
+/// that edge where the cursor leaves. Moving into a corner picks the left or
+/// right edge over the top or bottom one.
+fn detect_edge(
+    old: (f32, f32),
+    new: (f32, f32),
+    screen: (u32, u32),
+    threshold: f32,
+) -> Option<(ScreenEdge, (f32, f32))> {
+    let edge = [ScreenEdge::Left, ScreenEdge::Right, ScreenEdge::Top, ScreenEdge::Bottom]
+        .into_iter()
+        .find(|&edge| !in_edge_zone(edge, old, screen, threshold) && in_edge_zone(edge, new, screen, threshold))?;
+    let position = match edge {
+        ScreenEdge::Left => (0.0, new.1),
+        ScreenEdge::Right => (screen.0 as f32, new.1),
+        ScreenEdge::Top => (new.0, 0.0),
+        ScreenEdge::Bottom => (new.0, screen.1 as f32),
+    };
+    Some((edge, position))
+}
+
+const MODIFIER_KEYS: [Key; 8] = [
+    Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTALT, Key::KEY_RIGHTALT,
+    Key::KEY_LEFTSHIFT, Key::KEY_RIGHTSHIFT,
+    Key::KEY_LEFTMETA, Key::KEY_RIGHTMETA,
+];
+
+/// Modifier keys currently held on a keyboard
+#[derive(Debug, Clone, Default)]
+struct Modifiers {
+    held: Vec<Key>,
+}
+
+impl Modifiers {
+    /// Record a key event; returns false if `key` isn't a modifier
+    fn update(&mut self, key: Key, pressed: bool) -> bool {
+        if !MODIFIER_KEYS.contains(&key) {
+            return false;
+        }
+        if !pressed {
+            self.held.retain(|held| *held != key);
+        } else if !self.held.contains(&key) {
+            self.held.push(key);
+        }
+        true
+    }
+
+    fn any(&self, keys: [Key; 2]) -> bool {
+        self.held.iter().any(|held| keys.contains(held))
+    }
+
+    fn ctrl(&self) -> bool { self.any([Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL]) }
+    fn alt(&self) -> bool { self.any([Key::KEY_LEFTALT, Key::KEY_RIGHTALT]) }
+    fn shift(&self) -> bool { self.any([Key::KEY_LEFTSHIFT, Key::KEY_RIGHTSHIFT]) }
+    fn meta(&self) -> bool { self.any([Key::KEY_LEFTMETA, Key::KEY_RIGHTMETA]) }
+}
+
+/// A key combination such as "ctrl+alt+scrolllock". Modifiers are ctrl, alt,
+/// shift and super/meta; the last part is an evdev key name without `KEY_`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotkey {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    meta: bool,
+    key: Key,
+}
+
+impl Hotkey {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let parts: Vec<String> = spec.split('+').map(|part| part.trim().to_ascii_lowercase()).collect();
+        let (key, modifiers) = parts.split_last().context("Empty key combination")?;
+
+        let mut hotkey = Self {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            meta: false,
+            key: format!("KEY_{}", key.to_ascii_uppercase()).parse()
+                .map_err(|_| anyhow!("Unknown key {:?}", key))?,
+        };
+        for modifier in modifiers {
+            match modifier.as_str() {
+                "ctrl" | "control" => hotkey.ctrl = true,
+                "alt" => hotkey.alt = true,
+                "shift" => hotkey.shift = true,
+                "super" | "meta" => hotkey.meta = true,
+                other => bail!("Unknown modifier {:?}", other),
+            }
+        }
+        Ok(hotkey)
+    }
+
+    /// Whether pressing `key` with `modifiers` held triggers this combination
+    fn matches(&self, key: Key, modifiers: &Modifiers) -> bool {
+        key == self.key
+            && modifiers.ctrl() == self.ctrl
+            && modifiers.alt() == self.alt
+            && modifiers.shift() == self.shift
+            && modifiers.meta() == self.meta
+    }
+}
+
+/// Coordinate ranges of an absolute pointer's X and Y axes
+#[derive(Debug, Clone, Copy)]
+struct AbsRange {
+    x: (i32, i32),
+    y: (i32, i32),
+}
+
+impl AbsRange {
+    fn of(device: &Device) -> Option<Self> {
+        let abs = device.get_abs_state().ok()?;
+        let range = |axis: AbsoluteAxisType| {
+            let info = abs[axis.0 as usize];
+            (info.minimum, info.maximum)
+        };
+        Some(Self {
+            x: range(AbsoluteAxisType::ABS_X),
+            y: range(AbsoluteAxisType::ABS_Y),
+        })
+    }
+}
+
+/// Map `value` from a device axis range onto `0..=size` screen pixels
+fn scale_absolute(value: i32, (min, max): (i32, i32), size: u32) -> f32 {
+    if max <= min {
+        return 0.0;
+    }
+    let fraction = (value - min) as f32 / (max - min) as f32;
+    (fraction * size as f32).clamp(0.0, size as f32)
+}
+
+/// `input.force_local_hotkey` and `input.blocked_keys`, parsed once per
+/// config change rather than on every key event
+struct KeyBindings {
+    // What the combinations were parsed from, to notice changes
+    force_local_spec: String,
+    blocked_specs: Vec<String>,
+    force_local: Option<Hotkey>,
+    blocked: Vec<Hotkey>,
+}
+
+impl KeyBindings {
+    /// Combinations that don't parse are skipped; `Config::validate` already
+    /// refuses configs with any
+    fn new(config: &InputConfig) -> Self {
+        let spec = &config.force_local_hotkey;
+        Self {
+            force_local_spec: spec.clone(),
+            blocked_specs: config.blocked_keys.clone(),
+            force_local: (!spec.is_empty()).then(|| Hotkey::parse(spec).ok()).flatten(),
+            blocked: config.blocked_keys.iter().filter_map(|spec| Hotkey::parse(spec).ok()).collect(),
+        }
+    }
+
+    /// Parse the combinations again if `config` changed them
+    fn refresh(&mut self, config: &InputConfig) {
+        if self.force_local_spec != config.force_local_hotkey || self.blocked_specs != config.blocked_keys {
+            *self = Self::new(config);
+        }
+    }
+
+    /// Whether `key` completes the force-local hotkey, given the held modifiers
+    fn is_force_local(&self, key: Key, modifiers: &Modifiers) -> bool {
+        self.force_local.as_ref().is_some_and(|hotkey| hotkey.matches(key, modifiers))
+    }
+
+    /// Whether pressing `key` with `modifiers` held is one of the blocked keys
+    fn is_blocked(&self, key: Key, modifiers: &Modifiers) -> bool {
+        self.blocked.iter().any(|hotkey| hotkey.matches(key, modifiers))
+    }
+}
+
+/// State owned by a single device's reader
+struct ReaderState {
+    click_filter: ClickFilter,
+    coalescer: MotionCoalescer,
+    scroll: ScrollSmoother,
+    edge_dwell: EdgeDwell,
+    modifiers: Modifiers,
+    keys: KeyBindings,
+    // Keys pressed as part of a blocked combination, whose repeats and release
+    // are kept from the peer as well
+    blocked_held: Vec<Key>,
+    // Set for touchscreens and tablets; axis values wait here for the SYN_REPORT
+    abs_range: Option<AbsRange>,
+    abs_pending: (Option<i32>, Option<i32>),
+    // Devices with hi-res wheel axes also emit the coarse ones; only one is forwarded
+    hi_res_wheel: bool,
+    hi_res_hwheel: bool,
+    // MSC_SCAN comes just before the key event it belongs to in the same report
+    scan_code: Option<u32>,
+}
+
+impl ReaderState {
+    fn simulated(config: &InputConfig) -> Self {
+        Self {
+            click_filter: ClickFilter::new(config),
+            coalescer: MotionCoalescer::new(config),
+            scroll: ScrollSmoother::new(config),
+            edge_dwell: EdgeDwell::new(config),
+            modifiers: Modifiers::default(),
+            keys: KeyBindings::new(config),
+            blocked_held: Vec::new(),
+            abs_range: None,
+            abs_pending: (None, None),
+            hi_res_wheel: false,
+            hi_res_hwheel: false,
+            scan_code: None,
+        }
+    }
+
+    fn new(config: &InputConfig, device: &Device) -> Self {
+        let axes = device.supported_relative_axes();
+        let supports = |axis| axes.map(|a| a.contains(axis)).unwrap_or(false);
+
+        Self {
+            click_filter: ClickFilter::new(config),
+            coalescer: MotionCoalescer::new(config),
+            scroll: ScrollSmoother::new(config),
+            edge_dwell: EdgeDwell::new(config),
+            modifiers: Modifiers::default(),
+            keys: KeyBindings::new(config),
+            blocked_held: Vec::new(),
+            abs_range: is_absolute_pointer(device).then(|| AbsRange::of(device)).flatten(),
+            abs_pending: (None, None),
+            hi_res_wheel: supports(evdev::RelativeAxisType::REL_WHEEL_HI_RES),
+            hi_res_hwheel: supports(evdev::RelativeAxisType::REL_HWHEEL_HI_RES),
+            scan_code: None,
+        }
+    }
+}
+
+/// Cloneable handle for steering the input readers from other subsystems
+#[derive(Clone, Default)]
+pub struct InputControl {
+    grabbed: Arc<AtomicBool>,
+    // Cursor position to jump to, applied by the readers before the next event
+    warp: Arc<parking_lot::Mutex<Option<(f32, f32)>>>,
+}
+
+impl InputControl {
+    /// Take exclusive access (EVIOCGRAB) to all captured devices so local
+    /// apps stop seeing input while a remote peer owns the mouse
+    pub fn grab(&self) {
+        if !self.grabbed.swap(true, Ordering::SeqCst) {
+            info!("Grabbing local input devices");
+        }
+    }
+
+    /// Release captured devices back to the local desktop
+    pub fn ungrab(&self) {
+        if self.grabbed.swap(false, Ordering::SeqCst) {
+            info!("Releasing local input devices");
+        }
+    }
+
+    fn is_grabbed(&self) -> bool {
+        self.grabbed.load(Ordering::SeqCst)
+    }
+
+    /// Move the tracked cursor, e.g. to where it re-enters from a peer
+    pub fn warp_to(&self, x: f32, y: f32) {
+        *self.warp.lock() = Some((x, y));
+    }
+
+    fn take_warp(&self) -> Option<(f32, f32)> {
+        self.warp.lock().take()
+    }
+}
+
 pub struct InputManager {
-    config: Config,
+    config: Arc<parking_lot::RwLock<Config>>,
+    control: InputControl,
     mouse_state: Arc<RwLock<MouseState>>,
-    event_tx: mpsc::Sender<InputEvent>,
-    event_rx: Option<mpsc::Receiver<InputEvent>>,
-    mouse_device: Option<Device>,
+    event_tx: broadcast::Sender<InputEvent>,
+    mouse_devices: Vec<(PathBuf, Device)>,
+    keyboard_device: Option<(PathBuf, Device)>,
+    // Devices with a running reader, so rescans only pick up new ones
+    attached: Arc<parking_lot::Mutex<HashMap<PathBuf, DeviceRole>>>,
+    config_updates: Option<watch::Receiver<Config>>,
+    simulate: bool,
 }
 
 impl InputManager {
     pub fn new(config: Config) -> Result<Self> {
-        let (event_tx, event_rx) = mpsc::channel(1000);
+        let (event_tx, _) = broadcast::channel(config.input.event_channel_capacity);
 
-        // Find mouse device
-        let mouse_device = Self::find_mouse_device()?;
+        // Find mouse devices (e.g. a trackpad and an external mouse)
+        let mouse_devices = match Self::find_mouse_devices(config.input.preferred_device.as_deref()) {
+            Ok(mice) if mice.is_empty() => {
+                warn!("⚠ No mouse device found - mouse capture starts once one is plugged in");
+                mice
+            }
+            Ok(mice) => mice,
+            Err(e) => {
+                warn!("⚠ Can't capture the mouse: {:#}", e);
+                Vec::new()
+            }
+        };
         
-        if let Some(ref device) = mouse_device {
+        for (_, device) in &mouse_devices {
             info!("✓ Found mouse device: {}", device.name().unwrap_or("unknown"));
-        } else {
-            warn!("⚠ No mouse device found - input capture disabled");
         }
 
+        // Find keyboard device
+        let keyboard_device = match Self::find_keyboard_device() {
+            Ok(None) => {
+                warn!("⚠ No keyboard device found - keyboard capture starts once one is plugged in");
+                None
+            }
+            Ok(keyboard) => keyboard,
+            Err(e) => {
+                warn!("⚠ Can't capture the keyboard: {:#}", e);
+                None
+            }
+        };
+
+        if let Some((_, ref device)) = keyboard_device {
+            info!("✓ Found keyboard device: {}", device.name().unwrap_or("unknown"));
+        }
+
+        let (screen_width, screen_height) = detect_screen_size().unwrap_or_else(|| {
+            warn!("⚠ Could not detect display resolution, assuming {}x{}",
+                FALLBACK_SCREEN_SIZE.0, FALLBACK_SCREEN_SIZE.1);
+            FALLBACK_SCREEN_SIZE
+        });
+        info!("✓ Display resolution: {}x{}", screen_width, screen_height);
+
         let mouse_state = Arc::new(RwLock::new(MouseState {
             x: 0.0,
             y: 0.0,
             buttons: MouseButtons::default(),
-            screen_width: 1920, // TODO: Get actual screen dimensions
-            screen_height: 1080,
+            screen_width,
+            screen_height,
         }));
 
         Ok(Self {
-            config,
+            config: Arc::new(parking_lot::RwLock::new(config)),
+            control: InputControl::default(),
             mouse_state,
             event_tx,
-            event_rx: Some(event_rx),
-            mouse_device,
+            mouse_devices,
+            keyboard_device,
+            attached: Arc::default(),
+            config_updates: None,
+            simulate: false,
         })
     }
 
-    fn find_mouse_device() -> Result<Option<Device>> {
-        // Try to find a mouse or pointer device
+    /// Share grab state with a previous instance, e.g. when restarted by the supervisor
+    pub fn with_control(mut self, control: InputControl) -> Self {
+        self.control = control;
+        self
+    }
+
+    pub fn control(&self) -> InputControl {
+        self.control.clone()
+    }
+
+    /// Generate input with a `SimulatedSource` instead of reading the devices
+    pub fn with_simulation(mut self) -> Self {
+        self.simulate = true;
+        self
+    }
+
+    /// Publish events on an existing channel so subscribers survive a restart
+    pub fn with_event_sender(mut self, event_tx: broadcast::Sender<InputEvent>) -> Self {
+        self.event_tx = event_tx;
+        self
+    }
+
+    pub fn event_sender(&self) -> broadcast::Sender<InputEvent> {
+        self.event_tx.clone()
+    }
+
+    pub fn grab(&self) {
+        self.control.grab();
+    }
+
+    pub fn ungrab(&self) {
+        self.control.ungrab();
+    }
+
+    /// Every evdev input device, except our own virtual one
+    pub fn list_devices() -> Vec<DeviceInfo> {
+        let mut devices: Vec<DeviceInfo> = evdev::enumerate()
+            .filter(|(_, device)| device.name() != Some(VIRTUAL_DEVICE_NAME))
+            .map(|(path, device)| DeviceInfo {
+                name: device.name().unwrap_or("unknown").to_string(),
+                is_mouse: is_mouse(&device),
+                is_keyboard: is_keyboard(&device),
+                path,
+            })
+            .collect();
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+        devices
+    }
+
+    /// Mice whose name contains `preferred` (case-insensitive), or every mouse
+    /// or pointer device if it's unset or nothing matches
+    fn find_mouse_devices(preferred: Option<&str>) -> Result<Vec<(PathBuf, Device)>> {
+        // Collect every mouse or pointer device
         let devices = evdev::enumerate().collect::<Vec<_>>();
+        let mut mice = Vec::new();
         
         for (path, device) in devices {
-            // Check if device supports relative movement (mouse/touchpad)
-            if device.supported_events().contains(EventType::RELATIVE) {
-                debug!("Found input device: {} at {:?}", 
+            if device.name() == Some(VIRTUAL_DEVICE_NAME) {
+                continue;
+            }
+
+            if is_mouse(&device) {
+                debug!("Found mouse device: {} at {:?}",
                     device.name().unwrap_or("unknown"), path);
-                
-                // Check if it's a mouse (has button events)
-                if device.supported_keys().map(|keys| {
-                    keys.contains(Key::BTN_LEFT) || keys.contains(Key::BTN_MOUSE)
-                }).unwrap_or(false) {
-                    info!("Selected mouse device: {}", device.name().unwrap_or("unknown"));
-                    return Ok(Some(device));
-                }
+                mice.push((path, device));
+            }
+        }
+
+        if let Some(preferred) = preferred.filter(|name| !name.is_empty()) {
+            let preferred = preferred.to_lowercase();
+            let matches_preferred = |(_, device): &(PathBuf, Device)| {
+                device.name().is_some_and(|name| name.to_lowercase().contains(&preferred))
+            };
+            if mice.iter().any(matches_preferred) {
+                mice.retain(matches_preferred);
+            } else {
+                warn!("⚠ No mouse matches input.preferred_device {:?}, selecting automatically", preferred);
             }
         }
 
-        warn!("No suitable mouse device found");
+        for (_, device) in &mice {
+            debug!("Selected mouse device: {}", device.name().unwrap_or("unknown"));
+        }
+        if mice.is_empty() {
+            check_device_access()?;
+            debug!("No suitable mouse device found");
+        }
+        Ok(mice)
+    }
+
+    fn find_keyboard_device() -> Result<Option<(PathBuf, Device)>> {
+        // Try to find a device with a full set of alphanumeric keys
+        let devices = evdev::enumerate().collect::<Vec<_>>();
+
+        for (path, device) in devices {
+            if device.name() == Some(VIRTUAL_DEVICE_NAME) {
+                continue;
+            }
+
+            if is_keyboard(&device) {
+                debug!("Found keyboard device: {} at {:?}",
+                    device.name().unwrap_or("unknown"), path);
+                debug!("Selected keyboard device: {}", device.name().unwrap_or("unknown"));
+                return Ok(Some((path, device)));
+            }
+        }
+
+        check_device_access()?;
+        debug!("No suitable keyboard device found");
         Ok(None)
     }
 
     pub async fn run(mut self) -> Result<()> {
-        if self.mouse_device.is_none() {
-            error!("Cannot run input manager: no mouse device available");
-            return Ok(());
+        if !self.simulate && self.mouse_devices.is_empty() && self.keyboard_device.is_none() {
+            warn!("⚠ No input devices available yet, waiting for one to be plugged in");
         }
 
         info!("Starting input event monitoring...");
 
-        let mut device = self.mouse_device.take().unwrap();
-        let event_tx = self.event_tx.clone();
-        let mouse_state = Arc::clone(&self.mouse_state);
-        let edge_threshold = self.config.host.display_edge_threshold as f32;
+        // One blocking reader per device, all feeding the same channel and
+        // sharing the virtual cursor in `mouse_state`
+        let mut readers = JoinSet::new();
+        if self.simulate {
+            info!("🧪 Simulating input, local devices are ignored");
+            let source = SimulatedSource::new(
+                self.event_tx.clone(),
+                Arc::clone(&self.mouse_state),
+                Arc::clone(&self.config),
+                self.control.clone(),
+            );
+            readers.spawn(source.run());
+        } else {
+            for (path, device) in std::mem::take(&mut self.mouse_devices) {
+                self.spawn_reader(&mut readers, path, DeviceRole::Mouse, device);
+            }
+            if let Some((path, device)) = self.keyboard_device.take() {
+                self.spawn_reader(&mut readers, path, DeviceRole::Keyboard, device);
+            }
+        }
+
+        let config_task = self.config_updates.take().map(|mut updates| {
+            let config = Arc::clone(&self.config);
+            tokio::spawn(async move {
+                while updates.changed().await.is_ok() {
+                    *config.write() = updates.borrow().clone();
+                    debug!("Input settings updated");
+                }
+            })
+        });
+
+        // New device nodes show up in /dev/input; without the watcher we fall
+        // back to rescanning on a timer
+        let (_watcher, mut hotplug) = match (!self.simulate).then(watch_input_devices).transpose() {
+            Ok(Some((watcher, hotplug))) => (Some(watcher), Some(hotplug)),
+            Ok(None) => (None, None),
+            Err(e) => {
+                warn!("⚠ Cannot watch for new input devices, rescanning periodically: {:#}", e);
+                (None, None)
+            }
+        };
+        let mut rescan = tokio::time::interval(DEVICE_RESCAN_INTERVAL);
+        rescan.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-        // Spawn input monitoring task
-        tokio::task::spawn_blocking(move || {
+        // Real devices are re-attached as they come back, so only a simulated
+        // source ends the loop
+        let result = async {
             loop {
-                match device.fetch_events() {
-                    Ok(events) => {
-                        for event in events {
-                            let rt = tokio::runtime::Handle::current();
-                            
-                            rt.block_on(async {
-                                if let Err(e) = Self::process_event(
-                                    event,
-                                    &event_tx,
-                                    &mouse_state,
-                                    edge_threshold,
-                                ).await {
-                                    warn!("Error processing event: {}", e);
-                                }
-                            });
+                let periodic = !self.simulate && (hotplug.is_none() || self.missing_devices());
+                tokio::select! {
+                    Some(result) = readers.join_next() => {
+                        result.context("Input reader thread panicked")?;
+                        if self.simulate && readers.is_empty() {
+                            return Ok(());
                         }
                     }
-                    Err(e) => {
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
-                            error!("Error fetching events: {}", e);
-                            break;
-                        }
-                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    Some(()) = async { hotplug.as_mut()?.recv().await } => {
+                        // udev sets permissions just after the node appears
+                        tokio::time::sleep(HOTPLUG_SETTLE).await;
+                        while hotplug.as_mut().is_some_and(|rx| rx.try_recv().is_ok()) {}
+                        self.attach_new_devices(&mut readers);
                     }
+                    _ = rescan.tick(), if periodic => self.attach_new_devices(&mut readers),
                 }
             }
-        });
+        }.await;
 
-        Ok(())
+        if let Some(task) = config_task {
+            task.abort();
+        }
+        result
     }
 
-    async fn process_event(
-        event: evdev::InputEvent,
-        event_tx: &mpsc::Sender<InputEvent>,
-        mouse_state: &Arc<RwLock<MouseState>>,
-        edge_threshold: f32,
-    ) -> Result<()> {
-        match event.kind() {
-            InputEventKind::RelAxis(axis) => {
-                match axis {
-                    evdev::RelativeAxisType::REL_X => {
-                        let delta_x = event.value() as f32;
-                        let mut state = mouse_state.write().await;
-                        
-                        let old_x = state.x;
-                        state.x = (state.x + delta_x).clamp(0.0, state.screen_width as f32);
-                        
-                        // Check for edge crossing
-                        if old_x >= edge_threshold && state.x < edge_threshold {
-                            // Crossed left edge
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::EdgeCrossed {
-                                edge: ScreenEdge::Left,
-                                position: (0.0, state.y),
-                            }).await;
-                        } else if old_x <= (state.screen_width as f32 - edge_threshold) 
-                            && state.x > (state.screen_width as f32 - edge_threshold) {
-                            // Crossed right edge
-                            let y = state.y;
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::EdgeCrossed {
-                                edge: ScreenEdge::Right,
-                                position: (state.screen_width as f32, y),
-                            }).await;
-                        } else {
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::MouseMove {
-                                delta_x,
-                                delta_y: 0.0,
-                            }).await;
-                        }
-                    }
-                    evdev::RelativeAxisType::REL_Y => {
-                        let delta_y = event.value() as f32;
+    /// Whether a mouse or the keyboard has no reader right now
+    fn missing_devices(&self) -> bool {
+        let attached = self.attached.lock();
+        !attached.values().any(|role| *role == DeviceRole::Mouse)
+            || !attached.values().any(|role| *role == DeviceRole::Keyboard)
+    }
+
+    /// Start readers for devices that appeared since the last scan
+    fn attach_new_devices(&self, readers: &mut JoinSet<()>) {
+        let preferred = self.config.read().input.preferred_device.clone();
+        let mice = match Self::find_mouse_devices(preferred.as_deref()) {
+            Ok(mice) => mice,
+            Err(e) => {
+                warn!("Failed to scan for mouse devices: {:#}", e);
+                Vec::new()
+            }
+        };
+        for (path, device) in mice {
+            if !self.attached.lock().contains_key(&path) {
+                info!("✓ Attached mouse device: {}", device.name().unwrap_or("unknown"));
+                self.spawn_reader(readers, path, DeviceRole::Mouse, device);
+            }
+        }
+
+        if self.attached.lock().values().any(|role| *role == DeviceRole::Keyboard) {
+            return;
+        }
+        match Self::find_keyboard_device() {
+            Ok(Some((path, device))) => {
+                info!("✓ Attached keyboard device: {}", device.name().unwrap_or("unknown"));
+                self.spawn_reader(readers, path, DeviceRole::Keyboard, device);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to scan for keyboard devices: {:#}", e),
+        }
+    }
+
+    fn spawn_reader(&self, readers: &mut JoinSet<()>, path: PathBuf, role: DeviceRole, mut device: Device) {
+        self.attached.lock().insert(path.clone(), role);
+        let attached = Arc::clone(&self.attached);
+        let event_tx = self.event_tx.clone();
+        let mouse_state = Arc::clone(&self.mouse_state);
+        let config = Arc::clone(&self.config);
+        let control = self.control.clone();
+        let warp_control = self.control.clone();
+        let mut reader = ReaderState::new(&self.config.read().input, &device);
+        let (raw_tx, mut raw_rx) = mpsc::channel::<evdev::InputEvent>(RAW_EVENT_CAPACITY);
+
+        // Non-blocking reads let the loop react to grab requests while the device is idle
+        if let Err(e) = set_nonblocking(&device) {
+            warn!("Could not make {} non-blocking: {}", device.name().unwrap_or("unknown"), e);
+        }
+
+        // Blocking side: only reads the device and hands raw events over
+        readers.spawn_blocking(move || {
+            let fd = device.as_raw_fd();
+            let name = device.name().unwrap_or("unknown").to_string();
+            let mut grabbed = false;
+
+            'read: loop {
+                let want_grab = control.is_grabbed();
+                if want_grab != grabbed {
+                    let result = if want_grab { device.grab() } else { device.ungrab() };
+                    if let Err(e) = result {
+                        warn!("Failed to {} {}: {}", if want_grab { "grab" } else { "ungrab" },
+                            device.name().unwrap_or("unknown"), e);
+                    }
+                    grabbed = want_grab;
+                }
+
+                match device.fetch_events() {
+                    Ok(events) => {
+                        for event in events {
+                            match raw_tx.try_send(event) {
+                                Ok(()) => {}
+                                Err(mpsc::error::TrySendError::Full(_)) => {
+                                    warn_dropped("the event processor fell behind");
+                                }
+                                Err(mpsc::error::TrySendError::Closed(_)) => {
+                                    debug!("Event processor stopped, closing {}", name);
+                                    break 'read;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        wait_readable(fd, IDLE_POLL);
+                    }
+                    Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                        warn!("⚠ {} was disconnected", name);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error fetching events: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            // Lets the next rescan attach it again if it comes back
+            attached.lock().remove(&path);
+        });
+
+        // Async side: turns raw events into InputEvents until the reader goes away
+        readers.spawn(async move {
+            loop {
+                let due = [
+                    reader.coalescer.time_until_due(),
+                    reader.scroll.time_until_due(),
+                    reader.edge_dwell.time_until_due(),
+                ]
+                    .into_iter()
+                    .flatten()
+                    .min();
+                let next = match due {
+                    Some(wait) => match tokio::time::timeout(wait, raw_rx.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            Self::flush_due(&mut reader, &event_tx);
+                            continue;
+                        }
+                    },
+                    None => raw_rx.recv().await,
+                };
+
+                let Some(event) = next else {
+                    break;
+                };
+
+                if let Err(e) = Self::process_event(
+                    event,
+                    &event_tx,
+                    &mouse_state,
+                    &mut reader,
+                    &config,
+                    &warp_control,
+                ).await {
+                    warn!("Error processing event: {}", e);
+                }
+            }
+
+            Self::flush_motion(&mut reader, &event_tx);
+        });
+    }
+
+    async fn process_event(
+        event: evdev::InputEvent,
+        event_tx: &broadcast::Sender<InputEvent>,
+        mouse_state: &Arc<RwLock<MouseState>>,
+        reader: &mut ReaderState,
+        config: &parking_lot::RwLock<Config>,
+        control: &InputControl,
+    ) -> Result<()> {
+        metrics().event_processed();
+
+        if let Some((x, y)) = control.take_warp() {
+            let mut state = mouse_state.write().await;
+            state.x = x.clamp(0.0, state.screen_width as f32);
+            state.y = y.clamp(0.0, state.screen_height as f32);
+        }
+
+        // Read settings per event so config updates apply to running readers
+        let (edge_threshold, acceleration, curve_threshold, axis_x, axis_y) = {
+            let config = config.read();
+            let input = &config.input;
+            reader.keys.refresh(input);
+            (
+                config.input.edge_threshold as f32,
+                input.mouse_acceleration,
+                input.acceleration_curve_threshold,
+                (input.dead_zone_x, input.sensitivity_x),
+                (input.dead_zone_y, input.sensitivity_y),
+            )
+        };
+
+        // Anything but motion must not overtake motion that is still being coalesced
+        if !matches!(event.kind(),
+            InputEventKind::Synchronization(_)
+            | InputEventKind::RelAxis(evdev::RelativeAxisType::REL_X | evdev::RelativeAxisType::REL_Y))
+        {
+            Self::flush_motion(reader, event_tx);
+        }
+        // Likewise a touch or pen press must land where the contact is
+        if !matches!(event.kind(), InputEventKind::AbsAxis(_)) {
+            Self::flush_absolute(reader, event_tx, mouse_state, edge_threshold).await;
+        }
+
+        match event.kind() {
+            InputEventKind::Misc(evdev::MiscType::MSC_SCAN) => reader.scan_code = Some(event.value() as u32),
+            InputEventKind::Synchronization(evdev::Synchronization::SYN_REPORT) => reader.scan_code = None,
+            InputEventKind::AbsAxis(axis) if reader.abs_range.is_some() => match axis {
+                AbsoluteAxisType::ABS_X => reader.abs_pending.0 = Some(event.value()),
+                AbsoluteAxisType::ABS_Y => reader.abs_pending.1 = Some(event.value()),
+                _ => {}
+            },
+            InputEventKind::RelAxis(axis) => {
+                match axis {
+                    evdev::RelativeAxisType::REL_X => {
+                        let Some(delta_x) = shape_axis(event.value() as f32, axis_x.0, axis_x.1) else {
+                            return Ok(());
+                        };
+                        let Some(delta_x) = reader.click_filter.motion(Axis::X, delta_x) else {
+                            return Ok(());
+                        };
+                        let delta_x = accelerate(delta_x, acceleration, curve_threshold);
+                        let mut state = mouse_state.write().await;
+                        let old = (state.x, state.y);
+                        state.x = (state.x + delta_x).clamp(0.0, state.screen_width as f32);
+                        let position = (state.x, state.y);
+                        let screen = (state.screen_width, state.screen_height);
+                        drop(state);
+
+                        Self::cursor_moved(reader, event_tx, (delta_x, 0.0), old, position, screen, edge_threshold);
+                    }
+                    evdev::RelativeAxisType::REL_Y => {
+                        let Some(delta_y) = shape_axis(event.value() as f32, axis_y.0, axis_y.1) else {
+                            return Ok(());
+                        };
+                        let Some(delta_y) = reader.click_filter.motion(Axis::Y, delta_y) else {
+                            return Ok(());
+                        };
+                        let delta_y = accelerate(delta_y, acceleration, curve_threshold);
                         let mut state = mouse_state.write().await;
-                        
-                        let old_y = state.y;
+                        let old = (state.x, state.y);
                         state.y = (state.y + delta_y).clamp(0.0, state.screen_height as f32);
-                        
-                        // Check for edge crossing
-                        if old_y >= edge_threshold && state.y < edge_threshold {
-                            // Crossed top edge
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::EdgeCrossed {
-                                edge: ScreenEdge::Top,
-                                position: (state.x, 0.0),
-                            }).await;
-                        } else if old_y <= (state.screen_height as f32 - edge_threshold)
-                            && state.y > (state.screen_height as f32 - edge_threshold) {
-                            // Crossed bottom edge
-                            let x = state.x;
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::EdgeCrossed {
-                                edge: ScreenEdge::Bottom,
-                                position: (x, state.screen_height as f32),
-                            }).await;
-                        } else {
-                            drop(state);
-                            let _ = event_tx.send(InputEvent::MouseMove {
-                                delta_x: 0.0,
-                                delta_y,
-                            }).await;
+                        let position = (state.x, state.y);
+                        let screen = (state.screen_width, state.screen_height);
+                        drop(state);
+
+                        Self::cursor_moved(reader, event_tx, (0.0, delta_y), old, position, screen, edge_threshold);
+                    }
+                    evdev::RelativeAxisType::REL_WHEEL if !reader.hi_res_wheel => {
+                        if let Some(wheel) = reader.scroll.add(event.value() as f32, false) {
+                            publish(event_tx, wheel);
                         }
                     }
-                    evdev::RelativeAxisType::REL_WHEEL => {
-                        let delta = event.value() as f32;
-                        let _ = event_tx.send(InputEvent::MouseWheel {
-                            delta,
-                            horizontal: false,
-                        }).await;
+                    evdev::RelativeAxisType::REL_HWHEEL if !reader.hi_res_hwheel => {
+                        if let Some(wheel) = reader.scroll.add(event.value() as f32, true) {
+                            publish(event_tx, wheel);
+                        }
                     }
-                    evdev::RelativeAxisType::REL_HWHEEL => {
-                        let delta = event.value() as f32;
-                        let _ = event_tx.send(InputEvent::MouseWheel {
-                            delta,
-                            horizontal: true,
-                        }).await;
+                    evdev::RelativeAxisType::REL_WHEEL_HI_RES => {
+                        let delta = event.value() as f32 / HI_RES_SCROLL_UNITS;
+                        if let Some(wheel) = reader.scroll.add(delta, false) {
+                            publish(event_tx, wheel);
+                        }
+                    }
+                    evdev::RelativeAxisType::REL_HWHEEL_HI_RES => {
+                        let delta = event.value() as f32 / HI_RES_SCROLL_UNITS;
+                        if let Some(wheel) = reader.scroll.add(delta, true) {
+                            publish(event_tx, wheel);
+                        }
                     }
                     _ => {}
                 }
             }
             InputEventKind::Key(key) => {
                 let pressed = event.value() != 0;
+                let scan_code = reader.scan_code.take();
                 
                 let button = match key {
-                    Key::BTN_LEFT | Key::BTN_MOUSE => Some(MouseButton::Left),
+                    Key::BTN_LEFT => Some(MouseButton::Left),
                     Key::BTN_RIGHT => Some(MouseButton::Right),
                     Key::BTN_MIDDLE => Some(MouseButton::Middle),
                     Key::BTN_SIDE => Some(MouseButton::Back),
                     Key::BTN_EXTRA => Some(MouseButton::Forward),
+                    // Touching the screen or tablet surface clicks
+                    Key::BTN_TOUCH => Some(MouseButton::Left),
                     _ => None,
                 };
 
                 if let Some(button) = button {
+                    reader.click_filter.button(button, pressed);
+
                     let mut state = mouse_state.write().await;
                     match button {
                         MouseButton::Left => state.buttons.left = pressed,
                         MouseButton::Right => state.buttons.right = pressed,
                         MouseButton::Middle => state.buttons.middle = pressed,
-                        _ => {}
+                        MouseButton::Back => state.buttons.back = pressed,
+                        MouseButton::Forward => state.buttons.forward = pressed,
                     }
                     drop(state);
 
-                    let _ = event_tx.send(InputEvent::MouseButton {
+                    publish(event_tx, InputEvent::MouseButton {
                         button,
                         pressed,
-                    }).await;
+                    });
+                } else if is_keyboard_key(key) {
+                    let is_modifier = reader.modifiers.update(key, pressed);
+                    if !is_modifier && pressed && reader.keys.is_force_local(key, &reader.modifiers) {
+                        // Release the modifiers on the peer so none stay stuck
+                        // there, and keep the hotkey itself local
+                        for held in &reader.modifiers.held {
+                            publish(event_tx, InputEvent::KeyPress {
+                                key_code: held.code() as u32,
+                                pressed: false,
+                                scan_code: None,
+                            });
+                        }
+                        info!("🔒 Force-local hotkey pressed");
+                        publish(event_tx, InputEvent::ForceLocal);
+                        return Ok(());
+                    }
+
+                    if !is_modifier {
+                        if event.value() == 1 && reader.keys.is_blocked(key, &reader.modifiers) {
+                            debug!("Keeping blocked key {:?} from the peer", key);
+                            reader.blocked_held.push(key);
+                            return Ok(());
+                        }
+                        if reader.blocked_held.contains(&key) {
+                            if !pressed {
+                                reader.blocked_held.retain(|held| *held != key);
+                            }
+                            return Ok(());
+                        }
+                    }
+
+                    // Autorepeat (value 2) is forwarded as a continued press
+                    let scancodes = config.read().input.key_forwarding == KeyForwarding::Scancode;
+                    publish(event_tx, InputEvent::KeyPress {
+                        key_code: key.code() as u32,
+                        pressed,
+                        scan_code: scan_code.filter(|_| scancodes),
+                    });
                 }
             }
             _ => {}
@@ -281,11 +1242,948 @@ impl InputManager {
         Ok(())
     }
 
-    pub fn subscribe(&mut self) -> mpsc::Receiver<InputEvent> {
-        self.event_rx.take().unwrap()
+    /// Move the cursor to the absolute position collected since the last
+    /// SYN_REPORT and check whether that put it at an edge
+    async fn flush_absolute(
+        reader: &mut ReaderState,
+        event_tx: &broadcast::Sender<InputEvent>,
+        mouse_state: &Arc<RwLock<MouseState>>,
+        edge_threshold: f32,
+    ) {
+        let Some(range) = reader.abs_range else {
+            return;
+        };
+        let (x, y) = std::mem::take(&mut reader.abs_pending);
+        if x.is_none() && y.is_none() {
+            return;
+        }
+
+        let mut state = mouse_state.write().await;
+        let old = (state.x, state.y);
+        if let Some(x) = x {
+            state.x = scale_absolute(x, range.x, state.screen_width);
+        }
+        if let Some(y) = y {
+            state.y = scale_absolute(y, range.y, state.screen_height);
+        }
+        let position = (state.x, state.y);
+        let screen = (state.screen_width, state.screen_height);
+        drop(state);
+
+        publish(event_tx, InputEvent::MouseAbsolute { x: position.0, y: position.1 });
+
+        let crossing = match detect_edge(old, position, screen, edge_threshold) {
+            Some((edge, position)) => reader.edge_dwell.enter(edge, position, Instant::now()),
+            None => reader.edge_dwell.moved(Instant::now(), |edge| in_edge_zone(edge, position, screen, edge_threshold)),
+        };
+        if let Some(crossing) = crossing {
+            publish(event_tx, crossing);
+        }
+    }
+
+    /// Forward relative motion that took the cursor from `old` to `new`, or
+    /// start crossing if it carried the cursor into an edge zone
+    fn cursor_moved(
+        reader: &mut ReaderState,
+        event_tx: &broadcast::Sender<InputEvent>,
+        delta: (f32, f32),
+        old: (f32, f32),
+        new: (f32, f32),
+        screen: (u32, u32),
+        edge_threshold: f32,
+    ) {
+        if let Some((edge, position)) = detect_edge(old, new, screen, edge_threshold) {
+            Self::flush_motion(reader, event_tx);
+            if let Some(crossing) = reader.edge_dwell.enter(edge, position, Instant::now()) {
+                publish(event_tx, crossing);
+            }
+            return;
+        }
+
+        if let Some(motion) = reader.coalescer.add(delta.0, delta.1) {
+            publish(event_tx, motion);
+        }
+        let at_edge = |edge| in_edge_zone(edge, new, screen, edge_threshold);
+        if let Some(crossing) = reader.edge_dwell.moved(Instant::now(), at_edge) {
+            Self::flush_motion(reader, event_tx);
+            publish(event_tx, crossing);
+        }
+    }
+
+    fn flush_motion(reader: &mut ReaderState, event_tx: &broadcast::Sender<InputEvent>) {
+        if let Some(motion) = reader.coalescer.take() {
+            publish(event_tx, motion);
+        }
+    }
+
+    /// Emit coalesced motion and smooth-scroll steps whose time has come
+    fn flush_due(reader: &mut ReaderState, event_tx: &broadcast::Sender<InputEvent>) {
+        if reader.coalescer.time_until_due() == Some(Duration::ZERO) {
+            Self::flush_motion(reader, event_tx);
+        }
+        if reader.scroll.time_until_due() == Some(Duration::ZERO) {
+            for wheel in reader.scroll.step() {
+                publish(event_tx, wheel);
+            }
+        }
+        // The cursor has rested against an edge long enough
+        if let Some(crossing) = reader.edge_dwell.take_due(Instant::now()) {
+            Self::flush_motion(reader, event_tx);
+            publish(event_tx, crossing);
+        }
+    }
+
+    /// Subscribe to captured input. Every subscriber sees every event; the buffer
+    /// holds the last `input.event_channel_capacity`, and a subscriber that falls
+    /// further behind gets `RecvError::Lagged(n)` and resumes from the oldest
+    /// event still buffered.
+    pub fn subscribe(&self) -> broadcast::Receiver<InputEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Apply config reloads as they arrive (see `Config::watch`)
+    pub fn with_config_updates(mut self, updates: watch::Receiver<Config>) -> Self {
+        self.config_updates = Some(updates);
+        self
+    }
+
+    /// Swap in new settings; running readers pick them up on their next event
+    pub fn update_config(&self, config: Config) {
+        *self.config.write() = config;
     }
 
     pub async fn get_mouse_state(&self) -> MouseState {
         self.mouse_state.read().await.clone()
     }
 }
+
+/// Scripted stand-in for the device readers: sweeps the cursor back and forth
+/// across the screen so it crosses the left and right edges, clicking at each
+/// turn. Its raw events go through the same processing as real devices and
+/// come out on the same channel.
+pub struct SimulatedSource {
+    event_tx: broadcast::Sender<InputEvent>,
+    mouse_state: Arc<RwLock<MouseState>>,
+    config: Arc<parking_lot::RwLock<Config>>,
+    control: InputControl,
+    reader: ReaderState,
+}
+
+impl SimulatedSource {
+    // Pixels per simulated motion event and the time between them
+    const STEP: i32 = 16;
+    const INTERVAL: Duration = Duration::from_millis(8);
+
+    pub fn new(
+        event_tx: broadcast::Sender<InputEvent>,
+        mouse_state: Arc<RwLock<MouseState>>,
+        config: Arc<parking_lot::RwLock<Config>>,
+        control: InputControl,
+    ) -> Self {
+        let reader = ReaderState::simulated(&config.read().input);
+        Self { event_tx, mouse_state, config, control, reader }
+    }
+
+    pub async fn run(mut self) {
+        // Start in the middle of the screen
+        let (width, height) = {
+            let mut state = self.mouse_state.write().await;
+            state.x = state.screen_width as f32 / 2.0;
+            state.y = state.screen_height as f32 / 2.0;
+            (state.screen_width as i32, state.screen_height as i32)
+        };
+        debug!("Simulating a {}x{} screen", width, height);
+
+        let mut direction = 1;
+        let mut interval = tokio::time::interval(Self::INTERVAL);
+        loop {
+            // Overshoot the screen so the sweep always reaches the edge
+            let steps = width / Self::STEP + 1;
+            for _ in 0..steps {
+                interval.tick().await;
+                let motion = evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, direction * Self::STEP);
+                self.process(motion).await;
+            }
+
+            // Rest against the edge until the crossing activates
+            tokio::time::sleep(self.reader.edge_dwell.time_until_due().unwrap_or_default()).await;
+            InputManager::flush_due(&mut self.reader, &self.event_tx);
+
+            for pressed in [1, 0] {
+                interval.tick().await;
+                let click = evdev::InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), pressed);
+                self.process(click).await;
+            }
+
+            direction = -direction;
+        }
+    }
+
+    /// Run one raw event through the same processing as a device reader
+    pub async fn process(&mut self, event: evdev::InputEvent) {
+        if let Err(e) = InputManager::process_event(
+            event,
+            &self.event_tx,
+            &self.mouse_state,
+            &mut self.reader,
+            &self.config,
+            &self.control,
+        ).await {
+            warn!("Error processing simulated event: {}", e);
+        }
+    }
+
+    /// Emit motion held back for coalescing without waiting out its window
+    pub fn flush_motion(&mut self) {
+        InputManager::flush_motion(&mut self.reader, &self.event_tx);
+    }
+}
+
+/// Virtual uinput device that replays input received from a peer, so a Linux
+/// host can also be the controlled side
+pub struct VirtualInput {
+    device: VirtualDevice,
+    // Sub-unit remainders carried between events so fractional deltas aren't lost
+    motion_remainder: (f32, f32),
+    wheel_remainder: (f32, f32),
+}
+
+impl VirtualInput {
+    pub fn new() -> Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        for code in 1..Key::BTN_0.code() {
+            keys.insert(Key::new(code));
+        }
+        // Some keys peers can send, like the media keys, are past BTN_0
+        for &(key, _) in HID_KEYS {
+            keys.insert(key);
+        }
+        for button in [MouseButton::Left, MouseButton::Right, MouseButton::Middle,
+            MouseButton::Back, MouseButton::Forward]
+        {
+            keys.insert(button_key(button));
+        }
+
+        let axes: AttributeSet<RelativeAxisType> = [
+            RelativeAxisType::REL_X,
+            RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
+            RelativeAxisType::REL_WHEEL_HI_RES,
+            RelativeAxisType::REL_HWHEEL_HI_RES,
+        ].into_iter().collect();
+
+        let device = VirtualDeviceBuilder::new()
+            .context("Failed to open /dev/uinput")?
+            .name(VIRTUAL_DEVICE_NAME)
+            .with_keys(&keys)?
+            .with_relative_axes(&axes)?
+            .build()
+            .context("Failed to create virtual input device")?;
+
+        info!("✓ Created virtual input device");
+
+        Ok(Self {
+            device,
+            motion_remainder: (0.0, 0.0),
+            wheel_remainder: (0.0, 0.0),
+        })
+    }
+
+    /// Put the pointer at `(x, y)`. A relative device can't be positioned directly,
+    /// so it's pinned to the top-left corner first and moved from there.
+    pub fn warp_to(&mut self, x: f32, y: f32) -> Result<()> {
+        const PIN: i32 = -100_000;
+        let events = [
+            evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, PIN),
+            evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, PIN),
+            evdev::InputEvent::new(EventType::SYNCHRONIZATION, 0, 0),
+            evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, x.round() as i32),
+            evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, y.round() as i32),
+        ];
+        self.motion_remainder = (0.0, 0.0);
+        self.device.emit(&events).context("Failed to write to virtual input device")
+    }
+
+    /// Translate an `InputEvent` back into evdev events and write them to the device
+    pub fn inject(&mut self, event: &InputEvent) -> Result<()> {
+        let events = match *event {
+            InputEvent::MouseMove { delta_x, delta_y } => {
+                let dx = take_whole(&mut self.motion_remainder.0, delta_x);
+                let dy = take_whole(&mut self.motion_remainder.1, delta_y);
+                let mut events = Vec::with_capacity(2);
+                if dx != 0 {
+                    events.push(evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx));
+                }
+                if dy != 0 {
+                    events.push(evdev::InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy));
+                }
+                events
+            }
+            InputEvent::MouseButton { button, pressed } => {
+                vec![evdev::InputEvent::new(EventType::KEY, button_key(button).code(), pressed as i32)]
+            }
+            InputEvent::MouseWheel { delta, horizontal } => {
+                let (axis, hi_res_axis, remainder) = if horizontal {
+                    (RelativeAxisType::REL_HWHEEL, RelativeAxisType::REL_HWHEEL_HI_RES, &mut self.wheel_remainder.1)
+                } else {
+                    (RelativeAxisType::REL_WHEEL, RelativeAxisType::REL_WHEEL_HI_RES, &mut self.wheel_remainder.0)
+                };
+
+                // Hi-res for smooth scrolling clients, whole notches for everyone else
+                let mut events = vec![evdev::InputEvent::new(
+                    EventType::RELATIVE,
+                    hi_res_axis.0,
+                    (delta * HI_RES_SCROLL_UNITS).round() as i32,
+                )];
+                let notches = take_whole(remainder, delta);
+                if notches != 0 {
+                    events.push(evdev::InputEvent::new(EventType::RELATIVE, axis.0, notches));
+                }
+                events
+            }
+            InputEvent::KeyPress { key_code, pressed, .. } => {
+                vec![evdev::InputEvent::new(EventType::KEY, key_code as u16, pressed as i32)]
+            }
+            InputEvent::MouseAbsolute { x, y } => return self.warp_to(x, y),
+            // Edge crossings and hotkeys are handled by the session layer, not replayed
+            InputEvent::EdgeCrossed { .. } | InputEvent::ForceLocal => return Ok(()),
+        };
+
+        if !events.is_empty() {
+            self.device.emit(&events).context("Failed to write to virtual input device")?;
+        }
+        Ok(())
+    }
+}
+
+fn button_key(button: MouseButton) -> Key {
+    match button {
+        MouseButton::Left => Key::BTN_LEFT,
+        MouseButton::Right => Key::BTN_RIGHT,
+        MouseButton::Middle => Key::BTN_MIDDLE,
+        MouseButton::Back => Key::BTN_SIDE,
+        MouseButton::Forward => Key::BTN_EXTRA,
+    }
+}
+
+/// Hand an event to subscribers, counting it as dropped if nobody is listening
+fn publish(event_tx: &broadcast::Sender<InputEvent>, event: InputEvent) {
+    if event_tx.send(event).is_err() {
+        warn_dropped("no subscriber is listening");
+    }
+}
+
+/// Count a lost event and warn about it, at most once per `DROP_WARNING_INTERVAL`
+fn warn_dropped(reason: &str) {
+    static LAST_WARNING: parking_lot::Mutex<Option<Instant>> = parking_lot::const_mutex(None);
+
+    metrics().events_dropped(1);
+
+    let mut last = LAST_WARNING.lock();
+    if last.map_or(true, |at| at.elapsed() >= DROP_WARNING_INTERVAL) {
+        *last = Some(Instant::now());
+        warn!("Dropping input events, {} ({} dropped so far)", reason, metrics().dropped_events());
+    }
+}
+
+/// Add `delta` to `remainder` and take out the whole units
+fn take_whole(remainder: &mut f32, delta: f32) -> i32 {
+    *remainder += delta;
+    let whole = remainder.trunc();
+    *remainder -= whole;
+    whole as i32
+}
+
+/// Block until the device has input or the timeout passes
+fn wait_readable(fd: RawFd, timeout: Duration) {
+    let mut fds = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+    // SAFETY: polling a single valid pollfd owned by this stack frame
+    unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+}
+
+fn set_nonblocking(device: &Device) -> std::io::Result<()> {
+    let fd = device.as_raw_fd();
+    // SAFETY: fcntl on a file descriptor owned by `device` for the duration of the call
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resolution of the primary display. `MIRAGE_SCREEN_SIZE=WIDTHxHEIGHT` overrides
+/// detection; otherwise the preferred mode of the first connected DRM connector is used,
+/// which works without a connection to the display server.
+pub fn detect_screen_size() -> Option<(u32, u32)> {
+    if let Ok(value) = std::env::var("MIRAGE_SCREEN_SIZE") {
+        match parse_mode(&value) {
+            Some(size) => return Some(size),
+            None => warn!("Ignoring invalid MIRAGE_SCREEN_SIZE={:?}", value),
+        }
+    }
+
+    platform::drm_outputs().first().map(|output| (output.width, output.height))
+}
+
+pub fn parse_mode(mode: &str) -> Option<(u32, u32)> {
+    let (width, height) = mode.trim().split_once('x')?;
+    // Interlaced modes are listed as e.g. "1920x1080i"
+    let height = height.trim_end_matches(|c: char| !c.is_ascii_digit());
+    let size = (width.parse().ok()?, height.parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+/// Drop motion inside the axis dead zone and apply its sensitivity.
+/// A delta exactly at the dead-zone size still moves.
+fn shape_axis(delta: f32, dead_zone: f32, sensitivity: f32) -> Option<f32> {
+    if delta.abs() < dead_zone {
+        return None;
+    }
+    Some(delta * sensitivity)
+}
+
+/// Scale a motion delta by `mouse_acceleration`. With a curve threshold set and
+/// acceleration above 1.0, faster motion gets proportionally more gain:
+/// `delta * accel * (1 + |delta| / threshold)`.
+fn accelerate(delta: f32, acceleration: f32, curve_threshold: Option<f32>) -> f32 {
+    match curve_threshold {
+        Some(threshold) if acceleration > 1.0 && threshold > 0.0 => {
+            delta * acceleration * (1.0 + delta.abs() / threshold)
+        }
+        _ => delta * acceleration,
+    }
+}
+
+/// Watch /dev/input for new event nodes
+fn watch_input_devices() -> Result<(notify::RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+    let (added_tx, added_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        let Ok(event) = result else { return };
+        let added = matches!(event.kind, notify::EventKind::Create(_))
+            && event.paths.iter().any(|p| {
+                p.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("event"))
+            });
+        if added {
+            let _ = added_tx.send(());
+        }
+    })
+    .context("Failed to create input device watcher")?;
+
+    watcher.watch(Path::new(INPUT_DEVICE_DIR), notify::RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", INPUT_DEVICE_DIR))?;
+    Ok((watcher, added_rx))
+}
+
+/// Relative pointer with a left button, or an absolute one (touchscreen or tablet)
+fn is_mouse(device: &Device) -> bool {
+    (device.supported_events().contains(EventType::RELATIVE)
+        && device.supported_keys().map(|keys| keys.contains(Key::BTN_LEFT)).unwrap_or(false))
+        || is_absolute_pointer(device)
+}
+
+/// Touchscreens and pen tablets. Touchpads also report absolute axes but are
+/// neither direct nor pens, and are left alone.
+fn is_absolute_pointer(device: &Device) -> bool {
+    let has_axes = device.supported_absolute_axes()
+        .map(|axes| axes.contains(AbsoluteAxisType::ABS_X) && axes.contains(AbsoluteAxisType::ABS_Y))
+        .unwrap_or(false);
+    let is_direct = device.properties().contains(PropType::DIRECT);
+    let is_pen = device.supported_keys().map(|keys| keys.contains(Key::BTN_TOOL_PEN)).unwrap_or(false);
+    has_axes && (is_direct || is_pen)
+}
+
+/// Has a full set of alphanumeric keys
+fn is_keyboard(device: &Device) -> bool {
+    device.supported_keys().map(|keys| {
+        keys.contains(Key::KEY_A) && keys.contains(Key::KEY_Z) && keys.contains(Key::KEY_ENTER)
+    }).unwrap_or(false)
+}
+
+/// Fail with a hint if there are event devices this user may not open, since
+/// `evdev::enumerate` skips those without saying why
+fn check_device_access() -> Result<()> {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return Ok(());
+    };
+    let denied = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .filter(|entry| matches!(
+            std::fs::File::open(entry.path()),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied
+        ))
+        .count();
+    if denied > 0 {
+        bail!(
+            "permission denied on {} device{} in /dev/input; add yourself to the input group \
+             (`sudo usermod -aG input $USER`, then log in again) or give your user access with a udev rule",
+            denied, if denied == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
+
+/// Keyboard keys, as opposed to mouse/joystick/tablet buttons
+fn is_keyboard_key(key: Key) -> bool {
+    let code = key.code();
+    (code > 0 && code < Key::BTN_0.code())
+        || (code >= Key::KEY_OK.code() && code < Key::BTN_TRIGGER_HAPPY1.code())
+}
+
+// USB HID usages are what keys are sent to peers as, since they name the key's
+// position rather than what a layout puts there. A usage is `page << 16 | id`.
+const fn desktop(id: u32) -> u32 { 0x01 << 16 | id }
+const fn keyboard(id: u32) -> u32 { 0x07 << 16 | id }
+const fn consumer(id: u32) -> u32 { 0x0C << 16 | id }
+
+/// Every key with a HID usage. A key listed twice is sent as its first usage;
+/// both are understood coming from a peer.
+const HID_KEYS: &[(Key, u32)] = &[
+    // Letters
+    (Key::KEY_A, keyboard(0x04)), (Key::KEY_B, keyboard(0x05)), (Key::KEY_C, keyboard(0x06)),
+    (Key::KEY_D, keyboard(0x07)), (Key::KEY_E, keyboard(0x08)), (Key::KEY_F, keyboard(0x09)),
+    (Key::KEY_G, keyboard(0x0A)), (Key::KEY_H, keyboard(0x0B)), (Key::KEY_I, keyboard(0x0C)),
+    (Key::KEY_J, keyboard(0x0D)), (Key::KEY_K, keyboard(0x0E)), (Key::KEY_L, keyboard(0x0F)),
+    (Key::KEY_M, keyboard(0x10)), (Key::KEY_N, keyboard(0x11)), (Key::KEY_O, keyboard(0x12)),
+    (Key::KEY_P, keyboard(0x13)), (Key::KEY_Q, keyboard(0x14)), (Key::KEY_R, keyboard(0x15)),
+    (Key::KEY_S, keyboard(0x16)), (Key::KEY_T, keyboard(0x17)), (Key::KEY_U, keyboard(0x18)),
+    (Key::KEY_V, keyboard(0x19)), (Key::KEY_W, keyboard(0x1A)), (Key::KEY_X, keyboard(0x1B)),
+    (Key::KEY_Y, keyboard(0x1C)), (Key::KEY_Z, keyboard(0x1D)),
+    // Number row
+    (Key::KEY_1, keyboard(0x1E)), (Key::KEY_2, keyboard(0x1F)), (Key::KEY_3, keyboard(0x20)),
+    (Key::KEY_4, keyboard(0x21)), (Key::KEY_5, keyboard(0x22)), (Key::KEY_6, keyboard(0x23)),
+    (Key::KEY_7, keyboard(0x24)), (Key::KEY_8, keyboard(0x25)), (Key::KEY_9, keyboard(0x26)),
+    (Key::KEY_0, keyboard(0x27)),
+    // Editing and punctuation
+    (Key::KEY_ENTER, keyboard(0x28)), (Key::KEY_ESC, keyboard(0x29)), (Key::KEY_BACKSPACE, keyboard(0x2A)),
+    (Key::KEY_TAB, keyboard(0x2B)), (Key::KEY_SPACE, keyboard(0x2C)), (Key::KEY_MINUS, keyboard(0x2D)),
+    (Key::KEY_EQUAL, keyboard(0x2E)), (Key::KEY_LEFTBRACE, keyboard(0x2F)), (Key::KEY_RIGHTBRACE, keyboard(0x30)),
+    (Key::KEY_BACKSLASH, keyboard(0x31)), (Key::KEY_BACKSLASH, keyboard(0x32)), (Key::KEY_SEMICOLON, keyboard(0x33)),
+    (Key::KEY_APOSTROPHE, keyboard(0x34)), (Key::KEY_GRAVE, keyboard(0x35)), (Key::KEY_COMMA, keyboard(0x36)),
+    (Key::KEY_DOT, keyboard(0x37)), (Key::KEY_SLASH, keyboard(0x38)), (Key::KEY_CAPSLOCK, keyboard(0x39)),
+    (Key::KEY_102ND, keyboard(0x64)), (Key::KEY_COMPOSE, keyboard(0x65)),
+    // Function keys
+    (Key::KEY_F1, keyboard(0x3A)), (Key::KEY_F2, keyboard(0x3B)), (Key::KEY_F3, keyboard(0x3C)),
+    (Key::KEY_F4, keyboard(0x3D)), (Key::KEY_F5, keyboard(0x3E)), (Key::KEY_F6, keyboard(0x3F)),
+    (Key::KEY_F7, keyboard(0x40)), (Key::KEY_F8, keyboard(0x41)), (Key::KEY_F9, keyboard(0x42)),
+    (Key::KEY_F10, keyboard(0x43)), (Key::KEY_F11, keyboard(0x44)), (Key::KEY_F12, keyboard(0x45)),
+    (Key::KEY_F13, keyboard(0x68)), (Key::KEY_F14, keyboard(0x69)), (Key::KEY_F15, keyboard(0x6A)),
+    (Key::KEY_F16, keyboard(0x6B)), (Key::KEY_F17, keyboard(0x6C)), (Key::KEY_F18, keyboard(0x6D)),
+    (Key::KEY_F19, keyboard(0x6E)), (Key::KEY_F20, keyboard(0x6F)), (Key::KEY_F21, keyboard(0x70)),
+    (Key::KEY_F22, keyboard(0x71)), (Key::KEY_F23, keyboard(0x72)), (Key::KEY_F24, keyboard(0x73)),
+    // Navigation
+    (Key::KEY_SYSRQ, keyboard(0x46)), (Key::KEY_SCROLLLOCK, keyboard(0x47)), (Key::KEY_PAUSE, keyboard(0x48)),
+    (Key::KEY_INSERT, keyboard(0x49)), (Key::KEY_HOME, keyboard(0x4A)), (Key::KEY_PAGEUP, keyboard(0x4B)),
+    (Key::KEY_DELETE, keyboard(0x4C)), (Key::KEY_END, keyboard(0x4D)), (Key::KEY_PAGEDOWN, keyboard(0x4E)),
+    (Key::KEY_RIGHT, keyboard(0x4F)), (Key::KEY_LEFT, keyboard(0x50)), (Key::KEY_DOWN, keyboard(0x51)),
+    (Key::KEY_UP, keyboard(0x52)),
+    // Keypad
+    (Key::KEY_NUMLOCK, keyboard(0x53)), (Key::KEY_KPSLASH, keyboard(0x54)), (Key::KEY_KPASTERISK, keyboard(0x55)),
+    (Key::KEY_KPMINUS, keyboard(0x56)), (Key::KEY_KPPLUS, keyboard(0x57)), (Key::KEY_KPENTER, keyboard(0x58)),
+    (Key::KEY_KP1, keyboard(0x59)), (Key::KEY_KP2, keyboard(0x5A)), (Key::KEY_KP3, keyboard(0x5B)),
+    (Key::KEY_KP4, keyboard(0x5C)), (Key::KEY_KP5, keyboard(0x5D)), (Key::KEY_KP6, keyboard(0x5E)),
+    (Key::KEY_KP7, keyboard(0x5F)), (Key::KEY_KP8, keyboard(0x60)), (Key::KEY_KP9, keyboard(0x61)),
+    (Key::KEY_KP0, keyboard(0x62)), (Key::KEY_KPDOT, keyboard(0x63)), (Key::KEY_KPEQUAL, keyboard(0x67)),
+    (Key::KEY_KPCOMMA, keyboard(0x85)),
+    // Modifiers, with Meta being the Super/Windows/Command key
+    (Key::KEY_LEFTCTRL, keyboard(0xE0)), (Key::KEY_LEFTSHIFT, keyboard(0xE1)), (Key::KEY_LEFTALT, keyboard(0xE2)),
+    (Key::KEY_LEFTMETA, keyboard(0xE3)), (Key::KEY_RIGHTCTRL, keyboard(0xE4)), (Key::KEY_RIGHTSHIFT, keyboard(0xE5)),
+    (Key::KEY_RIGHTALT, keyboard(0xE6)), (Key::KEY_RIGHTMETA, keyboard(0xE7)),
+    // International keys
+    (Key::KEY_RO, keyboard(0x87)), (Key::KEY_KATAKANAHIRAGANA, keyboard(0x88)), (Key::KEY_YEN, keyboard(0x89)),
+    (Key::KEY_HENKAN, keyboard(0x8A)), (Key::KEY_MUHENKAN, keyboard(0x8B)), (Key::KEY_HANGEUL, keyboard(0x90)),
+    (Key::KEY_HANJA, keyboard(0x91)),
+    // Editing keys some keyboards have
+    (Key::KEY_HELP, keyboard(0x75)), (Key::KEY_MENU, keyboard(0x76)), (Key::KEY_STOP, keyboard(0x78)),
+    (Key::KEY_AGAIN, keyboard(0x79)), (Key::KEY_UNDO, keyboard(0x7A)), (Key::KEY_CUT, keyboard(0x7B)),
+    (Key::KEY_COPY, keyboard(0x7C)), (Key::KEY_PASTE, keyboard(0x7D)), (Key::KEY_FIND, keyboard(0x7E)),
+    // Media keys live on the consumer page; some keyboards send volume on the keyboard page
+    (Key::KEY_MUTE, consumer(0xE2)), (Key::KEY_VOLUMEUP, consumer(0xE9)), (Key::KEY_VOLUMEDOWN, consumer(0xEA)),
+    (Key::KEY_MUTE, keyboard(0x7F)), (Key::KEY_VOLUMEUP, keyboard(0x80)), (Key::KEY_VOLUMEDOWN, keyboard(0x81)),
+    (Key::KEY_PLAYPAUSE, consumer(0xCD)), (Key::KEY_STOPCD, consumer(0xB7)), (Key::KEY_NEXTSONG, consumer(0xB5)),
+    (Key::KEY_PREVIOUSSONG, consumer(0xB6)), (Key::KEY_EJECTCD, consumer(0xB8)), (Key::KEY_RECORD, consumer(0xB2)),
+    (Key::KEY_FASTFORWARD, consumer(0xB3)), (Key::KEY_REWIND, consumer(0xB4)),
+    (Key::KEY_BRIGHTNESSUP, consumer(0x6F)), (Key::KEY_BRIGHTNESSDOWN, consumer(0x70)),
+    (Key::KEY_MAIL, consumer(0x18A)), (Key::KEY_CALC, consumer(0x192)), (Key::KEY_FILE, consumer(0x194)),
+    (Key::KEY_SEARCH, consumer(0x221)), (Key::KEY_HOMEPAGE, consumer(0x223)), (Key::KEY_BACK, consumer(0x224)),
+    (Key::KEY_FORWARD, consumer(0x225)), (Key::KEY_REFRESH, consumer(0x227)), (Key::KEY_BOOKMARKS, consumer(0x22A)),
+    // System keys
+    (Key::KEY_POWER, desktop(0x81)), (Key::KEY_SLEEP, desktop(0x82)), (Key::KEY_WAKEUP, desktop(0x83)),
+    (Key::KEY_POWER, keyboard(0x66)),
+];
+
+/// The HID usage `key` is sent to peers as
+pub fn hid_usage(key: Key) -> Option<u32> {
+    HID_KEYS.iter().find(|(k, _)| *k == key).map(|&(_, usage)| usage)
+}
+
+/// The key a peer means by a HID usage, for injecting it here
+pub fn key_for_hid_usage(usage: u32) -> Option<Key> {
+    HID_KEYS.iter().find(|(_, u)| *u == usage).map(|&(key, _)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hid_usages_round_trip() {
+        for &(key, usage) in HID_KEYS {
+            assert!(is_keyboard_key(key), "{:?} isn't forwarded", key);
+            let sent = hid_usage(key).unwrap();
+            assert_eq!(key_for_hid_usage(sent), Some(key));
+            assert_eq!(key_for_hid_usage(usage), Some(key));
+        }
+        assert_eq!(hid_usage(Key::KEY_LEFTMETA), Some(0x07_00E3));
+        assert_eq!(hid_usage(Key::BTN_LEFT), None);
+    }
+
+    fn smoother(enabled: bool) -> ScrollSmoother {
+        ScrollSmoother::new(&InputConfig { enable_smooth_scroll: enabled, ..Default::default() })
+    }
+
+    /// Step the smoother until it's idle, summing what it emitted per axis
+    fn drain(scroll: &mut ScrollSmoother) -> (usize, f32, f32) {
+        let (mut steps, mut vertical, mut horizontal) = (0, 0.0, 0.0);
+        while scroll.time_until_due().is_some() {
+            for event in scroll.step() {
+                let InputEvent::MouseWheel { delta, horizontal: is_horizontal } = event else {
+                    panic!("unexpected event {:?}", event);
+                };
+                assert!(delta.abs() < 1.0, "step of {} isn't smooth", delta);
+                if is_horizontal { horizontal += delta } else { vertical += delta }
+            }
+            steps += 1;
+        }
+        (steps, vertical, horizontal)
+    }
+
+    fn dwell(delay_ms: u32) -> EdgeDwell {
+        EdgeDwell::new(&InputConfig { edge_activation_delay_ms: delay_ms, ..Default::default() })
+    }
+
+    #[test]
+    fn grazing_an_edge_does_not_cross() {
+        let mut dwell = dwell(100);
+        let start = Instant::now();
+
+        assert_eq!(dwell.enter(ScreenEdge::Right, (1920.0, 500.0), start), None);
+        // Back out of the edge zone before the delay is up
+        assert_eq!(dwell.moved(start + Duration::from_millis(30), |_| false), None);
+        assert_eq!(dwell.take_due(start + Duration::from_millis(200)), None);
+        assert_eq!(dwell.time_until_due(), None);
+    }
+
+    #[test]
+    fn pressing_against_an_edge_crosses_after_the_delay() {
+        let mut dwell = dwell(100);
+        let start = Instant::now();
+        let crossing = InputEvent::EdgeCrossed { edge: ScreenEdge::Right, position: (1920.0, 500.0) };
+
+        assert_eq!(dwell.enter(ScreenEdge::Right, (1920.0, 500.0), start), None);
+        assert_eq!(dwell.moved(start + Duration::from_millis(50), |edge| edge == ScreenEdge::Right), None);
+        assert_eq!(
+            dwell.moved(start + Duration::from_millis(120), |edge| edge == ScreenEdge::Right),
+            Some(crossing.clone())
+        );
+
+        // Resting without further motion crosses too
+        dwell.enter(ScreenEdge::Right, (1920.0, 500.0), start);
+        assert_eq!(dwell.take_due(start + Duration::from_millis(100)), Some(crossing));
+    }
+
+    #[test]
+    fn zero_delay_crosses_immediately() {
+        let mut dwell = dwell(0);
+        assert_eq!(
+            dwell.enter(ScreenEdge::Top, (300.0, 0.0), Instant::now()),
+            Some(InputEvent::EdgeCrossed { edge: ScreenEdge::Top, position: (300.0, 0.0) })
+        );
+    }
+
+    const SCREEN: (u32, u32) = (1920, 1080);
+
+    #[test]
+    fn moving_into_each_edge_zone_crosses_it() {
+        assert_eq!(detect_edge((15.0, 500.0), (5.0, 500.0), SCREEN, 10.0), Some((ScreenEdge::Left, (0.0, 500.0))));
+        assert_eq!(
+            detect_edge((1900.0, 500.0), (1915.0, 510.0), SCREEN, 10.0),
+            Some((ScreenEdge::Right, (1920.0, 510.0)))
+        );
+        assert_eq!(detect_edge((800.0, 12.0), (800.0, 0.0), SCREEN, 10.0), Some((ScreenEdge::Top, (800.0, 0.0))));
+        assert_eq!(
+            detect_edge((800.0, 1060.0), (790.0, 1080.0), SCREEN, 10.0),
+            Some((ScreenEdge::Bottom, (790.0, 1080.0)))
+        );
+    }
+
+    #[test]
+    fn moving_inside_the_screen_does_not_cross() {
+        assert_eq!(detect_edge((500.0, 500.0), (600.0, 400.0), SCREEN, 10.0), None);
+        // Right up to the threshold is still inside
+        assert_eq!(detect_edge((20.0, 500.0), (10.0, 500.0), SCREEN, 10.0), None);
+        assert_eq!(detect_edge((1900.0, 500.0), (1910.0, 500.0), SCREEN, 10.0), None);
+    }
+
+    #[test]
+    fn moving_within_an_edge_zone_does_not_cross_again() {
+        assert_eq!(detect_edge((5.0, 500.0), (2.0, 520.0), SCREEN, 10.0), None);
+        assert_eq!(detect_edge((1915.0, 1075.0), (1920.0, 1080.0), SCREEN, 10.0), None);
+    }
+
+    #[test]
+    fn leaving_an_edge_zone_does_not_cross() {
+        assert_eq!(detect_edge((5.0, 500.0), (50.0, 500.0), SCREEN, 10.0), None);
+        assert_eq!(detect_edge((800.0, 1075.0), (800.0, 1000.0), SCREEN, 10.0), None);
+    }
+
+    #[test]
+    fn moving_into_a_corner_crosses_the_side_edge() {
+        assert_eq!(detect_edge((100.0, 100.0), (5.0, 5.0), SCREEN, 10.0), Some((ScreenEdge::Left, (0.0, 5.0))));
+        assert_eq!(
+            detect_edge((1800.0, 1000.0), (1920.0, 1080.0), SCREEN, 10.0),
+            Some((ScreenEdge::Right, (1920.0, 1080.0)))
+        );
+    }
+
+    #[test]
+    fn sliding_along_an_edge_into_a_corner_crosses_the_other_edge() {
+        // Already at the left edge, so only the top edge is newly entered
+        assert_eq!(detect_edge((5.0, 100.0), (5.0, 5.0), SCREEN, 10.0), Some((ScreenEdge::Top, (5.0, 0.0))));
+        assert_eq!(
+            detect_edge((1915.0, 1000.0), (1915.0, 1075.0), SCREEN, 10.0),
+            Some((ScreenEdge::Bottom, (1915.0, 1080.0)))
+        );
+    }
+
+    #[test]
+    fn dead_zone_drops_motion_below_the_threshold() {
+        assert_eq!(shape_axis(1.0, 2.0, 1.0), None);
+        assert_eq!(shape_axis(-1.9, 2.0, 1.0), None);
+    }
+
+    #[test]
+    fn dead_zone_boundary_still_moves() {
+        assert_eq!(shape_axis(2.0, 2.0, 1.0), Some(2.0));
+        assert_eq!(shape_axis(-2.0, 2.0, 1.0), Some(-2.0));
+    }
+
+    #[test]
+    fn zero_dead_zone_passes_everything() {
+        assert_eq!(shape_axis(0.5, 0.0, 1.0), Some(0.5));
+    }
+
+    #[test]
+    fn acceleration_scales_motion() {
+        assert_eq!(accelerate(5.0, 2.0, None), 10.0);
+        assert_eq!(accelerate(-5.0, 2.0, None), -10.0);
+    }
+
+    #[test]
+    fn acceleration_curve_adds_gain_to_faster_motion() {
+        // 5 * 2 * (1 + 5/10)
+        assert_eq!(accelerate(5.0, 2.0, Some(10.0)), 15.0);
+        assert_eq!(accelerate(-5.0, 2.0, Some(10.0)), -15.0);
+        // The curve only applies above 1.0
+        assert_eq!(accelerate(5.0, 1.0, Some(10.0)), 5.0);
+    }
+
+    fn click_filter() -> ClickFilter {
+        ClickFilter::new(&InputConfig {
+            click_motion_window_ms: 200,
+            click_motion_threshold: 4.0,
+            ..InputConfig::default()
+        })
+    }
+
+    #[test]
+    fn tap_jitter_is_dropped() {
+        let mut filter = click_filter();
+        filter.button(MouseButton::Left, true);
+        assert_eq!(filter.motion(Axis::X, 1.0), None);
+        assert_eq!(filter.motion(Axis::Y, -2.0), None);
+        filter.button(MouseButton::Left, false);
+
+        // Motion after the release moves on its own, without the jitter
+        assert_eq!(filter.motion(Axis::X, 3.0), Some(3.0));
+        assert_eq!(filter.motion(Axis::Y, 1.0), Some(1.0));
+    }
+
+    #[test]
+    fn drag_releases_held_back_motion() {
+        let mut filter = click_filter();
+        filter.button(MouseButton::Left, true);
+        assert_eq!(filter.motion(Axis::X, 2.0), None);
+        // Past the threshold: everything held back on this axis comes out at once
+        assert_eq!(filter.motion(Axis::X, 3.0), Some(5.0));
+        assert_eq!(filter.motion(Axis::X, 1.0), Some(1.0));
+        filter.button(MouseButton::Left, false);
+    }
+
+    #[test]
+    fn click_filter_off_passes_motion_through() {
+        let mut filter = ClickFilter::new(&InputConfig::default());
+        filter.button(MouseButton::Left, true);
+        assert_eq!(filter.motion(Axis::X, 1.0), Some(1.0));
+    }
+
+    fn simulated_source(config: Config) -> (SimulatedSource, broadcast::Receiver<InputEvent>) {
+        let (event_tx, events) = broadcast::channel(16);
+        let source = SimulatedSource::new(
+            event_tx,
+            Arc::new(RwLock::new(MouseState {
+                x: 960.0,
+                y: 540.0,
+                buttons: MouseButtons::default(),
+                screen_width: 1920,
+                screen_height: 1080,
+            })),
+            Arc::new(parking_lot::RwLock::new(config)),
+            InputControl::default(),
+        );
+        (source, events)
+    }
+
+    #[tokio::test]
+    async fn crossing_reports_where_the_cursor_left() {
+        let mut config = Config::default();
+        config.input.edge_activation_delay_ms = 0;
+        let rel = |axis: evdev::RelativeAxisType, value| evdev::InputEvent::new(EventType::RELATIVE, axis.0, value);
+        let crossings = |events: &mut broadcast::Receiver<InputEvent>| {
+            std::iter::from_fn(|| events.try_recv().ok())
+                .filter(|event| matches!(event, InputEvent::EdgeCrossed { .. }))
+                .collect::<Vec<_>>()
+        };
+
+        // The cursor starts mid-screen at (960, 540)
+        let (mut source, mut events) = simulated_source(config.clone());
+        source.process(rel(evdev::RelativeAxisType::REL_Y, 100)).await;
+        source.process(rel(evdev::RelativeAxisType::REL_X, 2000)).await;
+        assert_eq!(crossings(&mut events), [InputEvent::EdgeCrossed { edge: ScreenEdge::Right, position: (1920.0, 640.0) }]);
+
+        let (mut source, mut events) = simulated_source(config);
+        source.process(rel(evdev::RelativeAxisType::REL_X, -300)).await;
+        source.process(rel(evdev::RelativeAxisType::REL_Y, 2000)).await;
+        assert_eq!(crossings(&mut events), [InputEvent::EdgeCrossed { edge: ScreenEdge::Bottom, position: (660.0, 1080.0) }]);
+    }
+
+    #[tokio::test]
+    async fn blocked_keys_stay_local() {
+        let mut config = Config::default();
+        config.input.blocked_keys = vec!["super+l".to_string()];
+        let (mut source, mut events) = simulated_source(config);
+
+        let key = |key: Key, value| evdev::InputEvent::new(EventType::KEY, key.code(), value);
+        for event in [
+            key(Key::KEY_LEFTMETA, 1),
+            key(Key::KEY_L, 1),
+            key(Key::KEY_L, 2),
+            key(Key::KEY_L, 0),
+            key(Key::KEY_LEFTMETA, 0),
+            key(Key::KEY_L, 1),
+        ] {
+            source.process(event).await;
+        }
+
+        let meta = Key::KEY_LEFTMETA.code() as u32;
+        assert_eq!(events.try_recv().unwrap(), InputEvent::KeyPress { key_code: meta, pressed: true, scan_code: None });
+        assert_eq!(events.try_recv().unwrap(), InputEvent::KeyPress { key_code: meta, pressed: false, scan_code: None });
+        // L on its own isn't blocked
+        let l = InputEvent::KeyPress { key_code: Key::KEY_L.code() as u32, pressed: true, scan_code: None };
+        assert_eq!(events.try_recv().unwrap(), l);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn key_bindings_follow_config_changes() {
+        let (mut source, mut events) = simulated_source(Config::default());
+        let f1 = |value| evdev::InputEvent::new(EventType::KEY, Key::KEY_F1.code(), value);
+        source.process(f1(1)).await;
+        source.process(f1(0)).await;
+        assert!(events.try_recv().is_ok());
+        assert!(events.try_recv().is_ok());
+
+        source.config.write().input.blocked_keys = vec!["f1".to_string()];
+        source.process(f1(1)).await;
+        source.process(f1(0)).await;
+        assert!(events.try_recv().is_err());
+
+        source.config.write().input.force_local_hotkey = "f1".to_string();
+        source.process(f1(1)).await;
+        assert_eq!(events.try_recv().unwrap(), InputEvent::ForceLocal);
+    }
+
+    #[tokio::test]
+    async fn scancodes_go_with_their_key() {
+        let mut config = Config::default();
+        config.input.key_forwarding = KeyForwarding::Scancode;
+        let (mut source, mut events) = simulated_source(config);
+
+        let scan = evdev::InputEvent::new(EventType::MISC, evdev::MiscType::MSC_SCAN.0, 0x07_0004);
+        let key = |value| evdev::InputEvent::new(EventType::KEY, Key::KEY_A.code(), value);
+        let report = evdev::InputEvent::new(EventType::SYNCHRONIZATION, 0, 0);
+        // The release comes without a scancode, which must not carry over
+        for event in [scan, key(1), report, key(0), report] {
+            source.process(event).await;
+        }
+
+        let a = Key::KEY_A.code() as u32;
+        assert_eq!(events.try_recv().unwrap(), InputEvent::KeyPress { key_code: a, pressed: true, scan_code: Some(0x07_0004) });
+        assert_eq!(events.try_recv().unwrap(), InputEvent::KeyPress { key_code: a, pressed: false, scan_code: None });
+    }
+
+    #[tokio::test]
+    async fn scancodes_stay_home_by_default() {
+        let (mut source, mut events) = simulated_source(Config::default());
+        let scan = evdev::InputEvent::new(EventType::MISC, evdev::MiscType::MSC_SCAN.0, 0x07_0004);
+        source.process(scan).await;
+        source.process(evdev::InputEvent::new(EventType::KEY, Key::KEY_A.code(), 1)).await;
+
+        let a = Key::KEY_A.code() as u32;
+        assert_eq!(events.try_recv().unwrap(), InputEvent::KeyPress { key_code: a, pressed: true, scan_code: None });
+    }
+
+    #[test]
+    fn sensitivity_scales_motion_outside_the_dead_zone() {
+        assert_eq!(shape_axis(4.0, 2.0, 1.5), Some(6.0));
+        assert_eq!(shape_axis(-3.0, 0.0, 0.5), Some(-1.5));
+    }
+
+    #[test]
+    fn discrete_scroll_passes_notches_through() {
+        let mut scroll = smoother(false);
+        assert_eq!(scroll.add(-1.0, false), Some(InputEvent::MouseWheel { delta: -1.0, horizontal: false }));
+        assert_eq!(scroll.add(2.0, true), Some(InputEvent::MouseWheel { delta: 2.0, horizontal: true }));
+        assert_eq!(scroll.time_until_due(), None);
+    }
+
+    #[test]
+    fn smooth_scroll_spreads_a_notch_over_several_steps() {
+        let mut scroll = smoother(true);
+        assert_eq!(scroll.add(1.0, false), None);
+        assert_eq!(scroll.add(-0.5, true), None);
+
+        let (steps, vertical, horizontal) = drain(&mut scroll);
+        assert_eq!(steps, SMOOTH_SCROLL_STEPS as usize);
+        assert!((vertical - 1.0).abs() < 1e-5);
+        assert!((horizontal + 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn smooth_scroll_reversal_cancels_the_remainder() {
+        let mut scroll = smoother(true);
+        scroll.add(1.0, false);
+        scroll.step();
+        scroll.add(-1.0, false);
+
+        // Only the first step got out, and the rest takes it back
+        let (_, vertical, _) = drain(&mut scroll);
+        assert!((vertical + 1.0 / SMOOTH_SCROLL_STEPS).abs() < 1e-5);
+    }
+}
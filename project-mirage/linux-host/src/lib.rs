@@ -0,0 +1,30 @@
+// Project Mirage Linux host. `main.rs` is the daemon and its CLI; the modules
+// live here so benchmarks can drive them as well.
+
+pub mod config;
+pub mod ctl;
+pub mod discovery;
+pub mod doctor;
+pub mod idle;
+pub mod input;
+pub mod logging;
+pub mod metrics;
+pub mod motion;
+pub mod session;
+#[cfg(feature = "streaming")]
+pub mod bitrate;
+#[cfg(feature = "streaming")]
+pub mod capture;
+pub mod clipboard;
+pub mod network;
+pub mod platform;
+pub mod proto;
+pub mod record;
+pub mod script;
+pub mod security;
+#[cfg(feature = "streaming")]
+pub mod stream;
+pub mod supervisor;
+pub mod transport;
+#[cfg(feature = "web-ui")]
+pub mod web;
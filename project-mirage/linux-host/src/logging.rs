@@ -0,0 +1,157 @@
+// Log output: the console, filtered by `--verbose` or RUST_LOG as always, and
+// optionally a rotating file set up from `[logging]` with its own level. Both
+// write plain lines or, for log shippers, JSON carrying the span fields.
+// tracing-appender rotates by time; rotating by size is done here since it
+// has no such policy.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry};
+
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Install the console and file loggers. Relative `logging.file` paths are
+/// taken from `config_dir`. The console logs to stderr when `to_stderr` is set,
+/// leaving stdout to output meant for other programs. The returned guard
+/// flushes the file when dropped, so it has to live until the process exits.
+pub fn init(verbose: bool, to_stderr: bool, logging: &LoggingConfig, config_dir: &Path) -> Result<Option<WorkerGuard>> {
+    let log_level = if verbose { "debug" } else { "info" };
+    let console_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| format!("mirage_host={},mirage={}", log_level, log_level).into());
+
+    let console = if to_stderr {
+        format_layer(logging.format, io::stderr, true)
+    } else {
+        format_layer(logging.format, io::stdout, true)
+    };
+    let mut layers = vec![console.with_filter(console_filter).boxed()];
+    let guard = match logging.file {
+        Some(ref file) => {
+            let path = config_dir.join(shellexpand::tilde(file).as_ref());
+            let (writer, guard) = tracing_appender::non_blocking(open(&path, logging)?);
+            let filter = file_filter(&logging.level)?;
+            layers.push(format_layer(logging.format, writer, false).with_filter(filter).boxed());
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(layers).init();
+    Ok(guard)
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Plain => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// A bare level applies to our own crates, like `--verbose` does; anything
+/// else is taken as full `EnvFilter` directives
+fn file_filter(level: &str) -> Result<EnvFilter> {
+    let directives = match level.parse::<LevelFilter>() {
+        Ok(_) => format!("mirage_host={},mirage={}", level, level),
+        Err(_) => level.to_string(),
+    };
+    EnvFilter::try_new(&directives).with_context(|| format!("Invalid logging.level {:?}", level))
+}
+
+fn open(path: &Path, logging: &LoggingConfig) -> Result<Box<dyn Write + Send>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+
+    let rotation = match logging.rotation {
+        LogRotation::Size => {
+            let max_bytes = logging.max_size_mb.saturating_mul(1024 * 1024);
+            return Ok(Box::new(SizeRotatingFile::open(path.to_path_buf(), max_bytes, logging.max_files)?));
+        }
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let file_name = path.file_name()
+        .with_context(|| format!("logging.file {} has no file name", path.display()))?;
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name.to_string_lossy())
+        .max_log_files(logging.max_files)
+        .build(dir)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+    Ok(Box::new(appender))
+}
+
+/// Writes to `path` until it would grow past `max_bytes`, then shifts it to
+/// `path.1`, `path.1` to `path.2` and so on, keeping `max_files` in all
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let file = append(&path).with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let written = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self { path, max_bytes, max_files, file, written })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for index in (1..self.max_files).rev() {
+            let from = if index == 1 { self.path.clone() } else { self.rotated(index - 1) };
+            if from.exists() {
+                fs::rename(&from, self.rotated(index))?;
+            }
+        }
+        if self.max_files <= 1 {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
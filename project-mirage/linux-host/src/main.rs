@@ -1,62 +1,236 @@
-use anyhow::Result;
-use clap::Parser;
-use tracing::{info, error};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-mod config;
-mod discovery;
-mod input;
-mod session;
-mod capture;
-mod network;
-mod security;
-
-use config::Config;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use tokio::sync::broadcast;
+use tracing::{info, warn, error};
+
+use mirage_host::{
+    clipboard, config, ctl, discovery, doctor, idle, input, logging, metrics, network, platform, record, script,
+    security, session, supervisor, transport,
+};
+
+use config::{Config, FileAccess, LogFormat};
 use discovery::DiscoveryService;
-use input::InputManager;
-use session::SessionManager;
+use input::{InputEvent, InputManager};
+use network::ControlServer;
+use record::Recorder;
+use script::ScriptTool;
+use security::{Identity, PairingManager};
+use session::{SessionLogger, SessionManager};
+use supervisor::{Shutdown, Supervisor};
+#[cfg(feature = "streaming")]
+use mirage_host::stream::StreamServer;
+
+// Created with defaults on first run; an explicit --config path must already exist
+// (unless --no-write-config, which runs on the defaults instead). A named
+// --instance keeps its config, and the state files next to it, in a directory
+// of its own instead.
+const DEFAULT_CONFIG_PATH: &str = "~/.config/mirage/config.toml";
+const INSTANCE_CONFIG_DIR: &str = "~/.config/mirage/instances";
+
+// Instance names end up in file names and the mDNS label
+const MAX_INSTANCE_NAME: usize = 32;
+
+// How long `--discover --once` browses before printing what it found
+const DISCOVER_ONCE_WINDOW: std::time::Duration = std::time::Duration::from_secs(3);
+
+// Time given to in-flight input and peer goodbyes when the daemon stops
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
 
 #[derive(Parser, Debug)]
 #[command(name = "mirage-host")]
 #[command(about = "Project Mirage - Linux Host Daemon", long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Enable discovery mode to find peer devices
     #[arg(short, long)]
     discover: bool,
 
-    /// Configuration file path
-    #[arg(short, long, default_value = "~/.config/mirage/config.toml")]
-    config: String,
+    /// With --discover, browse for a few seconds, print the peers and exit
+    #[arg(long, requires = "discover")]
+    once: bool,
+
+    /// Print peers as JSON (with --discover --once or `peers`); logs go to stderr
+    #[arg(long)]
+    json: bool,
+
+    /// Configuration file path [default: ~/.config/mirage/config.toml, or
+    /// ~/.config/mirage/instances/<NAME>/config.toml with --instance]
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Run as a separately named daemon next to others on this machine: it
+    /// gets its own default config directory, control socket and mDNS name
+    #[arg(long, value_name = "NAME", value_parser = parse_instance)]
+    instance: Option<String>,
+
+    /// Apply the `[profiles.<NAME>]` overrides from the config file
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Never write the config file: run on the defaults if it's missing and
+    /// upgrade older files in memory only
+    #[arg(long)]
+    no_write_config: bool,
 
     /// Verbose logging
     #[arg(short, long)]
     verbose: bool,
 
+    /// Log line format, overriding `logging.format` [default: plain]
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+
     /// Node name (defaults to hostname)
     #[arg(short, long)]
     name: Option<String>,
+
+    /// Record local input until Ctrl+C and export it as a replayable shell script
+    #[arg(long, value_name = "FILE")]
+    export_script: Option<String>,
+
+    /// Tool the exported script uses to replay input
+    #[arg(long, value_enum, default_value = "ydotool")]
+    script_tool: ScriptTool,
+
+    /// List paired devices and exit
+    #[arg(long)]
+    list_paired: bool,
+
+    /// Forget a paired device by node ID and exit
+    #[arg(long, value_name = "NODE_ID")]
+    revoke: Option<String>,
+
+    /// Add a peer by address for networks that block mDNS (repeatable)
+    #[arg(long, value_name = "IP:PORT")]
+    connect: Vec<std::net::SocketAddr>,
+
+    /// Generate scripted input that crosses the screen edges instead of reading devices
+    #[arg(long)]
+    simulate: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Browse for peers for a few seconds, print them and exit
+    Peers {
+        /// Seconds to wait for peers to answer
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Measure round-trip time to a peer's control channel
+    Ping {
+        /// Node ID or name of a discovered peer, or IP[:PORT]
+        target: String,
+
+        /// Number of probes to send
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: u32,
+
+        /// Milliseconds between probes
+        #[arg(long, default_value_t = 200)]
+        interval_ms: u64,
+    },
+    /// List input devices and which ones can be captured
+    Devices,
+    /// Check the setup: input devices, uinput, the config, the control port and mDNS
+    Doctor,
+    /// Query or control the running daemon
+    Ctl {
+        #[command(subcommand)]
+        request: ctl::Request,
+    },
+}
+
+/// Accept `--instance` names that are safe in a file name and an mDNS label
+fn parse_instance(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.len() > MAX_INSTANCE_NAME {
+        return Err(format!("must be 1 to {} characters", MAX_INSTANCE_NAME));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(name.to_string())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Initialize logging
-    let log_level = if args.verbose { "debug" } else { "info" };
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("mirage_host={},mirage={}", log_level, log_level).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    // Talking to a running daemon needs neither logging nor the config
+    let socket_path = ctl::socket_path(args.instance.as_deref());
+    if let Some(Command::Ctl { request }) = args.command {
+        return ctl::run_client(&socket_path, request).await;
+    }
+
+    let default_config = match args.instance {
+        Some(ref instance) => format!("{}/{}/config.toml", INSTANCE_CONFIG_DIR, instance),
+        None => DEFAULT_CONFIG_PATH.to_string(),
+    };
+    let config_file = args.config.as_deref().unwrap_or(&default_config);
+    let config_path = std::path::PathBuf::from(shellexpand::tilde(config_file).as_ref());
+    let config_access = if args.no_write_config {
+        FileAccess::ReadOnly
+    } else if args.config.is_none() {
+        FileAccess::CreateMissing
+    } else {
+        FileAccess::Existing
+    };
+
+    // The doctor reports a broken config itself instead of stopping at it
+    if let Some(Command::Doctor) = args.command {
+        return doctor::run(config_file, config_access, args.profile.as_deref()).await;
+    }
+
+    // Logging is set up before the config is watched, so its own section is
+    // read up front; a broken config file is reported once logging is up
+    let mut logging = Config::load(config_file, FileAccess::ReadOnly, args.profile.as_deref()).await
+        .map(|config| config.logging)
+        .unwrap_or_default();
+    if let Some(format) = args.log_format {
+        logging.format = format;
+    }
+    let config_dir = config_path.parent().unwrap_or(std::path::Path::new("."));
+    let _log_guard = logging::init(args.verbose, args.json, &logging, config_dir)?;
 
     info!("🌟 Project Mirage - Linux Host Daemon v{}", env!("CARGO_PKG_VERSION"));
     info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
 
     // Load configuration
-    let config = Config::load(&args.config).await?;
-    info!("✓ Configuration loaded from {}", args.config);
+    let (config_updates, config_handle) =
+        Config::watch(config_file, config_access, args.profile.as_deref()).await?;
+    let config = config_updates.borrow().clone();
+    match args.profile {
+        Some(ref profile) => info!("✓ Configuration loaded from {} with profile {}", config_file, profile),
+        None => info!("✓ Configuration loaded from {}", config_file),
+    }
+
+    // Paired devices and the peer cache live next to the config file
+    let pairing = PairingManager::load(config_path.with_file_name("paired_peers.toml"))?;
+
+    if args.list_paired {
+        let paired = pairing.list();
+        if paired.is_empty() {
+            info!("No paired devices");
+        }
+        for peer in paired {
+            info!("  {} ({}) paired {}", peer.name, peer.node_id, peer.paired_at);
+        }
+        return Ok(());
+    }
+
+    if let Some(ref node_id) = args.revoke {
+        if pairing.revoke(node_id)? {
+            info!("✓ Revoked pairing with {}", node_id);
+        } else {
+            warn!("⚠ {} is not paired", node_id);
+        }
+        return Ok(());
+    }
+
+    // Our node ID and key stay the same across restarts, so pairings hold
+    let identity = Identity::load_or_create(&config_path.with_file_name("identity.toml"))?;
 
     // Determine node name
     let node_name = args.name
@@ -69,10 +243,37 @@ async fn main() -> Result<()> {
         });
     
     info!("✓ Node name: {}", node_name);
+    if let Some(ref instance) = args.instance {
+        info!("✓ Instance: {}", instance);
+    }
+    platform::init(config.host.display_server);
+
+    if args.once {
+        return list_peers(config, identity, node_name, DISCOVER_ONCE_WINDOW, args.json).await;
+    }
+    match args.command {
+        Some(Command::Peers { timeout }) => {
+            return list_peers(config, identity, node_name, std::time::Duration::from_secs(timeout), args.json).await;
+        }
+        Some(Command::Ping { ref target, count, interval_ms }) => {
+            let interval = std::time::Duration::from_millis(interval_ms);
+            return ping_peer(config, identity, node_name, &config_path, target, count, interval).await;
+        }
+        Some(Command::Devices) => return list_devices(&config),
+        Some(Command::Ctl { .. }) | Some(Command::Doctor) | None => {}
+    }
+
+    // Everything from here on listens, except exporting a script
+    if let Some(instance) = args.instance.as_ref().filter(|_| args.export_script.is_none()) {
+        check_instance_port(instance, &config, &config_path)?;
+    }
 
     // Initialize input manager (Phase 0.1 - Mouse sharing)
     info!("Initializing input manager...");
-    let input_manager = InputManager::new(config.clone())?;
+    let mut input_manager = InputManager::new(config.clone())?;
+    if args.simulate {
+        input_manager = input_manager.with_simulation();
+    }
     info!("✓ Input manager ready");
 
     // Initialize session manager
@@ -80,10 +281,17 @@ async fn main() -> Result<()> {
     let session_manager = SessionManager::new(config.clone(), node_name.clone()).await?;
     info!("✓ Session manager ready");
 
-    if args.discover {
+    if let Some(ref path) = args.export_script {
+        run_export_script(input_manager, path, args.script_tool).await?;
+    } else if args.discover {
         // Start discovery service
         info!("Starting mDNS discovery service...");
-        let mut discovery = DiscoveryService::new(config.clone(), node_name.clone()).await?;
+        let mut discovery = DiscoveryService::new(config.clone(), identity.clone(), node_name.clone())
+            .await?
+            .with_peer_cache(config_path.with_file_name("peers.json"));
+        if let Some(ref instance) = args.instance {
+            discovery = discovery.with_instance(instance.clone());
+        }
         
         info!("✓ Discovery service started");
         info!("🔍 Scanning for peer devices on local network...");
@@ -92,6 +300,7 @@ async fn main() -> Result<()> {
 
         // Start discovery
         discovery.start().await?;
+        add_manual_peers(&discovery, &args.connect).await;
 
         // Wait for Ctrl+C
         tokio::signal::ctrl_c().await?;
@@ -101,20 +310,306 @@ async fn main() -> Result<()> {
     } else {
         // Normal daemon mode
         info!("Starting Mirage Host Daemon in normal mode...");
+
+        // Discovery tells the session manager where the peers in the screen layout are
+        let mut discovery = DiscoveryService::new(config.clone(), identity.clone(), node_name.clone())
+            .await?
+            .with_peer_cache(config_path.with_file_name("peers.json"));
+        if let Some(ref instance) = args.instance {
+            discovery = discovery.with_instance(instance.clone());
+        }
+        discovery.start().await?;
+        add_manual_peers(&discovery, &args.connect).await;
+
+        let audit_handle = config.security.audit_log.then(|| {
+            let path = match config.security.audit_log_path {
+                Some(ref path) => std::path::PathBuf::from(shellexpand::tilde(path).as_ref()),
+                None => config_path.with_file_name("sessions.jsonl"),
+            };
+            let logger = SessionLogger::new(path, &session_manager);
+            tokio::spawn(async move {
+                if let Err(e) = logger.run().await {
+                    warn!("⚠ Session audit log stopped: {:#}", e);
+                }
+            })
+        });
+
+        #[cfg(feature = "web-ui")]
+        let web_handle = (config.network.web_ui_port != 0).then(|| {
+            let dashboard = mirage_host::web::Dashboard::new(session_manager.clone(), discovery.peer_table());
+            let port = config.network.web_ui_port;
+            tokio::spawn(async move {
+                if let Err(e) = dashboard.serve(port).await {
+                    warn!("⚠ Dashboard stopped: {:#}", e);
+                }
+            })
+        });
+        #[cfg(not(feature = "web-ui"))]
+        if config.network.web_ui_port != 0 {
+            warn!("⚠ network.web_ui_port is set, but this build has no dashboard (enable the web-ui feature)");
+        }
+
+        let ctl_socket =
+            ctl::ControlSocket::new(socket_path.clone(), session_manager.clone(), discovery.peer_table(), config_handle);
+        let ctl_handle = tokio::spawn(async move {
+            if let Err(e) = ctl_socket.run().await {
+                warn!("⚠ Control socket stopped: {:#}", e);
+            }
+        });
+
         info!("✓ Daemon ready");
         info!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
         
         // Run the main event loop
-        run_daemon(input_manager, session_manager).await?;
+        let result = run_daemon(
+            config,
+            config_updates,
+            input_manager,
+            session_manager,
+            pairing,
+            &discovery,
+            args.simulate,
+        ).await;
+        if let Some(handle) = audit_handle {
+            handle.abort();
+        }
+        ctl_handle.abort();
+        #[cfg(feature = "web-ui")]
+        if let Some(handle) = web_handle {
+            handle.abort();
+        }
+        let _ = std::fs::remove_file(&socket_path);
+        discovery.stop().await?;
+        result?;
     }
 
     info!("✓ Mirage Host Daemon stopped");
     Ok(())
 }
 
+async fn run_export_script(
+    input_manager: InputManager,
+    path: &str,
+    tool: ScriptTool,
+) -> Result<()> {
+    let mut events = input_manager.subscribe();
+    let input_handle = tokio::spawn(async move {
+        if let Err(e) = input_manager.run().await {
+            error!("Input manager error: {}", e);
+        }
+    });
+
+    info!("⏺ Recording input for a {:?} script. Press Ctrl+C to stop.", tool);
+    let recorded = Recorder::new()
+        .record(&mut events, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+    input_handle.abort();
+
+    let path = shellexpand::tilde(path).into_owned();
+    tokio::fs::write(&path, script::render(&recorded, tool))
+        .await
+        .with_context(|| format!("Failed to write script to {}", path))?;
+
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).await?;
+
+    info!("✓ Exported {} recorded events to {}", recorded.len(), path);
+    Ok(())
+}
+
+/// Add the `--connect` peers, which mDNS can't find, to the peer table
+/// Every default config listens on the same control port, so a named instance
+/// left at it would clash with the default daemon or another instance
+fn check_instance_port(instance: &str, config: &Config, config_path: &std::path::Path) -> Result<()> {
+    let default_port = Config::default().network.control_port;
+    if config.network.control_port == default_port {
+        anyhow::bail!(
+            "Instance {} would listen on the default control port {}; set network.control_port in {} to a port no other daemon uses",
+            instance, default_port, config_path.display()
+        );
+    }
+    Ok(())
+}
+
+async fn add_manual_peers(discovery: &DiscoveryService, addrs: &[std::net::SocketAddr]) {
+    for addr in addrs {
+        if let Err(e) = discovery.add_manual_peer(addr.ip(), addr.port()).await {
+            warn!("⚠ Could not add peer {}: {:#}", addr, e);
+        }
+    }
+}
+
+/// Print the peers that answer within `window` as a table, or a JSON array
+async fn list_peers(
+    config: Config,
+    identity: Identity,
+    node_name: String,
+    window: std::time::Duration,
+    json: bool,
+) -> Result<()> {
+    let mut discovery = DiscoveryService::new(config, identity, node_name).await?;
+    discovery.start().await?;
+    info!("🔍 Browsing for peers for {}s...", window.as_secs());
+    tokio::time::sleep(window).await;
+
+    let mut peers: Vec<_> = discovery.get_peers().await.into_iter().filter(|p| p.online).collect();
+    discovery.stop().await?;
+    peers.sort_by(|a, b| a.node_name.cmp(&b.node_name));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&peers)?);
+        return Ok(());
+    }
+    if peers.is_empty() {
+        println!("No peers found");
+        return Ok(());
+    }
+
+    println!("{:<24} {:<10} {:<40} {:>5}  CAPABILITIES", "NAME", "OS", "ADDRESS", "PORT");
+    for peer in peers {
+        let address = peer.addresses.first().map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string());
+        let caps = &peer.capabilities;
+        let mut capabilities: Vec<String> = [
+            (caps.can_host_mouse, "mouse"),
+            (caps.can_capture_windows, "capture"),
+            (caps.can_render_streams, "render"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| name.to_string())
+        .collect();
+        if !caps.video_codecs.is_empty() {
+            capabilities.push(caps.video_codecs.join("/"));
+        }
+
+        println!(
+            "{:<24} {:<10} {:<40} {:>5}  {}",
+            peer.node_name, peer.os_type, address, peer.control_port, capabilities.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Print every input device, marking the mice that would be captured
+fn list_devices(config: &Config) -> Result<()> {
+    let devices = InputManager::list_devices();
+    if devices.is_empty() {
+        println!("No input devices found (is this user in the `input` group?)");
+        return Ok(());
+    }
+
+    let preferred = config.input.preferred_device.as_deref()
+        .filter(|name| !name.is_empty())
+        .map(str::to_lowercase);
+    let yes_no = |value: bool| if value { "yes" } else { "-" };
+    println!("{:<24} {:<40} {:<6} {:<8}  PREFERRED", "PATH", "NAME", "MOUSE", "KEYBOARD");
+    for device in devices {
+        let is_preferred = device.is_mouse
+            && preferred.as_ref().is_some_and(|p| device.name.to_lowercase().contains(p));
+        println!(
+            "{:<24} {:<40} {:<6} {:<8}  {}",
+            device.path.display(), device.name, yes_no(device.is_mouse),
+            yes_no(device.is_keyboard), yes_no(is_preferred)
+        );
+    }
+    Ok(())
+}
+
+/// Probe a peer's control channel and report round-trip statistics
+async fn ping_peer(
+    config: Config,
+    identity: Identity,
+    node_name: String,
+    config_path: &std::path::Path,
+    target: &str,
+    count: u32,
+    interval: std::time::Duration,
+) -> Result<()> {
+    let manual = target.parse::<std::net::SocketAddr>().ok()
+        .or_else(|| target.parse().ok().map(|ip| std::net::SocketAddr::new(ip, config.network.control_port)));
+
+    let peer = match manual {
+        Some(addr) => discovery::PeerDevice {
+            node_id: String::new(),
+            node_name: addr.to_string(),
+            os_type: String::new(),
+            addresses: vec![addr.ip()],
+            control_port: addr.port(),
+            capabilities: discovery::PeerCapabilities::local(),
+            extra: Default::default(),
+            last_seen: std::time::Instant::now(),
+            online: true,
+            manual: true,
+        },
+        None => {
+            // Give mDNS a moment to resolve the peer; cached addresses are tried too
+            let mut discovery = DiscoveryService::new(config.clone(), identity.clone(), node_name.clone())
+                .await?
+                .with_peer_cache(config_path.with_file_name("peers.json"));
+            discovery.start().await?;
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(3);
+            let peer = loop {
+                let found = discovery.get_peers().await.into_iter()
+                    .filter(|p| p.node_id == target || p.node_name == target)
+                    .max_by_key(|p| p.online);
+                match found {
+                    Some(peer) if peer.online => break Some(peer),
+                    found if tokio::time::Instant::now() >= deadline => break found,
+                    _ => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+                }
+            };
+            discovery.stop().await?;
+            peer.with_context(|| format!("No peer named {} was found", target))?
+        }
+    };
+
+    let connector = transport::connector(&config)?;
+    let mut client = network::ControlClient::connect(&peer, &identity, &node_name, &connector).await?;
+
+    let mut samples = Vec::new();
+    for seq in 0..count {
+        if seq > 0 {
+            tokio::time::sleep(interval).await;
+        }
+        match client.probe().await {
+            Ok(rtt) => {
+                println!("seq={} time={:.2} ms", seq, rtt.as_secs_f64() * 1000.0);
+                samples.push(rtt.as_secs_f64() * 1000.0);
+            }
+            Err(e) => println!("seq={} {:#}", seq, e),
+        }
+    }
+    client.close().await?;
+
+    println!("--- {} ping statistics ---", peer.node_name);
+    println!("{} probes sent, {} replies", count, samples.len());
+    if samples.is_empty() {
+        anyhow::bail!("{} did not answer any probes", peer.node_name);
+    }
+
+    let min = samples.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = samples.iter().cloned().fold(0.0, f64::max);
+    let avg = samples.iter().sum::<f64>() / samples.len() as f64;
+    // Mean difference between consecutive round trips
+    let jitter = if samples.len() > 1 {
+        samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (samples.len() - 1) as f64
+    } else {
+        0.0
+    };
+    println!("rtt min/avg/max/jitter = {:.2}/{:.2}/{:.2}/{:.2} ms", min, avg, max, jitter);
+    Ok(())
+}
+
 async fn run_daemon(
+    config: Config,
+    config_updates: tokio::sync::watch::Receiver<Config>,
     input_manager: InputManager,
     session_manager: SessionManager,
+    pairing: PairingManager,
+    discovery: &DiscoveryService,
+    simulate: bool,
 ) -> Result<()> {
     // Main daemon event loop
     // This will handle:
@@ -123,33 +618,225 @@ async fn run_daemon(
     // - Window capture and streaming (Phase 0.2+)
     
     info!("Daemon running. Press Ctrl+C to exit.");
-    
-    // Spawn input monitoring task
-    let input_handle = tokio::spawn(async move {
-        if let Err(e) = input_manager.run().await {
-            error!("Input manager error: {}", e);
+
+    // Subsystems run under a supervisor that re-creates them when they fail.
+    // The managers built during startup are used for the first run.
+    let shutdown = Shutdown::new();
+    let supervisor = Supervisor::new().with_shutdown(shutdown.clone());
+
+    // Grab state outlives restarts so ownership and device grabs stay in sync
+    let input_control = input_manager.control();
+    // ...and so do input subscribers
+    let input_events = input_manager.event_sender();
+
+    let input_updates = config_updates.clone();
+    let restart_control = input_control.clone();
+    let restart_events = input_events.clone();
+    let mut initial_input = Some(input_manager);
+    let mut input_handle = supervisor.watch("Input manager", None, move |_heartbeat| {
+        let initial = initial_input.take();
+        let updates = input_updates.clone();
+        let control = restart_control.clone();
+        let events = restart_events.clone();
+        async move {
+            let input_manager = match initial {
+                Some(manager) => manager,
+                // Restarts pick up the latest reloaded config
+                None => {
+                    let manager = InputManager::new(updates.borrow().clone())?
+                        .with_control(control)
+                        .with_event_sender(events);
+                    if simulate { manager.with_simulation() } else { manager }
+                }
+            };
+            input_manager.with_config_updates(updates).run().await
+        }
+    });
+
+    // The session table is shared with the control server, so a restarted
+    // session loop picks up where the failed one left off
+    let session_manager = match input::VirtualInput::new() {
+        Ok(virtual_input) => session_manager.with_virtual_input(virtual_input),
+        Err(e) => {
+            warn!("⚠ No virtual input device, the pointer won't follow returning cursors: {:#}", e);
+            session_manager
+        }
+    };
+    let clipboard = clipboard::ClipboardSync::new(
+        discovery.node_id().to_string(),
+        config.input.sync_primary_selection,
+    );
+    let session_manager = match clipboard {
+        Ok(clipboard) => session_manager.with_clipboard(clipboard),
+        Err(e) => {
+            warn!("⚠ Clipboard sync disabled: {:#}", e);
+            session_manager
+        }
+    };
+    let session_manager = session_manager
+        .with_input_control(input_control)
+        .with_config_updates(config_updates)
+        .with_outgoing(
+            discovery.identity().clone(),
+            discovery.peer_table(),
+            transport::connector(&config)?,
+        );
+
+    let restart_sessions = session_manager.clone();
+    let mut session_handle = supervisor.watch(
+        "Session manager",
+        Some(std::time::Duration::from_secs(10)),
+        move |heartbeat| {
+            let session_manager = restart_sessions.clone();
+            async move {
+                session_manager.with_heartbeat(heartbeat).run().await
+            }
+        },
+    );
+
+    // Certificate problems are configuration errors, so fail before supervising
+    let tls = security::server_config(&config.security)?;
+
+    let control_config = config.clone();
+    let control_sessions = session_manager.clone();
+    let control_shutdown = shutdown.clone();
+    let mut control_handle = supervisor.watch("Control server", None, move |_heartbeat| {
+        let server = ControlServer::new(
+            control_config.clone(),
+            control_sessions.clone(),
+            tls.clone(),
+            pairing.clone(),
+        )
+        .with_shutdown(control_shutdown.clone());
+        async move { server.run().await }
+    });
+
+    // Peers start and stop streams of this screen over their sessions
+    #[cfg(feature = "streaming")]
+    let stream_handle = {
+        let stream_sessions = session_manager.clone();
+        supervisor.watch("Stream server", None, move |_heartbeat| {
+            StreamServer::new(stream_sessions.clone()).run()
+        })
+    };
+
+    let metrics_port = config.network.metrics_port;
+    let metrics_handle = tokio::spawn(async move {
+        if metrics_port != 0 {
+            if let Err(e) = metrics::serve(metrics_port).await {
+                warn!("⚠ Metrics endpoint stopped: {:#}", e);
+            }
+        }
+    });
+
+    let lock_sessions = session_manager.clone();
+    let lock_handle = tokio::spawn(async move {
+        if let Err(e) = idle::LockWatcher::new().run(lock_sessions).await {
+            warn!("⚠ Screen lock watcher stopped: {:#}", e);
         }
     });
 
-    // Spawn session management task
-    let session_handle = tokio::spawn(async move {
-        if let Err(e) = session_manager.run().await {
-            error!("Session manager error: {}", e);
+    // The force-local hotkey has a task of its own, so a send to a peer that
+    // stopped answering can't hold it up
+    let force_sessions = session_manager.clone();
+    let mut force_events = input_events.subscribe();
+    let force_handle = tokio::spawn(async move {
+        loop {
+            match force_events.recv().await {
+                Ok(InputEvent::ForceLocal) => force_sessions.force_local().await,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Edge crossings pick the peer to hand the mouse to; everything else goes
+    // to whichever peer currently owns it
+    let forward_sessions = session_manager.clone();
+    let forward_shutdown = shutdown.clone();
+    let mut events = input_events.subscribe();
+    let forward_handle = tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = forward_shutdown.wait() => break,
+            };
+            match event {
+                Ok(InputEvent::EdgeCrossed { edge, position }) => {
+                    if let Err(e) = forward_sessions.handle_edge_crossed(edge, position).await {
+                        warn!("⚠ Could not move the mouse past the {:?} edge: {:#}", edge, e);
+                    }
+                }
+                Ok(InputEvent::ForceLocal) => {}
+                Ok(event) => {
+                    if let Err(e) = forward_sessions.forward(&event).await {
+                        warn!("Forwarding input failed: {:#}", e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    metrics::metrics().events_dropped(missed);
+                    warn!(
+                        "Input forwarding fell behind, {} events were dropped; raising input.event_channel_capacity may help",
+                        missed
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+
+        // Deliver input that was already captured, e.g. key releases, so
+        // nothing is left held down on the peer
+        while let Ok(event) = events.try_recv() {
+            if matches!(event, InputEvent::EdgeCrossed { .. } | InputEvent::ForceLocal) {
+                continue;
+            }
+            if forward_sessions.forward(&event).await.is_err() {
+                break;
+            }
         }
     });
 
-    // Wait for Ctrl+C or task completion
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("Failed to install the SIGTERM handler")?;
+
+    // Wait for Ctrl+C / SIGTERM or for the supervisor to give up on a subsystem
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             info!("Received shutdown signal");
         }
-        _ = input_handle => {
-            error!("Input manager task terminated");
+        _ = terminate.recv() => {
+            info!("Received shutdown signal");
+        }
+        _ = &mut input_handle => {
+            error!("Input manager could not be kept running");
+        }
+        _ = &mut session_handle => {
+            error!("Session manager could not be kept running");
         }
-        _ = session_handle => {
-            error!("Session manager task terminated");
+        _ = &mut control_handle => {
+            error!("Control server could not be kept running");
         }
     }
 
+    // Stop capturing, flush what was captured, then say goodbye to peers
+    shutdown.trigger();
+    input_handle.abort();
+    if tokio::time::timeout(SHUTDOWN_GRACE, forward_handle).await.is_err() {
+        warn!("⚠ Gave up forwarding the remaining input");
+    }
+    session_manager.close_all(SHUTDOWN_GRACE).await;
+
+    session_handle.abort();
+    control_handle.abort();
+    metrics_handle.abort();
+    lock_handle.abort();
+    force_handle.abort();
+    #[cfg(feature = "streaming")]
+    stream_handle.abort();
+
+    for (name, count) in supervisor.restart_counts() {
+        info!("{} was restarted {} time(s)", name, count);
+    }
+
     Ok(())
 }
@@ -0,0 +1,304 @@
+// Process-wide counters for input, network and capture throughput, served in
+// Prometheus text format on `network.metrics_port`
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, debug};
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+// Frames and encoded bytes are counted over this window to derive the
+// current fps and bitrate
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// The shared metrics registry
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub events_processed: u64,
+    pub events_dropped: u64,
+    pub active_sessions: u64,
+    /// Control channel bytes per session
+    pub bytes_sent: HashMap<String, u64>,
+    pub capture_fps: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    events_processed: AtomicU64,
+    events_dropped: AtomicU64,
+    active_sessions: AtomicU64,
+    bytes_sent: Mutex<HashMap<String, u64>>,
+    subsystem_restarts: Mutex<HashMap<&'static str, u64>>,
+    frames: Mutex<FrameRate>,
+    frames_dropped: AtomicU64,
+    frames_unchanged: AtomicU64,
+    damage: Mutex<DamageRate>,
+    encoder_backend: Mutex<Option<&'static str>>,
+    target_bitrate_kbps: AtomicU64,
+    encoded: Mutex<EncodedRate>,
+}
+
+#[derive(Default)]
+struct FrameRate {
+    window_start: Option<Instant>,
+    frames_in_window: u64,
+    fps: f64,
+}
+
+#[derive(Default)]
+struct EncodedRate {
+    window_start: Option<Instant>,
+    bytes_in_window: u64,
+    packets_in_window: u64,
+    kbps: f64,
+    /// Mean size of an encoded packet
+    packet_bytes: f64,
+}
+
+/// How much of the screen changes, with `streaming.damage_tracking`
+#[derive(Default)]
+struct DamageRate {
+    window_start: Option<Instant>,
+    frames_in_window: u64,
+    share_in_window: f64,
+    unchanged_in_window: u64,
+    /// Mean share of the screen changed per frame
+    share: f64,
+    unchanged_per_sec: f64,
+}
+
+impl Metrics {
+    pub fn event_processed(&self) {
+        self.events_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn events_dropped(&self, count: u64) {
+        self.events_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.events_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn set_active_sessions(&self, count: usize) {
+        self.active_sessions.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self, session_id: &str, bytes: usize) {
+        *self.bytes_sent.lock().entry(session_id.to_string()).or_default() += bytes as u64;
+    }
+
+    /// Stop reporting a closed session
+    pub fn session_closed(&self, session_id: &str) {
+        self.bytes_sent.lock().remove(session_id);
+    }
+
+    /// The supervisor restarted `subsystem` after it failed, panicked or hung
+    pub fn subsystem_restarted(&self, subsystem: &'static str) {
+        *self.subsystem_restarts.lock().entry(subsystem).or_default() += 1;
+    }
+
+    pub fn frame_captured(&self) {
+        let mut frames = self.frames.lock();
+        let now = Instant::now();
+        let start = *frames.window_start.get_or_insert(now);
+
+        frames.frames_in_window += 1;
+        let elapsed = now.duration_since(start);
+        if elapsed >= FPS_WINDOW {
+            frames.fps = frames.frames_in_window as f64 / elapsed.as_secs_f64();
+            frames.frames_in_window = 0;
+            frames.window_start = Some(now);
+        }
+    }
+
+    /// A captured frame was pushed out of the queue before the encoder took it
+    pub fn frame_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Damage tracking found `share` (0.0 to 1.0) of a captured frame changed
+    pub fn frame_damaged(&self, share: f64) {
+        let mut damage = self.damage.lock();
+        let now = Instant::now();
+        let start = *damage.window_start.get_or_insert(now);
+
+        damage.frames_in_window += 1;
+        damage.share_in_window += share;
+        let elapsed = now.duration_since(start);
+        if elapsed >= FPS_WINDOW {
+            damage.share = damage.share_in_window / damage.frames_in_window as f64;
+            damage.unchanged_per_sec = damage.unchanged_in_window as f64 / elapsed.as_secs_f64();
+            damage.frames_in_window = 0;
+            damage.share_in_window = 0.0;
+            damage.unchanged_in_window = 0;
+            damage.window_start = Some(now);
+        }
+    }
+
+    /// A captured frame changed nothing, so it wasn't encoded
+    pub fn frame_unchanged(&self) {
+        self.frames_unchanged.fetch_add(1, Ordering::Relaxed);
+        self.damage.lock().unchanged_in_window += 1;
+    }
+
+    /// Record which encoder backend the stream ended up on
+    pub fn set_encoder_backend(&self, backend: &'static str) {
+        *self.encoder_backend.lock() = Some(backend);
+    }
+
+    pub fn set_target_bitrate(&self, kbps: u32) {
+        self.target_bitrate_kbps.store(kbps as u64, Ordering::Relaxed);
+    }
+
+    pub fn packet_encoded(&self, bytes: usize) {
+        let mut encoded = self.encoded.lock();
+        let now = Instant::now();
+        let start = *encoded.window_start.get_or_insert(now);
+
+        encoded.bytes_in_window += bytes as u64;
+        encoded.packets_in_window += 1;
+        let elapsed = now.duration_since(start);
+        if elapsed >= FPS_WINDOW {
+            encoded.kbps = encoded.bytes_in_window as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64();
+            encoded.packet_bytes = encoded.bytes_in_window as f64 / encoded.packets_in_window as f64;
+            encoded.bytes_in_window = 0;
+            encoded.packets_in_window = 0;
+            encoded.window_start = Some(now);
+        }
+    }
+
+    /// The counters worth a glance, for the dashboard
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            events_processed: self.events_processed.load(Ordering::Relaxed),
+            events_dropped: self.events_dropped.load(Ordering::Relaxed),
+            active_sessions: self.active_sessions.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.lock().clone(),
+            capture_fps: self.frames.lock().fps,
+        }
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        };
+        counter(&mut out, "mirage_input_events_total", "Input events processed",
+            self.events_processed.load(Ordering::Relaxed));
+        counter(&mut out, "mirage_input_events_dropped_total", "Input events lost before delivery",
+            self.events_dropped.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP mirage_active_sessions Sessions currently open");
+        let _ = writeln!(out, "# TYPE mirage_active_sessions gauge");
+        let _ = writeln!(out, "mirage_active_sessions {}", self.active_sessions.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP mirage_capture_fps Frames captured per second");
+        let _ = writeln!(out, "# TYPE mirage_capture_fps gauge");
+        let _ = writeln!(out, "mirage_capture_fps {:.2}", self.frames.lock().fps);
+        counter(&mut out, "mirage_capture_frames_dropped_total", "Captured frames skipped because the encoder was behind",
+            self.frames_dropped.load(Ordering::Relaxed));
+        counter(&mut out, "mirage_capture_frames_unchanged_total", "Captured frames not encoded because nothing on screen changed",
+            self.frames_unchanged.load(Ordering::Relaxed));
+
+        let (damage_share, unchanged_per_sec) = {
+            let damage = self.damage.lock();
+            (damage.share, damage.unchanged_per_sec)
+        };
+        let _ = writeln!(out, "# HELP mirage_capture_damage_ratio Share of the screen changed per frame over the last second");
+        let _ = writeln!(out, "# TYPE mirage_capture_damage_ratio gauge");
+        let _ = writeln!(out, "mirage_capture_damage_ratio {:.3}", damage_share);
+
+        if let Some(backend) = *self.encoder_backend.lock() {
+            let _ = writeln!(out, "# HELP mirage_encoder_backend Encoder in use, vaapi or software");
+            let _ = writeln!(out, "# TYPE mirage_encoder_backend gauge");
+            let _ = writeln!(out, "mirage_encoder_backend{{backend=\"{}\"}} 1", backend);
+        }
+
+        let _ = writeln!(out, "# HELP mirage_encoder_target_kbps Bitrate the encoder is asked for");
+        let _ = writeln!(out, "# TYPE mirage_encoder_target_kbps gauge");
+        let _ = writeln!(out, "mirage_encoder_target_kbps {}", self.target_bitrate_kbps.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP mirage_encoder_actual_kbps Bitrate the encoder produced over the last second");
+        let _ = writeln!(out, "# TYPE mirage_encoder_actual_kbps gauge");
+        let (actual_kbps, packet_bytes) = {
+            let encoded = self.encoded.lock();
+            (encoded.kbps, encoded.packet_bytes)
+        };
+        let _ = writeln!(out, "mirage_encoder_actual_kbps {:.0}", actual_kbps);
+
+        // Unchanged frames would have cost about as much as the average packet
+        let _ = writeln!(out, "# HELP mirage_encoder_saved_kbps Estimated bitrate saved by not encoding unchanged frames");
+        let _ = writeln!(out, "# TYPE mirage_encoder_saved_kbps gauge");
+        let _ = writeln!(out, "mirage_encoder_saved_kbps {:.0}", unchanged_per_sec * packet_bytes * 8.0 / 1000.0);
+
+        let _ = writeln!(out, "# HELP mirage_session_bytes_sent_total Control channel bytes sent per session");
+        let _ = writeln!(out, "# TYPE mirage_session_bytes_sent_total counter");
+        for (session_id, bytes) in self.bytes_sent.lock().iter() {
+            let _ = writeln!(out, "mirage_session_bytes_sent_total{{session=\"{}\"}} {}", session_id, bytes);
+        }
+
+        let _ = writeln!(out, "# HELP mirage_subsystem_restarts_total Times the supervisor restarted each subsystem");
+        let _ = writeln!(out, "# TYPE mirage_subsystem_restarts_total counter");
+        for (subsystem, restarts) in self.subsystem_restarts.lock().iter() {
+            let _ = writeln!(out, "mirage_subsystem_restarts_total{{subsystem=\"{}\"}} {}", subsystem, restarts);
+        }
+
+        out
+    }
+}
+
+/// Serve `GET /metrics` on localhost until the listener fails
+pub async fn serve(port: u16) -> Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics port {}", addr))?;
+    info!("✓ Metrics available at http://{}/metrics", addr);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await
+            .context("Failed to accept metrics connection")?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                debug!("Metrics request from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    // The request line is all we need; scrapers send small requests
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, content_type, body) = if request.starts_with("GET ") && path == "/metrics" {
+        ("200 OK", "text/plain; version=0.0.4", metrics().render())
+    } else {
+        ("404 Not Found", "text/plain", "Not found\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
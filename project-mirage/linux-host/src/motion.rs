@@ -0,0 +1,393 @@
+// Optional UDP fast path for mouse motion (`network.motion_over_udp`).
+// Only the latest position matters, so motion doesn't need to wait behind a
+// retransmit on the control channel. Buttons and keys never take this path.
+// Datagrams aren't encrypted, but each is signed with a key issued over the
+// control connection and names its session only by an opaque ID.
+
+use anyhow::{anyhow, bail, Context, Result};
+use prost::Message;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::input::InputEvent;
+use crate::proto::{control_message, input_message, mouse_event, ControlMessage, InputMessage, MotionDatagram, MouseEvent};
+use crate::session::SessionManager;
+
+// Motion datagrams are a few dozen bytes; anything much bigger isn't one
+const MAX_DATAGRAM_SIZE: usize = 512;
+
+// HMAC-SHA256 tag after the encoded datagram
+const TAG_LEN: usize = 32;
+
+const MOTION_ID_LEN: usize = 16;
+const MOTION_KEY_LEN: usize = 32;
+
+// How often drift is reported while a peer is moving the mouse
+const DRIFT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Drift beyond this many pixels is worth a warning
+const DRIFT_WARN_PIXELS: f64 = 25.0;
+
+/// Whether `event` may take the UDP fast path
+pub fn is_motion(event: &InputEvent) -> bool {
+    matches!(event, InputEvent::MouseMove { .. } | InputEvent::MouseAbsolute { .. })
+}
+
+/// Encode `datagram` and sign it with `key`
+fn seal(key: &hmac::Key, datagram: &MotionDatagram) -> Vec<u8> {
+    let mut packet = datagram.encode_to_vec();
+    let tag = hmac::sign(key, &packet);
+    packet.extend_from_slice(tag.as_ref());
+    packet
+}
+
+/// Sends motion to the `motion_port` a peer advertised
+pub struct MotionSender {
+    socket: UdpSocket,
+    motion_id: Vec<u8>,
+    key: hmac::Key,
+    sequence: u32,
+    // Relative motion sent so far, so the peer can work out its drift
+    total: (f64, f64),
+}
+
+impl MotionSender {
+    /// `motion_id` and `key` come from the peer's handshake reply
+    pub async fn connect(peer: SocketAddr, motion_id: Vec<u8>, key: &[u8]) -> Result<Self> {
+        if motion_id.is_empty() || key.is_empty() {
+            bail!("The peer issued no motion key");
+        }
+        let local = match peer {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await.context("Failed to open a motion socket")?;
+        socket.connect(peer)
+            .await
+            .with_context(|| format!("Failed to reach {} over UDP", peer))?;
+
+        Ok(Self {
+            socket,
+            motion_id,
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+            sequence: 0,
+            total: (0.0, 0.0),
+        })
+    }
+
+    /// Send one motion event, returning the number of bytes sent
+    pub async fn send(&mut self, event: &InputEvent) -> Result<usize> {
+        if !is_motion(event) {
+            bail!("Only mouse motion goes over UDP");
+        }
+        let Some(input_message::Event::Mouse(mut mouse)) = InputMessage::try_from(event.clone())?.event else {
+            bail!("Only mouse motion goes over UDP");
+        };
+
+        let mut total = self.total;
+        if let InputEvent::MouseMove { delta_x, delta_y } = *event {
+            total.0 += delta_x as f64;
+            total.1 += delta_y as f64;
+        }
+        let sequence = self.sequence.wrapping_add(1);
+        mouse.sequence = sequence;
+
+        let datagram = MotionDatagram {
+            motion_id: self.motion_id.clone(),
+            mouse: Some(mouse),
+            total_x: total.0,
+            total_y: total.1,
+        };
+        let bytes = self.socket.send(&seal(&self.key, &datagram)).await?;
+
+        // Only count what left, the caller sends the rest reliably
+        self.sequence = sequence;
+        self.total = total;
+        Ok(bytes)
+    }
+}
+
+/// What a peer needs to send motion for its session, handed over in the
+/// handshake reply
+pub struct MotionTicket {
+    pub motion_id: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+/// Receives motion for sessions whose control connection came to us. Cheap to
+/// clone; clones share the socket and the session table.
+#[derive(Clone)]
+pub struct MotionReceiver {
+    socket: Arc<UdpSocket>,
+    // Keyed by motion ID
+    peers: Arc<parking_lot::Mutex<HashMap<Vec<u8>, MotionPeer>>>,
+}
+
+struct MotionPeer {
+    session_id: String,
+    key: hmac::Key,
+    ip: IpAddr,
+    last_sequence: Option<u32>,
+    // Relative motion applied so far, against the sender's running total
+    applied: (f64, f64),
+    sent: (f64, f64),
+    received: u64,
+    skipped: u64,
+    stale: u64,
+    reported: Instant,
+}
+
+impl MotionPeer {
+    fn drift(&self) -> f64 {
+        (self.sent.0 - self.applied.0).hypot(self.sent.1 - self.applied.1)
+    }
+
+    fn report(&mut self) {
+        let drift = self.drift();
+        if drift > DRIFT_WARN_PIXELS {
+            warn!(
+                "⚠ Motion from session {} is {:.0}px off ({} datagrams skipped, {} stale)",
+                self.session_id, drift, self.skipped, self.stale
+            );
+        } else {
+            debug!(
+                "Motion from session {}: {} datagrams, {} skipped, {} stale, {:.1}px drift",
+                self.session_id, self.received, self.skipped, self.stale, drift
+            );
+        }
+        self.reported = Instant::now();
+    }
+}
+
+impl MotionReceiver {
+    /// Bind an ephemeral port, which peers learn from the handshake reply
+    pub async fn bind() -> Result<Self> {
+        let socket = match UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("IPv6 bind failed ({}), taking motion over IPv4 only", e);
+                UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+                    .await
+                    .context("Failed to bind a motion socket")?
+            }
+        };
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            peers: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map(|addr| addr.port()).unwrap_or(0)
+    }
+
+    /// Take motion for `session_id`, but only from `ip`, where its control
+    /// connection came from, and only signed with the returned key. Replaces
+    /// whatever the session was issued before.
+    pub fn register(&self, session_id: &str, ip: IpAddr) -> Result<MotionTicket> {
+        let rng = SystemRandom::new();
+        let mut motion_id = vec![0u8; MOTION_ID_LEN];
+        let mut key = vec![0u8; MOTION_KEY_LEN];
+        rng.fill(&mut motion_id)
+            .and_then(|_| rng.fill(&mut key))
+            .map_err(|_| anyhow!("Failed to generate a motion key"))?;
+
+        let mut peers = self.peers.lock();
+        peers.retain(|_, peer| peer.session_id != session_id);
+        peers.insert(motion_id.clone(), MotionPeer {
+            session_id: session_id.to_string(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            ip: ip.to_canonical(),
+            last_sequence: None,
+            applied: (0.0, 0.0),
+            sent: (0.0, 0.0),
+            received: 0,
+            skipped: 0,
+            stale: 0,
+            reported: Instant::now(),
+        });
+        Ok(MotionTicket { motion_id, key })
+    }
+
+    pub fn unregister(&self, session_id: &str) {
+        self.peers.lock().retain(|_, peer| {
+            if peer.session_id != session_id {
+                return true;
+            }
+            if peer.received > 0 {
+                peer.report();
+            }
+            false
+        });
+    }
+
+    /// Hand motion to `session_manager` like any other input from the peer
+    pub async fn run(self, session_manager: SessionManager) -> Result<()> {
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await.context("Motion socket failed")?;
+            let Some((session_id, mouse)) = self.accept(&buf[..len], from) else {
+                continue;
+            };
+
+            let message = ControlMessage {
+                session_id: session_id.clone(),
+                payload: Some(control_message::Payload::Input(InputMessage {
+                    event: Some(input_message::Event::Mouse(mouse)),
+                })),
+                ..Default::default()
+            };
+            session_manager.handle_message(&session_id, &message).await;
+        }
+    }
+
+    /// Check a datagram against its session and count it towards the drift,
+    /// returning the session and the motion if it is newer than anything
+    /// applied so far
+    fn accept(&self, packet: &[u8], from: SocketAddr) -> Option<(String, MouseEvent)> {
+        let Some(split) = packet.len().checked_sub(TAG_LEN) else {
+            debug!("Ignoring a truncated motion datagram from {}", from);
+            return None;
+        };
+        let (body, tag) = packet.split_at(split);
+        let datagram = match MotionDatagram::decode(body) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                debug!("Ignoring malformed motion datagram from {}: {}", from, e);
+                return None;
+            }
+        };
+
+        let mut peers = self.peers.lock();
+        let peer = peers.get_mut(&datagram.motion_id)?;
+        if peer.ip != from.ip().to_canonical() || hmac::verify(&peer.key, body, tag).is_err() {
+            return None;
+        }
+        let mouse = datagram.mouse?;
+        if !matches!(mouse.r#type(), mouse_event::Type::Move | mouse_event::Type::Absolute) {
+            return None;
+        }
+
+        if let Some(last) = peer.last_sequence {
+            let ahead = mouse.sequence.wrapping_sub(last) as i32;
+            if ahead <= 0 {
+                peer.stale += 1;
+                return None;
+            }
+            peer.skipped += (ahead - 1) as u64;
+        }
+        peer.last_sequence = Some(mouse.sequence);
+        peer.received += 1;
+        peer.sent = (datagram.total_x, datagram.total_y);
+        if mouse.r#type() == mouse_event::Type::Move {
+            peer.applied.0 += mouse.delta_x as f64;
+            peer.applied.1 += mouse.delta_y as f64;
+        }
+
+        if peer.reported.elapsed() >= DRIFT_REPORT_INTERVAL {
+            peer.report();
+        }
+        Some((peer.session_id.clone(), mouse))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "192.168.1.20:40000";
+
+    fn datagram(ticket: &MotionTicket, sequence: u32) -> MotionDatagram {
+        MotionDatagram {
+            motion_id: ticket.motion_id.clone(),
+            mouse: Some(MouseEvent {
+                r#type: mouse_event::Type::Move as i32,
+                delta_x: 5.0,
+                sequence,
+                ..Default::default()
+            }),
+            total_x: sequence as f64 * 5.0,
+            total_y: 0.0,
+        }
+    }
+
+    fn signed(ticket: &MotionTicket, sequence: u32) -> Vec<u8> {
+        seal(&hmac::Key::new(hmac::HMAC_SHA256, &ticket.key), &datagram(ticket, sequence))
+    }
+
+    fn counts(receiver: &MotionReceiver) -> (u64, u64, u64) {
+        let peers = receiver.peers.lock();
+        let peer = peers.values().next().unwrap();
+        (peer.received, peer.skipped, peer.stale)
+    }
+
+    #[tokio::test]
+    async fn stale_and_skipped_sequences_are_counted() {
+        let receiver = MotionReceiver::bind().await.unwrap();
+        let from: SocketAddr = PEER.parse().unwrap();
+        let ticket = receiver.register("session", from.ip()).unwrap();
+
+        let (session_id, mouse) = receiver.accept(&signed(&ticket, 1), from).unwrap();
+        assert_eq!(session_id, "session");
+        assert_eq!(mouse.sequence, 1);
+
+        // 2 and 3 never arrive
+        assert!(receiver.accept(&signed(&ticket, 4), from).is_some());
+        assert_eq!(counts(&receiver), (2, 2, 0));
+
+        // Late or repeated motion would move the cursor backwards
+        assert!(receiver.accept(&signed(&ticket, 3), from).is_none());
+        assert!(receiver.accept(&signed(&ticket, 4), from).is_none());
+        assert_eq!(counts(&receiver), (2, 2, 2));
+
+        // Sequence numbers wrap
+        let ticket = receiver.register("session", from.ip()).unwrap();
+        assert!(receiver.accept(&signed(&ticket, u32::MAX), from).is_some());
+        assert!(receiver.accept(&signed(&ticket, 0), from).is_some());
+        assert_eq!(counts(&receiver), (2, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn motion_from_another_address_is_dropped() {
+        let receiver = MotionReceiver::bind().await.unwrap();
+        let from: SocketAddr = PEER.parse().unwrap();
+        let ticket = receiver.register("session", from.ip()).unwrap();
+
+        let elsewhere: SocketAddr = "192.168.1.21:40000".parse().unwrap();
+        assert!(receiver.accept(&signed(&ticket, 1), elsewhere).is_none());
+        // Same host over IPv6
+        let mapped: SocketAddr = "[::ffff:192.168.1.20]:40001".parse().unwrap();
+        assert!(receiver.accept(&signed(&ticket, 1), mapped).is_some());
+    }
+
+    #[tokio::test]
+    async fn motion_must_be_signed_with_the_session_key() {
+        let receiver = MotionReceiver::bind().await.unwrap();
+        let from: SocketAddr = PEER.parse().unwrap();
+        let ticket = receiver.register("session", from.ip()).unwrap();
+
+        let forged = seal(&hmac::Key::new(hmac::HMAC_SHA256, b"guessed"), &datagram(&ticket, 1));
+        assert!(receiver.accept(&forged, from).is_none());
+
+        let mut tampered = signed(&ticket, 1);
+        tampered[0] ^= 1;
+        assert!(receiver.accept(&tampered, from).is_none());
+        assert!(receiver.accept(&signed(&ticket, 1)[..TAG_LEN - 1], from).is_none());
+
+        // A new ticket retires the old one
+        let renewed = receiver.register("session", from.ip()).unwrap();
+        assert_ne!(renewed.motion_id, ticket.motion_id);
+        assert!(receiver.accept(&signed(&ticket, 2), from).is_none());
+        assert!(receiver.accept(&signed(&renewed, 1), from).is_some());
+
+        receiver.unregister("session");
+        assert!(receiver.accept(&signed(&renewed, 2), from).is_none());
+    }
+}
@@ -1,12 +1,1589 @@
 // Network communication layer
-// WebRTC media channels and QUIC control channel
+// TLS control channel (over TCP or QUIC) carrying length-prefixed protobuf messages
 
-pub struct NetworkManager {
-    // TODO: Implement in Phase 0.2
+use anyhow::{bail, Context, Result};
+use prost::Message;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::{field, info, info_span, debug, warn, Instrument, Span};
+
+use crate::config::Config;
+use crate::discovery::{is_ipv6_link_local, PeerCapabilities, PeerDevice};
+use crate::input::{self, InputEvent};
+use crate::metrics::metrics;
+use crate::motion::{self, MotionReceiver, MotionSender};
+use crate::platform;
+use crate::proto::{
+    control_message, error_report, node_advertisement, pairing_response, session_control, AuthChallenge, AuthResponse,
+    ControlMessage, CursorState, ErrorReport, InputMessage, NodeAdvertisement, PairingRequest, PairingResponse,
+    ProtocolVersion, SessionControl, PROTOCOL_VERSION,
+};
+use crate::security::{self, Identity, PairingManager};
+use crate::supervisor::Shutdown;
+use crate::transport::{self, ByteStream, Channel, Channels, Connector, Link, PathStats};
+use crate::session::{reconnect_delay, CloseReason, SessionManager, SessionState, RECONNECT_ATTEMPTS};
+
+// Frame limit when none is configured
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A write that takes longer than this is to a peer that stopped reading
+const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Unanswered pings after which a peer is considered gone
+const MISSED_HEARTBEATS: u32 = 3;
+
+// Sequence numbers behind the newest that can still arrive late. Input has a
+// stream of its own on QUIC, so messages can overtake each other.
+const REPLAY_WINDOW: u32 = 64;
+
+/// Accepts control connections from peers on `network.control_port`
+pub struct ControlServer {
+    config: Config,
+    session_manager: SessionManager,
+    tls: Arc<rustls::ServerConfig>,
+    pairing: PairingManager,
+    /// Open connections per peer address, for `security.max_connections_per_ip`
+    connections: Arc<parking_lot::Mutex<HashMap<IpAddr, u32>>>,
+    shutdown: Shutdown,
+}
+
+impl ControlServer {
+    pub fn new(
+        config: Config,
+        session_manager: SessionManager,
+        tls: Arc<rustls::ServerConfig>,
+        pairing: PairingManager,
+    ) -> Self {
+        Self {
+            config,
+            session_manager,
+            tls,
+            pairing,
+            connections: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Stop accepting and say goodbye to connected peers when `shutdown` fires
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut acceptor = transport::listen(&self.config.network, self.tls.clone()).await?;
+        info!("✓ Control server listening on {} ({:?})", acceptor.local_addr(), self.config.network.transport);
+
+        let motion = if self.config.network.motion_over_udp {
+            let receiver = MotionReceiver::bind().await?;
+            info!("✓ Taking mouse motion over UDP on port {}", receiver.port());
+            Some(receiver)
+        } else {
+            None
+        };
+        let motion_task = motion.clone().map(|receiver| tokio::spawn(receiver.run(self.session_manager.clone())));
+
+        let result = loop {
+            let (peer_addr, handshake) = tokio::select! {
+                accepted = acceptor.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Err(e),
+                },
+                _ = self.shutdown.wait() => break Ok(()),
+            };
+            debug!("Control connection from {}", peer_addr);
+
+            let security = &self.config.security;
+            let Some(slot) = ConnectionSlot::claim(&self.connections, peer_addr.ip(), security.max_connections_per_ip) else {
+                warn!(
+                    "⚠ Refusing connection from {}: already {} open from that address",
+                    peer_addr, security.max_connections_per_ip
+                );
+                continue;
+            };
+
+            let session_manager = self.session_manager.clone();
+            let pairing = security.require_pairing.then(|| self.pairing.clone());
+            let motion = motion.clone();
+            let limit = TokenBucket::new(security.max_message_rate, security.message_burst);
+            let shutdown = self.shutdown.clone();
+            // Filled in once the peer says who it is and gets a session
+            let span = info_span!("connection", %peer_addr, node_id = field::Empty, session_id = field::Empty);
+            tokio::spawn(async move {
+                let _slot = slot;
+                let result = match handshake.await {
+                    Ok(link) => Self::handle_connection(link, session_manager, pairing, motion, limit, shutdown).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Control connection from {} failed: {:#}", peer_addr, e);
+                }
+            }.instrument(span));
+        };
+
+        if let Some(task) = motion_task {
+            task.abort();
+        }
+        result
+    }
+
+    async fn handle_connection(
+        link: Link,
+        session_manager: SessionManager,
+        pairing: Option<PairingManager>,
+        motion: Option<MotionReceiver>,
+        mut limit: TokenBucket,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        let Link { control: mut stream, peer_addr, channels, frame } = link;
+
+        // The first message either identifies the peer or completes a pairing
+        let hello: ControlMessage = read_frame(&mut stream, frame).await?;
+        let advertisement = match hello.payload {
+            Some(control_message::Payload::Advertisement(advertisement)) => advertisement,
+            Some(control_message::Payload::PairingRequest(request)) => {
+                let Some(pairing) = pairing else {
+                    bail!("Pairing request received but pairing is disabled");
+                };
+                if let Some(reason) = session_manager.refuses_node(&request.initiator_node_id) {
+                    warn!("🔒 Refused pairing with {} from {}: {}", request.initiator_name, peer_addr, reason);
+                    return Ok(());
+                }
+                return Self::handle_pairing(stream, request, &pairing).await;
+            }
+            _ => bail!("Expected an advertisement as the first message"),
+        };
+        Span::current().record("node_id", advertisement.node_id.as_str());
+
+        // Nothing is answered, so a refused peer learns no more than that the connection closed
+        if let Some(reason) = session_manager.refuses_node(&advertisement.node_id) {
+            warn!("🔒 Refused {} ({}) from {}: {}", advertisement.node_name, advertisement.node_id, peer_addr, reason);
+            return Ok(());
+        }
+
+        debug!(
+            "{} runs mirage {} (protocol {})",
+            advertisement.node_name,
+            advertisement.software_version,
+            ProtocolVersion::from_wire(advertisement.protocol_version)
+        );
+        let protocol = match PROTOCOL_VERSION.negotiate(ProtocolVersion::from_wire(advertisement.protocol_version)) {
+            Ok(protocol) => protocol,
+            Err(e) => {
+                let message = format!(
+                    "{} runs mirage {}: {:#}; upgrade both hosts to the same release",
+                    session_manager.node_name(), env!("CARGO_PKG_VERSION"), e
+                );
+                let refusal = error_reply(error_report::Code::IncompatibleVersion, &message, "handshake");
+                write_frame(&mut stream, &refusal).await?;
+                bail!("Refused {} {}: {:#}", advertisement.node_name, advertisement.software_version, e);
+            }
+        };
+
+        if let Some(pairing) = &pairing {
+            if !pairing.is_paired(&advertisement.node_id) {
+                pairing.begin(&advertisement.node_id, &advertisement.node_name)?;
+
+                let message = format!(
+                    "Not paired with {}; enter the PIN it displays to pair",
+                    session_manager.node_name()
+                );
+                let refusal = error_reply(error_report::Code::PermissionDenied, &message, "pairing");
+                write_frame(&mut stream, &refusal).await?;
+
+                info!("Refused unpaired peer {} from {}", advertisement.node_name, peer_addr);
+                return Ok(());
+            }
+
+            // Anyone can claim a paired node ID; only the paired peer holds its key
+            let nonce = security::challenge()?;
+            let challenge = ControlMessage {
+                payload: Some(control_message::Payload::AuthChallenge(AuthChallenge { nonce: nonce.clone() })),
+                ..Default::default()
+            };
+            write_frame(&mut stream, &challenge).await?;
+            let answer: ControlMessage = tokio::time::timeout(CONNECT_TIMEOUT, read_frame(&mut stream, frame))
+                .await
+                .context("No answer to the pairing challenge")??;
+            let proven = match answer.payload {
+                Some(control_message::Payload::AuthResponse(response)) => {
+                    pairing.verify(&advertisement.node_id, &nonce, &response.signature)
+                }
+                _ => Err(anyhow::anyhow!("Expected an answer to the pairing challenge")),
+            };
+            if let Err(e) = proven {
+                let refusal = error_reply(error_report::Code::PermissionDenied, "Pairing could not be verified", "pairing");
+                write_frame(&mut stream, &refusal).await?;
+                warn!("🔒 Refused {} ({}) from {}: {:#}", advertisement.node_name, advertisement.node_id, peer_addr, e);
+                return Ok(());
+            }
+        }
+
+        let capabilities = advertisement.capabilities.clone().map(PeerCapabilities::from);
+
+        // A hello tagged with a session ID is a peer coming back after a dropped connection
+        let resumed = if hello.session_id.is_empty() {
+            None
+        } else {
+            session_manager.resume_session(&hello.session_id, &advertisement.node_id).await
+        };
+        let session = match resumed {
+            Some(session) => session,
+            None => {
+                let resume_token = Some(advertisement.resume_token.as_str()).filter(|token| !token.is_empty());
+                let session = session_manager
+                    .create_session(advertisement.node_id.clone(), advertisement.node_name.clone(), protocol, resume_token)
+                    .await?;
+                info!("🔗 Peer {} connected from {}", session.peer_name, peer_addr);
+
+                // Settle the codec before any encoder is started for this peer.
+                // Peers that don't advertise capabilities get the configured codec.
+                if let Some(ref capabilities) = capabilities {
+                    if let Err(e) = session_manager.negotiate_stream(&session.session_id, capabilities).await {
+                        warn!("⚠ Streaming to {} is unavailable: {:#}", session.peer_name, e);
+                    }
+                }
+                session
+            }
+        };
+
+        Span::current().record("session_id", session.session_id.as_str());
+
+        if let Some(display) = advertisement.displays.first() {
+            session_manager.set_peer_screen(&session.session_id, (display.width, display.height)).await;
+        }
+        if let Some(ref cursor) = advertisement.cursor {
+            if let Err(e) = session_manager.restore_cursor(cursor) {
+                warn!("⚠ Could not restore the cursor for {}: {:#}", session.peer_name, e);
+            }
+        }
+
+        // Motion can arrive as soon as the peer has the reply. Peers that
+        // don't sign it send it on this connection instead.
+        let motion = motion.filter(|_| protocol.signs_motion());
+        let ticket = motion.as_ref().and_then(|motion| {
+            motion.register(&session.session_id, peer_addr.ip())
+                .map_err(|e| warn!("⚠ Taking motion from {} reliably: {:#}", session.peer_name, e))
+                .ok()
+        });
+
+        // Answer the hello with the session the peer should tag its messages with
+        let reply = ControlMessage {
+            session_id: session.session_id.clone(),
+            sequence: 0,
+            nonce: session.nonce.clone(),
+            payload: Some(control_message::Payload::Advertisement(NodeAdvertisement {
+                node_name: session_manager.node_name().to_string(),
+                os_type: "linux".to_string(),
+                capabilities: Some(PeerCapabilities::local().into()),
+                displays: local_displays(),
+                motion_port: motion.as_ref().map_or(0, |motion| motion.port() as u32),
+                resume_token: session.resume_token.clone(),
+                protocol_version: PROTOCOL_VERSION.to_wire(),
+                software_version: env!("CARGO_PKG_VERSION").to_string(),
+                motion_id: ticket.as_ref().map(|ticket| ticket.motion_id.clone()).unwrap_or_default(),
+                motion_key: ticket.map(|ticket| ticket.key).unwrap_or_default(),
+                ..Default::default()
+            })),
+        };
+        match write_frame(&mut stream, &reply).await {
+            Ok(bytes) => metrics().bytes_sent(&session.session_id, bytes),
+            Err(e) => {
+                if let Some(ref motion) = motion {
+                    motion.unregister(&session.session_id);
+                }
+                session_manager.connection_lost(&session.session_id).await;
+                return Err(e);
+            }
+        }
+
+        // Reads run on their own task so pings can go out while waiting for messages
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (message_tx, mut message_rx) = mpsc::channel(16);
+        let channel_task = tokio::spawn(forward_channels(channels, frame, message_tx.clone()));
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let result = read_frame(&mut reader, frame).await;
+                let failed = result.is_err();
+                if message_tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut ping = tokio::time::interval(session_manager.heartbeat_interval());
+        ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ping.tick().await;
+        let mut missed_pings = 0;
+
+        // Messages other parts of the host want to push to this peer
+        let mut outbound = session_manager.open_outbound(&session.session_id);
+        let stamp = Stamp::new(session.nonce.clone());
+
+        let result = loop {
+            tokio::select! {
+                message = message_rx.recv() => {
+                    // Anything but an explicit Disconnect leaves the session open for a reconnect
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => {
+                            session_manager.connection_lost(&session.session_id).await;
+                            break if is_disconnect(&e) { Ok(()) } else { Err(e) };
+                        }
+                        None => {
+                            session_manager.connection_lost(&session.session_id).await;
+                            break Ok(());
+                        }
+                    };
+
+                    if !limit.take() {
+                        warn!(
+                            "⚠ Dropping {}: sending faster than {} messages per second",
+                            session.peer_name, limit.rate
+                        );
+                        session_manager.close_session(&session.session_id, CloseReason::RateLimited).await;
+                        break Ok(());
+                    }
+
+                    if !session_manager.check_replay(&session.session_id, &message).await {
+                        warn!("⚠ Dropping replayed message #{} from {}", message.sequence, session.peer_name);
+                        continue;
+                    }
+                    session_manager.update_activity(&session.session_id).await;
+                    debug!("Control message #{} from {}", message.sequence, session.peer_name);
+
+                    match &message.payload {
+                        Some(control_message::Payload::SessionControl(control)) => match control.command() {
+                            session_control::Command::Heartbeat => missed_pings = 0,
+                            session_control::Command::Probe => {
+                                let reply = stamp.apply(probe_reply(&session.session_id, control.timestamp_ms));
+                                if let Err(e) = write_frame(&mut writer, &reply).await {
+                                    session_manager.connection_lost(&session.session_id).await;
+                                    break Err(e);
+                                }
+                            }
+                            session_control::Command::Disconnect => {
+                                info!("Peer {} disconnected", session.peer_name);
+                                session_manager.close_session(&session.session_id, CloseReason::Disconnected).await;
+                                break Ok(());
+                            }
+                            _ => {}
+                        },
+                        _ => session_manager.handle_message(&session.session_id, &message).await,
+                    }
+                }
+                Some(payload) = outbound.recv() => {
+                    let message = stamp.apply(ControlMessage {
+                        session_id: session.session_id.clone(),
+                        payload: Some(payload),
+                        ..Default::default()
+                    });
+                    match write_frame(&mut writer, &message).await {
+                        Ok(bytes) => metrics().bytes_sent(&session.session_id, bytes),
+                        Err(e) => {
+                            session_manager.connection_lost(&session.session_id).await;
+                            break Err(e);
+                        }
+                    }
+                }
+                _ = shutdown.wait() => {
+                    let disconnect = stamp.apply(ControlMessage {
+                        session_id: session.session_id.clone(),
+                        payload: Some(control_message::Payload::SessionControl(SessionControl {
+                            command: session_control::Command::Disconnect as i32,
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    });
+                    if let Err(e) = write_frame(&mut writer, &disconnect).await {
+                        debug!("Could not say goodbye to {}: {:#}", session.peer_name, e);
+                    }
+                    session_manager.close_session(&session.session_id, CloseReason::Shutdown).await;
+                    break Ok(());
+                }
+                _ = ping.tick() => {
+                    if missed_pings >= MISSED_HEARTBEATS {
+                        warn!("Peer {} missed {} heartbeats, closing session", session.peer_name, missed_pings);
+                        session_manager.heartbeat_lost(&session.session_id, missed_pings).await;
+                        break Ok(());
+                    }
+                    missed_pings += 1;
+
+                    let ping = stamp.apply(heartbeat(&session.session_id, chrono::Utc::now().timestamp_millis() as u64));
+                    match write_frame(&mut writer, &ping).await {
+                        Ok(bytes) => metrics().bytes_sent(&session.session_id, bytes),
+                        Err(e) => {
+                            session_manager.connection_lost(&session.session_id).await;
+                            break Err(e);
+                        }
+                    }
+                }
+            }
+        };
+
+        reader_task.abort();
+        channel_task.abort();
+        if let Some(ref motion) = motion {
+            motion.unregister(&session.session_id);
+        }
+        result
+    }
+
+    async fn handle_pairing<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        request: PairingRequest,
+        pairing: &PairingManager,
+    ) -> Result<()> {
+        let result = pairing.confirm(
+            &request.initiator_node_id,
+            &request.pairing_code,
+            &request.public_key,
+        );
+
+        let status = match result {
+            Ok(_) => pairing_response::Status::Accepted,
+            Err(_) => pairing_response::Status::Rejected,
+        };
+        let response = ControlMessage {
+            payload: Some(control_message::Payload::PairingResponse(PairingResponse {
+                status: status as i32,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        write_frame(&mut stream, &response).await?;
+
+        result.map(|_| ())
+    }
+}
+
+/// One of the connections an address may hold open; given back on drop
+struct ConnectionSlot {
+    connections: Arc<parking_lot::Mutex<HashMap<IpAddr, u32>>>,
+    ip: IpAddr,
 }
 
-impl NetworkManager {
-    pub fn new() -> Self {
-        Self {}
+impl ConnectionSlot {
+    fn claim(connections: &Arc<parking_lot::Mutex<HashMap<IpAddr, u32>>>, ip: IpAddr, limit: u32) -> Option<Self> {
+        let ip = ip.to_canonical();
+        let mut open = connections.lock();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self { connections: connections.clone(), ip })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.connections.lock();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Token bucket for messages from one peer: refills at `rate` per second up
+/// to `burst`
+struct TokenBucket {
+    rate: u32,
+    burst: f64,
+    tokens: f64,
+    refilled: std::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled: std::time::Instant::now(),
+        }
+    }
+
+    /// Spend a token for one message; false once the peer has run out
+    fn take(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Outgoing control connection to a peer, used to forward input once the
+/// cursor crosses onto it
+pub struct ControlClient {
+    writer: Arc<Mutex<WriteHalf<Box<dyn ByteStream>>>>,
+    // Input gets a stream of its own where the transport has them
+    input: Option<Box<dyn AsyncWrite + Unpin + Send>>,
+    // Motion skips the connection entirely once `enable_motion_over_udp` is called
+    motion: Option<MotionSender>,
+    motion_over_udp: bool,
+    peer_addr: SocketAddr,
+    channels: Channels,
+    health: LinkHealth,
+    // Answers the peer's heartbeats while we're only sending
+    responder: JoinHandle<()>,
+    // Our own pings and their interval, once `keep_alive` is called
+    pinger: Option<(Duration, JoinHandle<()>)>,
+    // Everything else the peer sends us
+    inbox_tx: mpsc::UnboundedSender<ControlMessage>,
+    inbox_rx: Option<mpsc::UnboundedReceiver<ControlMessage>>,
+    peer: PeerDevice,
+    identity: Identity,
+    node_name: String,
+    connector: Arc<dyn Connector>,
+    peer_info: NodeAdvertisement,
+    session_id: String,
+    stamp: Stamp,
+    state: SessionState,
+    // Told to the peer when reconnecting, so its pointer ends up where we left it
+    cursor: Option<CursorState>,
+}
+
+impl ControlClient {
+    /// Connect to `peer` and introduce ourselves as `node_id`/`node_name`
+    pub async fn connect(
+        peer: &PeerDevice,
+        identity: &Identity,
+        node_name: &str,
+        connector: &Arc<dyn Connector>,
+    ) -> Result<Self> {
+        let (link, peer_info, session_id, nonce) =
+            Self::establish(peer, identity, node_name, "", "", None, connector).await?;
+        let input = link.channels.open(Channel::Input).await?;
+        let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+        let peer_addr = link.peer_addr;
+        let health = LinkHealth::default();
+        let stamp = Stamp::new(nonce);
+        let checks_replays = negotiated(&peer_info).checks_replays();
+        let (writer, responder) =
+            Self::attach(link.control, link.frame, inbox_tx.clone(), health.clone(), stamp.clone(), checks_replays);
+
+        Ok(Self {
+            writer,
+            input,
+            motion: None,
+            motion_over_udp: false,
+            peer_addr,
+            channels: link.channels,
+            health,
+            responder,
+            pinger: None,
+            inbox_tx,
+            inbox_rx: Some(inbox_rx),
+            peer: peer.clone(),
+            identity: identity.clone(),
+            node_name: node_name.to_string(),
+            connector: connector.clone(),
+            peer_info,
+            session_id,
+            stamp,
+            state: SessionState::Connected,
+            cursor: None,
+        })
+    }
+
+    /// Open a connection and say hello, optionally asking to resume `session_id`
+    /// or, once the peer has closed it, the session `resume_token` was issued for.
+    /// A resuming hello carries the shared `cursor` as we last saw it.
+    /// Returns the session the peer put us in and the nonce it issued for it.
+    async fn establish(
+        peer: &PeerDevice,
+        identity: &Identity,
+        node_name: &str,
+        session_id: &str,
+        resume_token: &str,
+        cursor: Option<CursorState>,
+        connector: &Arc<dyn Connector>,
+    ) -> Result<(Link, NodeAdvertisement, String, Vec<u8>)> {
+        let mut link = Self::open(peer, connector).await?;
+
+        let hello = ControlMessage {
+            session_id: session_id.to_string(),
+            payload: Some(control_message::Payload::Advertisement(NodeAdvertisement {
+                node_id: identity.node_id().to_string(),
+                node_name: node_name.to_string(),
+                os_type: "linux".to_string(),
+                capabilities: Some(PeerCapabilities::local().into()),
+                displays: local_displays(),
+                resume_token: resume_token.to_string(),
+                protocol_version: PROTOCOL_VERSION.to_wire(),
+                software_version: env!("CARGO_PKG_VERSION").to_string(),
+                cursor,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let reply = Self::handshake(&mut link.control, link.frame, peer, hello, identity).await?;
+
+        let peer_info = match reply.payload {
+            Some(control_message::Payload::Advertisement(advertisement)) => advertisement,
+            Some(control_message::Payload::Error(error)) => {
+                bail!("{} refused the connection: {}", peer.node_name, error.message)
+            }
+            _ => bail!("Unexpected handshake reply from {}", peer.node_name),
+        };
+        PROTOCOL_VERSION.negotiate(ProtocolVersion::from_wire(peer_info.protocol_version))
+            .with_context(|| format!("{} runs mirage {}", peer.node_name, peer_info.software_version))?;
+
+        info!("🔗 Connected to {} at {} (session {})", peer.node_name, link.peer_addr, reply.session_id);
+
+        Ok((link, peer_info, reply.session_id, reply.nonce))
+    }
+
+    /// Split the stream, echo heartbeats from the read half and pass on the rest,
+    /// noting in `health` that the peer was heard from and the round trip of
+    /// probe replies on the way. A failed read reports the connection lost. With
+    /// `checks_replays`, messages without `stamp`'s nonce or with a sequence
+    /// number already seen on this connection are dropped.
+    fn attach(
+        stream: Box<dyn ByteStream>,
+        frame: Frame,
+        inbox: mpsc::UnboundedSender<ControlMessage>,
+        health: LinkHealth,
+        stamp: Stamp,
+        checks_replays: bool,
+    ) -> (Arc<Mutex<WriteHalf<Box<dyn ByteStream>>>>, JoinHandle<()>) {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(Mutex::new(writer));
+
+        let pong_writer = writer.clone();
+        let responder = tokio::spawn(async move {
+            let mut replay = ReplayWindow::default();
+            while let Ok(message) = read_frame::<ControlMessage, _>(&mut reader, frame).await {
+                health.unanswered.store(0, Ordering::Relaxed);
+                if checks_replays && !(message.nonce == stamp.nonce && replay.accept(message.sequence)) {
+                    warn!("⚠ Dropping replayed message #{} in session {}", message.sequence, message.session_id);
+                    continue;
+                }
+                match &message.payload {
+                    Some(control_message::Payload::SessionControl(control))
+                        if control.command() == session_control::Command::Heartbeat =>
+                    {
+                        let pong = stamp.apply(heartbeat(&message.session_id, control.timestamp_ms));
+                        if write_frame(&mut *pong_writer.lock().await, &pong).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(control_message::Payload::SessionControl(control))
+                        if control.command() == session_control::Command::Probe =>
+                    {
+                        let reply = stamp.apply(probe_reply(&message.session_id, control.timestamp_ms));
+                        if write_frame(&mut *pong_writer.lock().await, &reply).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(control_message::Payload::SessionControl(control))
+                        if control.command() == session_control::Command::ProbeReply =>
+                    {
+                        let now_ms = chrono::Utc::now().timestamp_millis() as u64;
+                        *health.rtt.lock() = Some(Duration::from_millis(now_ms.saturating_sub(control.timestamp_ms)));
+                        let _ = inbox.send(message);
+                    }
+                    _ => {
+                        let _ = inbox.send(message);
+                    }
+                }
+            }
+            health.lost.notify_one();
+        });
+
+        (writer, responder)
+    }
+
+    /// Re-establish a dropped connection with exponential backoff, resuming
+    /// the same session if the peer still has it
+    pub async fn reconnect(&mut self) -> Result<()> {
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            let delay = reconnect_delay(attempt);
+            self.state = SessionState::reconnecting(attempt);
+            debug!("Reconnecting to {} in {:?} (attempt {})", self.peer.node_name, delay, attempt + 1);
+            tokio::time::sleep(delay).await;
+
+            let resume_token = self.peer_info.resume_token.clone();
+            match Self::establish(
+                &self.peer,
+                &self.identity,
+                &self.node_name,
+                &self.session_id,
+                &resume_token,
+                self.cursor.clone(),
+                &self.connector,
+            ).await {
+                Ok((link, peer_info, session_id, nonce)) => {
+                    if session_id != self.session_id {
+                        warn!("{} did not resume session {}, continuing in {}",
+                            self.peer.node_name, self.session_id, session_id);
+                    }
+                    // Every connection gets a nonce of its own, numbered from 1 again
+                    self.stamp = Stamp::new(nonce);
+                    self.input = match link.channels.open(Channel::Input).await {
+                        Ok(input) => input,
+                        Err(e) => {
+                            debug!("Reconnecting to {} failed: {:#}", self.peer.node_name, e);
+                            continue;
+                        }
+                    };
+                    self.responder.abort();
+                    self.peer_addr = link.peer_addr;
+                    self.channels = link.channels;
+                    *self.health.rtt.lock() = None;
+                    self.health.unanswered.store(0, Ordering::Relaxed);
+                    let checks_replays = negotiated(&peer_info).checks_replays();
+                    (self.writer, self.responder) = Self::attach(
+                        link.control, link.frame, self.inbox_tx.clone(), self.health.clone(), self.stamp.clone(), checks_replays,
+                    );
+                    self.peer_info = peer_info;
+                    self.session_id = session_id;
+                    self.state = SessionState::Connected;
+                    if let Some((interval, _)) = self.pinger {
+                        self.keep_alive(interval);
+                    }
+                    if self.motion_over_udp {
+                        if let Err(e) = self.open_motion().await {
+                            warn!("⚠ Sending motion to {} reliably: {:#}", self.peer.node_name, e);
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => debug!("Reconnecting to {} failed: {:#}", self.peer.node_name, e),
+            }
+        }
+
+        self.state = SessionState::Disconnected;
+        bail!("Gave up reconnecting to {} after {} attempts", self.peer.node_name, RECONNECT_ATTEMPTS)
+    }
+
+    /// Ping the peer every `interval` from now on. Once `MISSED_HEARTBEATS` go
+    /// unanswered in a row the connection is reported lost, see `lost`.
+    pub fn keep_alive(&mut self, interval: Duration) {
+        if let Some((_, pinger)) = self.pinger.take() {
+            pinger.abort();
+        }
+        let health = self.health.clone();
+        let writer = self.writer.clone();
+        let stamp = self.stamp.clone();
+        let session_id = self.session_id.clone();
+        let peer = self.peer.node_name.clone();
+        let pinger = tokio::spawn(async move {
+            let mut ping = tokio::time::interval(interval);
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ping.tick().await;
+            loop {
+                ping.tick().await;
+                let missed = health.unanswered.fetch_add(1, Ordering::Relaxed);
+                if missed >= MISSED_HEARTBEATS {
+                    warn!("Peer {} missed {} heartbeats", peer, missed);
+                    health.lost.notify_one();
+                    return;
+                }
+                // Peers answer probes; anything they send counts as an answer
+                let probe = stamp.apply(ControlMessage {
+                    session_id: session_id.clone(),
+                    payload: Some(control_message::Payload::SessionControl(SessionControl {
+                        command: session_control::Command::Probe as i32,
+                        timestamp_ms: chrono::Utc::now().timestamp_millis() as u64,
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                });
+                let _ = tokio::time::timeout(SEND_TIMEOUT, async {
+                    write_frame(&mut *writer.lock().await, &probe).await
+                }).await;
+            }
+        });
+        self.pinger = Some((interval, pinger));
+    }
+
+    /// Notified when the connection is found dead: the peer closed it, or
+    /// stopped answering the pings of `keep_alive`
+    pub fn lost(&self) -> Arc<Notify> {
+        self.health.lost.clone()
+    }
+
+    /// Record where the shared cursor is, for the hello if we have to reconnect
+    pub fn set_cursor(&mut self, cursor: CursorState) {
+        self.cursor = Some(cursor);
+    }
+
+    /// Pair with `peer` using the PIN it displayed after a refused `connect`
+    pub async fn pair(
+        peer: &PeerDevice,
+        identity: &Identity,
+        node_name: &str,
+        pin: &str,
+        connector: &Arc<dyn Connector>,
+    ) -> Result<()> {
+        let mut link = Self::open(peer, connector).await?;
+
+        let request = ControlMessage {
+            payload: Some(control_message::Payload::PairingRequest(PairingRequest {
+                initiator_node_id: identity.node_id().to_string(),
+                initiator_name: node_name.to_string(),
+                public_key: identity.public_key().to_vec(),
+                pairing_code: pin.to_string(),
+                timestamp_ms: chrono::Utc::now().timestamp_millis() as u64,
+            })),
+            ..Default::default()
+        };
+        let reply = Self::handshake(&mut link.control, link.frame, peer, request, identity).await?;
+        let _ = link.control.shutdown().await;
+
+        match reply.payload {
+            Some(control_message::Payload::PairingResponse(response))
+                if response.status() == pairing_response::Status::Accepted =>
+            {
+                info!("✓ Paired with {}", peer.node_name);
+                Ok(())
+            }
+            Some(control_message::Payload::PairingResponse(_)) => {
+                bail!("{} rejected the PIN", peer.node_name)
+            }
+            _ => bail!("Unexpected pairing reply from {}", peer.node_name),
+        }
+    }
+
+    /// Try the peer's addresses in preference order until one connects
+    async fn open(peer: &PeerDevice, connector: &Arc<dyn Connector>) -> Result<Link> {
+        let mut last_error = None;
+
+        for addr in socket_addrs(peer) {
+            match tokio::time::timeout(CONNECT_TIMEOUT, connector.connect(addr)).await {
+                Ok(Ok(link)) => return Ok(link),
+                Ok(Err(e)) => {
+                    debug!("Connecting to {} at {} failed: {:#}", peer.node_name, addr, e);
+                    last_error = Some(e);
+                }
+                Err(_) => {
+                    debug!("Connecting to {} at {} timed out", peer.node_name, addr);
+                    last_error = Some(anyhow::anyhow!("Timed out connecting to {}", addr));
+                }
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("No known addresses"))
+            .context(format!("Could not reach {}", peer.node_name)))
+    }
+
+    /// Send `hello` and return the peer's reply. A peer we paired with first
+    /// challenges us to prove we hold the key we paired with.
+    async fn handshake(
+        stream: &mut Box<dyn ByteStream>,
+        frame: Frame,
+        peer: &PeerDevice,
+        hello: ControlMessage,
+        identity: &Identity,
+    ) -> Result<ControlMessage> {
+        write_frame(stream, &hello).await?;
+
+        let reply: ControlMessage = tokio::time::timeout(CONNECT_TIMEOUT, read_frame(stream, frame))
+            .await
+            .with_context(|| format!("{} did not answer the handshake", peer.node_name))??;
+        let Some(control_message::Payload::AuthChallenge(ref challenge)) = reply.payload else {
+            return Ok(reply);
+        };
+
+        let answer = ControlMessage {
+            payload: Some(control_message::Payload::AuthResponse(AuthResponse {
+                signature: identity.sign_challenge(&challenge.nonce),
+            })),
+            ..Default::default()
+        };
+        write_frame(stream, &answer).await?;
+        tokio::time::timeout(CONNECT_TIMEOUT, read_frame(stream, frame))
+            .await
+            .with_context(|| format!("{} did not answer the handshake", peer.node_name))?
+    }
+
+    /// What the peer told us about itself during the handshake
+    pub fn peer_info(&self) -> &NodeAdvertisement {
+        &self.peer_info
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Protocol version agreed with the peer; `establish` refused any other major version
+    pub fn protocol(&self) -> ProtocolVersion {
+        negotiated(&self.peer_info)
+    }
+
+    /// Nonce the peer issued for this session
+    pub fn nonce(&self) -> &[u8] {
+        &self.stamp.nonce
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Messages the peer sends us other than heartbeats. Can only be taken once.
+    pub fn take_inbox(&mut self) -> Option<mpsc::UnboundedReceiver<ControlMessage>> {
+        self.inbox_rx.take()
+    }
+
+    /// Send mouse motion over UDP from now on if the peer takes it there.
+    /// Everything else stays on the control connection.
+    pub async fn enable_motion_over_udp(&mut self) -> Result<()> {
+        self.motion_over_udp = true;
+        self.open_motion().await
+    }
+
+    async fn open_motion(&mut self) -> Result<()> {
+        self.motion = None;
+        let port = self.peer_info.motion_port as u16;
+        if port == 0 || self.peer_info.motion_key.is_empty() {
+            debug!("{} doesn't take motion over UDP", self.peer.node_name);
+            return Ok(());
+        }
+
+        // Keep the scope of link-local addresses
+        let mut addr = self.peer_addr;
+        addr.set_port(port);
+        let motion_id = self.peer_info.motion_id.clone();
+        self.motion = Some(MotionSender::connect(addr, motion_id, &self.peer_info.motion_key).await?);
+        debug!("Sending motion to {} over UDP at {}", self.peer.node_name, addr);
+        Ok(())
+    }
+
+    /// Forward one input event. Fails if the connection has dropped; `reconnect`
+    /// brings it back.
+    pub async fn send(&mut self, event: &InputEvent) -> Result<()> {
+        if let Some(ref mut motion) = self.motion {
+            if motion::is_motion(event) {
+                match motion.send(event).await {
+                    Ok(bytes) => {
+                        metrics().bytes_sent(&self.session_id, bytes);
+                        return Ok(());
+                    }
+                    Err(e) => debug!("Motion datagram to {} failed, sending it reliably: {:#}", self.peer.node_name, e),
+                }
+            }
+        }
+        let message = InputMessage::try_from(event.clone())?;
+        self.send_message(control_message::Payload::Input(message)).await
+    }
+
+    /// Send any control payload. Fails if the connection has dropped; `reconnect`
+    /// brings it back.
+    pub async fn send_message(&mut self, payload: control_message::Payload) -> Result<()> {
+        self.send_payload(payload).await
+    }
+
+    /// Measure one round trip to the peer. Replies arrive through the inbox, so
+    /// this doesn't work once `take_inbox` has been called.
+    pub async fn probe(&mut self) -> Result<Duration> {
+        let timestamp_ms = chrono::Utc::now().timestamp_millis() as u64;
+        let probe = SessionControl {
+            command: session_control::Command::Probe as i32,
+            timestamp_ms,
+            ..Default::default()
+        };
+
+        let started = std::time::Instant::now();
+        self.send_payload(control_message::Payload::SessionControl(probe)).await?;
+
+        let peer = self.peer.node_name.clone();
+        let Some(inbox) = self.inbox_rx.as_mut() else {
+            bail!("Probe replies from {} are consumed elsewhere", peer);
+        };
+        let reply = async {
+            while let Some(message) = inbox.recv().await {
+                if let Some(control_message::Payload::SessionControl(control)) = message.payload {
+                    if control.command() == session_control::Command::ProbeReply
+                        && control.timestamp_ms == timestamp_ms
+                    {
+                        return Ok(started.elapsed());
+                    }
+                }
+            }
+            bail!("Connection to {} closed", peer)
+        };
+        tokio::time::timeout(PROBE_TIMEOUT, reply)
+            .await
+            .with_context(|| format!("No probe reply from {} within {:?}", peer, PROBE_TIMEOUT))?
+    }
+
+    /// How the connection is doing, for adapting the stream to it. QUIC keeps
+    /// its own statistics; on TCP this sends a probe and reports the round trip
+    /// of the previous one, so the first call has nothing yet.
+    pub async fn link_stats(&mut self) -> Result<Option<PathStats>> {
+        if let Some(stats) = self.channels.stats() {
+            return Ok(Some(stats));
+        }
+
+        let probe = SessionControl {
+            command: session_control::Command::Probe as i32,
+            timestamp_ms: chrono::Utc::now().timestamp_millis() as u64,
+            ..Default::default()
+        };
+        self.send_payload(control_message::Payload::SessionControl(probe)).await?;
+        Ok(self.health.rtt.lock().map(|rtt| PathStats { rtt, ..Default::default() }))
+    }
+
+    /// Tell the peer we're leaving, then shut the connection down
+    pub async fn close(&mut self) -> Result<()> {
+        if let Some((_, pinger)) = self.pinger.take() {
+            pinger.abort();
+        }
+        let disconnect = SessionControl {
+            command: session_control::Command::Disconnect as i32,
+            ..Default::default()
+        };
+        self.send_payload(control_message::Payload::SessionControl(disconnect)).await?;
+        self.writer.lock().await.shutdown().await?;
+
+        info!("Disconnected from {}", self.peer.node_name);
+        Ok(())
+    }
+
+    async fn send_payload(&mut self, payload: control_message::Payload) -> Result<()> {
+        let message = self.stamp.apply(ControlMessage {
+            session_id: self.session_id.clone(),
+            payload: Some(payload),
+            ..Default::default()
+        });
+        let write = async {
+            match (&message.payload, self.input.as_mut()) {
+                (Some(control_message::Payload::Input(_)), Some(input)) => write_frame(input, &message).await,
+                _ => write_frame(&mut *self.writer.lock().await, &message).await,
+            }
+        };
+        let written = match tokio::time::timeout(SEND_TIMEOUT, write).await {
+            Ok(written) => written,
+            Err(_) => Err(anyhow::anyhow!("Timed out after {:?}", SEND_TIMEOUT)),
+        };
+        let bytes = written.with_context(|| format!("Failed to send to {}", self.peer.node_name))?;
+        metrics().bytes_sent(&self.session_id, bytes);
+        Ok(())
+    }
+}
+
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        self.responder.abort();
+        if let Some((_, ref pinger)) = self.pinger {
+            pinger.abort();
+        }
+    }
+}
+
+/// How a client's connection is doing, as its read half and pings see it
+#[derive(Clone, Default)]
+struct LinkHealth {
+    // Round trip of the latest probe reply, for transports without stats
+    rtt: Arc<parking_lot::Mutex<Option<Duration>>>,
+    // Pings sent since the peer was last heard from
+    unanswered: Arc<AtomicU32>,
+    lost: Arc<Notify>,
+}
+
+/// Protocol version agreed with a peer that answered the handshake with
+/// `peer_info`; `establish` refused any other major version
+fn negotiated(peer_info: &NodeAdvertisement) -> ProtocolVersion {
+    PROTOCOL_VERSION.min(ProtocolVersion::from_wire(peer_info.protocol_version))
+}
+
+/// Numbers a sender's control messages and tags them with the session nonce.
+/// Clones count on together, so pongs and payloads never share a number.
+#[derive(Clone)]
+struct Stamp {
+    nonce: Vec<u8>,
+    sequence: Arc<AtomicU32>,
+}
+
+impl Stamp {
+    fn new(nonce: Vec<u8>) -> Self {
+        Self { nonce, sequence: Arc::new(AtomicU32::new(0)) }
+    }
+
+    fn apply(&self, mut message: ControlMessage) -> ControlMessage {
+        message.sequence = self.sequence.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        message.nonce = self.nonce.clone();
+        message
+    }
+}
+
+/// Sequence numbers taken from a peer: the next one expected past the newest,
+/// and which of the `REPLAY_WINDOW` before it have arrived. Anything older
+/// than that, or seen before, is a replay.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayWindow {
+    expected: u32,
+    // Bit n is set once `expected - 1 - n` has been taken
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn expected(&self) -> u32 {
+        self.expected
+    }
+
+    /// Take `sequence` if it hasn't been before, and report whether it was new
+    pub fn accept(&mut self, sequence: u32) -> bool {
+        let ahead = sequence.wrapping_sub(self.expected) as i32;
+        if ahead >= 0 {
+            let shift = ahead as u32 + 1;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.expected = sequence.wrapping_add(1);
+            return true;
+        }
+
+        let behind = ahead.unsigned_abs() - 1;
+        if behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// Socket addresses for a peer. Link-local IPv6 addresses need an interface
+/// scope, so they're tried on every interface that has one.
+fn socket_addrs(peer: &PeerDevice) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+
+    for ip in &peer.addresses {
+        match ip {
+            IpAddr::V6(v6) if is_ipv6_link_local(ip) => {
+                for scope in link_local_scopes() {
+                    addrs.push(SocketAddr::V6(SocketAddrV6::new(*v6, peer.control_port, 0, scope)));
+                }
+            }
+            _ => addrs.push(SocketAddr::new(*ip, peer.control_port)),
+        }
+    }
+
+    addrs
+}
+
+fn link_local_scopes() -> Vec<u32> {
+    let Ok(interfaces) = local_ip_address::list_afinet_netifas() else {
+        return Vec::new();
+    };
+
+    let mut scopes: Vec<u32> = interfaces.iter()
+        .filter(|(_, ip)| is_ipv6_link_local(ip))
+        .filter_map(|(name, _)| CString::new(name.as_str()).ok())
+        .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) })
+        .filter(|&index| index != 0)
+        .collect();
+    scopes.dedup();
+    scopes
+}
+
+/// Length-prefixed framing for control messages, the same on both ends: a
+/// 4-byte big-endian length followed by the encoded message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    max_size: usize,
+}
+
+impl Frame {
+    /// Frames refuse anything over `max_size` bytes, so a peer can't make us
+    /// allocate whatever length it claims
+    pub fn new(max_size: usize) -> Self {
+        Self { max_size }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+/// Read one frame and decode it. Oversized frames are refused before their
+/// body is read.
+pub async fn read_frame<M: Message + Default, R: AsyncRead + Unpin>(reader: &mut R, frame: Frame) -> Result<M> {
+    let len = reader.read_u32().await? as usize;
+    if len > frame.max_size {
+        bail!("Frame of {} bytes exceeds the {} byte limit", len, frame.max_size);
+    }
+
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    M::decode(buf.as_slice()).context("Malformed frame")
+}
+
+/// Pass messages arriving on the link's channel streams on with the ones from
+/// the control stream
+async fn forward_channels(channels: Channels, frame: Frame, messages: mpsc::Sender<Result<ControlMessage>>) {
+    while let Ok((channel, mut stream)) = channels.accept().await {
+        debug!("Peer opened the {:?} channel", channel);
+        let messages = messages.clone();
+        tokio::spawn(async move {
+            // A closed channel stream is not a lost connection; the control stream decides that
+            while let Ok(message) = read_frame(&mut stream, frame).await {
+                if messages.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Write one message with the framing `read_frame` expects, returning the
+/// number of bytes written
+pub async fn write_frame<M: Message, W: AsyncWrite + Unpin>(writer: &mut W, message: &M) -> Result<usize> {
+    let buf = message.encode_to_vec();
+    let len = u32::try_from(buf.len()).context("Message too large to frame")?;
+    writer.write_u32(len).await?;
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(4 + buf.len())
+}
+
+/// The displays to advertise. Peers map the cursor onto the first, so it is the
+/// screen edges are detected on; streams can capture any of them by name.
+fn local_displays() -> Vec<node_advertisement::DisplayInfo> {
+    let (width, height) = input::detect_screen_size().unwrap_or(input::FALLBACK_SCREEN_SIZE);
+    let outputs = platform::outputs();
+    let mut displays = vec![node_advertisement::DisplayInfo {
+        width,
+        height,
+        name: outputs.first().map(|output| output.name.clone()).unwrap_or_default(),
+        ..Default::default()
+    }];
+    displays.extend(outputs.into_iter().skip(1).map(|output| node_advertisement::DisplayInfo {
+        width: output.width,
+        height: output.height,
+        name: output.name,
+        ..Default::default()
+    }));
+    displays
+}
+
+/// An error report refusing a connection, as the only reply to its hello
+fn error_reply(code: error_report::Code, message: &str, component: &str) -> ControlMessage {
+    ControlMessage {
+        payload: Some(control_message::Payload::Error(ErrorReport {
+            code: code as i32,
+            message: message.to_string(),
+            component: component.to_string(),
+            timestamp_ms: chrono::Utc::now().timestamp_millis() as u64,
+        })),
+        ..Default::default()
+    }
+}
+
+fn heartbeat(session_id: &str, timestamp_ms: u64) -> ControlMessage {
+    ControlMessage {
+        session_id: session_id.to_string(),
+        payload: Some(control_message::Payload::SessionControl(SessionControl {
+            command: session_control::Command::Heartbeat as i32,
+            timestamp_ms,
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+fn probe_reply(session_id: &str, timestamp_ms: u64) -> ControlMessage {
+    ControlMessage {
+        session_id: session_id.to_string(),
+        payload: Some(control_message::Payload::SessionControl(SessionControl {
+            command: session_control::Command::ProbeReply as i32,
+            timestamp_ms,
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+fn is_disconnect(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>()
+        .map(|e| matches!(e.kind(),
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::BrokenPipe))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::StreamRequest;
+    use crate::transport::BoxFuture;
+
+    /// Connects straight into `handle_connection` over an in-memory pipe. A new
+    /// connection drops the one before, like a link that went down.
+    struct Loopback {
+        session_manager: SessionManager,
+        server: parking_lot::Mutex<Option<JoinHandle<Result<()>>>>,
+    }
+
+    impl Loopback {
+        fn connector(session_manager: &SessionManager) -> Arc<dyn Connector> {
+            Arc::new(Self { session_manager: session_manager.clone(), server: parking_lot::Mutex::new(None) })
+        }
+    }
+
+    impl Connector for Loopback {
+        fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, Result<Link>> {
+            Box::pin(async move {
+                let link = |stream: tokio::io::DuplexStream| Link {
+                    control: Box::new(stream),
+                    peer_addr: addr,
+                    channels: Channels::default(),
+                    frame: Frame::default(),
+                };
+                let (ours, theirs) = tokio::io::duplex(64 * 1024);
+                let server = tokio::spawn(ControlServer::handle_connection(
+                    link(theirs),
+                    self.session_manager.clone(),
+                    None,
+                    None,
+                    TokenBucket::new(10_000, 10_000),
+                    Shutdown::new(),
+                ));
+                if let Some(previous) = self.server.lock().replace(server) {
+                    previous.abort();
+                }
+                Ok(link(ours))
+            })
+        }
+    }
+
+    /// A peer that completes the handshake, then reads everything and answers nothing
+    struct Silent;
+
+    impl Connector for Silent {
+        fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, Result<Link>> {
+            Box::pin(async move {
+                let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+                tokio::spawn(async move {
+                    let _hello: ControlMessage = read_frame(&mut theirs, Frame::default()).await?;
+                    let reply = ControlMessage {
+                        session_id: "silent".to_string(),
+                        payload: Some(control_message::Payload::Advertisement(NodeAdvertisement {
+                            protocol_version: PROTOCOL_VERSION.to_wire(),
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    };
+                    write_frame(&mut theirs, &reply).await?;
+                    while read_frame::<ControlMessage, _>(&mut theirs, Frame::default()).await.is_ok() {}
+                    Ok::<_, anyhow::Error>(())
+                });
+                Ok(Link { control: Box::new(ours), peer_addr: addr, channels: Channels::default(), frame: Frame::default() })
+            })
+        }
+    }
+
+    fn loopback_peer() -> PeerDevice {
+        PeerDevice {
+            node_id: "host".to_string(),
+            node_name: "host".to_string(),
+            os_type: "linux".to_string(),
+            addresses: vec![IpAddr::from([127, 0, 0, 1])],
+            control_port: 8443,
+            capabilities: PeerCapabilities::local(),
+            extra: HashMap::new(),
+            last_seen: std::time::Instant::now(),
+            online: true,
+            manual: false,
+        }
+    }
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mirage-network-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// Send a stream request and wait for the host to act on it, which it only
+    /// does for messages that pass the replay check
+    async fn gets_through(
+        client: &mut ControlClient,
+        requests: &mut tokio::sync::broadcast::Receiver<(String, StreamRequest)>,
+        stream_id: &str,
+    ) -> bool {
+        let request = StreamRequest { stream_id: stream_id.to_string(), ..Default::default() };
+        client.send_message(control_message::Payload::StreamRequest(request)).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                match requests.recv().await {
+                    Ok((_, request)) if request.stream_id == stream_id => return true,
+                    Ok(_) => {}
+                    Err(_) => return false,
+                }
+            }
+        });
+        received.await.unwrap_or(false)
+    }
+
+    fn heartbeat_frame() -> Vec<u8> {
+        let mut buf = Vec::new();
+        let message = heartbeat("session", 42);
+        let len = message.encoded_len() as u32;
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&message.encode_to_vec());
+        buf
+    }
+
+    #[tokio::test]
+    async fn frame_round_trips() {
+        let message = heartbeat("session", 42);
+        let mut buf = Vec::new();
+        let written = write_frame(&mut buf, &message).await.unwrap();
+        assert_eq!(written, buf.len());
+        assert_eq!(buf, heartbeat_frame());
+
+        let read: ControlMessage = read_frame(&mut buf.as_slice(), Frame::default()).await.unwrap();
+        assert_eq!(read, message);
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_an_eof() {
+        let mut buf = heartbeat_frame();
+        buf.truncate(buf.len() - 1);
+
+        let error = read_frame::<ControlMessage, _>(&mut buf.as_slice(), Frame::default()).await.unwrap_err();
+        assert!(is_disconnect(&error));
+    }
+
+    #[tokio::test]
+    async fn truncated_length_is_an_eof() {
+        let buf = [0u8, 0];
+        let error = read_frame::<ControlMessage, _>(&mut buf.as_slice(), Frame::default()).await.unwrap_err();
+        assert!(is_disconnect(&error));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_refused() {
+        // Only the length prefix is there; the body must never be waited for
+        let buf = (DEFAULT_MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        let error = read_frame::<ControlMessage, _>(&mut buf.as_slice(), Frame::default()).await.unwrap_err();
+        assert!(!is_disconnect(&error));
+        assert!(error.to_string().contains("exceeds"));
+    }
+
+    #[tokio::test]
+    async fn frame_limit_is_configurable() {
+        let buf = heartbeat_frame();
+        let frame = Frame::new(buf.len() - 5);
+        assert!(read_frame::<ControlMessage, _>(&mut buf.as_slice(), frame).await.is_err());
+
+        let frame = Frame::new(buf.len() - 4);
+        assert!(read_frame::<ControlMessage, _>(&mut buf.as_slice(), frame).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_an_error() {
+        let mut buf = 3u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0xff, 0xff, 0xff]);
+        let error = read_frame::<ControlMessage, _>(&mut buf.as_slice(), Frame::default()).await.unwrap_err();
+        assert!(error.to_string().contains("Malformed"));
+    }
+
+    #[test]
+    fn replay_window_takes_each_sequence_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        // Late, but inside the window
+        assert!(window.accept(2));
+        assert!(!window.accept(1));
+        assert_eq!(window.expected(), 4);
+
+        assert!(window.accept(3 + REPLAY_WINDOW));
+        assert!(!window.accept(3));
+        assert!(window.accept(4));
+    }
+
+    #[test]
+    fn replay_window_survives_wrapping() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(u32::MAX));
+        assert!(window.accept(0));
+        assert!(!window.accept(u32::MAX));
+        assert_eq!(window.expected(), 1);
+    }
+
+    #[tokio::test]
+    async fn resumed_session_takes_input_again() {
+        let manager = SessionManager::new(Config::default(), "host".to_string()).await.unwrap();
+        let mut requests = manager.subscribe_stream_requests();
+        let connector = Loopback::connector(&manager);
+        let dir = temp_dir();
+        let identity = Identity::load_or_create(&dir.join("identity.toml")).unwrap();
+        let mut client = ControlClient::connect(&loopback_peer(), &identity, "viewer", &connector).await.unwrap();
+        let session_id = client.session_id().to_string();
+        let first_nonce = client.nonce().to_vec();
+
+        // Well past the replay window
+        let key = InputEvent::KeyPress { key_code: 30, pressed: true, scan_code: None };
+        for _ in 0..2 * REPLAY_WINDOW {
+            client.send(&key).await.unwrap();
+        }
+        assert!(gets_through(&mut client, &mut requests, "before").await);
+
+        client.reconnect().await.unwrap();
+        assert_eq!(client.session_id(), session_id);
+        assert_ne!(client.nonce(), first_nonce.as_slice());
+        let session = manager.get_session(&session_id).await.unwrap();
+        assert_eq!(session.nonce, client.nonce());
+
+        client.send(&key).await.unwrap();
+        assert!(gets_through(&mut client, &mut requests, "after").await);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_that_stops_answering_is_lost() {
+        let dir = temp_dir();
+        let identity = Identity::load_or_create(&dir.join("identity.toml")).unwrap();
+        let interval = Duration::from_millis(20);
+
+        // A live peer answers every ping
+        let manager = SessionManager::new(Config::default(), "host".to_string()).await.unwrap();
+        let mut client =
+            ControlClient::connect(&loopback_peer(), &identity, "viewer", &Loopback::connector(&manager)).await.unwrap();
+        client.keep_alive(interval);
+        let lost = client.lost();
+        assert!(tokio::time::timeout(interval * 10, lost.notified()).await.is_err());
+        assert!(client.link_stats().await.unwrap().is_some(), "pings measure the round trip");
+
+        let silent: Arc<dyn Connector> = Arc::new(Silent);
+        let mut client = ControlClient::connect(&loopback_peer(), &identity, "viewer", &silent).await.unwrap();
+        client.keep_alive(interval);
+        let lost = client.lost();
+        tokio::time::timeout(interval * (MISSED_HEARTBEATS + 3), lost.notified())
+            .await
+            .expect("a silent peer wasn't noticed");
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,185 @@
+// Which display server the host runs under. evdev input works with either, but
+// screen capture, the clipboard and virtual input go through different APIs:
+// the xdg-desktop-portal and data-control protocols on Wayland, Xlib on X11.
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{info, debug, warn};
+
+use crate::input;
+
+static DISPLAY_SERVER: OnceCell<Option<DisplayServer>> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayServer {
+    Wayland,
+    X11,
+}
+
+impl fmt::Display for DisplayServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisplayServer::Wayland => "Wayland",
+            DisplayServer::X11 => "X11",
+        })
+    }
+}
+
+/// The display server of the session we were started in. XWayland sets
+/// DISPLAY as well, so WAYLAND_DISPLAY wins; XDG_SESSION_TYPE is the fallback
+/// for services started before either is exported.
+pub fn detect() -> Option<DisplayServer> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return Some(DisplayServer::Wayland);
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        return Some(DisplayServer::X11);
+    }
+    match std::env::var("XDG_SESSION_TYPE").ok()?.as_str() {
+        "wayland" => Some(DisplayServer::Wayland),
+        "x11" => Some(DisplayServer::X11),
+        _ => None,
+    }
+}
+
+/// Settle the display server for the rest of the run: `forced` (from
+/// `host.display_server`) or whatever `detect` finds. Only the first call counts.
+pub fn init(forced: Option<DisplayServer>) -> Option<DisplayServer> {
+    *DISPLAY_SERVER.get_or_init(|| {
+        let detected = detect();
+        match (forced, detected) {
+            (Some(forced), Some(detected)) if forced != detected => {
+                warn!("⚠ Using the {} backends as configured, but this looks like a {} session", forced, detected);
+            }
+            (Some(forced), _) => info!("✓ Display server: {} (configured)", forced),
+            (None, Some(detected)) => info!("✓ Display server: {}", detected),
+            (None, None) => warn!("⚠ No display server found; capture and clipboard sync are unavailable"),
+        }
+        forced.or(detected)
+    })
+}
+
+/// The display server picked by `init`, or the detected one if it wasn't called
+pub fn display_server() -> Option<DisplayServer> {
+    *DISPLAY_SERVER.get_or_init(detect)
+}
+
+/// One monitor of the desktop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Connector name, e.g. "DP-1"
+    pub name: String,
+    /// Top-left corner on the desktop. Only X11 tells clients where outputs are.
+    pub position: Option<(i32, i32)>,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+}
+
+/// The monitors of the desktop, primary first. X11 lists them through xrandr;
+/// otherwise they are the connected DRM connectors.
+pub fn outputs() -> Vec<Output> {
+    if display_server() == Some(DisplayServer::X11) {
+        match xrandr_monitors() {
+            Ok(outputs) if !outputs.is_empty() => return outputs,
+            Ok(_) => debug!("xrandr listed no monitors"),
+            Err(e) => debug!("Listing monitors with xrandr failed: {:#}", e),
+        }
+    }
+    drm_outputs()
+}
+
+/// Connected DRM connectors at their preferred modes, in connector order, the
+/// first counting as primary. Works without a connection to the display server.
+pub fn drm_outputs() -> Vec<Output> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/drm") else {
+        return Vec::new();
+    };
+    let mut connectors: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("status").exists())
+        .collect();
+    connectors.sort();
+
+    let mut outputs: Vec<Output> = connectors.iter()
+        .filter(|path| {
+            std::fs::read_to_string(path.join("status"))
+                .map(|status| status.trim() == "connected")
+                .unwrap_or(false)
+        })
+        .filter_map(|path| {
+            // The first listed mode is the preferred one
+            let modes = std::fs::read_to_string(path.join("modes")).ok()?;
+            let (width, height) = input::parse_mode(modes.lines().next()?)?;
+            // "card0-DP-1" is connector DP-1 of card0
+            let name = path.file_name()?.to_str()?;
+            let name = name.split_once('-').map_or(name, |(_, connector)| connector);
+            Some(Output { name: name.to_string(), position: None, width, height, primary: false })
+        })
+        .collect();
+    if let Some(first) = outputs.first_mut() {
+        first.primary = true;
+    }
+    outputs
+}
+
+fn xrandr_monitors() -> Result<Vec<Output>> {
+    let output = std::process::Command::new("xrandr")
+        .arg("--listmonitors")
+        .output()
+        .context("Failed to run xrandr")?;
+    if !output.status.success() {
+        bail!("xrandr failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(parse_monitors(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The monitors in `xrandr --listmonitors` output, primary first
+pub fn parse_monitors(listing: &str) -> Vec<Output> {
+    let mut outputs: Vec<Output> = listing.lines().filter_map(parse_monitor).collect();
+    // Stable, so the others keep xrandr's order
+    outputs.sort_by_key(|output| !output.primary);
+    outputs
+}
+
+// One monitor line, e.g. " 1: +*HDMI-1 1920/527x1080/296+2560+0  HDMI-1", where
+// the sizes after the slashes are in millimetres
+fn parse_monitor(line: &str) -> Option<Output> {
+    let mut fields = line.split_whitespace();
+    fields.next()?.strip_suffix(':')?.parse::<u32>().ok()?;
+    let flagged_name = fields.next()?;
+    let (width, rest) = fields.next()?.split_once('x')?;
+    let width = width.split('/').next()?.parse().ok()?;
+    let (height, rest) = rest.split_once('/')?;
+    let mut offsets = rest.split('+').skip(1);
+    let position = (offsets.next()?.parse().ok()?, offsets.next()?.parse().ok()?);
+
+    Some(Output {
+        name: flagged_name.trim_start_matches(['+', '*']).to_string(),
+        position: Some(position),
+        width,
+        height: height.parse().ok()?,
+        primary: flagged_name.contains('*'),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xrandr_monitors_are_listed_primary_first() {
+        let listing = "Monitors: 2\n \
+                       0: +HDMI-1 1920/527x1080/296+2560+0  HDMI-1\n \
+                       1: +*DP-1 2560/597x1440/336+0+0  DP-1\n";
+        let outputs = parse_monitors(listing);
+        assert_eq!(outputs, vec![
+            Output { name: "DP-1".to_string(), position: Some((0, 0)), width: 2560, height: 1440, primary: true },
+            Output { name: "HDMI-1".to_string(), position: Some((2560, 0)), width: 1920, height: 1080, primary: false },
+        ]);
+    }
+}
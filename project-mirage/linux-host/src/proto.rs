@@ -0,0 +1,363 @@
+// Protobuf wire types generated from common/proto/mirage.proto by build.rs,
+// plus conversions between them and the host's own input types
+
+use anyhow::{anyhow, bail, Error, Result};
+use std::fmt;
+
+use crate::clipboard::{ClipboardContents, Selection};
+use crate::discovery::PeerCapabilities;
+use crate::input::{self, InputEvent, MouseButton, ScreenEdge};
+
+include!("proto/mirage.protocol.rs");
+
+/// The wire protocol this host speaks. Peers must share the major version; a
+/// newer minor version only adds fields older peers ignore.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 2 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    /// Unpack the `protocol_version` field of an advertisement. Peers from
+    /// before versioning send nothing, which reads as 0.0.
+    pub fn from_wire(value: u32) -> Self {
+        Self { major: (value >> 16) as u16, minor: value as u16 }
+    }
+
+    pub fn to_wire(self) -> u32 {
+        (self.major as u32) << 16 | self.minor as u32
+    }
+
+    /// The version to speak with a peer running `peer`: the older minor
+    /// version of a shared major one
+    pub fn negotiate(self, peer: ProtocolVersion) -> Result<ProtocolVersion> {
+        if self.major != peer.major {
+            bail!("protocol version {} is incompatible with ours ({})", peer, self);
+        }
+        Ok(self.min(peer))
+    }
+
+    /// Whether messages carry the session nonce and are checked for replays,
+    /// which peers started doing in 1.1
+    pub fn checks_replays(self) -> bool {
+        self >= ProtocolVersion { major: 1, minor: 1 }
+    }
+
+    /// Whether UDP motion carries an opaque ID and a signature, which peers
+    /// started doing in 1.2
+    pub fn signs_motion(self) -> bool {
+        self >= ProtocolVersion { major: 1, minor: 2 }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl TryFrom<InputEvent> for InputMessage {
+    type Error = Error;
+
+    fn try_from(event: InputEvent) -> Result<Self> {
+        let event = match event {
+            InputEvent::MouseMove { delta_x, delta_y } => input_message::Event::Mouse(MouseEvent {
+                r#type: mouse_event::Type::Move as i32,
+                delta_x,
+                delta_y,
+                ..Default::default()
+            }),
+            InputEvent::MouseButton { button, pressed } => {
+                let r#type = if pressed { mouse_event::Type::ButtonDown } else { mouse_event::Type::ButtonUp };
+                let button = match button {
+                    MouseButton::Left => mouse_event::Button::Left,
+                    MouseButton::Right => mouse_event::Button::Right,
+                    MouseButton::Middle => mouse_event::Button::Middle,
+                    MouseButton::Back => mouse_event::Button::Back,
+                    MouseButton::Forward => mouse_event::Button::Forward,
+                };
+                input_message::Event::Mouse(MouseEvent {
+                    r#type: r#type as i32,
+                    button: button as i32,
+                    ..Default::default()
+                })
+            }
+            InputEvent::MouseWheel { delta, horizontal } => input_message::Event::Mouse(MouseEvent {
+                r#type: mouse_event::Type::Wheel as i32,
+                wheel_delta: delta,
+                horizontal,
+                ..Default::default()
+            }),
+            InputEvent::MouseAbsolute { x, y } => input_message::Event::Mouse(MouseEvent {
+                r#type: mouse_event::Type::Absolute as i32,
+                x,
+                y,
+                ..Default::default()
+            }),
+            InputEvent::KeyPress { key_code, pressed, scan_code } => {
+                let r#type = if pressed { keyboard_event::Type::KeyDown } else { keyboard_event::Type::KeyUp };
+                input_message::Event::Keyboard(KeyboardEvent {
+                    r#type: r#type as i32,
+                    key_code,
+                    virtual_key: input::hid_usage(evdev::Key::new(key_code as u16)).unwrap_or(0),
+                    scan_code: scan_code.unwrap_or(0),
+                    ..Default::default()
+                })
+            }
+            InputEvent::EdgeCrossed { edge, position: (x, y) } => {
+                let edge = edge_crossing::Edge::from(edge);
+                input_message::Event::EdgeCrossed(EdgeCrossing { edge: edge as i32, x, y })
+            }
+            InputEvent::ForceLocal => bail!("The force-local hotkey is never sent to peers"),
+        };
+
+        Ok(InputMessage { event: Some(event) })
+    }
+}
+
+impl TryFrom<InputMessage> for InputEvent {
+    type Error = Error;
+
+    fn try_from(message: InputMessage) -> Result<Self> {
+        match message.event.ok_or_else(|| anyhow!("Input message has no event"))? {
+            input_message::Event::Mouse(mouse) => {
+                let r#type = mouse_event::Type::try_from(mouse.r#type)
+                    .map_err(|_| anyhow!("Unknown mouse event type {}", mouse.r#type))?;
+                match r#type {
+                    mouse_event::Type::Move => Ok(InputEvent::MouseMove {
+                        delta_x: mouse.delta_x,
+                        delta_y: mouse.delta_y,
+                    }),
+                    mouse_event::Type::ButtonDown | mouse_event::Type::ButtonUp => {
+                        let button = match mouse_event::Button::try_from(mouse.button) {
+                            Ok(mouse_event::Button::Left) => MouseButton::Left,
+                            Ok(mouse_event::Button::Right) => MouseButton::Right,
+                            Ok(mouse_event::Button::Middle) => MouseButton::Middle,
+                            Ok(mouse_event::Button::Back) => MouseButton::Back,
+                            Ok(mouse_event::Button::Forward) => MouseButton::Forward,
+                            Err(_) => bail!("Unknown mouse button {}", mouse.button),
+                        };
+                        Ok(InputEvent::MouseButton {
+                            button,
+                            pressed: r#type == mouse_event::Type::ButtonDown,
+                        })
+                    }
+                    mouse_event::Type::Wheel => Ok(InputEvent::MouseWheel {
+                        delta: mouse.wheel_delta,
+                        horizontal: mouse.horizontal,
+                    }),
+                    mouse_event::Type::Absolute => Ok(InputEvent::MouseAbsolute {
+                        x: mouse.x,
+                        y: mouse.y,
+                    }),
+                }
+            }
+            input_message::Event::Keyboard(key) => {
+                let r#type = keyboard_event::Type::try_from(key.r#type)
+                    .map_err(|_| anyhow!("Unknown keyboard event type {}", key.r#type))?;
+                // The HID usage doesn't depend on the peer's OS; its own
+                // keycode is only used for keys without one. A scancode that
+                // is a HID usage names the physical key, whatever the peer's
+                // layout made of it.
+                let key_code = [key.scan_code, key.virtual_key]
+                    .into_iter()
+                    .find_map(input::key_for_hid_usage)
+                    .map_or(key.key_code, |local| local.code() as u32);
+                Ok(InputEvent::KeyPress {
+                    key_code,
+                    pressed: r#type == keyboard_event::Type::KeyDown,
+                    scan_code: Some(key.scan_code).filter(|code| *code != 0),
+                })
+            }
+            input_message::Event::EdgeCrossed(crossing) => {
+                let edge = screen_edge(crossing.edge)?;
+                Ok(InputEvent::EdgeCrossed { edge, position: (crossing.x, crossing.y) })
+            }
+        }
+    }
+}
+
+impl From<ScreenEdge> for edge_crossing::Edge {
+    fn from(edge: ScreenEdge) -> Self {
+        match edge {
+            ScreenEdge::Left => edge_crossing::Edge::Left,
+            ScreenEdge::Right => edge_crossing::Edge::Right,
+            ScreenEdge::Top => edge_crossing::Edge::Top,
+            ScreenEdge::Bottom => edge_crossing::Edge::Bottom,
+        }
+    }
+}
+
+/// Decode a wire `EdgeCrossing.Edge` value
+pub fn screen_edge(value: i32) -> Result<ScreenEdge> {
+    match edge_crossing::Edge::try_from(value) {
+        Ok(edge_crossing::Edge::Left) => Ok(ScreenEdge::Left),
+        Ok(edge_crossing::Edge::Right) => Ok(ScreenEdge::Right),
+        Ok(edge_crossing::Edge::Top) => Ok(ScreenEdge::Top),
+        Ok(edge_crossing::Edge::Bottom) => Ok(ScreenEdge::Bottom),
+        Err(_) => bail!("Unknown screen edge {}", value),
+    }
+}
+
+impl From<PeerCapabilities> for node_advertisement::Capabilities {
+    fn from(capabilities: PeerCapabilities) -> Self {
+        Self {
+            can_host_mouse: capabilities.can_host_mouse,
+            can_capture_windows: capabilities.can_capture_windows,
+            can_render_streams: capabilities.can_render_streams,
+            video_codecs: capabilities.video_codecs,
+            audio_codecs: Vec::new(),
+        }
+    }
+}
+
+impl From<node_advertisement::Capabilities> for PeerCapabilities {
+    fn from(capabilities: node_advertisement::Capabilities) -> Self {
+        Self {
+            can_host_mouse: capabilities.can_host_mouse,
+            can_capture_windows: capabilities.can_capture_windows,
+            can_render_streams: capabilities.can_render_streams,
+            video_codecs: capabilities.video_codecs,
+        }
+    }
+}
+
+impl From<ClipboardContents> for ClipboardUpdate {
+    fn from(contents: ClipboardContents) -> Self {
+        let selection = match contents.selection {
+            Selection::Clipboard => clipboard_update::Selection::Clipboard,
+            Selection::Primary => clipboard_update::Selection::Primary,
+        };
+        Self {
+            selection: selection as i32,
+            origin_node_id: contents.origin_node_id,
+            text: contents.text,
+            timestamp_ms: chrono::Utc::now().timestamp_millis() as u64,
+        }
+    }
+}
+
+impl TryFrom<ClipboardUpdate> for ClipboardContents {
+    type Error = Error;
+
+    fn try_from(update: ClipboardUpdate) -> Result<Self> {
+        let selection = match clipboard_update::Selection::try_from(update.selection) {
+            Ok(clipboard_update::Selection::Clipboard) => Selection::Clipboard,
+            Ok(clipboard_update::Selection::Primary) => Selection::Primary,
+            Err(_) => bail!("Unknown clipboard selection {}", update.selection),
+        };
+        Ok(Self {
+            selection,
+            origin_node_id: update.origin_node_id,
+            text: update.text,
+        })
+    }
+}
+
+impl StreamResponse {
+    /// The answer to a StreamRequest for `stream_id`
+    pub fn answer(stream_id: &str, status: stream_response::Status, error_message: impl Into<String>) -> Self {
+        Self {
+            status: status as i32,
+            stream_id: stream_id.to_string(),
+            error_message: error_message.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn round_trip(event: InputEvent) -> InputEvent {
+        let bytes = InputMessage::try_from(event).unwrap().encode_to_vec();
+        let decoded = InputMessage::decode(bytes.as_slice()).unwrap();
+        InputEvent::try_from(decoded).unwrap()
+    }
+
+    #[test]
+    fn mouse_move_round_trips() {
+        let event = InputEvent::MouseMove { delta_x: 3.0, delta_y: -2.0 };
+        assert_eq!(round_trip(event.clone()), event);
+    }
+
+    #[test]
+    fn all_event_kinds_round_trip() {
+        let events = [
+            InputEvent::MouseButton { button: MouseButton::Forward, pressed: true },
+            InputEvent::MouseButton { button: MouseButton::Left, pressed: false },
+            InputEvent::MouseWheel { delta: -1.5, horizontal: true },
+            InputEvent::KeyPress { key_code: 30, pressed: true, scan_code: None },
+            InputEvent::KeyPress { key_code: 30, pressed: false, scan_code: Some(0x07_0004) },
+            InputEvent::EdgeCrossed { edge: ScreenEdge::Bottom, position: (640.0, 1079.0) },
+        ];
+
+        for event in events {
+            assert_eq!(round_trip(event.clone()), event);
+        }
+    }
+
+    #[test]
+    fn keys_arrive_by_hid_usage() {
+        // Windows' virtual key for the left Windows key, with its HID usage
+        let message = InputMessage {
+            event: Some(input_message::Event::Keyboard(KeyboardEvent {
+                r#type: keyboard_event::Type::KeyDown as i32,
+                key_code: 0x5B,
+                virtual_key: 0x07_00E3,
+                ..Default::default()
+            })),
+        };
+        let event = InputEvent::KeyPress {
+            key_code: evdev::Key::KEY_LEFTMETA.code() as u32,
+            pressed: true,
+            scan_code: None,
+        };
+        assert_eq!(InputEvent::try_from(message).unwrap(), event);
+    }
+
+    #[test]
+    fn hid_scancodes_name_the_physical_key() {
+        // The key at A's position, which the sender's layout made a Q
+        let message = InputMessage {
+            event: Some(input_message::Event::Keyboard(KeyboardEvent {
+                r#type: keyboard_event::Type::KeyDown as i32,
+                key_code: evdev::Key::KEY_Q.code() as u32,
+                virtual_key: 0x07_0014,
+                scan_code: 0x07_0004,
+                ..Default::default()
+            })),
+        };
+        let event = InputEvent::KeyPress {
+            key_code: evdev::Key::KEY_A.code() as u32,
+            pressed: true,
+            scan_code: Some(0x07_0004),
+        };
+        assert_eq!(InputEvent::try_from(message).unwrap(), event);
+    }
+
+    #[test]
+    fn empty_message_is_rejected() {
+        assert!(InputEvent::try_from(InputMessage { event: None }).is_err());
+    }
+
+    #[test]
+    fn protocol_version_round_trips() {
+        let version = ProtocolVersion { major: 3, minor: 7 };
+        assert_eq!(ProtocolVersion::from_wire(version.to_wire()), version);
+    }
+
+    #[test]
+    fn protocol_negotiation_needs_the_same_major_version() {
+        let ours = ProtocolVersion { major: 1, minor: 2 };
+        assert_eq!(ours.negotiate(ProtocolVersion { major: 1, minor: 0 }).unwrap(), ProtocolVersion { major: 1, minor: 0 });
+        assert_eq!(ours.negotiate(ProtocolVersion { major: 1, minor: 5 }).unwrap(), ours);
+        assert!(ours.negotiate(ProtocolVersion { major: 2, minor: 0 }).is_err());
+        assert!(ours.negotiate(ProtocolVersion::from_wire(0)).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+// Records the local input stream with its timing, so it can be exported or
+// replayed once recording stops
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::input::InputEvent;
+
+/// An input event with its offset from the start of the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub at: Duration,
+    pub event: InputEvent,
+}
+
+/// Collects input events in the order they arrive, stamped with how long
+/// after the start of the recording each came
+pub struct Recorder {
+    started: Instant,
+    events: Vec<RecordedEvent>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: InputEvent) {
+        self.events.push(RecordedEvent { at: self.started.elapsed(), event });
+    }
+
+    /// Record from `events` until `stop` resolves or the stream closes
+    pub async fn record(
+        mut self,
+        events: &mut broadcast::Receiver<InputEvent>,
+        stop: impl Future<Output = ()>,
+    ) -> Vec<RecordedEvent> {
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                event = events.recv() => match event {
+                    Ok(event) => self.push(event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Recording fell behind, {} events were not recorded", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+        self.events
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,267 @@
+// Export a recording (see `record`) as a standalone xdotool/ydotool shell script
+
+use evdev::Key;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::input::{InputEvent, MouseButton};
+use crate::record::RecordedEvent;
+
+// Gaps shorter than this are not worth a `sleep`
+const MIN_SLEEP: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScriptTool {
+    /// X11 only, uses keysym names
+    Xdotool,
+    /// Works on Wayland and X11 via /dev/uinput, uses Linux keycodes
+    Ydotool,
+}
+
+/// Render a recording as a POSIX shell script, preserving order and timing
+pub fn render(events: &[RecordedEvent], tool: ScriptTool) -> String {
+    let mut script = String::new();
+    let _ = writeln!(script, "#!/bin/sh");
+    let _ = writeln!(script, "# Recorded by mirage-host v{} ({} events)",
+        env!("CARGO_PKG_VERSION"), events.len());
+    let _ = writeln!(script, "set -e");
+    let _ = writeln!(script);
+
+    let mut last = Duration::ZERO;
+    for recorded in events {
+        let Some(command) = command_for(&recorded.event, tool) else {
+            continue;
+        };
+
+        let gap = recorded.at.saturating_sub(last);
+        if gap >= MIN_SLEEP {
+            let _ = writeln!(script, "sleep {:.3}", gap.as_secs_f64());
+        }
+        last = recorded.at;
+
+        let _ = writeln!(script, "{}", command);
+    }
+
+    script
+}
+
+fn command_for(event: &InputEvent, tool: ScriptTool) -> Option<String> {
+    match (tool, event) {
+        (ScriptTool::Xdotool, InputEvent::MouseMove { delta_x, delta_y }) => Some(format!(
+            "xdotool mousemove_relative -- {} {}",
+            delta_x.round() as i32, delta_y.round() as i32
+        )),
+        (ScriptTool::Xdotool, InputEvent::MouseAbsolute { x, y }) => Some(format!(
+            "xdotool mousemove {} {}",
+            x.round() as i32, y.round() as i32
+        )),
+        (ScriptTool::Xdotool, InputEvent::MouseButton { button, pressed }) => Some(format!(
+            "xdotool {} {}",
+            if *pressed { "mousedown" } else { "mouseup" },
+            x11_button(*button)
+        )),
+        (ScriptTool::Xdotool, InputEvent::MouseWheel { delta, horizontal }) => {
+            // X11 models the wheel as buttons 4/5 (vertical) and 6/7 (horizontal)
+            let button = match (*horizontal, *delta > 0.0) {
+                (false, true) => 4,
+                (false, false) => 5,
+                (true, true) => 7,
+                (true, false) => 6,
+            };
+            let clicks = delta.abs().round().max(1.0) as u32;
+            Some(format!("xdotool click --repeat {} {}", clicks, button))
+        }
+        (ScriptTool::Xdotool, InputEvent::KeyPress { key_code, pressed, .. }) => {
+            let key = Key::new(*key_code as u16);
+            Some(match keysym(key) {
+                Some(keysym) => format!(
+                    "xdotool {} {}",
+                    if *pressed { "keydown" } else { "keyup" },
+                    keysym
+                ),
+                None => format!("# no keysym for {:?}", key),
+            })
+        }
+
+        (ScriptTool::Ydotool, InputEvent::MouseMove { delta_x, delta_y }) => Some(format!(
+            "ydotool mousemove -x {} -y {}",
+            delta_x.round() as i32, delta_y.round() as i32
+        )),
+        (ScriptTool::Ydotool, InputEvent::MouseAbsolute { x, y }) => Some(format!(
+            "ydotool mousemove --absolute -x {} -y {}",
+            x.round() as i32, y.round() as i32
+        )),
+        (ScriptTool::Ydotool, InputEvent::MouseButton { button, pressed }) => {
+            // ydotool click codes: low nibble is the button, 0x40 = down, 0x80 = up
+            let state = if *pressed { 0x40 } else { 0x80 };
+            Some(format!("ydotool click 0x{:02X}", ydotool_button(*button) | state))
+        }
+        (ScriptTool::Ydotool, InputEvent::MouseWheel { delta, horizontal }) => {
+            let delta = delta.round() as i32;
+            Some(if *horizontal {
+                format!("ydotool mousemove -w -x {} -y 0", delta)
+            } else {
+                format!("ydotool mousemove -w -x 0 -y {}", delta)
+            })
+        }
+        (ScriptTool::Ydotool, InputEvent::KeyPress { key_code, pressed, .. }) => Some(format!(
+            "ydotool key {}:{}",
+            key_code, *pressed as u8
+        )),
+
+        // Edge crossings and hotkeys are Mirage-specific and have no replay equivalent
+        (_, InputEvent::EdgeCrossed { .. } | InputEvent::ForceLocal) => None,
+    }
+}
+
+fn x11_button(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 1,
+        MouseButton::Middle => 2,
+        MouseButton::Right => 3,
+        MouseButton::Back => 8,
+        MouseButton::Forward => 9,
+    }
+}
+
+fn ydotool_button(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0x00,
+        MouseButton::Right => 0x01,
+        MouseButton::Middle => 0x02,
+        MouseButton::Back => 0x03,
+        MouseButton::Forward => 0x04,
+    }
+}
+
+/// X keysym name for a Linux key, covering the keys xdotool scripts commonly need
+fn keysym(key: Key) -> Option<String> {
+    let named = match key {
+        Key::KEY_ENTER => "Return",
+        Key::KEY_ESC => "Escape",
+        Key::KEY_BACKSPACE => "BackSpace",
+        Key::KEY_TAB => "Tab",
+        Key::KEY_SPACE => "space",
+        Key::KEY_MINUS => "minus",
+        Key::KEY_EQUAL => "equal",
+        Key::KEY_LEFTBRACE => "bracketleft",
+        Key::KEY_RIGHTBRACE => "bracketright",
+        Key::KEY_SEMICOLON => "semicolon",
+        Key::KEY_APOSTROPHE => "apostrophe",
+        Key::KEY_GRAVE => "grave",
+        Key::KEY_BACKSLASH => "backslash",
+        Key::KEY_COMMA => "comma",
+        Key::KEY_DOT => "period",
+        Key::KEY_SLASH => "slash",
+        Key::KEY_LEFTSHIFT => "Shift_L",
+        Key::KEY_RIGHTSHIFT => "Shift_R",
+        Key::KEY_LEFTCTRL => "Control_L",
+        Key::KEY_RIGHTCTRL => "Control_R",
+        Key::KEY_LEFTALT => "Alt_L",
+        Key::KEY_RIGHTALT => "Alt_R",
+        Key::KEY_LEFTMETA => "Super_L",
+        Key::KEY_RIGHTMETA => "Super_R",
+        Key::KEY_CAPSLOCK => "Caps_Lock",
+        Key::KEY_UP => "Up",
+        Key::KEY_DOWN => "Down",
+        Key::KEY_LEFT => "Left",
+        Key::KEY_RIGHT => "Right",
+        Key::KEY_HOME => "Home",
+        Key::KEY_END => "End",
+        Key::KEY_PAGEUP => "Prior",
+        Key::KEY_PAGEDOWN => "Next",
+        Key::KEY_INSERT => "Insert",
+        Key::KEY_DELETE => "Delete",
+        _ => "",
+    };
+    if !named.is_empty() {
+        return Some(named.to_string());
+    }
+
+    // KEY_A -> a, KEY_7 -> 7, KEY_F5 -> F5
+    let name = format!("{:?}", key);
+    let suffix = name.strip_prefix("KEY_")?;
+    match suffix.len() {
+        1 => Some(suffix.to_ascii_lowercase()),
+        2 | 3 if suffix.starts_with('F') && suffix[1..].chars().all(|c| c.is_ascii_digit()) => {
+            Some(suffix.to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::ScreenEdge;
+    use crate::record::Recorder;
+    use tokio::sync::broadcast;
+
+    /// The lines that replay something, in order
+    fn commands(script: &str) -> Vec<&str> {
+        script.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("sleep"))
+            .filter(|line| *line != "set -e")
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recording_becomes_an_ordered_script() {
+        let (event_tx, mut events) = broadcast::channel(16);
+        let a = Key::KEY_A.code() as u32;
+        for event in [
+            InputEvent::MouseMove { delta_x: 5.0, delta_y: -3.0 },
+            InputEvent::MouseButton { button: MouseButton::Left, pressed: true },
+            InputEvent::MouseButton { button: MouseButton::Left, pressed: false },
+            InputEvent::EdgeCrossed { edge: ScreenEdge::Left, position: (0.0, 10.0) },
+            InputEvent::KeyPress { key_code: a, pressed: true, scan_code: None },
+            InputEvent::KeyPress { key_code: a, pressed: false, scan_code: None },
+        ] {
+            event_tx.send(event).unwrap();
+        }
+        drop(event_tx);
+
+        let recorded = Recorder::new().record(&mut events, std::future::pending()).await;
+        assert_eq!(recorded.len(), 6);
+        assert!(recorded.windows(2).all(|pair| pair[0].at <= pair[1].at));
+
+        let script = render(&recorded, ScriptTool::Xdotool);
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert_eq!(commands(&script), [
+            "xdotool mousemove_relative -- 5 -3",
+            "xdotool mousedown 1",
+            "xdotool mouseup 1",
+            "xdotool keydown a",
+            "xdotool keyup a",
+        ]);
+
+        let script = render(&recorded, ScriptTool::Ydotool);
+        assert_eq!(commands(&script), [
+            "ydotool mousemove -x 5 -y -3",
+            "ydotool click 0x40",
+            "ydotool click 0x80",
+            "ydotool key 30:1",
+            "ydotool key 30:0",
+        ]);
+    }
+
+    #[test]
+    fn pauses_become_sleeps() {
+        let at = |ms, event| RecordedEvent { at: Duration::from_millis(ms), event };
+        let recorded = [
+            at(0, InputEvent::MouseMove { delta_x: 1.0, delta_y: 0.0 }),
+            // Too close to the move to be worth a sleep
+            at(2, InputEvent::MouseButton { button: MouseButton::Right, pressed: true }),
+            at(502, InputEvent::MouseButton { button: MouseButton::Right, pressed: false }),
+        ];
+
+        let script = render(&recorded, ScriptTool::Xdotool);
+        let body: Vec<&str> = script.lines().skip_while(|line| !line.is_empty()).skip(1).collect();
+        assert_eq!(body, [
+            "xdotool mousemove_relative -- 1 0",
+            "xdotool mousedown 3",
+            "sleep 0.500",
+            "xdotool mouseup 3",
+        ]);
+    }
+}
@@ -1,11 +1,534 @@
-// Security layer - TLS/DTLS encryption and authentication
+// Security layer - TLS encryption and authentication for the control channel
 
-pub struct SecurityManager {
-    // TODO: Implement in Phase 0.1
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::SecurityConfig;
+
+/// Build the TLS config for incoming control connections from `cert_path`/`key_path`.
+/// Without them a self-signed certificate is generated, which is only allowed
+/// when pairing is disabled.
+pub fn server_config(config: &SecurityConfig) -> Result<Arc<ServerConfig>> {
+    let (certs, key) = match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = load_certs(cert_path)?;
+            let key = load_key(key_path)?;
+            info!("✓ Loaded TLS certificate from {}", cert_path);
+            (certs, key)
+        }
+        (None, None) if config.require_pairing => bail!(
+            "security.require_pairing is enabled but no certificate is configured; \
+             set security.cert_path and security.key_path"
+        ),
+        (None, None) => {
+            warn!("⚠ No TLS certificate configured, using a self-signed one");
+            self_signed()?
+        }
+        _ => bail!("security.cert_path and security.key_path must be set together"),
+    };
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(Arc::new(server_config))
+}
+
+/// Build the TLS config for outgoing control connections. Peer certificates are
+/// verified against `ca_path`; without it they can only be accepted unverified,
+/// which requires pairing to be disabled.
+pub fn client_config(config: &SecurityConfig) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    let client_config = match &config.ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(&cert).context("Invalid certificate in security.ca_path")?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        None if config.require_pairing => bail!(
+            "security.require_pairing is enabled but no trusted certificates are configured; \
+             set security.ca_path"
+        ),
+        None => {
+            warn!("⚠ No security.ca_path configured, peer certificates will not be verified");
+            builder
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+                .with_no_client_auth()
+        }
+    };
+
+    Ok(Arc::new(client_config))
+}
+
+// How long a PIN shown to the user stays valid
+const PIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Length of the nonce a paired peer signs on every connection
+const CHALLENGE_LEN: usize = 32;
+
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+/// This host's node ID and the Ed25519 key it proves itself with to peers it
+/// paired with. Kept in a file next to the config so both survive restarts.
+#[derive(Clone)]
+pub struct Identity {
+    node_id: String,
+    key: Arc<Ed25519KeyPair>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    node_id: String,
+    /// Hex-encoded PKCS#8
+    key: String,
 }
 
-impl SecurityManager {
-    pub fn new() -> Self {
-        Self {}
+impl Identity {
+    /// Load the identity in `path`, creating one on first start
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let file: IdentityFile = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            let key = from_hex(&file.key)
+                .and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(&pkcs8).ok())
+                .with_context(|| format!("Invalid key in {}", path.display()))?;
+            return Ok(Self { node_id: file.node_id, key: Arc::new(key) });
+        }
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate a node key"))?;
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|_| anyhow!("Failed to generate a node key"))?;
+        let file = IdentityFile {
+            node_id: Uuid::new_v4().to_string(),
+            key: to_hex(pkcs8.as_ref()),
+        };
+
+        let contents = toml::to_string_pretty(&file)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Holds the private key, so only we may read it
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut out| out.write_all(contents.as_bytes()))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        info!("✓ Created node identity {} in {}", file.node_id, path.display());
+        Ok(Self { node_id: file.node_id, key: Arc::new(key) })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Sent while pairing, and checked by the peer on every later connection
+    pub fn public_key(&self) -> &[u8] {
+        self.key.public_key().as_ref()
+    }
+
+    /// Answer a peer's `AuthChallenge`
+    pub fn sign_challenge(&self, nonce: &[u8]) -> Vec<u8> {
+        self.key.sign(&challenge_message(nonce, &self.node_id)).as_ref().to_vec()
+    }
+}
+
+/// A fresh nonce for a paired peer to sign
+pub fn challenge() -> Result<Vec<u8>> {
+    let mut nonce = vec![0u8; CHALLENGE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("Failed to generate a challenge"))?;
+    Ok(nonce)
+}
+
+// What a peer signs, so a signature can't be replayed as some other node's
+fn challenge_message(nonce: &[u8], node_id: &str) -> Vec<u8> {
+    [b"mirage-auth\0".as_slice(), nonce, node_id.as_bytes()].concat()
+}
+
+/// A peer the user has confirmed with a PIN
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairedPeer {
+    pub node_id: String,
+    pub name: String,
+    /// Hex-encoded Ed25519 key the peer presented while pairing, which it has
+    /// to prove it holds on every connection
+    pub public_key: String,
+    /// RFC 3339 timestamp
+    pub paired_at: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PairedPeersFile {
+    #[serde(default, rename = "peer")]
+    peers: Vec<PairedPeer>,
+}
+
+struct PendingPairing {
+    name: String,
+    pin: String,
+    expires: Instant,
+}
+
+/// Tracks paired peers, persisted to a TOML file next to the config
+#[derive(Clone)]
+pub struct PairingManager {
+    path: PathBuf,
+    paired: Arc<Mutex<Vec<PairedPeer>>>,
+    pending: Arc<Mutex<HashMap<String, PendingPairing>>>,
+}
+
+impl PairingManager {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let paired = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            toml::from_str::<PairedPeersFile>(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+                .peers
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path,
+            paired: Arc::new(Mutex::new(paired)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn is_paired(&self, node_id: &str) -> bool {
+        self.paired.lock().iter().any(|p| p.node_id == node_id)
+    }
+
+    /// Check that a paired peer signed `nonce` with the key it paired with.
+    /// A node ID alone is only a claim; anyone on the network can send one.
+    pub fn verify(&self, node_id: &str, nonce: &[u8], signature: &[u8]) -> Result<()> {
+        let public_key = {
+            let paired = self.paired.lock();
+            let Some(peer) = paired.iter().find(|p| p.node_id == node_id) else {
+                bail!("Not paired with {}", node_id);
+            };
+            from_hex(&peer.public_key)
+                .with_context(|| format!("Key stored for {} is not valid; pair it again", peer.name))?
+        };
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(&challenge_message(nonce, node_id), signature)
+            .map_err(|_| anyhow!("{} did not prove it holds its paired key", node_id))
+    }
+
+    /// Start pairing with an unknown peer. The PIN is shown locally and has to be
+    /// entered on the peer, which then sends it back in a `PairingRequest`.
+    pub fn begin(&self, node_id: &str, name: &str) -> Result<()> {
+        let mut pending = self.pending.lock();
+        if pending.get(node_id).is_some_and(|p| p.expires > Instant::now()) {
+            return Ok(());
+        }
+
+        let pin = generate_pin()?;
+        info!("🔑 Pairing requested by {} ({}). PIN: {}", name, node_id, pin);
+        info!("   Enter this PIN on {} within {}s to pair", name, PIN_TIMEOUT.as_secs());
+
+        pending.insert(node_id.to_string(), PendingPairing {
+            name: name.to_string(),
+            pin,
+            expires: Instant::now() + PIN_TIMEOUT,
+        });
+        Ok(())
+    }
+
+    /// Check the PIN a peer sent back and remember the peer on success.
+    /// A wrong PIN cancels the attempt.
+    pub fn confirm(&self, node_id: &str, pin: &str, public_key: &[u8]) -> Result<PairedPeer> {
+        let Some(pending) = self.pending.lock().remove(node_id) else {
+            bail!("No pairing in progress for {}", node_id);
+        };
+        if pending.expires <= Instant::now() {
+            bail!("PIN for {} expired", pending.name);
+        }
+        if pending.pin != pin {
+            bail!("Wrong PIN from {}", pending.name);
+        }
+        // Without a key later connections couldn't prove they come from this peer
+        if public_key.len() != ED25519_PUBLIC_KEY_LEN {
+            bail!("{} sent no usable public key", pending.name);
+        }
+
+        let peer = PairedPeer {
+            node_id: node_id.to_string(),
+            name: pending.name,
+            public_key: to_hex(public_key),
+            paired_at: chrono::Local::now().to_rfc3339(),
+        };
+
+        {
+            let mut paired = self.paired.lock();
+            paired.retain(|p| p.node_id != node_id);
+            paired.push(peer.clone());
+        }
+        self.save()?;
+
+        info!("✓ Paired with {}", peer.name);
+        Ok(peer)
+    }
+
+    pub fn list(&self) -> Vec<PairedPeer> {
+        self.paired.lock().clone()
+    }
+
+    /// Forget a paired peer. Returns false if it wasn't paired.
+    pub fn revoke(&self, node_id: &str) -> Result<bool> {
+        let removed = {
+            let mut paired = self.paired.lock();
+            let before = paired.len();
+            paired.retain(|p| p.node_id != node_id);
+            paired.len() != before
+        };
+
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = PairedPeersFile { peers: self.paired.lock().clone() };
+        let contents = toml::to_string_pretty(&file)?;
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, contents)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+fn generate_pin() -> Result<String> {
+    // Values from this bound up would make the low PINs more likely than the rest
+    const LIMIT: u32 = u32::MAX - u32::MAX % 1_000_000;
+
+    let rng = SystemRandom::new();
+    loop {
+        let mut bytes = [0u8; 4];
+        rng.fill(&mut bytes)
+            .map_err(|_| anyhow!("Failed to generate pairing PIN"))?;
+        let value = u32::from_le_bytes(bytes);
+        if value < LIMIT {
+            return Ok(format!("{:06}", value % 1_000_000));
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let pairs = hex.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let path = shellexpand::tilde(path).into_owned();
+    let file = std::fs::File::open(&path)
+        .with_context(|| format!("Failed to open certificate {}", path))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse certificate {}", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path);
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let path = shellexpand::tilde(path).into_owned();
+    let file = std::fs::File::open(&path)
+        .with_context(|| format!("Failed to open private key {}", path))?;
+
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key {}", path))?
+    {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    bail!("No private key found in {}", path)
+}
+
+/// Generate a throwaway certificate for this host's name and LAN addresses
+fn self_signed() -> Result<(Vec<Certificate>, PrivateKey)> {
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(hostname) = hostname::get() {
+        names.push(hostname.to_string_lossy().into_owned());
+    }
+    for ip in [local_ip_address::local_ip(), local_ip_address::local_ipv6()].into_iter().flatten() {
+        names.push(ip.to_string());
+    }
+
+    let cert = rcgen::generate_simple_self_signed(names)
+        .context("Failed to generate self-signed certificate")?;
+    let der = cert.serialize_der().context("Failed to serialize self-signed certificate")?;
+
+    Ok((vec![Certificate(der)], PrivateKey(cert.serialize_private_key_der())))
+}
+
+/// Used only when pairing is disabled: the connection is encrypted but the
+/// peer is not authenticated
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("mirage-security-{}", Uuid::new_v4()))
+    }
+
+    fn pin(pairing: &PairingManager, node_id: &str) -> String {
+        pairing.pending.lock()[node_id].pin.clone()
+    }
+
+    #[test]
+    fn pins_are_six_digits() {
+        for _ in 0..100 {
+            let pin = generate_pin().unwrap();
+            assert_eq!(pin.len(), 6);
+            assert!(pin.chars().all(|c| c.is_ascii_digit()), "{}", pin);
+        }
+    }
+
+    #[test]
+    fn pairing_takes_the_pin_and_can_be_revoked() {
+        let dir = temp_dir();
+        let path = dir.join("paired_peers.toml");
+        let pairing = PairingManager::load(path.clone()).unwrap();
+        let key = [7u8; ED25519_PUBLIC_KEY_LEN];
+
+        pairing.begin("laptop", "Laptop").unwrap();
+        let shown = pin(&pairing, "laptop");
+        let wrong = format!("{:06}", (shown.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert!(pairing.confirm("laptop", &wrong, &key).is_err());
+        // A wrong PIN cancels the attempt, so the right one no longer works either
+        assert!(pairing.confirm("laptop", &shown, &key).is_err());
+        assert!(!pairing.is_paired("laptop"));
+
+        pairing.begin("laptop", "Laptop").unwrap();
+        let shown = pin(&pairing, "laptop");
+        let peer = pairing.confirm("laptop", &shown, &key).unwrap();
+        assert_eq!(peer.name, "Laptop");
+        assert!(pairing.is_paired("laptop"));
+        assert!(PairingManager::load(path.clone()).unwrap().is_paired("laptop"));
+
+        assert!(pairing.revoke("laptop").unwrap());
+        assert!(!pairing.is_paired("laptop"));
+        assert!(!PairingManager::load(path).unwrap().is_paired("laptop"));
+        assert!(!pairing.revoke("laptop").unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_pin_is_refused() {
+        let dir = temp_dir();
+        let pairing = PairingManager::load(dir.join("paired_peers.toml")).unwrap();
+
+        pairing.begin("laptop", "Laptop").unwrap();
+        let shown = pin(&pairing, "laptop");
+        pairing.pending.lock().get_mut("laptop").unwrap().expires = Instant::now();
+        let refused = pairing.confirm("laptop", &shown, &[7u8; ED25519_PUBLIC_KEY_LEN]).unwrap_err();
+        assert!(refused.to_string().contains("expired"), "{}", refused);
+        assert!(!pairing.is_paired("laptop"));
+    }
+
+    #[test]
+    fn paired_peer_has_to_sign_with_its_key() {
+        let dir = temp_dir();
+        let laptop = Identity::load_or_create(&dir.join("laptop.toml")).unwrap();
+        let impostor = Identity::load_or_create(&dir.join("impostor.toml")).unwrap();
+        let pairing = PairingManager::load(dir.join("paired_peers.toml")).unwrap();
+
+        // No key, no pairing
+        pairing.begin(laptop.node_id(), "Laptop").unwrap();
+        let shown = pin(&pairing, laptop.node_id());
+        assert!(pairing.confirm(laptop.node_id(), &shown, &[]).is_err());
+
+        pairing.begin(laptop.node_id(), "Laptop").unwrap();
+        let shown = pin(&pairing, laptop.node_id());
+        pairing.confirm(laptop.node_id(), &shown, laptop.public_key()).unwrap();
+
+        let nonce = challenge().unwrap();
+        assert!(pairing.verify(laptop.node_id(), &nonce, &laptop.sign_challenge(&nonce)).is_ok());
+        // Claiming the laptop's node ID isn't enough
+        assert!(pairing.verify(laptop.node_id(), &nonce, &impostor.sign_challenge(&nonce)).is_err());
+        // Nor is replaying an answer to an earlier challenge
+        let other = challenge().unwrap();
+        assert!(pairing.verify(laptop.node_id(), &other, &laptop.sign_challenge(&nonce)).is_err());
+        assert!(pairing.verify(impostor.node_id(), &nonce, &impostor.sign_challenge(&nonce)).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn identity_survives_a_restart() {
+        let dir = temp_dir();
+        let path = dir.join("identity.toml");
+        let first = Identity::load_or_create(&path).unwrap();
+        let again = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.node_id(), again.node_id());
+        assert_eq!(first.public_key(), again.public_key());
+
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
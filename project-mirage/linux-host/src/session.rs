@@ -1,11 +1,95 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{info, debug};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, watch, Mutex, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, debug, warn, Instrument};
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::clipboard::{ClipboardContents, ClipboardSync};
+use crate::config::{Config, StreamingConfig};
+use crate::discovery::{PeerCapabilities, PeerDevice};
+use crate::input::{self, InputControl, InputEvent, ScreenEdge, VirtualInput};
+use crate::metrics::metrics;
+use crate::network::{ControlClient, ReplayWindow};
+use crate::transport::{BoxFuture, Connector, PathStats};
+use crate::proto::{self as wire, control_message, ControlMessage, ProtocolVersion};
+use crate::security::Identity;
+use crate::supervisor::Heartbeat;
+
+/// Reconnection attempts before a dropped session is closed
+pub const RECONNECT_ATTEMPTS: u32 = 6;
+
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Wait before reconnection attempt `attempt` (0-based): 1s, 2s, 4s, ... capped at 30s
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+// Codecs in order of preference when both sides support several
+const CODEC_PREFERENCE: [&str; 2] = ["h265", "h264"];
+
+/// Factors from our screen pixels to a peer screen of `peer_screen`
+fn screen_scale(peer_screen: Option<(u32, u32)>) -> (f32, f32) {
+    let Some((peer_width, peer_height)) = peer_screen else {
+        return (1.0, 1.0);
+    };
+    let (width, height) = input::detect_screen_size().unwrap_or(input::FALLBACK_SCREEN_SIZE);
+    (peer_width as f32 / width as f32, peer_height as f32 / height as f32)
+}
+
+/// `event` with its position converted to the peer's pixels
+fn scale_to_peer(event: &InputEvent, (scale_x, scale_y): (f32, f32)) -> InputEvent {
+    match *event {
+        InputEvent::MouseAbsolute { x, y } => InputEvent::MouseAbsolute { x: x * scale_x, y: y * scale_y },
+        InputEvent::EdgeCrossed { edge, position: (x, y) } => InputEvent::EdgeCrossed {
+            edge,
+            position: (x * scale_x, y * scale_y),
+        },
+        ref other => other.clone(),
+    }
+}
+
+/// Where `event`, already in the peer's pixels, leaves a cursor that was at
+/// `cursor`. Kept on a `screen` of known size; motion from an unknown
+/// position stays unknown.
+fn track_cursor(cursor: Option<(f32, f32)>, event: &InputEvent, screen: Option<(u32, u32)>) -> Option<(f32, f32)> {
+    let (x, y) = match *event {
+        InputEvent::EdgeCrossed { position, .. } => position,
+        InputEvent::MouseAbsolute { x, y } => (x, y),
+        InputEvent::MouseMove { delta_x, delta_y } => {
+            let (x, y) = cursor?;
+            (x + delta_x, y + delta_y)
+        }
+        _ => return cursor,
+    };
+    Some(match screen {
+        Some((width, height)) => (x.clamp(0.0, width as f32), y.clamp(0.0, height as f32)),
+        None => (x, y),
+    })
+}
+
+/// The best codec in `local` that `peer` can also decode, h265 over h264.
+/// `None` when there's no overlap.
+pub fn negotiate_codec(local: &[String], peer: &PeerCapabilities) -> Option<String> {
+    local.iter()
+        .filter(|codec| peer.supports_codec(codec))
+        .min_by_key(|codec| {
+            CODEC_PREFERENCE.iter()
+                .position(|preferred| codec.eq_ignore_ascii_case(preferred))
+                .unwrap_or(CODEC_PREFERENCE.len())
+        })
+        .cloned()
+}
 
 #[derive(Debug, Clone)]
 pub struct Session {
@@ -14,30 +98,363 @@ pub struct Session {
     pub peer_name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub last_activity: chrono::DateTime<chrono::Utc>,
-    pub mouse_owner: MouseOwner,
+    pub streaming: StreamingConfig,
+    pub state: SessionState,
+    /// Factors from our screen pixels to the peer's; 1.0 until its size is known
+    pub screen_scale: (f32, f32),
+    /// The peer's screen size, once known
+    pub peer_screen: Option<(u32, u32)>,
+    /// Where input we forwarded last left the cursor on the peer's screen, in
+    /// its pixels. Sent when the connection is resumed so the peer can put its
+    /// pointer back there.
+    pub cursor: Option<(f32, f32)>,
+    /// Lets the peer restore this session for a while after it closes. Empty
+    /// for sessions we opened, where the peer issues the token.
+    pub resume_token: String,
+    /// The peer had the mouse when its connection dropped, so resuming hands it back
+    pub held_mouse: bool,
+    /// Protocol version both sides agreed on in the handshake
+    pub protocol: ProtocolVersion,
+    /// Issued in the handshake reply; the peer's messages must carry it back.
+    /// Issued by the peer for sessions we opened.
+    pub nonce: Vec<u8>,
+    /// Sequence numbers already taken from the peer and the next one expected
+    pub replay: ReplayWindow,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionState {
+    Connected,
+    /// The connection dropped; the session is kept while the peer retries
+    Reconnecting {
+        attempt: u32,
+        next_attempt: chrono::DateTime<chrono::Utc>,
+    },
+    Disconnected,
+}
+
+impl Session {
+    /// The shared cursor as this session's peer should pick it up on resuming
+    fn cursor_state(&self, has_mouse: bool) -> wire::CursorState {
+        let (x, y) = self.cursor.unwrap_or_default();
+        wire::CursorState {
+            receiver_has_mouse: has_mouse,
+            position_known: self.cursor.is_some(),
+            x,
+            y,
+        }
+    }
+
+    /// Start a new connection's messages over: a fresh nonce, so nothing sent on
+    /// an earlier connection can be replayed into it, and a fresh window, since
+    /// the peer numbers its messages from 1 again
+    fn renew_nonce(&mut self) {
+        self.nonce = Uuid::new_v4().as_bytes().to_vec();
+        self.replay = ReplayWindow::default();
+    }
+}
+
+impl SessionState {
+    /// Reconnecting, with the next attempt due after the backoff for `attempt`
+    pub fn reconnecting(attempt: u32) -> Self {
+        let delay = chrono::Duration::from_std(reconnect_delay(attempt)).unwrap_or(chrono::Duration::zero());
+        SessionState::Reconnecting {
+            attempt,
+            next_attempt: chrono::Utc::now() + delay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MouseOwner {
     Local,
     Remote,
 }
 
+/// Session lifecycle changes, published to `SessionManager::subscribe`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Created {
+        session_id: String,
+        peer_node_id: String,
+        peer_name: String,
+    },
+    Resumed {
+        session_id: String,
+        peer_node_id: String,
+    },
+    /// The connection dropped; the peer may still reconnect
+    ConnectionLost {
+        session_id: String,
+        peer_node_id: String,
+    },
+    MouseTransferred {
+        session_id: String,
+        peer_node_id: String,
+        owner: MouseOwner,
+    },
+    /// The mouse went over to a peer's screen, for local cues such as a sound
+    /// or notification. Published after `MouseTransferred`.
+    MouseEntered {
+        session_id: String,
+        peer_node_id: String,
+        peer_name: String,
+    },
+    /// The mouse came back from a peer's screen. A peer whose connection drops
+    /// while it has the mouse is reported as `ConnectionLost` or `Closed` instead.
+    MouseLeft {
+        session_id: String,
+        peer_node_id: String,
+        peer_name: String,
+    },
+    HeartbeatLost {
+        session_id: String,
+        peer_node_id: String,
+        missed: u32,
+    },
+    Closed {
+        session_id: String,
+        peer_node_id: String,
+        reason: CloseReason,
+    },
+    /// The local screen locked; sessions stay open but get no input
+    InputPaused,
+    InputResumed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The peer sent a Disconnect
+    Disconnected,
+    HeartbeatLost,
+    /// A message couldn't be delivered even after reconnecting
+    SendFailed,
+    ReconnectFailed,
+    TimedOut,
+    OutsideAllowedHours,
+    /// This host is shutting down
+    Shutdown,
+    /// The peer sent messages faster than `security.max_message_rate`
+    RateLimited,
+    /// Closed over the local control socket
+    Requested,
+}
+
+impl CloseReason {
+    /// Whether the peer may still want the session back, i.e. the connection
+    /// failed rather than someone ending it
+    fn is_resumable(self) -> bool {
+        matches!(self, CloseReason::HeartbeatLost | CloseReason::SendFailed | CloseReason::ReconnectFailed)
+    }
+}
+
+/// A session that closed while its peer could still come back for it
+struct ClosedSession {
+    session: Session,
+    closed_at: Instant,
+}
+
+/// How this host's own screen is written in `[layout] grid`
+pub const LOCAL_SCREEN: &str = "local";
+
+/// A screen in the layout: ours, or a peer's by node ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Screen {
+    Local,
+    Peer(String),
+}
+
+impl Screen {
+    /// A `grid` entry; empty entries are gaps
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" => None,
+            LOCAL_SCREEN => Some(Screen::Local),
+            node_id => Some(Screen::Peer(node_id.to_string())),
+        }
+    }
+}
+
+/// Where the screens sit relative to each other. Either the peers past each
+/// edge of the local screen, by node ID, or a `grid` of rows of screens for
+/// arrangements that chain through peers, e.g. `[["a", "local", "c"]]`.
+/// Lives in the `[layout]` section of the config file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScreenLayout {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bottom: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grid: Vec<Vec<String>>,
+}
+
+impl ScreenLayout {
+    /// The screen past `edge` of `screen`
+    pub fn neighbor(&self, screen: &Screen, edge: ScreenEdge) -> Option<Screen> {
+        if let Some((row, column)) = self.grid_position(screen) {
+            let (row, column) = match edge {
+                ScreenEdge::Left => (row, column.checked_sub(1)?),
+                ScreenEdge::Right => (row, column + 1),
+                ScreenEdge::Top => (row.checked_sub(1)?, column),
+                ScreenEdge::Bottom => (row + 1, column),
+            };
+            return Screen::parse(self.grid.get(row)?.get(column)?);
+        }
+
+        match screen {
+            Screen::Local => self.edge(edge).clone().map(Screen::Peer),
+            // A peer named next to the local screen leads back to it
+            Screen::Peer(node_id) => {
+                (self.edge(edge.opposite()).as_deref() == Some(node_id.as_str())).then_some(Screen::Local)
+            }
+        }
+    }
+
+    pub fn set_neighbor(&mut self, edge: ScreenEdge, node_id: Option<String>) {
+        let slot = match edge {
+            ScreenEdge::Left => &mut self.left,
+            ScreenEdge::Right => &mut self.right,
+            ScreenEdge::Top => &mut self.top,
+            ScreenEdge::Bottom => &mut self.bottom,
+        };
+        *slot = node_id;
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.grid.is_empty() {
+            return Ok(());
+        }
+        if self.left.is_some() || self.right.is_some() || self.top.is_some() || self.bottom.is_some() {
+            bail!("layout.grid can't be combined with layout.left, right, top or bottom");
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        for name in self.grid.iter().flatten().filter(|name| !name.is_empty()) {
+            if !seen.insert(name.as_str()) {
+                bail!("layout.grid lists {:?} more than once", name);
+            }
+        }
+        if !seen.contains(LOCAL_SCREEN) {
+            bail!("layout.grid must include this host as {:?}", LOCAL_SCREEN);
+        }
+        Ok(())
+    }
+
+    fn edge(&self, edge: ScreenEdge) -> &Option<String> {
+        match edge {
+            ScreenEdge::Left => &self.left,
+            ScreenEdge::Right => &self.right,
+            ScreenEdge::Top => &self.top,
+            ScreenEdge::Bottom => &self.bottom,
+        }
+    }
+
+    fn grid_position(&self, screen: &Screen) -> Option<(usize, usize)> {
+        self.grid.iter().enumerate().find_map(|(row, screens)| {
+            let column = screens.iter().position(|name| Screen::parse(name).as_ref() == Some(screen))?;
+            Some((row, column))
+        })
+    }
+}
+
+/// What the session manager needs to open sessions to peers on its own
+#[derive(Clone)]
+struct Outgoing {
+    identity: Identity,
+    peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+    connector: Arc<dyn Connector>,
+}
+
+/// An outgoing connection, locked on its own so one slow peer holds up no other
+type SharedClient = Arc<Mutex<ControlClient>>;
+
+/// Cheap to clone; clones share the same session table
+#[derive(Clone)]
 pub struct SessionManager {
-    config: Config,
+    config: watch::Receiver<Config>,
     node_name: String,
     sessions: Arc<RwLock<HashMap<String, Session>>>,
+    /// The session whose peer currently controls the mouse; `None` means it's local
+    mouse_owner: Arc<RwLock<Option<String>>>,
+    /// Connections we opened, keyed by session ID; input is forwarded over these.
+    /// One that dropped is out of the table while it reconnects.
+    clients: Arc<Mutex<HashMap<String, SharedClient>>>,
+    /// Recently closed sessions by resumption token, kept for `security.resume_grace_secs`
+    closed: Arc<parking_lot::Mutex<HashMap<String, ClosedSession>>>,
+    /// Queues for connections peers opened to us, drained by `ControlServer`
+    outbound: Arc<parking_lot::Mutex<HashMap<String, mpsc::UnboundedSender<control_message::Payload>>>>,
+    outgoing: Option<Outgoing>,
+    heartbeat: Option<Heartbeat>,
+    input_control: Option<InputControl>,
+    virtual_input: Option<Arc<parking_lot::Mutex<VirtualInput>>>,
+    clipboard: Option<ClipboardSync>,
+    /// Pushes local clipboard changes to peers while any session is open
+    clipboard_task: Arc<parking_lot::Mutex<Option<JoinHandle<()>>>>,
+    /// Set while the local screen is locked, see `security.pause_on_lock`
+    input_paused: Arc<AtomicBool>,
+    events: broadcast::Sender<SessionEvent>,
+    /// StreamRequests from peers with the session they came on
+    stream_requests: broadcast::Sender<(String, wire::StreamRequest)>,
 }
 
 impl SessionManager {
     pub async fn new(config: Config, node_name: String) -> Result<Self> {
+        // Without `with_config_updates` the sender is gone and the config stays fixed
+        let (_, config) = watch::channel(config);
+        let (events, _) = broadcast::channel(100);
+        let (stream_requests, _) = broadcast::channel(16);
+
         Ok(Self {
             config,
             node_name,
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            mouse_owner: Arc::new(RwLock::new(None)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            outbound: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            outgoing: None,
+            heartbeat: None,
+            input_control: None,
+            virtual_input: None,
+            clipboard: None,
+            clipboard_task: Arc::new(parking_lot::Mutex::new(None)),
+            input_paused: Arc::new(AtomicBool::new(false)),
+            events,
+            stream_requests,
         })
     }
 
+    /// Follow config reloads (see `Config::watch`)
+    pub fn with_config_updates(mut self, updates: watch::Receiver<Config>) -> Self {
+        self.config = updates;
+        self
+    }
+
+    /// Let the manager connect to discovered peers itself, identifying as `identity`
+    pub fn with_outgoing(
+        mut self,
+        identity: Identity,
+        peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+        connector: Arc<dyn Connector>,
+    ) -> Self {
+        self.outgoing = Some(Outgoing { identity, peers, connector });
+        self
+    }
+
+    /// Report liveness to a supervisor on every loop iteration
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
     pub async fn run(self) -> Result<()> {
         info!("Session manager running...");
         
@@ -50,61 +467,1302 @@ impl SessionManager {
         
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+            if let Some(ref heartbeat) = self.heartbeat {
+                heartbeat.beat();
+            }
             
             // Clean up expired sessions
+            let security = self.config.borrow().security.clone();
+            if security.close_sessions_outside_hours
+                && !security.accepts_sessions_at(chrono::Local::now().naive_local())
+            {
+                let open: Vec<String> = self.sessions.read().await.keys().cloned().collect();
+                if !open.is_empty() {
+                    info!("Outside security.allowed_hours, closing {} active session(s)", open.len());
+                }
+                for session_id in open {
+                    self.close_session(&session_id, CloseReason::OutsideAllowedHours).await;
+                }
+            }
+
             let mut sessions = self.sessions.write().await;
+
             let now = chrono::Utc::now();
-            let timeout = chrono::Duration::minutes(self.config.security.session_timeout_minutes as i64);
-            
+            let timeout = chrono::Duration::minutes(security.session_timeout_minutes as i64);
+
+            let grace = Duration::from_secs(security.resume_grace_secs);
+            self.closed.lock().retain(|_, closed| closed.closed_at.elapsed() < grace);
+
+            // Give dropped peers one backoff interval per attempt to come back
+            for session in sessions.values_mut() {
+                if let SessionState::Reconnecting { attempt, next_attempt } = session.state {
+                    if now < next_attempt {
+                        continue;
+                    }
+                    if attempt + 1 >= RECONNECT_ATTEMPTS {
+                        info!(
+                            "Peer {} did not reconnect after {} attempts, closing session {}",
+                            session.peer_name, RECONNECT_ATTEMPTS, session.session_id
+                        );
+                        session.state = SessionState::Disconnected;
+                    } else {
+                        session.state = SessionState::reconnecting(attempt + 1);
+                    }
+                }
+            }
+
             sessions.retain(|_, session| {
                 let elapsed = now - session.last_activity;
-                if elapsed > timeout {
+                if session.state == SessionState::Disconnected {
+                    self.publish_closed(session, CloseReason::ReconnectFailed);
+                    self.keep_for_resume(session.clone(), CloseReason::ReconnectFailed);
+                    false
+                } else if elapsed > timeout {
                     debug!("Session {} timed out", session.session_id);
+                    self.publish_closed(session, CloseReason::TimedOut);
                     false
                 } else {
                     true
                 }
             });
+
+            metrics().set_active_sessions(sessions.len());
+            self.sync_clipboard(!sessions.is_empty());
+
+            // Don't leave the mouse with a session that was just cleaned up
+            let orphaned = self.mouse_owner.read().await.clone()
+                .filter(|owner| !sessions.contains_key(owner));
+            drop(sessions);
+            if let Some(owner) = orphaned {
+                self.release_mouse(&owner).await;
+            }
         }
     }
 
-    pub async fn create_session(&self, peer_node_id: String, peer_name: String) -> Result<Session> {
+    /// Grab local input while a peer owns the mouse, release it when it comes back
+    pub fn with_input_control(mut self, input_control: InputControl) -> Self {
+        self.input_control = Some(input_control);
+        self
+    }
+
+    /// Move the desktop pointer when the mouse comes back from a peer
+    pub fn with_virtual_input(mut self, virtual_input: VirtualInput) -> Self {
+        self.virtual_input = Some(Arc::new(parking_lot::Mutex::new(virtual_input)));
+        self
+    }
+
+    /// Share the clipboard with peers while at least one session is open
+    pub fn with_clipboard(mut self, clipboard: ClipboardSync) -> Self {
+        self.clipboard = Some(clipboard);
+        self
+    }
+
+    /// Lifecycle events for every session, e.g. for `SessionLogger`
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// StreamRequests from peers, with the session each came on. Peers are
+    /// told streaming isn't supported while nothing is subscribed.
+    pub fn subscribe_stream_requests(&self) -> broadcast::Receiver<(String, wire::StreamRequest)> {
+        self.stream_requests.subscribe()
+    }
+
+    fn publish(&self, event: SessionEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    fn publish_closed(&self, session: &Session, reason: CloseReason) {
+        self.publish(SessionEvent::Closed {
+            session_id: session.session_id.clone(),
+            peer_node_id: session.peer_node_id.clone(),
+            reason,
+        });
+    }
+
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    /// How often connections ping their peer, from the live config
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.config.borrow().security.heartbeat_interval_secs.max(1))
+    }
+
+    /// Open a session for a peer that connected to us. A valid `resume_token`
+    /// from an earlier session brings that session back instead.
+    pub async fn create_session(
+        &self,
+        peer_node_id: String,
+        peer_name: String,
+        protocol: ProtocolVersion,
+        resume_token: Option<&str>,
+    ) -> Result<Session> {
+        let config = self.config.borrow().clone();
+
+        if !config.security.accepts_sessions_at(chrono::Local::now().naive_local()) {
+            bail!(
+                "Refusing session from {}: outside the configured security.allowed_hours",
+                peer_name
+            );
+        }
+
+        let grace = Duration::from_secs(config.security.resume_grace_secs);
+        if let Some(mut session) = resume_token.and_then(|token| self.take_closed(token, &peer_node_id, grace)) {
+            session.protocol = protocol;
+            return Ok(self.restore_session(session).await);
+        }
+
         let session = Session {
             session_id: Uuid::new_v4().to_string(),
             peer_node_id: peer_node_id.clone(),
             peer_name: peer_name.clone(),
             created_at: chrono::Utc::now(),
             last_activity: chrono::Utc::now(),
-            mouse_owner: MouseOwner::Local,
+            streaming: config.streaming_for_peer(&peer_node_id, &peer_name),
+            state: SessionState::Connected,
+            screen_scale: screen_scale(config.peer_screen(&peer_node_id, &peer_name)),
+            peer_screen: config.peer_screen(&peer_node_id, &peer_name),
+            cursor: None,
+            resume_token: Uuid::new_v4().to_string(),
+            held_mouse: false,
+            protocol,
+            nonce: Uuid::new_v4().as_bytes().to_vec(),
+            replay: ReplayWindow::default(),
         };
 
         info!("Created session {} with peer {}", session.session_id, peer_name);
         
-        self.sessions.write().await.insert(session.session_id.clone(), session.clone());
+        let mut sessions = self.sessions.write().await;
+        sessions.insert(session.session_id.clone(), session.clone());
+        metrics().set_active_sessions(sessions.len());
+        self.publish(SessionEvent::Created {
+            session_id: session.session_id.clone(),
+            peer_node_id,
+            peer_name,
+        });
         Ok(session)
     }
 
+    /// The closed session `token` was issued for, if it belongs to `peer_node_id`
+    /// and closed less than `grace` ago
+    fn take_closed(&self, token: &str, peer_node_id: &str, grace: Duration) -> Option<Session> {
+        let mut closed = self.closed.lock();
+        let valid = closed.get(token)
+            .is_some_and(|closed| closed.session.peer_node_id == peer_node_id && closed.closed_at.elapsed() < grace);
+        if !valid {
+            debug!("Ignoring unknown or expired resumption token from {}", peer_node_id);
+            return None;
+        }
+        closed.remove(token).map(|closed| closed.session)
+    }
+
+    /// Put a closed session back in the table under a fresh token
+    async fn restore_session(&self, mut session: Session) -> Session {
+        session.state = SessionState::Connected;
+        session.last_activity = chrono::Utc::now();
+        session.resume_token = Uuid::new_v4().to_string();
+        session.renew_nonce();
+        info!("Peer {} restored closed session {}", session.peer_name, session.session_id);
+
+        let mut sessions = self.sessions.write().await;
+        sessions.insert(session.session_id.clone(), session.clone());
+        metrics().set_active_sessions(sessions.len());
+        drop(sessions);
+        self.publish(SessionEvent::Resumed {
+            session_id: session.session_id.clone(),
+            peer_node_id: session.peer_node_id.clone(),
+        });
+
+        self.return_mouse(&session).await;
+        session
+    }
+
+    /// Remember a session that closed because its connection failed, so the
+    /// peer can restore it with its token
+    fn keep_for_resume(&self, session: Session, reason: CloseReason) {
+        if !reason.is_resumable() || session.resume_token.is_empty() {
+            return;
+        }
+        self.closed.lock().insert(session.resume_token.clone(), ClosedSession {
+            session,
+            closed_at: Instant::now(),
+        });
+    }
+
+    /// Hand the mouse back to a resumed session's peer if it had it when the
+    /// connection dropped and nobody has taken it since
+    async fn return_mouse(&self, session: &Session) {
+        if !session.held_mouse || self.current_mouse_owner().await.is_some() {
+            return;
+        }
+        if let Some(stored) = self.sessions.write().await.get_mut(&session.session_id) {
+            stored.held_mouse = false;
+        }
+        if let Err(e) = self.transfer_mouse(&session.session_id, MouseOwner::Remote).await {
+            debug!("Could not hand the mouse back to session {}: {:#}", session.session_id, e);
+        }
+    }
+
     pub async fn get_session(&self, session_id: &str) -> Option<Session> {
         self.sessions.read().await.get(session_id).cloned()
     }
 
+    /// Keep a session whose connection dropped so the peer can resume it
+    pub async fn connection_lost(&self, session_id: &str) {
+        if let Some(session) = self.sessions.write().await.get_mut(session_id) {
+            session.state = SessionState::reconnecting(0);
+            session.held_mouse = self.mouse_owner.read().await.as_deref() == Some(session_id);
+            info!("Lost connection to {}, waiting for it to reconnect", session.peer_name);
+            self.publish(SessionEvent::ConnectionLost {
+                session_id: session.session_id.clone(),
+                peer_node_id: session.peer_node_id.clone(),
+            });
+        }
+
+        // Mouse control can't stay with a peer we can't reach
+        self.release_mouse(session_id).await;
+    }
+
+    /// Reattach a reconnecting peer to its session. Returns `None` if the
+    /// session is gone or belongs to a different peer.
+    pub async fn resume_session(&self, session_id: &str, peer_node_id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(session_id)?;
+        if session.peer_node_id != peer_node_id {
+            return None;
+        }
+
+        session.state = SessionState::Connected;
+        session.last_activity = chrono::Utc::now();
+        session.renew_nonce();
+        info!("Peer {} resumed session {}", session.peer_name, session_id);
+        self.publish(SessionEvent::Resumed {
+            session_id: session_id.to_string(),
+            peer_node_id: peer_node_id.to_string(),
+        });
+        let session = session.clone();
+        drop(sessions);
+
+        self.return_mouse(&session).await;
+        Some(session)
+    }
+
+    /// Whether `message` is fresh: it carries the session nonce and a sequence
+    /// number not taken before. Peers on protocol 1.0 stamp nothing, so all of
+    /// their messages pass.
+    pub async fn check_replay(&self, session_id: &str, message: &ControlMessage) -> bool {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(session_id) else {
+            return false;
+        };
+        if !session.protocol.checks_replays() {
+            return true;
+        }
+        message.nonce == session.nonce && session.replay.accept(message.sequence)
+    }
+
     pub async fn update_activity(&self, session_id: &str) {
         if let Some(session) = self.sessions.write().await.get_mut(session_id) {
             session.last_activity = chrono::Utc::now();
         }
     }
 
+    /// Give the mouse to a session's peer, or take it back. Only one session can
+    /// own the mouse at a time, so handing it to a second peer fails.
     pub async fn transfer_mouse(&self, session_id: &str, owner: MouseOwner) -> Result<()> {
+        let sessions = self.sessions.read().await;
+        let Some(session) = sessions.get(session_id) else {
+            bail!("Unknown session {}", session_id);
+        };
+
+        let mut current = self.mouse_owner.write().await;
+        match owner {
+            MouseOwner::Remote => {
+                if let Some(holder) = current.as_deref().filter(|holder| *holder != session_id) {
+                    bail!("Mouse is already owned by session {}", holder);
+                }
+                *current = Some(session_id.to_string());
+            }
+            MouseOwner::Local => {
+                if current.as_deref() != Some(session_id) {
+                    return Ok(());
+                }
+                *current = None;
+            }
+        }
+        info!("Mouse ownership transferred to {:?} for session {}", owner, session_id);
+        self.publish(SessionEvent::MouseTransferred {
+            session_id: session_id.to_string(),
+            peer_node_id: session.peer_node_id.clone(),
+            owner,
+        });
+        let (session_id, peer_node_id, peer_name) =
+            (session_id.to_string(), session.peer_node_id.clone(), session.peer_name.clone());
+        self.publish(match owner {
+            MouseOwner::Remote => SessionEvent::MouseEntered { session_id, peer_node_id, peer_name },
+            MouseOwner::Local => SessionEvent::MouseLeft { session_id, peer_node_id, peer_name },
+        });
+
+        if let Some(ref input_control) = self.input_control {
+            match owner {
+                MouseOwner::Remote => input_control.grab(),
+                MouseOwner::Local => input_control.ungrab(),
+            }
+        }
+        Ok(())
+    }
+
+    /// The session whose peer controls the mouse, if it isn't local
+    pub async fn current_mouse_owner(&self) -> Option<String> {
+        self.mouse_owner.read().await.clone()
+    }
+
+    /// Return the mouse to local control if `session_id` holds it
+    async fn release_mouse(&self, session_id: &str) {
+        let mut current = self.mouse_owner.write().await;
+        if current.as_deref() == Some(session_id) {
+            *current = None;
+            if let Some(ref input_control) = self.input_control {
+                input_control.ungrab();
+            }
+            debug!("Mouse returned from session {}", session_id);
+        }
+    }
+
+    /// Take the mouse back from whichever peer holds it and release the local
+    /// devices, even if ownership tracking got out of step
+    pub async fn force_local(&self) {
+        if let Some(owner) = self.current_mouse_owner().await {
+            warn!("⚠ Taking the mouse back from session {}", owner);
+            if let Err(e) = self.transfer_mouse(&owner, MouseOwner::Local).await {
+                warn!("Could not hand back the mouse cleanly: {:#}", e);
+                *self.mouse_owner.write().await = None;
+            }
+        }
+        if let Some(ref input_control) = self.input_control {
+            input_control.ungrab();
+        }
+    }
+
+    /// A snapshot of every open session
+    pub async fn list_sessions(&self) -> Vec<Session> {
+        self.sessions.read().await.values().cloned().collect()
+    }
+
+    /// Why the peer `node_id` may not have a session, by the node lists in the
+    /// live config
+    pub fn refuses_node(&self, node_id: &str) -> Option<&'static str> {
+        self.config.borrow().security.refuses_node(node_id)
+    }
+
+    /// Whether input should stop while the local screen is locked, from the live config
+    pub fn pauses_on_lock(&self) -> bool {
+        self.config.borrow().security.pause_on_lock
+    }
+
+    pub fn input_paused(&self) -> bool {
+        self.input_paused.load(Ordering::Relaxed)
+    }
+
+    /// Stop forwarding input, taking the mouse back from any peer, or start
+    /// again. Sessions stay open either way.
+    pub async fn set_input_paused(&self, paused: bool) {
+        if self.input_paused.swap(paused, Ordering::Relaxed) == paused {
+            return;
+        }
+        if paused {
+            self.force_local().await;
+            info!("🔒 Screen locked, input forwarding paused");
+            self.publish(SessionEvent::InputPaused);
+        } else {
+            info!("🔓 Screen unlocked, input forwarding resumed");
+            self.publish(SessionEvent::InputResumed);
+        }
+    }
+
+    /// The cursor hit a screen edge: hand the mouse to whichever peer the
+    /// layout puts there, connecting to it first if needed
+    pub async fn handle_edge_crossed(&self, edge: ScreenEdge, position: (f32, f32)) -> Result<()> {
+        if self.input_paused() || self.current_mouse_owner().await.is_some() {
+            return Ok(());
+        }
+
+        let layout = self.config.borrow().layout.clone();
+        let Some(Screen::Peer(target)) = layout.neighbor(&Screen::Local, edge) else {
+            debug!("No peer configured past the {:?} edge", edge);
+            return Ok(());
+        };
+
+        let session_id = self.session_with(&target).await?;
+        self.transfer_mouse(&session_id, MouseOwner::Remote).await?;
+
+        // Pull our cursor back from the edge, or the first local movement after
+        // the mouse returns would cross straight over again
+        let along = match edge {
+            ScreenEdge::Left | ScreenEdge::Right => position.1,
+            ScreenEdge::Top | ScreenEdge::Bottom => position.0,
+        };
+        let (x, y) = self.inside_edge(edge, along);
+        self.warp_local(x, y)?;
+
+        // Tell the peer where the cursor left us so it can enter at the matching spot
+        self.forward(&InputEvent::EdgeCrossed { edge, position }).await
+    }
+
+    /// The connected session with a peer, opening one if there is none
+    async fn session_with(&self, node_id: &str) -> Result<String> {
+        let existing = self.sessions.read().await.values()
+            .find(|s| s.peer_node_id == node_id && s.state == SessionState::Connected)
+            .map(|s| s.session_id.clone());
+        match existing {
+            Some(session_id) => Ok(session_id),
+            None => self.connect_peer(node_id).await,
+        }
+    }
+
+    /// The cursor left the screen of the peer holding the mouse towards another
+    /// peer: hand the mouse on without it passing through our screen. Boxed
+    /// because connecting spawns `handle_message`, which can end up back here.
+    fn pass_cursor<'a>(
+        &'a self,
+        from: &'a str,
+        to: &'a str,
+        edge: ScreenEdge,
+        position: f32,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let session_id = self.session_with(to).await?;
+            self.transfer_mouse(from, MouseOwner::Local).await?;
+            self.transfer_mouse(&session_id, MouseOwner::Remote).await?;
+
+            // Crossings are given in our pixels and scaled to the peer on the way out
+            let (width, height) = input::detect_screen_size().unwrap_or(input::FALLBACK_SCREEN_SIZE);
+            let (width, height) = (width as f32, height as f32);
+            let position = position.clamp(0.0, 1.0);
+            let position = match edge {
+                ScreenEdge::Left => (0.0, position * height),
+                ScreenEdge::Right => (width, position * height),
+                ScreenEdge::Top => (position * width, 0.0),
+                ScreenEdge::Bottom => (position * width, height),
+            };
+            debug!("Cursor passed from session {} to {} across the {:?} edge", from, session_id, edge);
+            self.forward(&InputEvent::EdgeCrossed { edge, position }).await
+        })
+    }
+
+    /// Record the screen size a session's peer reported. A `screen` set for the
+    /// peer in the config takes precedence.
+    pub async fn set_peer_screen(&self, session_id: &str, size: (u32, u32)) {
+        if size.0 == 0 || size.1 == 0 {
+            return;
+        }
+        let config = self.config.borrow().clone();
         if let Some(session) = self.sessions.write().await.get_mut(session_id) {
-            session.mouse_owner = owner;
-            info!("Mouse ownership transferred to {:?} for session {}", owner, session_id);
+            if config.peer_screen(&session.peer_node_id, &session.peer_name).is_some() {
+                return;
+            }
+            session.screen_scale = screen_scale(Some(size));
+            session.peer_screen = Some(size);
+            debug!("Peer {} has a {}x{} screen", session.peer_name, size.0, size.1);
+        }
+    }
+
+    /// Open a session to a discovered peer
+    async fn connect_peer(&self, node_id: &str) -> Result<String> {
+        let Some(ref outgoing) = self.outgoing else {
+            bail!("Cannot connect to {}: outgoing connections are not set up", node_id);
+        };
+        let Some(peer) = outgoing.peers.read().await.get(node_id).cloned() else {
+            bail!("Peer {} from the screen layout has not been discovered", node_id);
+        };
+        if let Some(reason) = self.refuses_node(node_id) {
+            bail!("Not connecting to {}: {}", peer.node_name, reason);
+        }
+
+        let mut client = ControlClient::connect(&peer, &outgoing.identity, &self.node_name, &outgoing.connector)
+            .instrument(info_span!("connect", node_id = %peer.node_id))
+            .await
+            .with_context(|| format!("Failed to connect to {}", peer.node_name))?;
+        client.keep_alive(self.heartbeat_interval());
+
+        let config = self.config.borrow().clone();
+        if config.network.motion_over_udp {
+            if let Err(e) = client.enable_motion_over_udp().await {
+                warn!("⚠ Sending motion to {} reliably: {:#}", peer.node_name, e);
+            }
+        }
+        let peer_info = client.peer_info().clone();
+        let session = Session {
+            session_id: client.session_id().to_string(),
+            peer_node_id: peer.node_id.clone(),
+            peer_name: peer.node_name.clone(),
+            created_at: chrono::Utc::now(),
+            last_activity: chrono::Utc::now(),
+            streaming: config.streaming_for_peer(&peer.node_id, &peer.node_name),
+            state: SessionState::Connected,
+            screen_scale: screen_scale(config.peer_screen(&peer.node_id, &peer.node_name)),
+            peer_screen: config.peer_screen(&peer.node_id, &peer.node_name),
+            cursor: None,
+            resume_token: String::new(),
+            held_mouse: false,
+            protocol: client.protocol(),
+            nonce: client.nonce().to_vec(),
+            replay: ReplayWindow::default(),
+        };
+        info!("Created session {} with peer {}", session.session_id, session.peer_name);
+
+        let session_id = session.session_id.clone();
+        if let Some(mut inbox) = client.take_inbox() {
+            let manager = self.clone();
+            let span = info_span!("session", %session_id, node_id = %peer.node_id);
+            let session_id = session_id.clone();
+            tokio::spawn(async move {
+                while let Some(message) = inbox.recv().await {
+                    manager.handle_message(&session_id, &message).await;
+                }
+            }.instrument(span));
         }
+
+        self.publish(SessionEvent::Created {
+            session_id: session_id.clone(),
+            peer_node_id: session.peer_node_id.clone(),
+            peer_name: session.peer_name.clone(),
+        });
+        let mut sessions = self.sessions.write().await;
+        sessions.insert(session_id.clone(), session);
+        metrics().set_active_sessions(sessions.len());
+        drop(sessions);
+        let lost = client.lost();
+        self.clients.lock().await.insert(session_id.clone(), Arc::new(Mutex::new(client)));
+        self.watch_client(&session_id, lost);
+
+        // Settle the codec before any encoder is started for this peer
+        if let Err(e) = self.negotiate_stream(&session_id, &peer.capabilities).await {
+            warn!("⚠ Streaming to {} is unavailable: {:#}", peer.node_name, e);
+        }
+        if let Some(display) = peer_info.displays.first() {
+            self.set_peer_screen(&session_id, (display.width, display.height)).await;
+        }
+        Ok(session_id)
+    }
+
+    /// Act on a message from a session's peer that the connection didn't handle itself
+    pub async fn handle_message(&self, session_id: &str, message: &ControlMessage) {
+        self.update_activity(session_id).await;
+
+        match message.payload {
+            Some(control_message::Payload::CursorReturn(ref cursor_return)) => {
+                let result = match wire::screen_edge(cursor_return.entry_edge) {
+                    Ok(edge) => self.cursor_returned(session_id, edge, cursor_return.position).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Ignoring cursor return from session {}: {:#}", session_id, e);
+                }
+            }
+            Some(control_message::Payload::Clipboard(ref update)) => {
+                let Some(clipboard) = self.clipboard.clone() else {
+                    return;
+                };
+                let contents = match ClipboardContents::try_from(update.clone()) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        warn!("Ignoring clipboard update from session {}: {:#}", session_id, e);
+                        return;
+                    }
+                };
+                // Clipboard backends block on the display server
+                let result = tokio::task::spawn_blocking(move || clipboard.apply(&contents)).await;
+                match result {
+                    Ok(Err(e)) => warn!("⚠ Clipboard update from session {} failed: {:#}", session_id, e),
+                    Err(e) => warn!("⚠ Clipboard update from session {} failed: {}", session_id, e),
+                    Ok(Ok(())) => {}
+                }
+            }
+            Some(control_message::Payload::StreamRequest(ref request)) => {
+                if self.stream_requests.send((session_id.to_string(), request.clone())).is_ok() {
+                    return;
+                }
+                // Built without the streaming feature
+                let answer = wire::StreamResponse::answer(
+                    &request.stream_id,
+                    wire::stream_response::Status::NotSupported,
+                    "This host doesn't stream its screen",
+                );
+                if let Err(e) = self.send_to(session_id, control_message::Payload::StreamResponse(answer)).await {
+                    debug!("Failed to answer a stream request from session {}: {:#}", session_id, e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue for messages to a peer that connected to us; replaces any earlier
+    /// queue for the same session
+    pub fn open_outbound(&self, session_id: &str) -> mpsc::UnboundedReceiver<control_message::Payload> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.outbound.lock().insert(session_id.to_string(), tx);
+        rx
+    }
+
+    /// Send a payload to one session's peer, whichever side opened the connection
+    pub async fn send_to(&self, session_id: &str, mut payload: control_message::Payload) -> Result<()> {
+        let queue = self.outbound.lock().get(session_id).cloned();
+        if let Some(queue) = queue {
+            match queue.send(payload) {
+                Ok(()) => return Ok(()),
+                // That connection is gone; an outgoing one may still reach the peer
+                Err(unsent) => {
+                    self.outbound.lock().remove(session_id);
+                    payload = unsent.0;
+                }
+            }
+        }
+        let Some(client) = self.client(session_id).await else {
+            bail!("No connection for session {}", session_id);
+        };
+        let result = client.lock().await.send_message(payload).await;
+        if result.is_err() {
+            self.client_lost(session_id).await;
+        }
+        result
+    }
+
+    /// Send a payload to every connected peer, whichever side opened the connection
+    pub async fn broadcast(&self, payload: control_message::Payload) {
+        self.outbound.lock().retain(|_, queue| queue.send(payload.clone()).is_ok());
+
+        // Any of these sends may reconnect, and the hello carries the cursor
+        let owner = self.current_mouse_owner().await;
+        let cursors: HashMap<String, wire::CursorState> = self.sessions.read().await
+            .values()
+            .map(|session| {
+                let has_mouse = owner.as_deref() == Some(session.session_id.as_str());
+                (session.session_id.clone(), session.cursor_state(has_mouse))
+            })
+            .collect();
+
+        let clients: Vec<(String, SharedClient)> = self.clients.lock().await
+            .iter()
+            .map(|(session_id, client)| (session_id.clone(), client.clone()))
+            .collect();
+        for (session_id, client) in clients {
+            let mut client = client.lock().await;
+            if let Some(cursor) = cursors.get(&session_id) {
+                client.set_cursor(cursor.clone());
+            }
+            let result = client.send_message(payload.clone()).await;
+            drop(client);
+            if let Err(e) = result {
+                debug!("Failed to send to session {}: {:#}", session_id, e);
+                self.client_lost(&session_id).await;
+            }
+        }
+    }
+
+    /// The outgoing connection for a session, if it has one that is up
+    async fn client(&self, session_id: &str) -> Option<SharedClient> {
+        self.clients.lock().await.get(session_id).cloned()
+    }
+
+    /// Treat a peer that closed or stopped answering an outgoing connection like
+    /// a failed send, until the session closes
+    fn watch_client(&self, session_id: &str, lost: Arc<Notify>) {
+        let manager = self.clone();
+        let watched = session_id.to_string();
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = lost.notified() => manager.client_lost(&watched).await,
+                    event = events.recv() => match event {
+                        Ok(SessionEvent::Closed { session_id, .. }) if session_id == watched => return,
+                        Err(broadcast::error::RecvError::Closed) => return,
+                        _ => {}
+                    },
+                }
+            }
+        });
+    }
+
+    /// A send over an outgoing connection failed, or its peer stopped answering.
+    /// The mouse comes back at once; the connection is re-established in the
+    /// background and the session resumed, or closed if the peer can't be
+    /// reached again.
+    async fn client_lost(&self, session_id: &str) {
+        // Already reconnecting or closed
+        let Some(client) = self.clients.lock().await.remove(session_id) else {
+            return;
+        };
+        let Some(peer_node_id) = self.get_session(session_id).await.map(|session| session.peer_node_id) else {
+            return;
+        };
+        warn!("Connection for session {} dropped, reconnecting", session_id);
+        self.connection_lost(session_id).await;
+
+        let manager = self.clone();
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            let mut connection = client.lock().await;
+            let result = connection.reconnect().await;
+            let same_session = connection.session_id() == session_id;
+            let nonce = connection.nonce().to_vec();
+            drop(connection);
+
+            match result {
+                Ok(()) if same_session => {
+                    manager.clients.lock().await.insert(session_id.clone(), client.clone());
+                    if manager.resume_session(&session_id, &peer_node_id).await.is_some() {
+                        // The peer issued the nonce for the new connection
+                        if let Some(session) = manager.sessions.write().await.get_mut(&session_id) {
+                            session.nonce = nonce;
+                        }
+                        return;
+                    }
+                    // Closed while we were reconnecting
+                    manager.clients.lock().await.remove(&session_id);
+                }
+                Ok(()) => warn!("Peer did not resume session {}, closing it", session_id),
+                Err(e) => warn!("⚠ {:#}", e),
+            }
+            manager.close_session(&session_id, CloseReason::SendFailed).await;
+            if let Err(e) = client.lock().await.close().await {
+                debug!("Closing connection for session {} failed: {:#}", session_id, e);
+            }
+        });
+    }
+
+    /// Start pushing clipboard changes when the first session opens and stop
+    /// after the last one closes
+    fn sync_clipboard(&self, active: bool) {
+        let Some(ref clipboard) = self.clipboard else {
+            return;
+        };
+
+        let mut task = self.clipboard_task.lock();
+        match (active, task.is_some()) {
+            (true, false) => {
+                let mut changes = clipboard.watch();
+                let manager = self.clone();
+                *task = Some(tokio::spawn(async move {
+                    while let Some(contents) = changes.recv().await {
+                        let payload = control_message::Payload::Clipboard(contents.into());
+                        manager.broadcast(payload).await;
+                    }
+                }));
+                info!("📋 Clipboard sync started");
+            }
+            (false, true) => {
+                // Dropping the receiver also stops the poller
+                if let Some(task) = task.take() {
+                    task.abort();
+                }
+                info!("📋 Clipboard sync stopped");
+            }
+            _ => {}
+        }
+    }
+
+    /// The peer holding the mouse sent the cursor back across `edge` of our
+    /// screen, `position` (0.0-1.0) of the way along it. If the layout has
+    /// another peer past that side of its screen, the cursor goes there instead.
+    pub async fn cursor_returned(&self, session_id: &str, edge: ScreenEdge, position: f32) -> Result<()> {
+        if self.current_mouse_owner().await.as_deref() != Some(session_id) {
+            debug!("Session {} returned the cursor but doesn't own the mouse", session_id);
+            return Ok(());
+        }
+
+        let peer_node_id = self.sessions.read().await.get(session_id).map(|s| s.peer_node_id.clone());
+        if let Some(peer_node_id) = peer_node_id {
+            // The peer left its screen on the side facing `edge` of ours
+            let layout = self.config.borrow().layout.clone();
+            let exit = edge.opposite();
+            if let Some(Screen::Peer(next)) = layout.neighbor(&Screen::Peer(peer_node_id.clone()), exit) {
+                if next != peer_node_id {
+                    return self.pass_cursor(session_id, &next, exit, position).await;
+                }
+            }
+        }
+
+        self.transfer_mouse(session_id, MouseOwner::Local).await?;
+
+        // Land clear of the edge so the cursor doesn't bounce straight back
+        let (width, height) = input::detect_screen_size().unwrap_or(input::FALLBACK_SCREEN_SIZE);
+        let position = position.clamp(0.0, 1.0);
+        let along = match edge {
+            ScreenEdge::Left | ScreenEdge::Right => position * height as f32,
+            ScreenEdge::Top | ScreenEdge::Bottom => position * width as f32,
+        };
+        let (x, y) = self.inside_edge(edge, along);
+        self.warp_local(x, y)?;
+
+        debug!("Cursor returned at {:?} edge ({:.0}, {:.0})", edge, x, y);
         Ok(())
     }
 
-    pub async fn close_session(&self, session_id: &str) {
-        if let Some(session) = self.sessions.write().await.remove(session_id) {
+    /// The point `along` pixels down or across `edge`, moved inward past the
+    /// edge zone by `input.edge_release_margin`
+    fn inside_edge(&self, edge: ScreenEdge, along: f32) -> (f32, f32) {
+        let (width, height) = input::detect_screen_size().unwrap_or(input::FALLBACK_SCREEN_SIZE);
+        let (width, height) = (width as f32, height as f32);
+        let inset = {
+            let config = self.config.borrow();
+            config.input.edge_threshold as f32 + config.input.edge_release_margin as f32
+        };
+        match edge {
+            ScreenEdge::Left => (inset, along),
+            ScreenEdge::Right => (width - inset, along),
+            ScreenEdge::Top => (along, inset),
+            ScreenEdge::Bottom => (along, height - inset),
+        }
+    }
+
+    /// Move the tracked cursor, and the real pointer if there's a virtual device
+    fn warp_local(&self, x: f32, y: f32) -> Result<()> {
+        if let Some(ref input_control) = self.input_control {
+            input_control.warp_to(x, y);
+        }
+        if let Some(ref virtual_input) = self.virtual_input {
+            virtual_input.lock().warp_to(x, y)?;
+        }
+        Ok(())
+    }
+
+    /// Send an input event to the peer that owns the mouse, if any
+    pub async fn forward(&self, event: &InputEvent) -> Result<()> {
+        if self.input_paused() {
+            return Ok(());
+        }
+        let Some(owner) = self.current_mouse_owner().await else {
+            return Ok(());
+        };
+
+        let Some((scale, cursor)) = self.sessions.read().await
+            .get(&owner)
+            .map(|session| (session.screen_scale, session.cursor_state(true)))
+        else {
+            return Ok(());
+        };
+
+        // Positions are in our pixels; the peer expects its own
+        let scaled;
+        let event = if matches!(event, InputEvent::MouseAbsolute { .. } | InputEvent::EdgeCrossed { .. }) {
+            scaled = scale_to_peer(event, scale);
+            &scaled
+        } else {
+            event
+        };
+
+        let Some(client) = self.client(&owner).await else {
+            return Ok(());
+        };
+        let result = {
+            let mut client = client.lock().await;
+            // Should this send have to reconnect, the peer learns where we left its cursor
+            client.set_cursor(cursor);
+            client.send(event).await
+        };
+        if result.is_err() {
+            // Local input stays local while the peer is reconnected
+            self.client_lost(&owner).await;
+        } else {
+            self.note_forwarded(&owner, event).await;
+        }
+        result
+    }
+
+    /// Note activity on a session and follow where forwarded `event` put the
+    /// cursor on its peer's screen
+    async fn note_forwarded(&self, session_id: &str, event: &InputEvent) {
+        if let Some(session) = self.sessions.write().await.get_mut(session_id) {
+            session.last_activity = chrono::Utc::now();
+            session.cursor = track_cursor(session.cursor, event, session.peer_screen);
+        }
+    }
+
+    /// The shared cursor as a session's peer last left it with us, or `None`
+    /// for an unknown session
+    pub async fn cursor_state(&self, session_id: &str) -> Option<wire::CursorState> {
+        let has_mouse = self.current_mouse_owner().await.as_deref() == Some(session_id);
+        self.get_session(session_id).await.map(|session| session.cursor_state(has_mouse))
+    }
+
+    /// Take up the cursor a resuming peer reports: if it was forwarding input
+    /// to us, put our pointer back where that input left it
+    pub fn restore_cursor(&self, cursor: &wire::CursorState) -> Result<()> {
+        if !cursor.receiver_has_mouse || !cursor.position_known {
+            return Ok(());
+        }
+        debug!("Restoring the cursor to ({:.0}, {:.0})", cursor.x, cursor.y);
+        self.warp_local(cursor.x, cursor.y)
+    }
+
+    /// How the connection behind an outgoing session is doing, if known yet
+    pub async fn link_stats(&self, session_id: &str) -> Result<Option<PathStats>> {
+        match self.client(session_id).await {
+            Some(client) => client.lock().await.link_stats().await,
+            None => bail!("No outgoing connection for session {}", session_id),
+        }
+    }
+
+    /// Settle the stream settings for a session against what the peer can decode.
+    /// A per-peer codec the peer doesn't advertise falls back to the global codec,
+    /// then to the best codec both sides support.
+    pub async fn negotiate_stream(&self, session_id: &str, peer: &PeerCapabilities) -> Result<StreamingConfig> {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(session_id) else {
+            bail!("Unknown session {}", session_id);
+        };
+
+        let local = PeerCapabilities::local().video_codecs;
+        let usable = |codec: &str| peer.supports_codec(codec) && local.iter().any(|c| c.eq_ignore_ascii_case(codec));
+
+        if !usable(&session.streaming.codec) {
+            let global = self.config.borrow().streaming.codec.clone();
+            let fallback = if usable(&global) {
+                global
+            } else {
+                match negotiate_codec(&local, peer) {
+                    Some(codec) => codec,
+                    None => bail!(
+                        "No video codec in common with {}: we encode {}, it decodes {}",
+                        session.peer_name,
+                        local.join(", "),
+                        peer.video_codecs.join(", ")
+                    ),
+                }
+            };
+
+            warn!(
+                "Peer {} can't use {}, falling back to {}",
+                session.peer_name, session.streaming.codec, fallback
+            );
+            session.streaming.codec = fallback;
+        }
+
+        debug!(
+            "Stream settings for session {}: {} @ {} Mbps",
+            session_id, session.streaming.codec, session.streaming.bitrate_mbps
+        );
+        Ok(session.streaming.clone())
+    }
+
+    /// Close every session on shutdown. Connections peers opened to us close
+    /// their own sessions when the shutdown fires, so give them `grace` first.
+    pub async fn close_all(&self, grace: Duration) {
+        let deadline = tokio::time::Instant::now() + grace;
+        loop {
+            // Sessions without a client of ours belong to incoming connections
+            let incoming = self.sessions.read().await.len()
+                .saturating_sub(self.clients.lock().await.len());
+            if incoming == 0 || tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let remaining: Vec<String> = self.sessions.read().await.keys().cloned().collect();
+        for session_id in remaining {
+            self.close_session(&session_id, CloseReason::Shutdown).await;
+        }
+    }
+
+    /// The peer stopped answering pings; the session is closed rather than
+    /// left waiting for a reconnect
+    pub async fn heartbeat_lost(&self, session_id: &str, missed: u32) {
+        if let Some(session) = self.get_session(session_id).await {
+            self.publish(SessionEvent::HeartbeatLost {
+                session_id: session_id.to_string(),
+                peer_node_id: session.peer_node_id,
+                missed,
+            });
+        }
+        self.close_session(session_id, CloseReason::HeartbeatLost).await;
+    }
+
+    pub async fn close_session(&self, session_id: &str, reason: CloseReason) {
+        let mut sessions = self.sessions.write().await;
+        if let Some(mut session) = sessions.remove(session_id) {
             info!("Closed session {} with peer {}", session.session_id, session.peer_name);
+            self.publish_closed(&session, reason);
+            session.held_mouse |= self.mouse_owner.read().await.as_deref() == Some(session_id);
+            self.keep_for_resume(session, reason);
+        }
+        metrics().set_active_sessions(sessions.len());
+        metrics().session_closed(session_id);
+        drop(sessions);
+
+        self.outbound.lock().remove(session_id);
+
+        self.release_mouse(session_id).await;
+
+        let client = self.clients.lock().await.remove(session_id);
+        if let Some(client) = client {
+            if let Err(e) = client.lock().await.close().await {
+                debug!("Closing connection for session {} failed: {:#}", session_id, e);
+            }
+        }
+    }
+}
+
+/// Appends every session event as a JSON line, for `security.audit_log`
+pub struct SessionLogger {
+    path: PathBuf,
+    node_name: String,
+    events: broadcast::Receiver<SessionEvent>,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    node_name: &'a str,
+    #[serde(flatten)]
+    event: &'a SessionEvent,
+}
+
+impl SessionLogger {
+    pub fn new(path: PathBuf, sessions: &SessionManager) -> Self {
+        Self {
+            path,
+            node_name: sessions.node_name().to_string(),
+            events: sessions.subscribe(),
+        }
+    }
+
+    /// Write records until the session manager goes away
+    pub async fn run(mut self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open audit log {}", self.path.display()))?;
+        info!("✓ Session audit log: {}", self.path.display());
+
+        loop {
+            let event = match self.events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Session audit log fell behind, {} events were not recorded", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+
+            let record = AuditRecord {
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                node_name: &self.node_name,
+                event: &event,
+            };
+            let mut line = serde_json::to_string(&record)?;
+            line.push('\n');
+            file.write_all(line.as_bytes()).await
+                .with_context(|| format!("Failed to write audit log {}", self.path.display()))?;
+            file.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::proto::PROTOCOL_VERSION;
+
+    #[test]
+    fn cursor_follows_forwarded_motion() {
+        let screen = Some((1920, 1080));
+        let entered = InputEvent::EdgeCrossed { edge: ScreenEdge::Left, position: (0.0, 500.0) };
+        let cursor = track_cursor(None, &entered, screen);
+        assert_eq!(cursor, Some((0.0, 500.0)));
+
+        let cursor = track_cursor(cursor, &InputEvent::MouseMove { delta_x: 120.0, delta_y: -40.0 }, screen);
+        assert_eq!(cursor, Some((120.0, 460.0)));
+        let cursor = track_cursor(cursor, &InputEvent::KeyPress { key_code: 30, pressed: true, scan_code: None }, screen);
+        assert_eq!(cursor, Some((120.0, 460.0)));
+        // Kept on the peer's screen
+        let cursor = track_cursor(cursor, &InputEvent::MouseMove { delta_x: -500.0, delta_y: 2000.0 }, screen);
+        assert_eq!(cursor, Some((0.0, 1080.0)));
+
+        // Relative motion alone can't place a cursor nobody has seen
+        assert_eq!(track_cursor(None, &InputEvent::MouseMove { delta_x: 1.0, delta_y: 1.0 }, screen), None);
+    }
+
+    #[tokio::test]
+    async fn cursor_survives_a_reconnect() {
+        let manager = SessionManager::new(Config::default(), "host".to_string()).await.unwrap();
+        let session = manager
+            .create_session("peer".to_string(), "Peer".to_string(), PROTOCOL_VERSION, None)
+            .await
+            .unwrap();
+        let id = session.session_id.clone();
+        manager.set_peer_screen(&id, (1920, 1080)).await;
+        manager.transfer_mouse(&id, MouseOwner::Remote).await.unwrap();
+        manager.note_forwarded(&id, &InputEvent::EdgeCrossed { edge: ScreenEdge::Left, position: (0.0, 500.0) }).await;
+        manager.note_forwarded(&id, &InputEvent::MouseMove { delta_x: 120.0, delta_y: -40.0 }).await;
+
+        let before = manager.cursor_state(&id).await.unwrap();
+        assert_eq!(before, wire::CursorState { receiver_has_mouse: true, position_known: true, x: 120.0, y: 460.0 });
+
+        // The connection drops and the peer comes back to the same session
+        manager.connection_lost(&id).await;
+        assert!(!manager.cursor_state(&id).await.unwrap().receiver_has_mouse);
+        manager.resume_session(&id, "peer").await.unwrap();
+        assert_eq!(manager.cursor_state(&id).await.unwrap(), before);
+
+        // The session closes instead and the peer restores it with its token
+        manager.close_session(&id, CloseReason::SendFailed).await;
+        let restored = manager
+            .create_session("peer".to_string(), "Peer".to_string(), PROTOCOL_VERSION, Some(&session.resume_token))
+            .await
+            .unwrap();
+        assert_eq!(restored.session_id, id);
+        assert_eq!(manager.cursor_state(&id).await.unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn peer_codec_override_only_applies_to_that_peer() {
+        let mut config = Config::default();
+        config.streaming.codec = "h264".to_string();
+        config.peers = vec![config::PeerOverride {
+            node_id: Some("tv".to_string()),
+            name: None,
+            codec: Some("h265".to_string()),
+            bitrate_mbps: Some(40),
+            screen: None,
+        }];
+        let manager = SessionManager::new(config, "host".to_string()).await.unwrap();
+        let decodes_both = PeerCapabilities {
+            video_codecs: vec!["h264".to_string(), "h265".to_string()],
+            ..PeerCapabilities::local()
+        };
+
+        let negotiate = |node_id: &'static str| {
+            let manager = manager.clone();
+            let caps = decodes_both.clone();
+            async move {
+                let session = manager
+                    .create_session(node_id.to_string(), node_id.to_string(), PROTOCOL_VERSION, None)
+                    .await
+                    .unwrap();
+                manager.negotiate_stream(&session.session_id, &caps).await.unwrap()
+            }
+        };
+        let tv = negotiate("tv").await;
+        assert_eq!((tv.codec.as_str(), tv.bitrate_mbps), ("h265", 40));
+        let laptop = negotiate("laptop").await;
+        assert_eq!(laptop.codec, "h264");
+        assert_eq!(laptop.bitrate_mbps, Config::default().streaming.bitrate_mbps);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_accepted_in_allowed_hours() {
+        // Ranges a couple of hours wide, so the test doesn't race the clock
+        let now = chrono::Local::now().naive_local();
+        let hours = |from: i64, to: i64| config::AllowedHours {
+            days: Vec::new(),
+            start: (now + chrono::Duration::hours(from)).format("%H:%M").to_string(),
+            end: (now + chrono::Duration::hours(to)).format("%H:%M").to_string(),
+        };
+        let open = |allowed_hours| async move {
+            let mut config = Config::default();
+            config.security.allowed_hours = allowed_hours;
+            let manager = SessionManager::new(config, "host".to_string()).await.unwrap();
+            manager.create_session("peer".to_string(), "Peer".to_string(), PROTOCOL_VERSION, None).await
+        };
+
+        assert!(open(vec![hours(-1, 1)]).await.is_ok());
+        let refused = open(vec![hours(2, 3)]).await.unwrap_err();
+        assert!(refused.to_string().contains("allowed_hours"), "{}", refused);
+        // Any one matching range is enough
+        assert!(open(vec![hours(2, 3), hours(-1, 1)]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn closing_hours_take_the_mouse_back() {
+        let now = chrono::Local::now().naive_local();
+        let hours = |from: i64, to: i64| config::AllowedHours {
+            days: Vec::new(),
+            start: (now + chrono::Duration::hours(from)).format("%H:%M").to_string(),
+            end: (now + chrono::Duration::hours(to)).format("%H:%M").to_string(),
+        };
+        let mut config = Config::default();
+        config.security.allowed_hours = vec![hours(-1, 1)];
+        config.security.close_sessions_outside_hours = true;
+        let (updates, config_rx) = watch::channel(config.clone());
+        let manager = SessionManager::new(config.clone(), "host".to_string())
+            .await
+            .unwrap()
+            .with_config_updates(config_rx);
+
+        let session = manager
+            .create_session("peer".to_string(), "Peer".to_string(), PROTOCOL_VERSION, None)
+            .await
+            .unwrap();
+        let mut outbound = manager.open_outbound(&session.session_id);
+        manager.transfer_mouse(&session.session_id, MouseOwner::Remote).await.unwrap();
+        let mut events = manager.subscribe();
+
+        config.security.allowed_hours = vec![hours(2, 3)];
+        updates.send(config).unwrap();
+        let run = tokio::spawn(manager.clone().run());
+        let closed = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let Ok(SessionEvent::Closed { session_id, reason, .. }) = events.recv().await {
+                    break (session_id, reason);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        run.abort();
+
+        assert_eq!(closed, (session.session_id.clone(), CloseReason::OutsideAllowedHours));
+        assert!(manager.get_session(&session.session_id).await.is_none());
+        assert_eq!(manager.current_mouse_owner().await, None);
+        // The connection's queue is gone, so it closes too
+        assert_eq!(outbound.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn stream_requests_reach_the_stream_server_or_are_refused() {
+        let manager = SessionManager::new(Config::default(), "host".to_string()).await.unwrap();
+        let session = manager
+            .create_session("laptop".to_string(), "laptop".to_string(), PROTOCOL_VERSION, None)
+            .await
+            .unwrap();
+        let mut outbound = manager.open_outbound(&session.session_id);
+        let start = ControlMessage {
+            session_id: session.session_id.clone(),
+            payload: Some(control_message::Payload::StreamRequest(wire::StreamRequest {
+                stream_id: "screen".to_string(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+
+        // Nothing serves streams
+        manager.handle_message(&session.session_id, &start).await;
+        match outbound.try_recv() {
+            Ok(control_message::Payload::StreamResponse(answer)) => {
+                assert_eq!(answer.stream_id, "screen");
+                assert_eq!(answer.status(), wire::stream_response::Status::NotSupported);
+            }
+            other => panic!("expected a stream response, got {:?}", other),
         }
+
+        let mut requests = manager.subscribe_stream_requests();
+        manager.handle_message(&session.session_id, &start).await;
+        let (session_id, request) = requests.try_recv().unwrap();
+        assert_eq!(session_id, session.session_id);
+        assert_eq!(request.stream_id, "screen");
+        assert!(outbound.try_recv().is_err());
     }
 }
@@ -0,0 +1,317 @@
+// Streams the local screen to peers that ask for it, one stream per output. A
+// StreamRequest START on a session starts capture of the requested output and
+// an encoder for that stream; the encoded video goes back over the session's
+// connection as VideoPackets. The streams of a session share its link, and
+// with it one adapted bitrate.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, debug, warn};
+
+use crate::bitrate::{self, SharedBitrate};
+use crate::capture::{Encoder, ScreenCapturer};
+use crate::platform::{self, Output};
+use crate::proto::{control_message, stream_request, stream_response, StreamRequest, StreamResponse, VideoPacket};
+use crate::session::{SessionEvent, SessionManager};
+use crate::supervisor::AbortOnDrop;
+
+// How long a new stream waits for its first frame, which sizes the encoder
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts, pauses and stops streams as peers request them
+pub struct StreamServer {
+    session_manager: SessionManager,
+    /// Detected on every START when not set
+    outputs: Option<Vec<Output>>,
+    /// Keyed by session and stream ID
+    streams: HashMap<(String, String), RunningStream>,
+    /// Keyed by session, while it has streams; adaptation stops with the entry
+    bitrates: HashMap<String, (SharedBitrate, AbortOnDrop)>,
+}
+
+struct RunningStream {
+    /// `None` captures the whole desktop, when no outputs could be detected
+    output: Option<Output>,
+    paused: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for RunningStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl StreamServer {
+    pub fn new(session_manager: SessionManager) -> Self {
+        Self {
+            session_manager,
+            outputs: None,
+            streams: HashMap::new(),
+            bitrates: HashMap::new(),
+        }
+    }
+
+    /// Offer these outputs instead of the detected ones
+    pub fn with_outputs(mut self, outputs: Vec<Output>) -> Self {
+        self.outputs = Some(outputs);
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut requests = self.session_manager.subscribe_stream_requests();
+        let mut events = self.session_manager.subscribe();
+        info!("✓ Streaming available to peers");
+
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Ok((session_id, request)) => self.handle(session_id, request).await,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("⚠ Missed {} stream requests", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                event = events.recv() => {
+                    if let Ok(SessionEvent::Closed { session_id, .. }) = event {
+                        self.streams.retain(|(session, _), _| *session != session_id);
+                    }
+                }
+            }
+            // Streams that failed or lost their connection
+            self.streams.retain(|_, stream| !stream.task.is_finished());
+            self.bitrates.retain(|session_id, _| self.streams.keys().any(|(session, _)| session == session_id));
+        }
+    }
+
+    async fn handle(&mut self, session_id: String, request: StreamRequest) {
+        let key = (session_id.clone(), request.stream_id.clone());
+        match request.r#type() {
+            stream_request::Type::Start => {
+                // Starting a running stream again restarts it
+                self.streams.remove(&key);
+                let output = match self.output(&request.output) {
+                    Ok(output) => output,
+                    Err(e) => {
+                        warn!("⚠ Refused stream {} for session {}: {:#}", request.stream_id, session_id, e);
+                        let answer =
+                            StreamResponse::answer(&request.stream_id, stream_response::Status::Failed, format!("{:#}", e));
+                        let _ = self.session_manager.send_to(&session_id, control_message::Payload::StreamResponse(answer)).await;
+                        return;
+                    }
+                };
+                let (bitrate, _) = self.bitrates.entry(session_id.clone()).or_insert_with(|| {
+                    let bitrate = SharedBitrate::new();
+                    let adapt = tokio::spawn(bitrate::run(bitrate.clone(), self.session_manager.clone(), session_id.clone()));
+                    (bitrate, AbortOnDrop::new(&adapt))
+                });
+                let bitrate = bitrate.clone();
+                let (paused, paused_rx) = watch::channel(false);
+                let task =
+                    tokio::spawn(serve(self.session_manager.clone(), session_id, request, output.clone(), bitrate, paused_rx));
+                self.streams.insert(key, RunningStream { output, paused, task });
+            }
+            stream_request::Type::Stop => {
+                if let Some(stream) = self.streams.remove(&key) {
+                    let output = stream.output.as_ref().map_or("the desktop", |output| output.name.as_str());
+                    info!("Stream {} of {} for session {} stopped", key.1, output, key.0);
+                }
+            }
+            stream_request::Type::Pause | stream_request::Type::Resume => {
+                let pause = request.r#type() == stream_request::Type::Pause;
+                match self.streams.get(&key) {
+                    Some(stream) => {
+                        stream.paused.send_replace(pause);
+                    }
+                    None => debug!("No stream {} for session {} to pause or resume", key.1, key.0),
+                }
+            }
+        }
+    }
+
+    /// The output named in a request, or the primary one for an empty name
+    fn output(&self, name: &str) -> Result<Option<Output>> {
+        let outputs = self.outputs.clone().unwrap_or_else(platform::outputs);
+        if name.is_empty() {
+            return Ok(outputs.into_iter().next());
+        }
+        match outputs.iter().find(|output| output.name == name) {
+            Some(output) => Ok(Some(output.clone())),
+            None if outputs.is_empty() => bail!("No output {}; none were detected", name),
+            None => {
+                let names: Vec<&str> = outputs.iter().map(|output| output.name.as_str()).collect();
+                bail!("No output {}; this host has {}", name, names.join(", "))
+            }
+        }
+    }
+}
+
+/// Run one stream, telling the peer if it can't be started or fails
+async fn serve(
+    session_manager: SessionManager,
+    session_id: String,
+    request: StreamRequest,
+    output: Option<Output>,
+    bitrate: SharedBitrate,
+    paused: watch::Receiver<bool>,
+) {
+    if let Err(e) = stream(&session_manager, &session_id, &request, output, &bitrate, paused).await {
+        warn!("⚠ Stream {} for session {} failed: {:#}", request.stream_id, session_id, e);
+        let answer = StreamResponse::answer(&request.stream_id, stream_response::Status::Failed, format!("{:#}", e));
+        let _ = session_manager.send_to(&session_id, control_message::Payload::StreamResponse(answer)).await;
+    }
+}
+
+/// Capture, encode and send until the stream is stopped or the peer can't be
+/// reached any more
+async fn stream(
+    session_manager: &SessionManager,
+    session_id: &str,
+    request: &StreamRequest,
+    output: Option<Output>,
+    bitrate: &SharedBitrate,
+    mut paused: watch::Receiver<bool>,
+) -> Result<()> {
+    let Some(session) = session_manager.get_session(session_id).await else {
+        bail!("Unknown session {}", session_id);
+    };
+    let mut streaming = session.streaming;
+    // The peer may ask for a lower frame rate than the session allows
+    if let Some(max_fps) = request.params.as_ref().map(|params| params.max_fps).filter(|&fps| fps > 0) {
+        streaming.max_fps = streaming.max_fps.min(max_fps);
+    }
+
+    let output_name = output.as_ref().map_or("the desktop".to_string(), |output| output.name.clone());
+    let (mut capturer, mut frames) = ScreenCapturer::new(streaming.clone(), output)?;
+    capturer.start()?;
+    let first = tokio::time::timeout(FIRST_FRAME_TIMEOUT, frames.recv())
+        .await
+        .context("No frame was captured")?
+        .context("Capture stopped before the first frame")?;
+    let (width, height) = (first.width, first.height);
+    let (encoder, mut packets) = Encoder::new(&streaming, width, height)?;
+    let encoder = Arc::new(encoder);
+    // Its share of the session's bitrate, until the stream drops the encoder
+    bitrate.add(&encoder);
+    encoder.encode(first)?;
+
+    let ready = StreamResponse::answer(&request.stream_id, stream_response::Status::Ready, "");
+    session_manager.send_to(session_id, control_message::Payload::StreamResponse(ready)).await?;
+    info!("✓ Streaming {} at {}x{} to session {} ({})", output_name, width, height, session_id, request.stream_id);
+
+    loop {
+        tokio::select! {
+            frame = frames.recv() => {
+                let Some(frame) = frame else {
+                    bail!("Screen capture stopped");
+                };
+                if *paused.borrow() {
+                    continue;
+                }
+                encoder.encode(frame)?;
+            }
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    bail!("The encoder stopped");
+                };
+                let packet = VideoPacket {
+                    stream_id: request.stream_id.clone(),
+                    data: packet.data,
+                    is_keyframe: packet.is_keyframe,
+                    pts_us: packet.pts.as_micros() as u64,
+                    width,
+                    height,
+                };
+                if let Err(e) = session_manager.send_to(session_id, control_message::Payload::VideoPacket(packet)).await {
+                    debug!("Stream {} for session {} ended: {:#}", request.stream_id, session_id, e);
+                    return Ok(());
+                }
+            }
+            changed = paused.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                // The peer's decoder needs a keyframe to pick up again
+                if !*paused.borrow() {
+                    encoder.force_keyframe();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::proto::PROTOCOL_VERSION;
+
+    fn output(name: &str, x: i32, width: u32, height: u32) -> Output {
+        Output { name: name.to_string(), position: Some((x, 0)), width, height, primary: x == 0 }
+    }
+
+    fn request(kind: stream_request::Type, stream_id: &str, output: &str) -> StreamRequest {
+        StreamRequest {
+            r#type: kind as i32,
+            stream_id: stream_id.to_string(),
+            output: output.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn running(server: &StreamServer) -> Vec<(String, (u32, u32))> {
+        let mut running: Vec<_> = server.streams.iter()
+            .map(|((_, stream_id), stream)| {
+                let output = stream.output.as_ref().unwrap();
+                (stream_id.clone(), (output.width, output.height))
+            })
+            .collect();
+        running.sort();
+        running
+    }
+
+    #[tokio::test]
+    async fn each_output_streams_on_its_own() {
+        let manager = SessionManager::new(Config::default(), "host".to_string()).await.unwrap();
+        let session = manager
+            .create_session("viewer".to_string(), "viewer".to_string(), PROTOCOL_VERSION, None)
+            .await
+            .unwrap();
+        let mut outbound = manager.open_outbound(&session.session_id);
+        let mut server = StreamServer::new(manager)
+            .with_outputs(vec![output("DP-1", 0, 2560, 1440), output("HDMI-1", 2560, 1920, 1080)]);
+        let id = session.session_id;
+
+        server.handle(id.clone(), request(stream_request::Type::Start, "left", "DP-1")).await;
+        server.handle(id.clone(), request(stream_request::Type::Start, "right", "HDMI-1")).await;
+        assert_eq!(running(&server), vec![
+            ("left".to_string(), (2560, 1440)),
+            ("right".to_string(), (1920, 1080)),
+        ]);
+
+        // Stopping one leaves the other running
+        server.handle(id.clone(), request(stream_request::Type::Stop, "left", "")).await;
+        assert_eq!(running(&server), vec![("right".to_string(), (1920, 1080))]);
+        server.handle(id.clone(), request(stream_request::Type::Start, "left", "")).await;
+        assert_eq!(running(&server), vec![
+            ("left".to_string(), (2560, 1440)),
+            ("right".to_string(), (1920, 1080)),
+        ]);
+
+        // An output the host doesn't have is refused
+        server.handle(id, request(stream_request::Type::Start, "tv", "HDMI-2")).await;
+        match outbound.try_recv() {
+            Ok(control_message::Payload::StreamResponse(answer)) => {
+                assert_eq!(answer.stream_id, "tv");
+                assert_eq!(answer.status(), stream_response::Status::Failed);
+                assert!(answer.error_message.contains("DP-1, HDMI-1"), "{}", answer.error_message);
+            }
+            other => panic!("expected a stream response, got {:?}", other),
+        }
+        assert_eq!(running(&server).len(), 2);
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+use tracing::{info, warn, error};
+
+use crate::metrics::metrics;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// A subsystem that stays up this long is considered healthy again
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+// Crash-loop guard: give up after this many restarts inside the window
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// Liveness signal a supervised subsystem ticks from its main loop
+#[derive(Clone)]
+pub struct Heartbeat {
+    epoch: Instant,
+    last_beat_ms: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last_beat_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_ms.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn since_last_beat(&self) -> Duration {
+        let last = Duration::from_millis(self.last_beat_ms.load(Ordering::Relaxed));
+        self.epoch.elapsed().saturating_sub(last)
+    }
+}
+
+/// One-shot signal telling subsystems the daemon is going down
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    notify: Arc<Notify>,
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `trigger` has been called, including before this was awaited
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_triggered() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Aborts a task when dropped, so a task that is aborted itself takes the
+/// tasks it spawned down with it
+pub struct AbortOnDrop(AbortHandle);
+
+impl AbortOnDrop {
+    pub fn new<T>(task: &JoinHandle<T>) -> Self {
+        Self(task.abort_handle())
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+enum Outcome {
+    Exited,
+    Failed(anyhow::Error),
+    Panicked(String),
+    Hung,
+}
+
+impl From<Result<Result<()>, JoinError>> for Outcome {
+    fn from(result: Result<Result<()>, JoinError>) -> Self {
+        match result {
+            Ok(Ok(())) => Outcome::Exited,
+            Ok(Err(e)) => Outcome::Failed(e),
+            Err(e) if e.is_panic() => {
+                let payload = e.into_panic();
+                let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Outcome::Panicked(message)
+            }
+            Err(_) => Outcome::Exited,
+        }
+    }
+}
+
+/// Restarts daemon subsystems that terminate, panic or stop heartbeating
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    restarts: Arc<Mutex<HashMap<&'static str, u64>>>,
+    shutdown: Shutdown,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let subsystems exit for good once `shutdown` fires
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Keep a subsystem running. `factory` builds a fresh instance for every (re)start.
+    /// With `hang_timeout` set, a task that doesn't beat its heartbeat in time is aborted
+    /// and restarted. The returned handle only completes once the supervisor gives up;
+    /// aborting it stops the subsystem too.
+    pub fn watch<F, Fut>(
+        &self,
+        name: &'static str,
+        hang_timeout: Option<Duration>,
+        mut factory: F,
+    ) -> JoinHandle<()>
+    where
+        F: FnMut(Heartbeat) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let restarts = Arc::clone(&self.restarts);
+        let shutdown = self.shutdown.clone();
+
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+
+            loop {
+                let heartbeat = Heartbeat::new();
+                let started = Instant::now();
+                let mut task = tokio::spawn(factory(heartbeat.clone()));
+                let _running = AbortOnDrop::new(&task);
+
+                let outcome = match hang_timeout {
+                    Some(timeout) => Self::wait_or_hang(&mut task, &heartbeat, timeout).await,
+                    None => (&mut task).await.into(),
+                };
+
+                if shutdown.is_triggered() {
+                    return;
+                }
+
+                match outcome {
+                    Outcome::Exited => error!("{} task terminated", name),
+                    Outcome::Failed(e) => error!("{} error: {}", name, e),
+                    Outcome::Panicked(msg) => error!("{} panicked: {}", name, msg),
+                    Outcome::Hung => error!("{} stopped responding, aborting it", name),
+                }
+
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = INITIAL_BACKOFF;
+                }
+
+                let now = Instant::now();
+                recent_restarts.retain(|t| now.duration_since(*t) < RESTART_WINDOW);
+                if recent_restarts.len() >= MAX_RESTARTS {
+                    error!("{} failed {} times within {:?}, giving up",
+                        name, recent_restarts.len(), RESTART_WINDOW);
+                    return;
+                }
+                recent_restarts.push_back(now);
+
+                let count = {
+                    let mut restarts = restarts.lock();
+                    let count = restarts.entry(name).or_insert(0);
+                    *count += 1;
+                    *count
+                };
+                metrics().subsystem_restarted(name);
+
+                warn!("Restarting {} in {:?} (restart #{})", name, backoff, count);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                info!("Restarting {}", name);
+            }
+        })
+    }
+
+    async fn wait_or_hang(
+        task: &mut JoinHandle<Result<()>>,
+        heartbeat: &Heartbeat,
+        timeout: Duration,
+    ) -> Outcome {
+        let mut check = tokio::time::interval(timeout / 2);
+
+        loop {
+            tokio::select! {
+                result = &mut *task => return result.into(),
+                _ = check.tick() => {
+                    if heartbeat.since_last_beat() > timeout {
+                        task.abort();
+                        return Outcome::Hung;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of restarts per subsystem since the daemon started
+    pub fn restart_counts(&self) -> HashMap<&'static str, u64> {
+        self.restarts.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn panicking_task_is_restarted() {
+        let supervisor = Supervisor::new();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let restarted = Arc::new(Notify::new());
+
+        let (counter, signal) = (starts.clone(), restarted.clone());
+        let handle = supervisor.watch("panicky", None, move |_| {
+            let (counter, signal) = (counter.clone(), signal.clone());
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("first run fails");
+                }
+                signal.notify_one();
+                std::future::pending::<Result<()>>().await
+            }
+        });
+
+        // One backoff period, plus slack for a slow machine
+        tokio::time::timeout(INITIAL_BACKOFF * 5, restarted.notified())
+            .await
+            .expect("the task wasn't restarted");
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(supervisor.restart_counts().get("panicky"), Some(&1));
+        assert!(metrics().render().contains("mirage_subsystem_restarts_total{subsystem=\"panicky\"} 1"));
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn aborting_the_watch_stops_the_task() {
+        let supervisor = Supervisor::new();
+        let (alive, mut running) = tokio::sync::mpsc::channel::<()>(1);
+        let handle = supervisor.watch("aborted", None, move |_| {
+            let alive = alive.clone();
+            async move {
+                alive.send(()).await?;
+                std::future::pending::<Result<()>>().await
+            }
+        });
+        running.recv().await.expect("the task didn't start");
+
+        handle.abort();
+        // The last sender goes with the task
+        let stopped = tokio::time::timeout(Duration::from_secs(1), running.recv()).await;
+        assert!(matches!(stopped, Ok(None)), "the task is still running");
+    }
+}
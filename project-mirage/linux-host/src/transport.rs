@@ -0,0 +1,284 @@
+// Transports for the control channel, picked with `network.transport`.
+// TCP carries everything on one TLS stream. QUIC opens the control stream plus
+// a stream per extra channel, so a lost video packet can't hold up a click.
+
+use anyhow::{bail, Context, Result};
+use rustls::{ClientConfig, ServerConfig};
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::debug;
+
+use crate::config::{Config, NetworkConfig, Transport};
+use crate::network::Frame;
+use crate::security;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A completed handshake with a peer that connected to us
+pub type Handshake = BoxFuture<'static, Result<Link>>;
+
+/// Bidirectional byte stream to a peer, whatever carries it
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ByteStream for T {}
+
+/// Traffic that gets a stream of its own when the transport supports it.
+/// The discriminant is the first byte sent on the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Input = 1,
+    Video = 2,
+}
+
+/// An established connection: the control stream and whatever else the
+/// transport can open next to it
+pub struct Link {
+    pub control: Box<dyn ByteStream>,
+    pub peer_addr: SocketAddr,
+    pub channels: Channels,
+    /// Framing for every stream of the link
+    pub frame: Frame,
+}
+
+/// How the path to a peer is doing. Packet counts are running totals since
+/// the connection opened and stay 0 where the transport doesn't report them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathStats {
+    pub rtt: Duration,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+}
+
+/// Opens and accepts the extra per-channel streams of a link. On TCP there are
+/// none and everything stays on the control stream.
+#[derive(Clone, Default)]
+pub struct Channels(Option<quinn::Connection>);
+
+impl Channels {
+    /// A stream of its own for `channel`, or `None` if the caller should use
+    /// the control stream
+    pub async fn open(&self, channel: Channel) -> Result<Option<Box<dyn AsyncWrite + Unpin + Send>>> {
+        let Some(ref connection) = self.0 else {
+            return Ok(None);
+        };
+        let mut stream = connection.open_uni().await.context("Failed to open a QUIC stream")?;
+        stream.write_u8(channel as u8).await?;
+        Ok(Some(Box::new(stream)))
+    }
+
+    /// Round-trip time and packet counts from the QUIC connection. TCP keeps
+    /// these to itself, so `None` there.
+    pub fn stats(&self) -> Option<PathStats> {
+        let connection = self.0.as_ref()?;
+        let path = connection.stats().path;
+        Some(PathStats {
+            rtt: connection.rtt(),
+            sent_packets: path.sent_packets,
+            lost_packets: path.lost_packets,
+        })
+    }
+
+    /// Wait for the peer to open a channel stream. Never completes on
+    /// transports without them.
+    pub async fn accept(&self) -> Result<(Channel, Box<dyn AsyncRead + Unpin + Send>)> {
+        let Some(ref connection) = self.0 else {
+            return std::future::pending().await;
+        };
+        let mut stream = connection.accept_uni().await?;
+        let channel = match stream.read_u8().await? {
+            1 => Channel::Input,
+            2 => Channel::Video,
+            tag => bail!("Unknown channel {}", tag),
+        };
+        Ok((channel, Box::new(stream)))
+    }
+}
+
+/// Dials peers over the configured transport
+pub trait Connector: Send + Sync {
+    /// Connect to `addr`. Peers are reached by address, so their certificate
+    /// must cover the IP.
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, Result<Link>>;
+}
+
+/// Accepts connections from peers
+pub trait Acceptor: Send {
+    /// Wait for the next peer. The returned handshake is finished separately
+    /// so a slow peer doesn't hold up the accept loop.
+    fn accept(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Handshake)>>;
+
+    fn local_addr(&self) -> SocketAddr;
+}
+
+/// A connector for `network.transport`
+pub fn connector(config: &Config) -> Result<Arc<dyn Connector>> {
+    let tls = security::client_config(&config.security)?;
+    let frame = Frame::new(config.network.max_frame_size);
+    Ok(match config.network.transport {
+        Transport::Tcp => Arc::new(TcpConnector { tls: TlsConnector::from(tls), frame }),
+        Transport::Quic => Arc::new(QuicConnector::new(tls, frame)?),
+    })
+}
+
+/// Listen on `network.control_port` over `network.transport`. [::] accepts
+/// IPv4 as well on dual-stack hosts; IPv4-only hosts fall back to 0.0.0.0.
+pub async fn listen(network: &NetworkConfig, tls: Arc<ServerConfig>) -> Result<Box<dyn Acceptor>> {
+    let port = network.control_port;
+    let frame = Frame::new(network.max_frame_size);
+    let v6_addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let v4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+
+    match network.transport {
+        Transport::Tcp => {
+            let listener = match TcpListener::bind(v6_addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    debug!("IPv6 bind failed ({}), listening on IPv4 only", e);
+                    TcpListener::bind(v4_addr)
+                        .await
+                        .with_context(|| format!("Failed to bind control port {}", v4_addr))?
+                }
+            };
+            Ok(Box::new(TcpAcceptor { listener, tls: TlsAcceptor::from(tls), frame }))
+        }
+        Transport::Quic => {
+            let server_config = quinn::ServerConfig::with_crypto(tls);
+            let endpoint = match quinn::Endpoint::server(server_config.clone(), v6_addr) {
+                Ok(endpoint) => endpoint,
+                Err(e) => {
+                    debug!("IPv6 bind failed ({}), listening on IPv4 only", e);
+                    quinn::Endpoint::server(server_config, v4_addr)
+                        .with_context(|| format!("Failed to bind QUIC control port {}", v4_addr))?
+                }
+            };
+            Ok(Box::new(QuicAcceptor { endpoint, frame }))
+        }
+    }
+}
+
+struct TcpConnector {
+    tls: TlsConnector,
+    frame: Frame,
+}
+
+impl Connector for TcpConnector {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, Result<Link>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("Failed to connect to {}", addr))?;
+            stream.set_nodelay(true)?;
+
+            let stream = self.tls.connect(ServerName::IpAddress(addr.ip()), stream)
+                .await
+                .context("TLS handshake failed")?;
+            Ok(Link {
+                control: Box::new(stream),
+                peer_addr: addr,
+                channels: Channels(None),
+                frame: self.frame,
+            })
+        })
+    }
+}
+
+struct TcpAcceptor {
+    listener: TcpListener,
+    tls: TlsAcceptor,
+    frame: Frame,
+}
+
+impl Acceptor for TcpAcceptor {
+    fn accept(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Handshake)>> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.listener.accept()
+                .await
+                .context("Failed to accept control connection")?;
+            let tls = self.tls.clone();
+            let frame = self.frame;
+            let handshake: Handshake = Box::pin(async move {
+                let stream = tls.accept(stream).await.context("TLS handshake failed")?;
+                Ok(Link {
+                    control: Box::new(stream),
+                    peer_addr,
+                    channels: Channels(None),
+                    frame,
+                })
+            });
+            Ok((peer_addr, handshake))
+        })
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
+
+struct QuicConnector {
+    endpoint: quinn::Endpoint,
+    frame: Frame,
+}
+
+impl QuicConnector {
+    fn new(tls: Arc<ClientConfig>, frame: Frame) -> Result<Self> {
+        let mut endpoint = quinn::Endpoint::client(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+            .or_else(|_| quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))
+            .context("Failed to open a QUIC client socket")?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(tls));
+        Ok(Self { endpoint, frame })
+    }
+}
+
+impl Connector for QuicConnector {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, Result<Link>> {
+        Box::pin(async move {
+            let connection = self.endpoint.connect(addr, &addr.ip().to_string())?
+                .await
+                .with_context(|| format!("QUIC handshake with {} failed", addr))?;
+            let (send, recv) = connection.open_bi().await.context("Failed to open the control stream")?;
+            Ok(Link {
+                control: Box::new(tokio::io::join(recv, send)),
+                peer_addr: addr,
+                channels: Channels(Some(connection)),
+                frame: self.frame,
+            })
+        })
+    }
+}
+
+struct QuicAcceptor {
+    endpoint: quinn::Endpoint,
+    frame: Frame,
+}
+
+impl Acceptor for QuicAcceptor {
+    fn accept(&mut self) -> BoxFuture<'_, Result<(SocketAddr, Handshake)>> {
+        Box::pin(async move {
+            let connecting = self.endpoint.accept().await.context("QUIC endpoint closed")?;
+            let peer_addr = connecting.remote_address();
+            let frame = self.frame;
+            let handshake: Handshake = Box::pin(async move {
+                let connection = connecting.await.context("QUIC handshake failed")?;
+                // The peer's hello opens the control stream
+                let (send, recv) = connection.accept_bi().await.context("Peer opened no control stream")?;
+                Ok(Link {
+                    control: Box::new(tokio::io::join(recv, send)),
+                    peer_addr,
+                    channels: Channels(Some(connection)),
+                    frame,
+                })
+            });
+            Ok((peer_addr, handshake))
+        })
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.endpoint.local_addr().unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
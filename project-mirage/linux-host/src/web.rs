@@ -0,0 +1,71 @@
+// Read-only status dashboard on `network.web_ui_port`, built with the `web-ui`
+// feature. It only reads the session and peer tables the daemon already
+// shares, and listens on localhost since nothing on it is authenticated.
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::ctl::{self, SessionSummary};
+use crate::discovery::PeerDevice;
+use crate::metrics::{self, MetricsSnapshot};
+use crate::session::SessionManager;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Everything the page shows, as served at `/api/status`
+#[derive(Serialize)]
+struct Status {
+    /// Session ID of the peer with the mouse, if any
+    mouse_owner: Option<String>,
+    sessions: Vec<SessionSummary>,
+    peers: Vec<PeerDevice>,
+    metrics: MetricsSnapshot,
+}
+
+#[derive(Clone)]
+pub struct Dashboard {
+    sessions: SessionManager,
+    peers: Arc<RwLock<HashMap<String, PeerDevice>>>,
+}
+
+impl Dashboard {
+    pub fn new(sessions: SessionManager, peers: Arc<RwLock<HashMap<String, PeerDevice>>>) -> Self {
+        Self { sessions, peers }
+    }
+
+    /// Serve the dashboard on `127.0.0.1:port` until the listener fails
+    pub async fn serve(self, port: u16) -> Result<()> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind dashboard on {}", addr))?;
+        info!("✓ Dashboard at http://{}/", addr);
+
+        let app = Router::new()
+            .route("/", get(|| async { Html(PAGE) }))
+            .route("/api/status", get(status))
+            .with_state(self);
+        axum::serve(listener, app).await.context("Dashboard server failed")
+    }
+}
+
+async fn status(State(dashboard): State<Dashboard>) -> Json<Status> {
+    let mut peers: Vec<PeerDevice> = dashboard.peers.read().await.values().cloned().collect();
+    peers.sort_by(|a, b| a.node_name.cmp(&b.node_name));
+    Json(Status {
+        mouse_owner: dashboard.sessions.current_mouse_owner().await,
+        sessions: ctl::session_summaries(&dashboard.sessions).await,
+        peers,
+        metrics: metrics::metrics().snapshot(),
+    })
+}